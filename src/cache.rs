@@ -0,0 +1,219 @@
+//! Optional read-ahead and write-back cache for namespaces.
+
+use alloc::sync::Arc;
+
+use crate::device::Namespace;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Cache configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Number of blocks read ahead on a miss (0 = disabled)
+    pub readahead_blocks: usize,
+    /// Number of blocks of write-back buffer (0 = disabled)
+    pub write_blocks: usize,
+}
+
+impl CacheConfig {
+    /// Size of the cache memory needed for a block size.
+    pub fn memory_size(&self, block_size: u64) -> usize {
+        (self.readahead_blocks + self.write_blocks) * block_size as usize
+    }
+}
+
+/// Read-ahead window state.
+struct ReadAhead {
+    /// First cached block
+    lba: u64,
+    /// Number of valid blocks
+    blocks: usize,
+}
+
+/// Dirty write-back run state.
+struct WriteBack {
+    /// First buffered block
+    lba: u64,
+    /// Number of buffered blocks
+    blocks: usize,
+}
+
+/// A namespace wrapped with a read-ahead window and a write-back buffer.
+///
+/// Small reads that miss the window fill it with the following blocks,
+/// so re-reads of adjacent blocks are served from memory. Sequential
+/// small writes are coalesced into one command. Buffered writes reach
+/// the device on `flush`, when the run cannot be extended, or when an
+/// overlapping read needs them.
+///
+/// The cache memory is supplied by the caller and is used for DMA, so it
+/// must be DMA-capable and page aligned, and the read-ahead part must be
+/// a whole number of pages when write-back is enabled.
+pub struct CachedNamespace<'a, A: Allocator> {
+    /// Underlying namespace
+    ns: Arc<Namespace<A>>,
+    /// Cache configuration
+    config: CacheConfig,
+    /// Read-ahead window memory
+    readahead_buf: &'a mut [u8],
+    /// Write-back buffer memory
+    write_buf: &'a mut [u8],
+    /// Read-ahead window state
+    readahead: Option<ReadAhead>,
+    /// Dirty write-back run
+    dirty: Option<WriteBack>,
+}
+
+impl<'a, A: Allocator> CachedNamespace<'a, A> {
+    /// Wrap a namespace using `memory` as cache storage.
+    pub fn new(ns: Arc<Namespace<A>>, config: CacheConfig, memory: &'a mut [u8]) -> Result<Self> {
+        let block_size = ns.block_size() as usize;
+        if memory.len() < config.memory_size(ns.block_size()) {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let readahead_len = config.readahead_blocks * block_size;
+        if config.write_blocks != 0 && !readahead_len.is_multiple_of(4096) {
+            return Err(Error::NotAlignedToPage);
+        }
+
+        let (readahead_buf, rest) = memory.split_at_mut(readahead_len);
+        let write_buf = &mut rest[..config.write_blocks * block_size];
+
+        Ok(Self {
+            ns,
+            config,
+            readahead_buf,
+            write_buf,
+            readahead: None,
+            dirty: None,
+        })
+    }
+
+    /// Get the underlying namespace.
+    pub fn namespace(&self) -> &Arc<Namespace<A>> {
+        &self.ns
+    }
+
+    /// Read from the namespace through the cache.
+    pub fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let block_size = self.ns.block_size() as usize;
+        if !buf.len().is_multiple_of(block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        let blocks = buf.len() / block_size;
+
+        // Buffered writes must reach the device before it is read
+        if self.dirty.as_ref().is_some_and(|d| overlaps(d.lba, d.blocks, lba, blocks)) {
+            self.write_back()?;
+        }
+
+        if let Some(offset) = self.readahead_offset(lba, blocks) {
+            buf.copy_from_slice(&self.readahead_buf[offset..offset + buf.len()]);
+            return Ok(());
+        }
+
+        let window = self.window_blocks(lba);
+        if blocks >= window {
+            return self.ns.read(lba, buf);
+        }
+
+        self.readahead = None;
+        self.ns.read(lba, &mut self.readahead_buf[..window * block_size])?;
+        self.readahead = Some(ReadAhead { lba, blocks: window });
+
+        buf.copy_from_slice(&self.readahead_buf[..buf.len()]);
+        Ok(())
+    }
+
+    /// Write to the namespace through the cache.
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let block_size = self.ns.block_size() as usize;
+        if !buf.len().is_multiple_of(block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        let blocks = buf.len() / block_size;
+
+        // Drop read-ahead data the write makes stale
+        if self.readahead.as_ref().is_some_and(|r| overlaps(r.lba, r.blocks, lba, blocks)) {
+            self.readahead = None;
+        }
+
+        // Extend the dirty run if the write follows it
+        if let Some(dirty) = self.dirty.as_mut()
+            && dirty.lba + dirty.blocks as u64 == lba
+            && dirty.blocks + blocks <= self.config.write_blocks
+        {
+            let offset = dirty.blocks * block_size;
+            self.write_buf[offset..offset + buf.len()].copy_from_slice(buf);
+            dirty.blocks += blocks;
+            return Ok(());
+        }
+
+        self.write_back()?;
+
+        if blocks > self.config.write_blocks {
+            return self.ns.write(lba, buf);
+        }
+
+        self.write_buf[..buf.len()].copy_from_slice(buf);
+        self.dirty = Some(WriteBack { lba, blocks });
+        Ok(())
+    }
+
+    /// Flush barrier.
+    ///
+    /// Writes back buffered data and commits the device's volatile
+    /// write cache, so all writes issued before it are durable.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_back()?;
+        self.ns.flush()
+    }
+
+    /// Drop all cached read data.
+    pub fn invalidate(&mut self) {
+        self.readahead = None;
+    }
+
+    /// Write the dirty run to the device.
+    fn write_back(&mut self) -> Result<()> {
+        let Some(dirty) = self.dirty.take() else {
+            return Ok(());
+        };
+
+        let len = dirty.blocks * self.ns.block_size() as usize;
+        if let Err(e) = self.ns.write(dirty.lba, &self.write_buf[..len]) {
+            self.dirty = Some(dirty);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Byte offset of a range in the read-ahead window, if fully cached.
+    fn readahead_offset(&self, lba: u64, blocks: usize) -> Option<usize> {
+        let window = self.readahead.as_ref()?;
+        let fits = lba >= window.lba && lba + blocks as u64 <= window.lba + window.blocks as u64;
+        fits.then(|| (lba - window.lba) as usize * self.ns.block_size() as usize)
+    }
+
+    /// Number of blocks to read ahead starting at `lba`.
+    fn window_blocks(&self, lba: u64) -> usize {
+        let block_size = self.ns.block_size() as usize;
+        let remaining = self.ns.block_count().saturating_sub(lba) as usize;
+        self.config.readahead_blocks
+            .min(self.ns.max_transfer_size() / block_size)
+            .min(remaining)
+    }
+}
+
+impl<A: Allocator> Drop for CachedNamespace<'_, A> {
+    fn drop(&mut self) {
+        // Best effort; call `flush` to observe errors
+        let _ = self.write_back();
+    }
+}
+
+/// Check whether two block ranges overlap.
+fn overlaps(a_lba: u64, a_blocks: usize, b_lba: u64, b_blocks: usize) -> bool {
+    a_lba < b_lba + b_blocks as u64 && b_lba < a_lba + a_blocks as u64
+}
@@ -0,0 +1,13 @@
+//! Host-provided time source.
+
+/// Provides a monotonic time source to the driver.
+///
+/// The driver has no notion of time on its own, so anything that needs
+/// timestamps (power state history, timeouts, latency measurement) asks
+/// the host through this trait. Without a clock, timestamps read as zero.
+pub trait Clock: Send + Sync {
+    /// Returns the current monotonic time in microseconds.
+    ///
+    /// The epoch is arbitrary, but the value must never go backwards.
+    fn now_us(&self) -> u64;
+}
@@ -1,3 +1,7 @@
+use crate::endian::le_struct;
+use crate::error::Result;
+use crate::protection::ProtectionCheck;
+
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C, packed)]
 pub(crate) struct Command {
@@ -9,8 +13,8 @@ pub(crate) struct Command {
     cmd_id: u16,
     /// Namespace ID
     ns_id: u32,
-    /// Reserved
-    _rsvd: u64,
+    /// Command dwords 2 and 3
+    cmd_2_3: u64,
     /// Metadata pointer
     md_ptr: u64,
     /// Data pointer (PRP or SGL)
@@ -29,6 +33,139 @@ pub(crate) struct Command {
     cmd_15: u32,
 }
 
+le_struct!(Command {
+    cmd_id, ns_id, cmd_2_3, md_ptr, data_ptr, cmd_10, cmd_11, cmd_12, cmd_13, cmd_14, cmd_15,
+});
+
+/// A range of a Dataset Management command.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C, packed)]
+pub(crate) struct DsmRange {
+    /// Context attributes
+    context_attributes: u32,
+    /// Number of logical blocks
+    length: u32,
+    /// Starting LBA
+    slba: u64,
+}
+
+le_struct!(DsmRange { context_attributes, length, slba });
+
+impl DsmRange {
+    /// Describe `length` blocks starting at `slba`, without context attributes.
+    pub fn new(slba: u64, length: u32) -> Self {
+        Self { slba, length, ..Default::default() }
+    }
+}
+
+/// A source range of a Copy command, descriptor format 0.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C, packed)]
+pub(crate) struct CopyRange {
+    _rsvd0: u64,
+    /// Starting LBA
+    slba: u64,
+    /// Number of logical blocks, 0-based
+    nlb: u16,
+    _rsvd1: [u8; 6],
+    /// Expected initial logical block reference tag
+    eilbrt: u32,
+    /// Expected logical block application tag
+    elbat: u16,
+    /// Expected logical block application tag mask
+    elbatm: u16,
+}
+
+le_struct!(CopyRange { slba, nlb, eilbrt, elbat, elbatm });
+
+impl CopyRange {
+    /// Describe `nlb + 1` blocks starting at `slba`, without protection tags.
+    pub fn new(slba: u64, nlb: u16) -> Self {
+        Self { slba, nlb, ..Default::default() }
+    }
+}
+
+/// Priority of an I/O submission queue (QPRIO).
+///
+/// Only used with weighted round robin with urgent priority class arbitration,
+/// see `DeviceConfig::weighted_round_robin`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePriority {
+    /// Urgent priority class, served before all weighted queues
+    #[default]
+    Urgent = 0,
+    /// High priority weight
+    High = 1,
+    /// Medium priority weight
+    Medium = 2,
+    /// Low priority weight
+    Low = 3,
+}
+
+/// Expected access frequency for a range of blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessFrequency {
+    /// No information provided
+    #[default]
+    Unknown = 0,
+    /// Typical number of reads and writes
+    Typical = 1,
+    /// Infrequent writes and infrequent reads
+    InfrequentWritesInfrequentReads = 2,
+    /// Infrequent writes and frequent reads
+    InfrequentWritesFrequentReads = 3,
+    /// Frequent writes and infrequent reads
+    FrequentWritesInfrequentReads = 4,
+    /// Frequent writes and frequent reads
+    FrequentWritesFrequentReads = 5,
+    /// One-time read or write
+    OneTime = 6,
+    /// Speculative read
+    Speculative = 7,
+    /// The range will be overwritten soon
+    WillBeOverwritten = 8,
+}
+
+/// Expected access latency for a range of blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLatency {
+    /// No information provided
+    #[default]
+    Unknown = 0,
+    /// Longer latency is acceptable
+    Idle = 1,
+    /// Typical latency
+    Normal = 2,
+    /// Smallest possible latency
+    Low = 3,
+}
+
+/// Dataset management hints for reads and writes.
+///
+/// Filled into the DSM field of command dword 13. The controller may
+/// use them to place data; they never change the result of a command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoHints {
+    /// Expected access frequency
+    pub access_frequency: AccessFrequency,
+    /// Expected access latency
+    pub access_latency: AccessLatency,
+    /// The command is part of a sequential access
+    pub sequential: bool,
+    /// The data is not compressible
+    pub incompressible: bool,
+}
+
+impl IoHints {
+    /// Encode as the DSM field.
+    pub(crate) fn dsm(&self) -> u8 {
+        (self.access_frequency as u8)
+            | ((self.access_latency as u8) << 4)
+            | ((self.sequential as u8) << 6)
+            | ((self.incompressible as u8) << 7)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum IdentifyType {
     Namespace(u32),
@@ -36,6 +173,105 @@ pub(crate) enum IdentifyType {
     NamespaceList(u32),
 }
 
+/// Controller or Namespace Structure (CNS) value of an Identify command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentifyCns {
+    /// Identify Namespace data structure for the NSID
+    Namespace,
+    /// Identify Controller data structure
+    Controller,
+    /// Active namespace IDs greater than the NSID
+    ActiveNamespaceList,
+    /// Namespace Identification Descriptor list for the NSID
+    NamespaceDescriptorList,
+    /// NVM Set list starting at the CNS specific identifier
+    NvmSetList,
+    /// I/O command set specific Identify Namespace data structure
+    IoCommandSetNamespace,
+    /// I/O command set specific Identify Controller data structure
+    IoCommandSetController,
+    /// Active namespace IDs of an I/O command set greater than the NSID
+    IoCommandSetActiveNamespaceList,
+    /// I/O command set independent Identify Namespace data structure
+    IndependentNamespace,
+    /// Allocated namespace IDs greater than the NSID
+    AllocatedNamespaceList,
+    /// Identify Namespace data structure of an allocated NSID
+    AllocatedNamespace,
+    /// Controllers attached to the NSID, starting at the controller ID
+    NamespaceControllerList,
+    /// Controllers in the subsystem, starting at the controller ID
+    ControllerList,
+    /// Primary Controller Capabilities data structure
+    PrimaryControllerCapabilities,
+    /// Secondary controllers, starting at the controller ID
+    SecondaryControllerList,
+    /// Namespace Granularity list
+    NamespaceGranularityList,
+    /// UUID list
+    UuidList,
+    /// Domain list starting at the CNS specific identifier
+    DomainList,
+    /// Endurance group list starting at the CNS specific identifier
+    EnduranceGroupList,
+    /// Allocated namespace IDs of an I/O command set greater than the NSID
+    IoCommandSetAllocatedNamespaceList,
+    /// I/O command set specific data structure of an allocated NSID
+    IoCommandSetAllocatedNamespace,
+    /// I/O Command Set data structure
+    IoCommandSetList,
+    /// Any other CNS value, passed through unchanged
+    Raw(u8),
+}
+
+impl IdentifyCns {
+    /// Get the CNS value.
+    pub fn value(self) -> u8 {
+        match self {
+            IdentifyCns::Namespace => 0x00,
+            IdentifyCns::Controller => 0x01,
+            IdentifyCns::ActiveNamespaceList => 0x02,
+            IdentifyCns::NamespaceDescriptorList => 0x03,
+            IdentifyCns::NvmSetList => 0x04,
+            IdentifyCns::IoCommandSetNamespace => 0x05,
+            IdentifyCns::IoCommandSetController => 0x06,
+            IdentifyCns::IoCommandSetActiveNamespaceList => 0x07,
+            IdentifyCns::IndependentNamespace => 0x08,
+            IdentifyCns::AllocatedNamespaceList => 0x10,
+            IdentifyCns::AllocatedNamespace => 0x11,
+            IdentifyCns::NamespaceControllerList => 0x12,
+            IdentifyCns::ControllerList => 0x13,
+            IdentifyCns::PrimaryControllerCapabilities => 0x14,
+            IdentifyCns::SecondaryControllerList => 0x15,
+            IdentifyCns::NamespaceGranularityList => 0x16,
+            IdentifyCns::UuidList => 0x17,
+            IdentifyCns::DomainList => 0x18,
+            IdentifyCns::EnduranceGroupList => 0x19,
+            IdentifyCns::IoCommandSetAllocatedNamespaceList => 0x1A,
+            IdentifyCns::IoCommandSetAllocatedNamespace => 0x1B,
+            IdentifyCns::IoCommandSetList => 0x1C,
+            IdentifyCns::Raw(cns) => cns,
+        }
+    }
+}
+
+/// Identify command parameters besides the CNS value.
+///
+/// Which fields are used depends on the CNS value.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdentifyParams {
+    /// Namespace ID
+    pub nsid: u32,
+    /// Controller identifier (CNTID)
+    pub controller_id: u16,
+    /// CNS specific identifier, such as an NVM set or domain ID
+    pub cns_id: u16,
+    /// Command set identifier (CSI)
+    pub csi: u8,
+    /// UUID index
+    pub uuid_index: u8,
+}
+
 // I/O Command Opcodes
 const OPCODE_FLUSH: u8 = 0x00;
 const OPCODE_WRITE: u8 = 0x01;
@@ -79,6 +315,9 @@ const OPCODE_SECURITY_SEND: u8 = 0x81;
 const OPCODE_SECURITY_RECEIVE: u8 = 0x82;
 const OPCODE_SANITIZE: u8 = 0x84;
 
+/// Sanitize CDW10 bit selecting a per-namespace sanitize.
+const SANITIZE_PER_NAMESPACE: u32 = 1 << 11;
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum LogPageId {
     SupportedLogPages = 0x00,
@@ -98,38 +337,150 @@ pub(crate) enum LogPageId {
     LbaStatusInformation = 0x0E,
     EnduranceGroupEventAggregate = 0x0F,
     MediaUnitStatus = 0x10,
+    PowerMeasurement = 0x25,
+    ReservationNotification = 0x80,
     SanitizeStatus = 0x81,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum FeatureId {
+/// Feature identifiers for Get/Set Features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureId {
+    /// Arbitration
     Arbitration = 0x01,
+    /// Power Management
     PowerManagement = 0x02,
+    /// LBA Range Type
     LbaRangeType = 0x03,
+    /// Temperature Threshold
     TemperatureThreshold = 0x04,
+    /// Error Recovery
     ErrorRecovery = 0x05,
+    /// Volatile Write Cache
     VolatileWriteCache = 0x06,
+    /// Number of Queues
     NumberOfQueues = 0x07,
+    /// Interrupt Coalescing
     InterruptCoalescing = 0x08,
+    /// Interrupt Vector Configuration
     InterruptVectorConfig = 0x09,
+    /// Write Atomicity Normal
     WriteAtomicityNormal = 0x0A,
+    /// Asynchronous Event Configuration
     AsyncEventConfig = 0x0B,
+    /// Autonomous Power State Transition
     AutonomousPowerState = 0x0C,
+    /// Host Memory Buffer
     HostMemBuffer = 0x0D,
+    /// Timestamp
     Timestamp = 0x0E,
+    /// Keep Alive Timer
     KeepAliveTimer = 0x0F,
+    /// Host Controlled Thermal Management
     HostControlledThermal = 0x10,
+    /// Non-Operational Power State Config
     NonOperationalPowerState = 0x11,
     // NVMe 2.3 specific features
+    /// Predictable Latency Mode Config
     PredictableLatencyModeConfig = 0x13,
+    /// Predictable Latency Mode Window
     PredictableLatencyModeWindow = 0x14,
+    /// LBA Status Information Attributes
     LbaStatusInformationAttributes = 0x15,
+    /// Host Behavior Support
     HostBehaviorSupport = 0x16,
+    /// Sanitize Config
     SanitizeConfig = 0x17,
+    /// Endurance Group Event Configuration
     EnduranceGroupEventConfig = 0x18,
+    /// Reservation Notification Mask
+    ReservationNotificationMask = 0x82,
+}
+
+impl FeatureId {
+    /// All feature identifiers known to the driver.
+    pub const ALL: [FeatureId; 24] = [
+        Self::Arbitration,
+        Self::PowerManagement,
+        Self::LbaRangeType,
+        Self::TemperatureThreshold,
+        Self::ErrorRecovery,
+        Self::VolatileWriteCache,
+        Self::NumberOfQueues,
+        Self::InterruptCoalescing,
+        Self::InterruptVectorConfig,
+        Self::WriteAtomicityNormal,
+        Self::AsyncEventConfig,
+        Self::AutonomousPowerState,
+        Self::HostMemBuffer,
+        Self::Timestamp,
+        Self::KeepAliveTimer,
+        Self::HostControlledThermal,
+        Self::NonOperationalPowerState,
+        Self::PredictableLatencyModeConfig,
+        Self::PredictableLatencyModeWindow,
+        Self::LbaStatusInformationAttributes,
+        Self::HostBehaviorSupport,
+        Self::SanitizeConfig,
+        Self::EnduranceGroupEventConfig,
+        Self::ReservationNotificationMask,
+    ];
+
+    /// Check whether the feature value is transferred in a data buffer.
+    pub fn has_data(&self) -> bool {
+        matches!(
+            self,
+            Self::LbaRangeType
+                | Self::AutonomousPowerState
+                | Self::Timestamp
+                | Self::PredictableLatencyModeConfig
+                | Self::HostBehaviorSupport
+        )
+    }
 }
 
 impl Command {
+    /// Get the command identifier.
+    pub fn cmd_id(&self) -> u16 {
+        self.cmd_id
+    }
+
+    /// Replace the command identifier.
+    pub(crate) fn with_cmd_id(mut self, cmd_id: u16) -> Self {
+        self.cmd_id = cmd_id;
+        self
+    }
+
+    /// Get the opcode.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    /// Set the second PRP entry for transfers spanning several pages.
+    pub fn with_prp2(mut self, prp2: u64) -> Self {
+        self.data_ptr[1] = prp2;
+        self
+    }
+
+    /// Set the protection information checks and tags of a read or write.
+    pub fn with_protection(mut self, check: &ProtectionCheck) -> Result<Self> {
+        let (tags, reference) = check.info.encode_tags(check.storage_tag, check.reference_tag)?;
+        self.cmd_2_3 = tags;
+        // STC (bit 24) and PRINFO (bits 29:26)
+        self.cmd_12 |= ((check.check_storage_tag as u32) << 24) | ((check.prinfo() as u32) << 26);
+        self.cmd_14 = reference;
+        self.cmd_15 = ((check.application_tag_mask as u32) << 16) | check.application_tag as u32;
+        Ok(self)
+    }
+
+    /// Select a UUID from the UUID List (0 = none).
+    ///
+    /// Used by Identify, Get Log Page and Get/Set Features to address
+    /// vendor specific data of the vendor the UUID belongs to.
+    pub fn with_uuid_index(mut self, index: u8) -> Self {
+        self.cmd_14 = (self.cmd_14 & !0x7F) | (index as u32 & 0x7F);
+        self
+    }
+
     pub fn read_write(
         cmd_id: u16,
         ns_id: u32,
@@ -137,6 +488,7 @@ impl Command {
         block_count: u16,
         data_ptr: [u64; 2],
         is_write: bool,
+        hints: IoHints,
     ) -> Self {
         Self {
             opcode: if is_write { OPCODE_WRITE } else { OPCODE_READ },
@@ -146,39 +498,54 @@ impl Command {
             cmd_10: lba as u32,
             cmd_11: (lba >> 32) as u32,
             cmd_12: block_count as u32,
+            cmd_13: hints.dsm() as u32,
             ..Default::default()
         }
     }
 
+    /// Create an I/O submission queue.
+    ///
+    /// Without `contiguous`, `address` is the address of a PRP list of the
+    /// queue's pages.
     pub fn create_submission_queue(
         cmd_id: u16,
         queue_id: u16,
-        address: usize,
+        address: u64,
         size: u16,
         cqueue_id: u16,
+        contiguous: bool,
+        priority: QueuePriority,
     ) -> Command {
         Self {
             opcode: OPCODE_SUB_QUEUE_CREATE,
             cmd_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: ((size as u32) << 16) | (queue_id as u32),
-            cmd_11: ((cqueue_id as u32) << 16) | 1,
+            cmd_11: ((cqueue_id as u32) << 16) | ((priority as u32) << 1) | contiguous as u32,
             ..Default::default()
         }
     }
 
+    /// Create an I/O completion queue.
+    ///
+    /// Without `contiguous`, `address` is the address of a PRP list of the
+    /// queue's pages. With a `vector`, interrupts are enabled (IEN) and
+    /// posted to it (IV).
     pub fn create_completion_queue(
         cmd_id: u16,
         queue_id: u16,
-        address: usize,
+        address: u64,
         size: u16,
+        contiguous: bool,
+        vector: Option<u16>,
     ) -> Command {
+        let interrupts = vector.map_or(0, |vector| (vector as u32) << 16 | 1 << 1);
         Self {
             opcode: OPCODE_COMP_QUEUE_CREATE,
             cmd_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: ((size as u32) << 16) | (queue_id as u32),
-            cmd_11: 1,
+            cmd_11: interrupts | contiguous as u32,
             ..Default::default()
         }
     }
@@ -201,51 +568,100 @@ impl Command {
         }
     }
 
-    pub fn identify(cmd_id: u16, address: usize, target: IdentifyType) -> Self {
-        let (ns_id, cmd_10) = match target {
-            IdentifyType::Namespace(id) => (id, 0),
-            IdentifyType::Controller => (0, 1),
-            IdentifyType::NamespaceList(base) => (base, 2),
+    pub fn identify(cmd_id: u16, address: u64, target: IdentifyType) -> Self {
+        let (cns, nsid) = match target {
+            IdentifyType::Namespace(id) => (IdentifyCns::Namespace, id),
+            IdentifyType::Controller => (IdentifyCns::Controller, 0),
+            IdentifyType::NamespaceList(base) => (IdentifyCns::ActiveNamespaceList, base),
         };
 
+        Self::identify_cns(cmd_id, address, cns, IdentifyParams { nsid, ..Default::default() })
+    }
+
+    pub fn identify_cns(
+        cmd_id: u16,
+        address: u64,
+        cns: IdentifyCns,
+        params: IdentifyParams,
+    ) -> Self {
         Self {
             opcode: OPCODE_IDENTIFY,
             cmd_id,
-            ns_id,
-            data_ptr: [address as u64, 0],
-            cmd_10,
+            ns_id: params.nsid,
+            data_ptr: [address, 0],
+            cmd_10: ((params.controller_id as u32) << 16) | cns.value() as u32,
+            cmd_11: ((params.csi as u32) << 24) | params.cns_id as u32,
             ..Default::default()
         }
+        .with_uuid_index(params.uuid_index)
     }
 
     pub fn get_log_page(
         cmd_id: u16,
-        address: usize,
+        address: u64,
         log_id: LogPageId,
         num_dwords: u32,
         offset: u64,
     ) -> Self {
+        Self::get_log_page_specific(cmd_id, address, log_id, 0, num_dwords, offset)
+    }
+
+    pub fn get_log_page_specific(
+        cmd_id: u16,
+        address: u64,
+        log_id: LogPageId,
+        lsi: u16,
+        num_dwords: u32,
+        offset: u64,
+    ) -> Self {
+        Self::get_log_page_raw(cmd_id, address, log_id as u8, lsi, num_dwords, offset)
+    }
+
+    /// Get Log Page for any log identifier, including vendor specific ones.
+    pub fn get_log_page_raw(
+        cmd_id: u16,
+        address: u64,
+        log_id: u8,
+        lsi: u16,
+        num_dwords: u32,
+        offset: u64,
+    ) -> Self {
+        let numd = num_dwords - 1;
         Self {
             opcode: OPCODE_GET_LOG_PAGE,
             cmd_id,
-            data_ptr: [address as u64, 0],
-            cmd_10: ((num_dwords - 1) << 16) | (log_id as u32),
-            cmd_11: (offset >> 32) as u32,
+            data_ptr: [address, 0],
+            cmd_10: ((numd & 0xFFFF) << 16) | (log_id as u32),
+            cmd_11: ((lsi as u32) << 16) | (numd >> 16),
             cmd_12: offset as u32,
+            cmd_13: (offset >> 32) as u32,
             ..Default::default()
         }
     }
 
     pub fn set_features(
         cmd_id: u16,
+        ns_id: u32,
         feature_id: FeatureId,
         value: u32,
         save: bool,
+    ) -> Self {
+        Self::set_features_raw(cmd_id, ns_id, feature_id as u8, value, save)
+    }
+
+    /// Set Features for any feature identifier, including vendor specific ones.
+    pub fn set_features_raw(
+        cmd_id: u16,
+        ns_id: u32,
+        feature_id: u8,
+        value: u32,
+        save: bool,
     ) -> Self {
         let sv = if save { 0x80000000 } else { 0 };
         Self {
             opcode: OPCODE_SET_FEATURES,
             cmd_id,
+            ns_id,
             cmd_10: sv | (feature_id as u32),
             cmd_11: value,
             ..Default::default()
@@ -254,17 +670,64 @@ impl Command {
 
     pub fn get_features(
         cmd_id: u16,
+        ns_id: u32,
         feature_id: FeatureId,
         sel: u8,
     ) -> Self {
+        Self::get_features_raw(cmd_id, ns_id, feature_id as u8, sel)
+    }
+
+    /// Get Features for any feature identifier, including vendor specific ones.
+    pub fn get_features_raw(cmd_id: u16, ns_id: u32, feature_id: u8, sel: u8) -> Self {
         Self {
             opcode: OPCODE_GET_FEATURES,
             cmd_id,
+            ns_id,
             cmd_10: ((sel as u32) << 8) | (feature_id as u32),
             ..Default::default()
         }
     }
 
+    pub fn set_features_with_data(
+        cmd_id: u16,
+        ns_id: u32,
+        feature_id: FeatureId,
+        value: u32,
+        save: bool,
+        address: u64,
+    ) -> Self {
+        Self {
+            data_ptr: [address, 0],
+            ..Self::set_features(cmd_id, ns_id, feature_id, value, save)
+        }
+    }
+
+    pub fn get_features_with_value(
+        cmd_id: u16,
+        ns_id: u32,
+        feature_id: FeatureId,
+        sel: u8,
+        value: u32,
+    ) -> Self {
+        Self {
+            cmd_11: value,
+            ..Self::get_features(cmd_id, ns_id, feature_id, sel)
+        }
+    }
+
+    pub fn get_features_with_data(
+        cmd_id: u16,
+        ns_id: u32,
+        feature_id: FeatureId,
+        sel: u8,
+        address: u64,
+    ) -> Self {
+        Self {
+            data_ptr: [address, 0],
+            ..Self::get_features(cmd_id, ns_id, feature_id, sel)
+        }
+    }
+
     pub fn abort(cmd_id: u16, sqid: u16, cid: u16) -> Self {
         Self {
             opcode: OPCODE_ABORT,
@@ -294,13 +757,13 @@ impl Command {
         cmd_id: u16,
         ns_id: u32,
         sel: u8,
-        address: usize,
+        address: u64,
     ) -> Self {
         Self {
             opcode: OPCODE_NAMESPACE_MANAGEMENT,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: sel as u32,
             ..Default::default()
         }
@@ -310,13 +773,13 @@ impl Command {
         cmd_id: u16,
         ns_id: u32,
         sel: u8,
-        address: usize,
+        address: u64,
     ) -> Self {
         Self {
             opcode: OPCODE_NAMESPACE_ATTACHMENT,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: sel as u32,
             ..Default::default()
         }
@@ -324,14 +787,14 @@ impl Command {
 
     pub fn firmware_image_download(
         cmd_id: u16,
-        address: usize,
+        address: u64,
         num_dwords: u32,
         offset: u32,
     ) -> Self {
         Self {
             opcode: OPCODE_FIRMWARE_IMAGE_DOWNLOAD,
             cmd_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: (num_dwords - 1),
             cmd_11: offset,
             ..Default::default()
@@ -365,7 +828,13 @@ impl Command {
             opcode: OPCODE_FORMAT_NVM,
             cmd_id,
             ns_id,
-            cmd_10: ((ses as u32) << 9) | ((pil as u32) << 8) | ((pi as u32) << 5) | ((mset as u32) << 4) | (lbaf as u32),
+            // LBAF holds the low four bits of the format index, LBAFU the upper two
+            cmd_10: (((lbaf as u32 >> 4) & 0x3) << 12)
+                | ((ses as u32) << 9)
+                | ((pil as u32) << 8)
+                | ((pi as u32) << 5)
+                | ((mset as u32) << 4)
+                | (lbaf as u32 & 0xF),
             ..Default::default()
         }
     }
@@ -373,7 +842,7 @@ impl Command {
     pub fn security_send(
         cmd_id: u16,
         ns_id: u32,
-        address: usize,
+        address: u64,
         secp: u8,
         spsp: u16,
         tl: u32,
@@ -382,7 +851,7 @@ impl Command {
             opcode: OPCODE_SECURITY_SEND,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: ((secp as u32) << 24) | (spsp as u32),
             cmd_11: tl,
             ..Default::default()
@@ -392,7 +861,7 @@ impl Command {
     pub fn security_receive(
         cmd_id: u16,
         ns_id: u32,
-        address: usize,
+        address: u64,
         secp: u8,
         spsp: u16,
         al: u32,
@@ -401,7 +870,7 @@ impl Command {
             opcode: OPCODE_SECURITY_RECEIVE,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: ((secp as u32) << 24) | (spsp as u32),
             cmd_11: al,
             ..Default::default()
@@ -432,6 +901,24 @@ impl Command {
         }
     }
 
+    /// Sanitize a single namespace (Sanitize Per Namespace).
+    ///
+    /// Same as `sanitize`, with the scope bit set so the controller
+    /// applies the action to `ns_id` only.
+    pub fn sanitize_namespace(
+        cmd_id: u16,
+        ns_id: u32,
+        sanact: u8,
+        ause: bool,
+        owpass: u8,
+        oipbp: bool,
+        ndas: bool,
+    ) -> Self {
+        let mut cmd = Self::sanitize(cmd_id, ns_id, sanact, ause, owpass, oipbp, ndas);
+        cmd.cmd_10 |= SANITIZE_PER_NAMESPACE;
+        cmd
+    }
+
     // I/O Commands
     pub fn flush(cmd_id: u16, ns_id: u32) -> Self {
         Self {
@@ -502,7 +989,7 @@ impl Command {
     pub fn dataset_management(
         cmd_id: u16,
         ns_id: u32,
-        address: usize,
+        address: u64,
         nr: u8,
         ad: bool,
         idw: bool,
@@ -517,7 +1004,7 @@ impl Command {
             opcode: OPCODE_DATASET_MANAGEMENT,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: nr as u32,
             cmd_11,
             ..Default::default()
@@ -544,7 +1031,7 @@ impl Command {
     pub fn copy(
         cmd_id: u16,
         ns_id: u32,
-        address: usize,
+        address: u64,
         sdlba: u64,
         nr: u8,
         desc_format: u8,
@@ -553,7 +1040,7 @@ impl Command {
             opcode: OPCODE_COPY,
             cmd_id,
             ns_id,
-            data_ptr: [address as u64, 0],
+            data_ptr: [address, 0],
             cmd_10: sdlba as u32,
             cmd_11: (sdlba >> 32) as u32,
             cmd_12: ((desc_format as u32) << 4) | (nr as u32),
@@ -1,25 +1,112 @@
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::Arc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use core::hint::spin_loop;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use spin::{Mutex, RwLock};
-
-use crate::cmd::{Command, IdentifyType, FeatureId};
-use crate::error::{Error, Result};
-use crate::memory::{Allocator, Dma, PrpManager};
-use crate::queues::{CompQueue, Completion, SubQueue};
+use core::cell::Cell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard, RwLock};
+
+use crate::clock::Clock;
+use crate::cmd::{
+    Command, CopyRange, DsmRange, IdentifyCns, IdentifyParams, IdentifyType, FeatureId, IoHints,
+    LogPageId, QueuePriority,
+};
+use crate::endian::{Le, le_struct, read_le};
+use crate::error::{Error, Result, StatusCode, StatusCodeType};
+use crate::events::{
+    AsyncEvent, AsyncEventInfo, AsyncEventManager, ControllerReset, NamespaceChange,
+};
+use crate::features::{
+    ArbitrationConfig, ErrorRecoveryConfig, FeatureCapabilities, FeatureSelector, FeatureValue,
+    HostBehaviorSupport, PowerStateDescriptor, PredictableLatencyConfig,
+    ReservationNotificationConfig, WorkloadHint,
+};
+use crate::health::HealthMonitor;
+use crate::format::{
+    BASIC_LBA_FORMATS, FormatConfig, LbaFormat, MAX_LBA_FORMATS, active_format_index,
+    parse_lba_formats,
+};
+use crate::firmware::{
+    FirmwareActivation, FirmwareImageChecker, FirmwareManager, FirmwareSlotInfo,
+    FirmwareUpdateConfig, FirmwareUpdateError, FirmwareValidator,
+};
+use crate::hook::{CompletionSink, WaitStrategy, Waiter, YieldHook, write_barrier};
+use crate::logger::nvme_log;
+use crate::log::{
+    PredictableLatencyEventAggregate, PredictableLatencyPerNvmSet, PredictableLatencyWindow,
+    CachedLog, EnduranceGroupInfo, ErrorLogEntry, LogPageManager, MediaUnitStatusLog,
+    ReservationNotification, SmartHealthInfo, TelemetryLog, TelemetryLogHeader,
+};
+use crate::metrics::{LatencyHistogram, LatencyStats};
+use crate::memory::{Allocator, Dma, DmaPool, PlacementHint, PoolBuffer, PrpManager, PrpResult};
+use crate::open::{NamespaceHandle, OpenMode, OpenTracker};
+use crate::operation::{Operation, OperationKind, OperationProgress};
+use crate::ordering::{IoOrdering, RangeGuard, RangeLocks};
+use crate::multipath::{ControllerPath, MultipathController, PathErrorRecord, PathProbe};
+use crate::power::{PowerManager, SelfReportedPower};
+use crate::profile::{DeviceProfile, PROFILE_FEATURES};
+use crate::protection::ProtectionCheck;
+use crate::provisioning::{UtilizationMonitor, UtilizationSample};
+use crate::qos::{RateLimit, RateLimiter};
+use crate::report::{
+    DeviceReport, FirmwareReport, NamespaceIdentifiers, NamespaceReport, OptionalCommands,
+    QueueReport,
+};
+use crate::security::{
+    SanitizeAction, SanitizeCapabilities, SanitizePerNamespace, SanitizeRecovery, SanitizeStatus,
+    SecurityManager, SecurityProtocol, SecurityTransfer, TCG_COMPACKET_HEADER, TcgDiscovery,
+    tcg_compacket_len, tcg_outstanding_data,
+};
+use crate::queues::{CommandIds, CompQueue, Completion, IoResult, SubQueue, with_active_queue};
+use crate::tuning::{QueueSample, QueueTuner};
 
 /// Minimum size of an admin queue.
-///
-/// We'll use the maximum supported by the controller
-/// to avoid queue full issues with many I/O queues.
 const MIN_ADMIN_QUEUE_SIZE: usize = 2;
 
+/// Default size of the admin queue.
+const DEFAULT_ADMIN_QUEUE_SIZE: usize = 64;
+
+/// Default admin command timeout in milliseconds.
+const DEFAULT_ADMIN_TIMEOUT_MS: u32 = 60_000;
+
+/// How long suspend, reset and drop wait for I/O in flight, while a clock is set.
+const TEARDOWN_TIMEOUT_MS: u32 = 30_000;
+
+/// How long a format or sanitize waits for I/O in flight to the
+/// namespaces it affects, while a clock is set.
+const FENCE_DRAIN_TIMEOUT_MS: u32 = 30_000;
+
+/// How long removing I/O queues waits for their commands, while a clock is set.
+const QUEUE_DRAIN_TIMEOUT_MS: u32 = 5_000;
+
+/// Number of free admin data buffers kept for reuse.
+const ADMIN_BUFFER_POOL_SIZE: usize = 4;
+
 /// Default size of I/O queues.
 const IO_QUEUE_SIZE: usize = 256;
 
+/// Largest read served through the per-queue inline buffer.
+const INLINE_READ_SIZE: usize = 4096;
+
+/// Submission queue entry size used by the driver (as a power of two).
+const SQ_ENTRY_SIZE_LOG2: u8 = 6;
+
+/// Completion queue entry size used by the driver (as a power of two).
+const CQ_ENTRY_SIZE_LOG2: u8 = 4;
+
+/// CSTS.NSSRO, set when an NVM subsystem reset occurred and cleared by writing 1.
+const CSTS_NSSRO: u32 = 1 << 4;
+
+/// CC.AMS value selecting weighted round robin with urgent priority class.
+const CC_AMS_WRR: u32 = 1 << 11;
+
+/// Largest plausible MDTS, a 4 GiB transfer with 4 KiB pages.
+const MAX_MDTS: u8 = 20;
+
+/// Smallest and largest plausible LBA data size (LBADS), 512 B to 1 MiB.
+const LBADS_RANGE: core::ops::RangeInclusive<u32> = 9..=20;
+
 /// Temperature threshold type.
 #[derive(Debug, Clone, Copy)]
 pub enum TempThresholdType {
@@ -40,6 +127,17 @@ pub enum SelfTestType {
     Abort,
 }
 
+impl SelfTestType {
+    /// Get the Self-test Code of the Device Self-test command.
+    fn code(self) -> u8 {
+        match self {
+            Self::Short => 0x1,
+            Self::Extended => 0x2,
+            Self::Abort => 0xF,
+        }
+    }
+}
+
 /// Self-test result.
 #[derive(Debug, Clone)]
 pub struct SelfTestResult {
@@ -51,61 +149,279 @@ pub struct SelfTestResult {
     pub results: Vec<u8>,
 }
 
-/// Error log entry.
-#[derive(Debug, Clone)]
-pub struct ErrorLogEntry {
-    /// Error count
-    pub error_count: u64,
-    /// Submission queue ID
-    pub sqid: u16,
-    /// Command ID
-    pub cmdid: u16,
-    /// Status field
-    pub status: u16,
-    /// Parameter error location
-    pub parameter_error_location: u16,
-    /// LBA of error
+/// Outcome of a compare command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOutcome {
+    /// The data matched
+    Match,
+    /// The data did not match
+    Mismatch {
+        /// First mismatching LBA, if it could be determined
+        lba: Option<u64>,
+    },
+}
+
+/// Cancellation token for long-running operations.
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    /// Set once cancellation is requested
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    /// Create a new token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Check whether cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// Value read from deallocated blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeallocatedRead {
+    /// Not reported; the data is undefined
+    Undefined,
+    /// All bytes read as 0x00
+    Zeroes,
+    /// All bytes read as 0xFF
+    Ones,
+}
+
+/// A block that failed verification.
+#[derive(Debug, Clone, Copy)]
+pub struct MediaError {
+    /// Failing LBA
     pub lba: u64,
-    /// Namespace
-    pub namespace: u32,
-    /// Vendor specific info
-    pub vendor_specific: u8,
-    /// Transport type
-    pub trtype: u8,
-    /// Command specific info
-    pub command_specific: u64,
-    /// Transport specific
-    pub transport_specific: u16,
+    /// Status reported for the block
+    pub status: StatusCode,
+}
+
+/// Result of a range verification.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of blocks processed
+    pub blocks_verified: u64,
+    /// Blocks that failed with a media error
+    pub media_errors: Vec<MediaError>,
+    /// Whether the scan was cancelled before the end of the range
+    pub cancelled: bool,
 }
 
+/// Per-namespace I/O statistics.
+///
+/// Commands rejected before submission, e.g. by a full queue or a rate
+/// limit, are not counted. Bytes are counted for successful commands only.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NamespaceStats {
+    /// Completed read commands
+    pub reads: u64,
+    /// Bytes read
+    pub read_bytes: u64,
+    /// Read commands that failed
+    pub read_errors: u64,
+    /// Completed write commands
+    pub writes: u64,
+    /// Bytes written
+    pub write_bytes: u64,
+    /// Write commands that failed
+    pub write_errors: u64,
+    /// Completed trim commands
+    pub trims: u64,
+    /// Bytes deallocated
+    pub trim_bytes: u64,
+    /// Trim commands that failed
+    pub trim_errors: u64,
+}
 
-/// Endurance group information.
-#[derive(Debug, Clone)]
-pub struct EnduranceGroupInfo {
-    /// Critical warning
-    pub critical_warning: u8,
-    /// Available spare
-    pub available_spare: u8,
-    /// Available spare threshold
-    pub available_spare_threshold: u8,
-    /// Percentage used
-    pub percentage_used: u8,
-    /// Endurance estimate
-    pub endurance_estimate: u128,
-    /// Data units read
-    pub data_units_read: u128,
-    /// Data units written
-    pub data_units_written: u128,
-    /// Media units written
-    pub media_units_written: u128,
-    /// Host read commands
-    pub host_read_commands: u128,
-    /// Host write commands
-    pub host_write_commands: u128,
-    /// Media errors
-    pub media_errors: u128,
-    /// Number of error log entries
-    pub num_error_entries: u128,
+/// Kind of I/O counted in `NamespaceStats`.
+#[derive(Clone, Copy)]
+enum IoKind {
+    Read,
+    Write,
+    Trim,
+}
+
+/// Live per-namespace I/O counters, indexed by `IoKind`.
+#[derive(Default)]
+struct IoCounters {
+    commands: [AtomicU64; 3],
+    bytes: [AtomicU64; 3],
+    errors: [AtomicU64; 3],
+    /// Commands in flight, waited for before a format or sanitize
+    in_flight: AtomicUsize,
+}
+
+impl IoCounters {
+    /// Count a completed command.
+    fn record(&self, kind: IoKind, bytes: u64, success: bool) {
+        let index = kind as usize;
+        self.commands[index].fetch_add(1, Ordering::Relaxed);
+        if success {
+            self.bytes[index].fetch_add(bytes, Ordering::Relaxed);
+        } else {
+            self.errors[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take a snapshot of the counters.
+    fn snapshot(&self) -> NamespaceStats {
+        let get = |counters: &[AtomicU64; 3], kind: IoKind| {
+            counters[kind as usize].load(Ordering::Relaxed)
+        };
+        NamespaceStats {
+            reads: get(&self.commands, IoKind::Read),
+            read_bytes: get(&self.bytes, IoKind::Read),
+            read_errors: get(&self.errors, IoKind::Read),
+            writes: get(&self.commands, IoKind::Write),
+            write_bytes: get(&self.bytes, IoKind::Write),
+            write_errors: get(&self.errors, IoKind::Write),
+            trims: get(&self.commands, IoKind::Trim),
+            trim_bytes: get(&self.bytes, IoKind::Trim),
+            trim_errors: get(&self.errors, IoKind::Trim),
+        }
+    }
+
+    /// Reset all counters to zero.
+    fn reset(&self) {
+        for counter in self.commands.iter().chain(&self.bytes).chain(&self.errors) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Basic namespace information.
+#[derive(Debug, Clone, Copy)]
+pub struct NamespaceInfo {
+    /// Namespace ID
+    pub id: u32,
+    /// Block size in bytes
+    pub block_size: u64,
+    /// Capacity in blocks
+    pub block_count: u64,
+    /// The namespace may be attached to several controllers
+    pub shared: bool,
+    /// ANA group ID (0 if not reported)
+    pub ana_group_id: u32,
+}
+
+/// Maximum number of identifiers in a Controller List data structure.
+const CONTROLLER_LIST_MAX: usize = 2047;
+
+/// List of controller identifiers.
+///
+/// Returned by the controller list Identify queries, and used as the
+/// payload of Namespace Attachment commands.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ControllerList {
+    /// Controller identifiers in increasing order
+    pub ids: Vec<u16>,
+}
+
+impl ControllerList {
+    /// Parse a Controller List data structure.
+    ///
+    /// Fails with `Error::InvalidBufferSize` if `data` is shorter than the
+    /// number of IDs it reports.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let count = match data {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]) as usize,
+            _ => return Err(Error::InvalidBufferSize),
+        };
+        let count = count.min(CONTROLLER_LIST_MAX);
+        if 2 + 2 * count > data.len() {
+            return Err(Error::InvalidBufferSize);
+        }
+        let ids = data[2..]
+            .chunks_exact(2)
+            .take(count)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        Ok(Self { ids })
+    }
+
+    /// Encode as a 4 KiB Controller List data structure.
+    ///
+    /// Fails with `Error::ControllerListTooLong` if there are more than
+    /// 2047 IDs.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.ids.len() > CONTROLLER_LIST_MAX {
+            return Err(Error::ControllerListTooLong);
+        }
+        let mut data = alloc::vec![0u8; 4096];
+        data[..2].copy_from_slice(&(self.ids.len() as u16).to_le_bytes());
+        for (chunk, id) in data[2..].chunks_exact_mut(2).zip(&self.ids) {
+            chunk.copy_from_slice(&id.to_le_bytes());
+        }
+        Ok(data)
+    }
+}
+
+/// An entry of the UUID List.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UuidEntry {
+    /// Index used to select the UUID in commands (1-based)
+    pub index: u8,
+    /// What the UUID is associated with: 0 = nothing, 1 = the vendor ID
+    /// of the subsystem, 2 = the vendor ID of the controller
+    pub association: u8,
+    /// The UUID
+    pub uuid: [u8; 16],
+}
+
+/// A controller inspected by `NVMeDevice::probe` but not yet started.
+pub struct ProbedDevice<A: Allocator> {
+    /// Device holding the probe admin queue and identify data
+    device: NVMeDevice<A>,
+}
+
+impl<A: Allocator> ProbedDevice<A> {
+    /// Get the version of the NVMe controller.
+    pub fn nvme_version(&self) -> (u16, u8, u8) {
+        self.device.nvme_version()
+    }
+
+    /// Get the controller data.
+    pub fn data(&self) -> ControllerData {
+        self.device.data()
+    }
+
+    /// Get the active namespaces.
+    pub fn namespaces(&self) -> Vec<NamespaceInfo> {
+        self.device.namespaces.read()
+            .values()
+            .map(|ns| NamespaceInfo {
+                id: ns.id(),
+                block_size: ns.block_size(),
+                block_count: ns.block_count(),
+                shared: ns.is_shared(),
+                ana_group_id: ns.ana_group_id(),
+            })
+            .collect()
+    }
+
+    /// Take over the controller and initialize it with `config`.
+    pub fn start(self, config: DeviceConfig) -> Result<NVMeDevice<A>> {
+        let probe = self.device;
+        let address = probe.address as usize;
+        let allocator = probe.inner.allocator.clone();
+
+        // The controller is disabled, so the probe memory is no longer in use
+        probe.admin_sq.deallocate(allocator.as_ref());
+        probe.admin_cq.deallocate(allocator.as_ref());
+        probe.admin_buffers.clear();
+        drop(probe);
+
+        NVMeDevice::init_shared(address, allocator, config)
+    }
 }
 
 /// NVMe controller registers.
@@ -146,38 +462,293 @@ pub(crate) enum Doorbell {
 pub(crate) struct DoorbellHelper {
     address: usize,
     stride: u8,
+    bar_size: usize,
 }
 
 impl DoorbellHelper {
     /// Create a new `DoorbellHelper` instance.
-    pub fn new(address: usize, stride: u8) -> Self {
-        Self { address, stride }
+    ///
+    /// A `bar_size` of 0 means the size of the mapped region is unknown.
+    pub fn new(address: usize, stride: u8, bar_size: usize) -> Self {
+        Self { address, stride, bar_size }
     }
 
-    /// Write a value to specified doorbell register.
-    pub fn write(&self, bell: Doorbell, val: u32) {
-        let stride = 4 << self.stride;
-        let base = self.address + 0x1000;
+    /// Calculate the address of a doorbell register.
+    fn address(&self, bell: Doorbell) -> usize {
+        let stride = 4usize << self.stride;
         let index = match bell {
-            Doorbell::SubTail(qid) => qid * 2,
-            Doorbell::CompHead(qid) => qid * 2 + 1,
+            Doorbell::SubTail(qid) => qid as usize * 2,
+            Doorbell::CompHead(qid) => qid as usize * 2 + 1,
         };
 
-        let addr = base + (index * stride) as usize;
-        unsafe { (addr as *mut u32).write_volatile(val) }
+        self.address + 0x1000 + index * stride
+    }
+
+    /// Number of queue IDs, including the admin queue,
+    /// whose doorbells lie within the mapped region.
+    pub fn max_queues(&self) -> Option<usize> {
+        let pair_size = 2 * (4usize << self.stride);
+        (self.bar_size != 0).then(|| self.bar_size.saturating_sub(0x1000) / pair_size)
+    }
+
+    /// Resolve the doorbells of a queue pair.
+    ///
+    /// Fails if the doorbells lie beyond the mapped region.
+    pub fn queue(&self, qid: u16) -> Result<QueueDoorbells> {
+        if self.max_queues().is_some_and(|max| qid as usize >= max) {
+            return Err(Error::DoorbellOutOfRange);
+        }
+
+        Ok(QueueDoorbells {
+            sq_tail: self.address(Doorbell::SubTail(qid)),
+            cq_head: self.address(Doorbell::CompHead(qid)),
+        })
+    }
+}
+
+/// Cached doorbell registers of a queue pair.
+#[derive(Clone, Debug)]
+pub(crate) struct QueueDoorbells {
+    sq_tail: usize,
+    cq_head: usize,
+}
+
+impl QueueDoorbells {
+    /// Write the submission queue tail doorbell.
+    pub fn ring_sq(&self, tail: usize) {
+        // Make the new entries visible before the controller fetches them
+        write_barrier();
+        unsafe { (self.sq_tail as *mut u32).write_volatile((tail as u32).to_le()) }
+    }
+
+    /// Write the completion queue head doorbell.
+    pub fn ring_cq(&self, head: usize) {
+        unsafe { (self.cq_head as *mut u32).write_volatile((head as u32).to_le()) }
     }
 }
 
 /// NVMe namespace data structure.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 struct NamespaceData {
-    _ignore1: u64,
+    size: u64,
     capacity: u64,
-    _ignore2: [u8; 10],
+    nuse: u64,
+    _ignore2: u8,
+    nlbaf: u8,
     lba_size: u8,
-    _ignore3: [u8; 101],
-    lba_format_support: [u32; 16],
+    _ignore3: [u8; 3],
+    nmic: u8,
+    _ignore5: u8,
+    fpi: u8,
+    dlfeat: u8,
+    _ignore4: [u8; 58],
+    anagrpid: u32,
+    _ignore6: [u8; 32],
+    lba_format_support: [u32; MAX_LBA_FORMATS],
+}
+
+le_struct!(NamespaceData { size, capacity, nuse, anagrpid, lba_format_support });
+
+impl NamespaceData {
+    /// Check the fields of an active namespace for implausible values.
+    fn check(&self) -> Result<()> {
+        let index = active_format_index(self.lba_size);
+        let lbads = (self.lba_format_support[index] >> 16) & 0xFF;
+        let invalid = if self.capacity > self.size {
+            "NCAP"
+        } else if self.nuse > self.size {
+            "NUSE"
+        } else if index > self.nlbaf as usize {
+            "FLBAS"
+        } else if !LBADS_RANGE.contains(&lbads) {
+            "LBADS"
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidIdentifyData(invalid))
+    }
+}
+
+/// Device configuration applied at initialization.
+#[derive(Clone)]
+pub struct DeviceConfig {
+    /// Number of admin queue entries.
+    ///
+    /// Clamped to the range supported by the controller.
+    pub admin_queue_depth: usize,
+    /// Admin command timeout in milliseconds (0 = wait forever).
+    ///
+    /// Only enforced while a clock is set, see `clock`.
+    pub admin_timeout_ms: u32,
+    /// Size of the mapped controller register region in bytes (0 = unknown).
+    ///
+    /// When known, queues whose doorbells would lie beyond it are not created.
+    pub bar_size: usize,
+    /// Clock installed before initialization.
+    ///
+    /// Needed for admin timeouts and phase timings during initialization.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Called after each initialization phase completes.
+    ///
+    /// If initialization fails, the phase after the last reported one failed.
+    pub on_phase: Option<fn(&PhaseTiming)>,
+    /// Selects the namespaces to attach by NSID (`None` = all).
+    ///
+    /// Namespaces it rejects are never identified or published, also when
+    /// the controller later reports them as changed.
+    pub namespace_filter: Option<fn(u32) -> bool>,
+    /// What removing I/O queues does with commands that do not complete.
+    pub queue_removal: QueueRemovalPolicy,
+    /// How I/O commands ride out ANA transitions.
+    pub ana_retry: AnaRetryPolicy,
+    /// How the driver waits on the controller, also during initialization.
+    pub wait_strategy: WaitStrategy,
+    /// Arbitrate with weighted round robin with urgent priority class (CC.AMS).
+    ///
+    /// Needed for the priorities of `QueueConfig` to take effect. Rejected
+    /// if the controller does not support it (CAP.AMS).
+    pub weighted_round_robin: bool,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            admin_queue_depth: DEFAULT_ADMIN_QUEUE_SIZE,
+            admin_timeout_ms: DEFAULT_ADMIN_TIMEOUT_MS,
+            bar_size: 0,
+            clock: None,
+            on_phase: None,
+            namespace_filter: None,
+            queue_removal: QueueRemovalPolicy::default(),
+            ana_retry: AnaRetryPolicy::default(),
+            wait_strategy: WaitStrategy::Spin,
+            weighted_round_robin: false,
+        }
+    }
+}
+
+impl core::fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("admin_queue_depth", &self.admin_queue_depth)
+            .field("admin_timeout_ms", &self.admin_timeout_ms)
+            .field("bar_size", &self.bar_size)
+            .field("clock", &self.clock.is_some())
+            .field("on_phase", &self.on_phase)
+            .field("namespace_filter", &self.namespace_filter)
+            .field("queue_removal", &self.queue_removal)
+            .field("ana_retry", &self.ana_retry)
+            .field("wait_strategy", &self.wait_strategy)
+            .field("weighted_round_robin", &self.weighted_round_robin)
+            .finish()
+    }
+}
+
+/// Parameters of an I/O queue pair created with `NVMeDevice::add_ioq_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Placement of the queue memory and its PRP lists
+    pub hint: PlacementHint,
+    /// Allocate the queues physically contiguous (PC)
+    ///
+    /// Otherwise their memory comes from `Allocator::allocate_scattered`
+    /// and is described to the controller by a PRP list. Only allowed if
+    /// the controller does not require contiguous queues (CAP.CQR).
+    pub contiguous: bool,
+    /// Priority of the submission queue (QPRIO)
+    pub priority: QueuePriority,
+    /// Interrupt vector of the completion queue (IV)
+    ///
+    /// `None` creates it with interrupts disabled, to be polled.
+    pub vector: Option<u16>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            hint: PlacementHint::Any,
+            contiguous: true,
+            priority: QueuePriority::Urgent,
+            vector: None,
+        }
+    }
+}
+
+/// Initialization phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitPhase {
+    /// Controller reset
+    Reset,
+    /// Admin queue setup and controller enable
+    Enable,
+    /// Identify Controller
+    Identify,
+    /// I/O queue count negotiation
+    QueueNegotiation,
+    /// I/O queue creation
+    QueueCreation,
+    /// Namespace identification
+    NamespaceScan,
+}
+
+/// Duration of a completed initialization phase.
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseTiming {
+    /// The phase
+    pub phase: InitPhase,
+    /// Time taken in microseconds (0 without a clock)
+    pub duration_us: u64,
+}
+
+/// Summary of device initialization.
+#[derive(Debug, Clone, Default)]
+pub struct InitReport {
+    /// Completed phases in order
+    pub phases: Vec<PhaseTiming>,
+    /// Controller NVMe version
+    pub version: (u16, u8, u8),
+    /// Memory page size in bytes
+    pub page_size: usize,
+    /// Maximum data transfer size in bytes
+    pub max_transfer_size: usize,
+    /// Maximum queue entries supported by the controller
+    pub max_queue_entries: u16,
+    /// Doorbell stride in bytes
+    pub doorbell_stride: usize,
+    /// Admin queue depth
+    pub admin_queue_depth: usize,
+    /// I/O submission queues granted by the controller
+    pub max_io_sq: u16,
+    /// I/O completion queues granted by the controller
+    pub max_io_cq: u16,
+    /// The controller rejected every Number of Queues request, so a single
+    /// I/O queue pair was assumed
+    pub queues_assumed: bool,
+    /// I/O queue pairs created
+    pub io_queues: usize,
+    /// Active namespaces found
+    pub namespaces: usize,
+}
+
+impl InitReport {
+    /// Record a completed phase that started at `start_us`.
+    fn record(
+        &mut self,
+        phase: InitPhase,
+        start_us: u64,
+        now_us: u64,
+        callback: Option<fn(&PhaseTiming)>,
+    ) {
+        let timing = PhaseTiming {
+            phase,
+            duration_us: now_us.saturating_sub(start_us),
+        };
+        self.phases.push(timing);
+        if let Some(callback) = callback {
+            callback(&timing);
+        }
+    }
 }
 
 /// Controller data structure.
@@ -199,454 +770,2110 @@ pub struct ControllerData {
     pub max_io_sq: u16,
     /// Maximum number of I/O completion queues (0-based)
     pub max_io_cq: u16,
+    /// Number of supported power states
+    pub num_power_states: u8,
+    /// RTD3 resume latency in microseconds (0 if not reported)
+    pub rtd3_resume_latency_us: u32,
+    /// RTD3 entry latency in microseconds (0 if not reported)
+    pub rtd3_entry_latency_us: u32,
+    /// Weighted round robin with urgent priority class is supported
+    pub wrr_supported: bool,
+    /// Recommended arbitration burst (as a power of two)
+    pub recommended_arbitration_burst: u8,
+    /// Optional NVM command support (ONCS)
+    pub oncs: u16,
+    /// Submission queue entry sizes (SQES): required in bits 3:0, maximum in bits 7:4
+    pub sqes: u8,
+    /// Completion queue entry sizes (CQES): required in bits 3:0, maximum in bits 7:4
+    pub cqes: u8,
+    /// Sanitize capabilities
+    pub sanitize: SanitizeCapabilities,
+    /// Firmware updates (FRMW): slot count in bits 3:1, slot 1 read-only in bit 0
+    pub firmware_updates: u8,
+    /// Firmware update granularity (FWUG) in 4 KiB units (0 = not reported, 0xFF = none)
+    pub firmware_update_granularity: u8,
+    /// ANA transition time (ANATT) in seconds (0 = not reported)
+    pub ana_transition_time_sec: u8,
 }
 
-/// I/O queue pair representing submission and completion queues.
-struct IoQueuePair {
-    /// Queue ID (1-based for I/O queues)
-    qid: u16,
-    /// Submission queue
-    sq: SubQueue,
-    /// Completion queue
-    cq: CompQueue,
-    /// PRP manager for this queue
-    prp_manager: PrpManager,
-    /// Number of outstanding commands
-    outstanding: AtomicUsize,
-    /// Queue shutdown flag - when true, no new I/O accepted
-    shutdown: AtomicBool,
+impl ControllerData {
+    /// Check Identify Controller data for implausible values.
+    ///
+    /// Garbage from a broken controller or DMA mapping fails here instead
+    /// of turning into absurd transfer sizes or queue parameters later.
+    fn check_identify(buf: &[u8]) -> Result<()> {
+        // Strings are ASCII, space padded, but some controllers pad with NULs
+        let ascii = |field: &[u8]| {
+            let end = field.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+            field[..end].iter().all(|&b| (0x20..=0x7E).contains(&b))
+        };
+        let entry_sizes = |es: u8| es & 0xF != 0 && es & 0xF <= es >> 4;
+
+        let invalid = if buf.iter().all(|&b| b == 0) {
+            "data (all zeroes)"
+        } else if !ascii(&buf[4..24]) {
+            "serial number"
+        } else if !ascii(&buf[24..64]) {
+            "model number"
+        } else if !ascii(&buf[64..72]) {
+            "firmware revision"
+        } else if buf[77] > MAX_MDTS {
+            "MDTS"
+        } else if buf[263] > 31 {
+            "NPSS"
+        } else if !entry_sizes(buf[512]) {
+            "SQES"
+        } else if !entry_sizes(buf[513]) {
+            "CQES"
+        } else {
+            // Descriptors of unsupported power states should be cleared
+            let unused = &buf[2048 + (buf[263] as usize + 1) * 32..3072];
+            if unused.iter().any(|&b| b != 0) {
+                nvme_log!(Warn, "identify data has nonzero unused power state descriptors");
+            }
+            return Ok(());
+        };
+
+        nvme_log!(Error, "identify controller data failed validation: {}", invalid);
+        Err(Error::InvalidIdentifyData(invalid))
+    }
+
+    /// Check whether the controller accepts the driver's queue entry sizes.
+    fn supports_entry_sizes(&self) -> bool {
+        let fits = |es: u8, size: u8| es & 0xF <= size && size <= es >> 4;
+        fits(self.sqes, SQ_ENTRY_SIZE_LOG2) && fits(self.cqes, CQ_ENTRY_SIZE_LOG2)
+    }
 }
 
-/// Internal device state - uses spin::Mutex for thread-safe interior mutability
-struct DeviceInner<A: Allocator> {
-    allocator: Arc<A>,
-    doorbell_helper: DoorbellHelper,
-    data: Mutex<ControllerData>,
-    ioq: Mutex<Vec<Arc<Mutex<IoQueuePair>>>>,
-    queue_selector: AtomicUsize,
-    next_queue_id: AtomicUsize,
-    shutting_down: AtomicBool,
+/// Controller state saved by `suspend` and restored by `resume`.
+struct SuspendState {
+    /// Number of I/O queue pairs before suspend
+    ioq_count: usize,
+    /// Volatile write cache enable, if the controller has one
+    write_cache: Option<bool>,
+    /// APST feature value and transition table, if supported
+    apst: Option<(u32, Vec<u8>)>,
 }
 
-/// A structure representing an NVMe namespace.
-pub struct Namespace<A: Allocator> {
-    id: u32,
-    block_count: u64,
-    block_size: u64,
-    device: Arc<DeviceInner<A>>,
+/// An admin command submitted without waiting for its completion.
+///
+/// Returned by the `start_` methods of `NVMeDevice`. Poll it with
+/// `NVMeDevice::poll_admin` until the command completes.
+#[derive(Debug)]
+pub struct AdminToken {
+    cmd_id: u16,
+    opcode: u8,
+    /// Tells this submission apart from later ones reusing the command ID
+    ticket: u64,
+    /// Namespace identified again once the command succeeds
+    rescan: Option<u32>,
+    /// Fence released by the command, and whether it outlives a
+    /// successful completion because the operation runs in the background
+    fence: Option<(DeviceOperation, bool)>,
 }
 
-impl<A: Allocator> Namespace<A> {
-    /// Get the namespace ID.
-    pub fn id(&self) -> u32 {
-        self.id
+impl AdminToken {
+    /// Get the command identifier.
+    pub fn cmd_id(&self) -> u16 {
+        self.cmd_id
     }
 
-    /// Get the block count.
-    pub fn block_count(&self) -> u64 {
-        self.block_count
+    /// Get the opcode.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
     }
+}
 
-    /// Get the block size (in bytes).
-    pub fn block_size(&self) -> u64 {
-        self.block_size
-    }
+/// A long-running operation that fences conflicting commands.
+///
+/// Only one runs at a time. Starting another fails with the error of the
+/// running one, as does I/O to the namespaces a format or sanitize affects
+/// and, during a format or sanitize, namespace attachment and firmware
+/// commits. A format or sanitize starts once the I/O already in flight to
+/// its namespaces has completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceOperation {
+    /// Format NVM of a namespace, or of all with 0xFFFFFFFF
+    Format(u32),
+    /// Sanitize of a namespace, or of the subsystem with 0xFFFFFFFF
+    Sanitize(u32),
+    /// Device self-test, which leaves I/O running
+    SelfTest,
+}
 
-    /// Read from the namespace.
-    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
-        if buf.len() as u64 % self.block_size != 0 {
-            return Err(Error::InvalidBufferSize);
+impl DeviceOperation {
+    /// Get the error commands fenced by the operation fail with.
+    pub fn error(self) -> Error {
+        match self {
+            Self::Format(_) => Error::FormatInProgress,
+            Self::Sanitize(_) => Error::SanitizeInProgress,
+            Self::SelfTest => Error::SelfTestInProgress,
         }
-        self.do_io(lba, buf.as_mut_ptr() as usize, buf.len(), false)
     }
 
-    /// Write to the namespace.
-    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<()> {
-        if buf.len() as u64 % self.block_size != 0 {
-            return Err(Error::InvalidBufferSize);
+    /// Check whether I/O to namespace `nsid` is rejected while the operation runs.
+    pub fn fences_io(self, nsid: u32) -> bool {
+        match self {
+            Self::Format(target) | Self::Sanitize(target) => {
+                target == nsid || target == 0xFFFFFFFF
+            }
+            Self::SelfTest => false,
         }
-        self.do_io(lba, buf.as_ptr() as usize, buf.len(), true)
     }
+}
 
-    /// Select the optimal I/O queue for this operation.
-    fn select_queue(&self) -> Option<Arc<Mutex<IoQueuePair>>> {
-        let queues = self.device.ioq.lock();
-        if queues.is_empty() {
-            return None;
-        }
+/// An admin command in flight.
+struct AdminSlot {
+    /// Tells this submission apart from later ones reusing the command ID
+    ticket: u64,
+    /// Completion, once reaped
+    completion: Option<Completion>,
+    /// The submitter stopped waiting, so the completion is dropped on arrival
+    abandoned: bool,
+    /// An Asynchronous Event Request, whose completion is queued for
+    /// `NVMeDevice::process_async_events`
+    async_event: bool,
+}
 
-        // Filter out shutdown queues
-        let active_queues: Vec<_> = queues
-            .iter()
-            .filter(|q| !q.lock().shutdown.load(Ordering::Acquire))
-            .cloned()
-            .collect();
+/// What removing I/O queues does with commands still outstanding after
+/// the wait for them, which lasts 5 seconds while a clock is set.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueueRemovalPolicy {
+    /// Keep the queues and fail with `Error::QueueRemovalStalled`
+    #[default]
+    Fail,
+    /// Abort the outstanding commands and wait once more, then fail as
+    /// with `Fail` if any are left
+    Abort,
+}
 
-        if active_queues.is_empty() {
-            return None;
-        }
+/// When the submission queue tail doorbell is written.
+///
+/// Only applies to commands submitted without waiting for them, with
+/// `Namespace::submit_read` and `Namespace::submit_write`. Commands that
+/// are waited for always ring the doorbell, which also submits any
+/// commands queued before them. Admin commands, including firmware
+/// download chunks, are never batched. Waits for the I/O in flight, as
+/// in `NVMeDevice::quiesce`, `suspend`, `reset` and drop, ring the
+/// doorbells of batched commands first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DoorbellPolicy {
+    /// Ring the doorbell for each command
+    #[default]
+    Immediate,
+    /// Ring the doorbell once `max_pending` commands are queued on the
+    /// submission queue, or on `NVMeDevice::ring`, `Namespace::ring` or
+    /// `NVMeDevice::poll_completions`
+    Batched {
+        /// Commands queued before the doorbell is rung
+        max_pending: u16,
+    },
+}
 
-        if active_queues.len() == 1 {
-            return Some(active_queues[0].clone());
-        }
+/// Error a command fails with once its ANA transition retries run out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AnaFallback {
+    /// Fail with `Error::AnaTransitionTimeout`
+    #[default]
+    Timeout,
+    /// Fail with the last `Error::AnaTransition`, leaving the retry on
+    /// another path to the caller
+    Transition,
+}
 
-        // Try to find least loaded active queue
-        let mut min_outstanding = usize::MAX;
-        let mut selected_queue = None;
+/// How blocking I/O commands that fail with `Error::AnaTransition` are
+/// retried.
+///
+/// The queue is released while waiting to retry. If a multipath
+/// controller is set and selects another path, the command fails with
+/// the error at once, to be retried on that path by the caller.
+/// Retries need a clock to time the wait; without one, commands fail at
+/// once as with `max_wait_ms` 0. Non-blocking and steered commands are
+/// never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnaRetryPolicy {
+    /// Longest time to retry a command in milliseconds (0 = no retries)
+    ///
+    /// Capped at the controller's ANA transition time (ANATT) if reported.
+    pub max_wait_ms: u32,
+    /// Delay between retries in microseconds
+    pub retry_interval_us: u32,
+    /// Error once the wait runs out
+    pub fallback: AnaFallback,
+}
 
-        for queue in active_queues.iter() {
-            let outstanding = queue.lock().outstanding.load(Ordering::Relaxed);
-            if outstanding < min_outstanding {
-                min_outstanding = outstanding;
-                selected_queue = Some(queue.clone());
-            }
+impl Default for AnaRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_wait_ms: 10_000,
+            retry_interval_us: 1_000,
+            fallback: AnaFallback::default(),
         }
+    }
+}
 
-        // If all queues are equally loaded, use round-robin
-        selected_queue.or_else(|| {
-            let idx = self.device.queue_selector.fetch_add(1, Ordering::Relaxed) % active_queues.len();
-            Some(active_queues[idx].clone())
-        })
-    }
-
-    /// TRIM/Discard - Essential for SSD performance and lifetime.
-    /// Informs the controller that specified LBA ranges contain no valid data.
-    pub fn trim(&self, lba: u64, block_count: u64) -> Result<()> {
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
-        }
+/// An I/O queue whose commands kept it from being removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StalledQueue {
+    /// Submission queue ID
+    pub qid: u16,
+    /// Number of outstanding commands
+    pub outstanding: usize,
+    /// Command IDs of the outstanding commands
+    pub cids: Vec<u16>,
+}
 
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let mut queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+/// Outcome of `NVMeDevice::quiesce`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuiesceReport {
+    /// Commands that completed on their own
+    pub drained: usize,
+    /// Commands the controller aborted
+    pub aborted: usize,
+    /// Commands still in flight when the second wait timed out
+    pub remaining: usize,
+}
 
-        // Prepare dataset management ranges (up to 256 ranges)
-        let range_data = [(lba as u32, (lba >> 32) as u32, block_count as u32)];
-        let range_addr = range_data.as_ptr() as usize;
+/// I/O completion queue, possibly shared by several submission queues.
+struct IoCompQueue {
+    /// Completion queue ID
+    cqid: u16,
+    /// Completion queue
+    cq: CompQueue,
+    /// Doorbell registers of this completion queue
+    doorbells: QueueDoorbells,
+    /// Completions reaped on behalf of other submission queues, keyed by (SQID, CID)
+    pending: Mutex<BTreeMap<(u16, u16), Completion>>,
+    /// PRP list of a queue that is not physically contiguous
+    prp_list: Option<Dma<u64>>,
+    /// Interrupt vector, `None` if the queue is polled
+    vector: Option<u16>,
+    /// The controller lost the queue in a reset, so waits on it fail
+    dead: AtomicBool,
+}
 
-        let cmd = Command::dataset_management(
-            queue.sq.tail() as u16,
-            self.id,
-            range_addr,
-            0, // nr = 0 means 1 range
-            true, // ad = true for deallocate (TRIM)
-            false,
-            false,
-        );
+impl IoCompQueue {
+    /// Free the PRP list once the controller has deleted the queue.
+    fn free_buffers<A: Allocator>(&self, allocator: &A) {
+        if let Some(list) = &self.prp_list {
+            list.deallocate(allocator);
+        }
+    }
 
-        // Submit command with dynamic queue management
-        let entry = self.submit_iocmd(&mut queue, cmd)?;
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+    /// Wait for the completion of command `cmd_id` on submission queue `sqid`.
+    ///
+    /// Completions of other submission queues found on the way are kept
+    /// until their submitter picks them up. Fails with
+    /// `Error::ControllerResetOccurred` once the queue is lost to a reset.
+    fn wait_for(&self, sqid: u16, cmd_id: u16, wait: impl Fn()) -> Result<Completion> {
+        loop {
+            {
+                let mut pending = self.pending.lock();
+                if let Some(entry) = pending.remove(&(sqid, cmd_id)) {
+                    return Ok(entry);
+                }
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+                if let Some((head, entry)) = self.cq.try_pop() {
+                    self.doorbells.ring_cq(head);
+                    let key = (entry.sq_id, entry.cmd_id);
+                    if key == (sqid, cmd_id) {
+                        return Ok(entry);
+                    }
+                    pending.insert(key, entry);
+                    continue;
+                }
+            }
+            if self.dead.load(Ordering::Acquire) {
+                return Err(Error::ControllerResetOccurred);
+            }
+            wait();
         }
-
-        Ok(())
     }
 
-    /// Write Zeroes - Efficient zeroing without data transfer.
-    /// Much faster than writing actual zero buffers.
-    pub fn write_zeroes(&self, lba: u64, block_count: u16) -> Result<()> {
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
+    /// Collect the completions posted so far for their submitters.
+    fn reap(&self) {
+        let mut pending = self.pending.lock();
+        while let Some((head, entry)) = self.cq.try_pop() {
+            self.doorbells.ring_cq(head);
+            pending.insert((entry.sq_id, entry.cmd_id), entry);
         }
+    }
+}
 
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+/// A command whose completion goes to a `CompletionSink`.
+struct SteeredIo {
+    /// Submitter's context ID
+    context: u64,
+    /// Receives the completion
+    sink: Arc<dyn CompletionSink>,
+    /// PRP resources released on completion
+    prp: PrpResult,
+    /// Counters of the submitting namespace
+    stats: Arc<IoCounters>,
+    kind: IoKind,
+    bytes: usize,
+    /// Submission time in microseconds
+    start_us: u64,
+}
 
-        let cmd = Command::write_zeroes(
-            queue.sq.tail() as u16,
-            self.id,
-            lba,
-            block_count - 1,
-            false, // deac = deallocate after write
-        );
+/// A steered command's sink, context ID and result.
+type SteeredCompletion = (Arc<dyn CompletionSink>, u64, Result<IoResult>);
 
-        let tail = queue.sq.push(cmd);
-        self.device.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+/// Command IDs in use on a submission queue, and its completion queue.
+type InFlightQueue = (Arc<CommandIds>, Arc<IoCompQueue>);
 
-        let (head, entry) = queue.cq.pop();
-        self.device.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-        queue.sq.set_head(entry.sq_head as usize);
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+/// I/O queue pair representing submission and completion queues.
+struct IoQueuePair {
+    /// Queue ID (1-based for I/O queues)
+    qid: u16,
+    /// Submission queue
+    sq: SubQueue,
+    /// Completion queue, shared if other submission queues are bound to it
+    cq: Arc<IoCompQueue>,
+    /// PRP manager for this queue
+    prp_manager: PrpManager,
+    /// Pre-mapped page small reads land in before being copied out, and
+    /// descriptors of synchronous commands are staged in
+    inline_buf: Dma<u8>,
+    /// PRP list of a submission queue that is not physically contiguous
+    prp_list: Option<Dma<u64>>,
+    /// Number of outstanding commands
+    outstanding: AtomicUsize,
+    /// Commands completed since the last tuning step
+    completed: AtomicU64,
+    /// Total completion latency since the last tuning step in microseconds
+    latency_us: AtomicU64,
+    /// Queue shutdown flag - when true, no new I/O accepted
+    shutdown: AtomicBool,
+    /// Rate limit applied to this queue
+    rate_limiter: Mutex<Option<RateLimiter>>,
+    /// Doorbell registers of this queue pair
+    doorbells: QueueDoorbells,
+    /// Commands completed through a sink, keyed by CID
+    steered: BTreeMap<u16, SteeredIo>,
+    /// Command IDs of the commands submitted and not yet completed
+    cids: Arc<CommandIds>,
+    /// Commands queued since the submission tail doorbell was last rung
+    unrung: AtomicUsize,
+    /// Path error of the last command waited for, if it failed on a multipath path
+    path_error: Cell<Option<PathErrorRecord>>,
+}
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+impl IoQueuePair {
+    /// Free the inline buffer and PRP list once the controller has deleted the queue.
+    fn free_buffers<A: Allocator>(&self, allocator: &A) {
+        self.inline_buf.deallocate(allocator);
+        if let Some(list) = &self.prp_list {
+            list.deallocate(allocator);
         }
+    }
 
-        Ok(())
+    /// Copy a descriptor into the inline buffer and get its physical address.
+    ///
+    /// The buffer is only reused once the queue lock is released, so the
+    /// caller must wait for the command reading the descriptor under it.
+    fn stage<T: Le>(&self, descriptor: T) -> u64 {
+        debug_assert!(size_of::<T>() <= INLINE_READ_SIZE);
+        unsafe { (self.inline_buf.addr as *mut T).write_unaligned(descriptor.swap_le()) };
+        self.inline_buf.phys_addr
     }
 
-    /// Compare - Atomically compare data without transferring to host.
-    /// Essential for lock-free algorithms and database implementations.
-    pub fn compare(&self, lba: u64, expected: &[u8]) -> Result<bool> {
-        if expected.len() as u64 % self.block_size != 0 {
-            return Err(Error::InvalidBufferSize);
-        }
+    /// Ring the submission tail doorbell, submitting every queued command.
+    fn ring_sq(&self, tail: usize) {
+        self.unrung.store(0, Ordering::Relaxed);
+        self.doorbells.ring_sq(tail);
+    }
 
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
+    /// Ring the submission tail doorbell if commands are waiting for it.
+    fn ring_pending(&self) {
+        if self.unrung.load(Ordering::Relaxed) != 0 {
+            self.ring_sq(self.sq.tail());
         }
+    }
 
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let mut queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
-
-        // Create PRP for expected data
-        let prp_result = queue.prp_manager.create(
-            self.device.allocator.as_ref(),
-            expected.as_ptr() as usize,
-            expected.len()
-        )?;
-        let prp = prp_result.get_prp();
-        let blocks = expected.len() as u64 / self.block_size;
-
-        let cmd = Command::compare(
-            queue.sq.tail() as u16,
-            self.id,
-            lba,
-            blocks as u16 - 1,
-            [prp.0 as u64, prp.1 as u64],
-        );
+    /// Wait for the completion of command `cmd_id` submitted to this queue.
+    fn wait_completion(&self, cmd_id: u16, wait: impl Fn()) -> Result<Completion> {
+        let entry = self.cq.wait_for(self.qid, cmd_id, wait)?;
 
-        let tail = queue.sq.push(cmd);
-        self.device.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+        // Update submission queue head from completion entry
+        self.sq.set_head(entry.sq_head as usize);
+        Ok(entry)
+    }
+}
 
-        let (head, entry) = queue.cq.pop();
-        self.device.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-        queue.sq.set_head(entry.sq_head as usize);
+/// Internal device state - uses spin::Mutex for thread-safe interior mutability
+struct DeviceInner<A: Allocator> {
+    allocator: Arc<A>,
+    doorbell_helper: DoorbellHelper,
+    data: Mutex<ControllerData>,
+    ioq: Mutex<Vec<Arc<Mutex<IoQueuePair>>>>,
+    queue_selector: AtomicUsize,
+    queue_depth: AtomicUsize,
+    next_queue_id: AtomicUsize,
+    shutting_down: AtomicBool,
+    /// Namespace commands currently in flight
+    active_io: AtomicUsize,
+    /// Command IDs in flight on each I/O submission queue and its
+    /// completion queue, keyed by SQID
+    ///
+    /// Only changed with the queues, so commands never take this lock.
+    /// Reachable while a command waiting on a queue holds the queue's lock.
+    in_flight: Mutex<BTreeMap<u16, InFlightQueue>>,
+    suspended: AtomicBool,
+    /// New I/O is rejected until `NVMeDevice::unquiesce`
+    quiesced: AtomicBool,
+    clock: RwLock<Option<Arc<dyn Clock>>>,
+    wait_strategy: RwLock<WaitStrategy>,
+    /// I/O command latencies, measured while a clock is set
+    latency: LatencyHistogram,
+    /// Multipath path fed with the measured latencies
+    path: RwLock<Option<Arc<ControllerPath>>>,
+    /// Multipath controller told about ANA path errors, with the path ID
+    multipath: RwLock<Option<(Arc<MultipathController>, u32)>>,
+    /// When doorbells of commands not waited for are rung
+    doorbell_policy: RwLock<DoorbellPolicy>,
+    /// Retries of I/O commands during ANA transitions
+    ana_retry: AnaRetryPolicy,
+    /// Format, sanitize or self-test in progress
+    operation: Mutex<Option<DeviceOperation>>,
+}
 
-        // Release PRP resources
-        queue.prp_manager.release(prp_result, self.device.allocator.as_ref());
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+impl<A: Allocator> DeviceInner<A> {
+    /// Current time from the host clock, or zero if none is set.
+    fn now_us(&self) -> u64 {
+        self.clock.read().as_ref().map_or(0, |clock| clock.now_us())
+    }
 
-        let status = (entry.status >> 1) & 0xff;
-        if status == 0 {
-            Ok(true) // Compare matched
-        } else if status == 0x85 { // Compare Failure
-            Ok(false) // Compare didn't match
-        } else {
-            Err(Error::CommandFailed(status))
+    /// Create a rate limiter, which needs a clock to refill its buckets.
+    fn rate_limiter(&self, limit: Option<RateLimit>) -> Result<Option<RateLimiter>> {
+        match limit {
+            Some(_) if self.clock.read().is_none() => Err(Error::ClockNotSet),
+            Some(limit) => Ok(Some(RateLimiter::new(limit, self.now_us()))),
+            None => Ok(None),
         }
     }
 
-    /// Verify - Check data integrity without transferring to host.
-    /// Critical for data scrubbing and integrity verification.
-    pub fn verify(&self, lba: u64, block_count: u16) -> Result<()> {
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
+    /// Record the latency of a completed I/O command.
+    ///
+    /// Nothing is recorded without a clock, as the latency would read zero.
+    fn record_latency(&self, latency_us: u64, success: bool, now_us: u64) {
+        if self.clock.read().is_none() {
+            return;
         }
+        self.latency.record(latency_us);
+        let latency_us = latency_us.min(u32::MAX as u64) as u32;
+        if let Some(path) = self.path.read().as_ref() {
+            path.update_metrics(latency_us, success, now_us);
+        }
+        if let Some((multipath, path_id)) = self.multipath.read().as_ref() {
+            multipath.record_io(*path_id, latency_us, success, now_us);
+        }
+    }
 
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+    /// Count an I/O command in or out of the multipath path's queue depth.
+    fn track_path_io(&self, started: bool) {
+        if let Some((multipath, path_id)) = self.multipath.read().as_ref() {
+            match started {
+                true => multipath.start_io(*path_id),
+                false => multipath.finish_io(*path_id),
+            }
+        }
+    }
 
-        let cmd = Command::verify(
-            queue.sq.tail() as u16,
-            self.id,
-            lba,
-            block_count - 1,
+    /// Record a failed I/O command with the multipath path it took.
+    ///
+    /// Returns the record, which the command's error is returned with.
+    fn report_path_status(
+        &self,
+        qid: u16,
+        entry: &Completion,
+        now_us: u64,
+    ) -> Option<PathErrorRecord> {
+        if entry.result().is_ok() {
+            return None;
+        }
+        let (multipath, path_id) = self.multipath.read().clone()?;
+        let status = StatusCode::from_raw(entry.status);
+        let record = multipath.record_error(path_id, qid, status, now_us)?;
+        nvme_log!(
+            Debug,
+            "path {} controller {} queue {}: {:?} error: {}",
+            record.path_id,
+            record.controller_id,
+            record.qid,
+            record.kind,
+            status.description()
         );
+        Some(record)
+    }
 
-        let tail = queue.sq.push(cmd);
-        self.device.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
-
-        let (head, entry) = queue.cq.pop();
-        self.device.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-        queue.sq.set_head(entry.sq_head as usize);
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
-
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+    /// Fail the path over if a completion reports an ANA path error.
+    fn report_path_error(&self, entry: &Completion, now_us: u64) {
+        let Some(state) = entry.result().err().and_then(|e| e.ana_state()) else {
+            return;
+        };
+        if let Some((multipath, path_id)) = self.multipath.read().as_ref() {
+            nvme_log!(Warn, "path {} reported ANA state {:?}", path_id, state);
+            let _ = multipath.handle_path_error(*path_id, state, now_us);
         }
+    }
 
-        Ok(())
+    /// Get how long to retry a command in an ANA transition, in microseconds.
+    ///
+    /// Zero without a clock, which the wait cannot be timed without.
+    fn ana_retry_wait_us(&self) -> u64 {
+        if self.clock.read().is_none() {
+            return 0;
+        }
+        let mut wait_ms = self.ana_retry.max_wait_ms as u64;
+        let anatt = self.data.lock().ana_transition_time_sec;
+        if anatt != 0 {
+            wait_ms = wait_ms.min(anatt as u64 * 1000);
+        }
+        wait_ms * 1000
     }
 
-    /// Copy - Server-side copy without host involvement.
-    /// Essential for efficient data migration and backup.
-    pub fn copy(&self, src_lba: u64, dst_lba: u64, block_count: u16) -> Result<()> {
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
+    /// Ring the doorbells of every I/O queue with commands waiting for it.
+    fn ring_pending(&self) {
+        let queues = self.ioq.lock().clone();
+        for queue in queues {
+            queue.lock().ring_pending();
         }
+    }
 
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+    /// Take the completions of a queue pair's steered commands.
+    ///
+    /// Returns each sink with its context ID and result. The sinks are
+    /// called by the caller once the queue pair is unlocked, so they may
+    /// submit further commands.
+    fn take_steered(&self, queue: &mut IoQueuePair) -> Vec<SteeredCompletion> {
+        queue.cq.reap();
+        let entries: Vec<_> = {
+            let mut pending = queue.cq.pending.lock();
+            let qid = queue.qid;
+            queue.steered.keys().filter_map(|&cmd_id| {
+                pending.remove(&(qid, cmd_id)).map(|entry| (cmd_id, entry))
+            }).collect()
+        };
 
-        // Copy descriptor format 0 (simple copy)
-        let copy_desc = [
-            src_lba as u64,
-            (src_lba >> 32) as u64 | ((block_count as u64 - 1) << 32),
-        ];
-        let desc_addr = copy_desc.as_ptr() as usize;
-
-        let cmd = Command::copy(
-            queue.sq.tail() as u16,
-            self.id,
-            desc_addr,
-            dst_lba,
-            0, // nr = 0 means 1 source range
-            0, // desc_format = 0 for simple copy
-        );
+        let now = self.now_us();
+        let mut done = Vec::with_capacity(entries.len());
+        for (cmd_id, entry) in entries {
+            let Some(io) = queue.steered.remove(&cmd_id) else {
+                continue;
+            };
+            queue.sq.set_head(entry.sq_head as usize);
+            queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+            self.track_path_io(false);
+            queue.prp_manager.release(io.prp, self.allocator.as_ref());
+            queue.cids.release(cmd_id);
+            // Ends the namespace command started in `Namespace::submit_steered`
+            io.stats.in_flight.fetch_sub(1, Ordering::Release);
+            self.active_io.fetch_sub(1, Ordering::Release);
+
+            let latency = now.saturating_sub(io.start_us);
+            let record = self.report_path_status(queue.qid, &entry, now);
+            let result = entry.io_result().map_err(|e| e.on_path(record));
+            queue.latency_us.fetch_add(latency, Ordering::Relaxed);
+            queue.completed.fetch_add(1, Ordering::Relaxed);
+            self.record_latency(latency, result.is_ok(), now);
+            self.report_path_error(&entry, now);
+            io.stats.record(io.kind, io.bytes as u64, result.is_ok());
+            done.push((io.sink, io.context, result));
+        }
+        done
+    }
 
-        let tail = queue.sq.push(cmd);
-        self.device.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+    /// Fail the steered commands of a queue pair lost to a controller reset.
+    ///
+    /// Returns each sink with its context ID and
+    /// `Error::ControllerResetOccurred`, like `take_steered`.
+    fn fail_steered(&self, queue: &mut IoQueuePair) -> Vec<SteeredCompletion> {
+        let steered = core::mem::take(&mut queue.steered);
+        let mut done = Vec::with_capacity(steered.len());
+        for (cmd_id, io) in steered {
+            queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+            self.track_path_io(false);
+            queue.prp_manager.release(io.prp, self.allocator.as_ref());
+            queue.cids.release(cmd_id);
+            io.stats.in_flight.fetch_sub(1, Ordering::Release);
+            self.active_io.fetch_sub(1, Ordering::Release);
+
+            io.stats.record(io.kind, io.bytes as u64, false);
+            done.push((io.sink, io.context, Err(Error::ControllerResetOccurred)));
+        }
+        done
+    }
 
-        let (head, entry) = queue.cq.pop();
-        self.device.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-        queue.sq.set_head(entry.sq_head as usize);
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+    /// Start a wait loop following the device's wait strategy.
+    fn waiter(&self) -> Waiter {
+        Waiter::new(self.wait_strategy.read().clone())
+    }
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+    /// Start a command to namespace `nsid` if the device currently accepts
+    /// new I/O for it.
+    ///
+    /// The returned guard keeps `NVMeDevice`'s drop from tearing down the
+    /// controller, and a format or sanitize of the namespace from starting,
+    /// until the command has completed.
+    fn begin_io<'a>(&'a self, nsid: u32, stats: &'a IoCounters) -> Result<IoGuard<'a>> {
+        // Count the command before checking, so teardown and fencing
+        // operations either see it or the command sees them
+        self.active_io.fetch_add(1, Ordering::SeqCst);
+        stats.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = IoGuard { active: &self.active_io, namespace: &stats.in_flight };
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(Error::NamespaceDetached);
         }
-
-        Ok(())
+        if self.suspended.load(Ordering::Acquire) {
+            return Err(Error::DeviceSuspended);
+        }
+        if self.quiesced.load(Ordering::Acquire) {
+            return Err(Error::DeviceQuiesced);
+        }
+        if let Some(operation) = *self.operation.lock()
+            && operation.fences_io(nsid)
+        {
+            return Err(operation.error());
+        }
+        Ok(guard)
     }
+}
 
-    /// Submit I/O command to hardware queue
-    fn submit_iocmd(&self, queue: &mut IoQueuePair, cmd: Command) -> Result<Completion> {
-        // Push command to submission queue (will spin if full)
-        let tail = queue.sq.push(cmd);
-        self.device.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+/// Per-command options of a read or write.
+#[derive(Debug, Default, Clone, Copy)]
+struct IoOptions {
+    /// Dataset management hints
+    hints: IoHints,
+    /// Protection information checks and tags
+    protection: Option<ProtectionCheck>,
+}
 
-        // Wait for completion
-        let (head, entry) = queue.cq.pop();
-        self.device.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
+/// An in-flight namespace command.
+struct IoGuard<'a> {
+    active: &'a AtomicUsize,
+    /// In-flight count of the namespace
+    namespace: &'a AtomicUsize,
+}
 
-        // Update submission queue head from completion entry
-        queue.sq.set_head(entry.sq_head as usize);
+impl Drop for IoGuard<'_> {
+    fn drop(&mut self) {
+        self.namespace.fetch_sub(1, Ordering::Release);
+        self.active.fetch_sub(1, Ordering::Release);
+    }
+}
 
-        Ok(entry)
+/// A structure representing an NVMe namespace.
+pub struct Namespace<A: Allocator> {
+    id: u32,
+    block_count: AtomicU64,
+    block_size: u64,
+    /// Metadata bytes per block
+    metadata_size: u16,
+    /// Metadata is transferred at the end of each block (FLBAS bit 4)
+    extended_metadata: bool,
+    /// The namespace may be attached to several controllers (NMIC bit 0)
+    shared: bool,
+    /// ANA group the namespace belongs to (ANAGRPID, 0 if not reported)
+    ana_group_id: u32,
+    dlfeat: u8,
+    device: Arc<DeviceInner<A>>,
+    rate_limiter: Mutex<Option<RateLimiter>>,
+    stats: Arc<IoCounters>,
+    opens: OpenTracker,
+    ordering: Mutex<IoOrdering>,
+    /// Block ranges in use, tracked with `IoOrdering::Overlapping`
+    ranges: RangeLocks,
+    /// Tail blocks kept unwritten as extra overprovisioning
+    reserved_blocks: AtomicU64,
+}
+
+impl<A: Allocator> Namespace<A> {
+    /// Get the namespace ID.
+    pub fn id(&self) -> u32 {
+        self.id
     }
 
-    /// Perform I/O operation.
-    fn do_io(&self, lba: u64, address: usize, bytes: usize, write: bool) -> Result<()> {
-        // Check if device is shutting down
-        if self.device.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::DeviceShuttingDown);
-        }
+    /// Open the namespace in `mode`.
+    ///
+    /// Fails with `Error::NamespaceBusy` while an exclusive handle is open,
+    /// or if `mode` is exclusive and any handle is open.
+    pub fn open(self: &Arc<Self>, mode: OpenMode) -> Result<NamespaceHandle<A>> {
+        self.opens.acquire(mode)?;
+        Ok(NamespaceHandle::new(self.clone(), mode))
+    }
 
-        let max_transfer_size = self.device.data.lock().max_transfer_size;
-        if bytes > max_transfer_size {
-            return Err(Error::IoSizeExceedsMdts);
-        }
+    /// Release a handle returned by `open`.
+    pub(crate) fn close(&self) {
+        self.opens.release();
+    }
 
-        // Select queue and perform I/O
-        let queue_arc = self.select_queue().ok_or(Error::NoActiveQueues)?;
-        let mut queue = queue_arc.lock();
-        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+    /// Get the I/O statistics of this namespace.
+    pub fn stats(&self) -> NamespaceStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset the I/O statistics of this namespace.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
 
-        // Create PRP list
-        let prp_result = queue.prp_manager.create(self.device.allocator.as_ref(), address, bytes)?;
-        let prp = prp_result.get_prp();
-        let blocks = bytes as u64 / self.block_size;
+    /// Check whether the device was dropped.
+    ///
+    /// I/O on a detached namespace fails with `Error::NamespaceDetached`.
+    pub fn is_detached(&self) -> bool {
+        self.device.shutting_down.load(Ordering::Acquire)
+    }
 
-        // Create command
-        let command = Command::read_write(
-            queue.sq.tail() as u16,
-            self.id,
-            lba,
-            blocks as u16 - 1,
-            [prp.0 as u64, prp.1 as u64],
-            write,
-        );
+    /// Get the block count.
+    ///
+    /// Updated by `refresh` and when the controller reports a capacity
+    /// change through `NVMeDevice::handle_async_event`. Zero once the
+    /// namespace has been removed. Blocks reserved with
+    /// `set_reserved_blocks` are not counted.
+    pub fn block_count(&self) -> u64 {
+        self.capacity().saturating_sub(self.reserved_blocks.load(Ordering::Acquire))
+    }
 
-        // Submit command with dynamic queue management
-        let entry = self.submit_iocmd(&mut queue, command)?;
+    /// Get the block count including reserved blocks.
+    fn capacity(&self) -> u64 {
+        self.block_count.load(Ordering::Acquire)
+    }
 
-        // Release PRP resources
-        queue.prp_manager.release(prp_result, self.device.allocator.as_ref());
-        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+    /// Re-read the namespace capacity from the controller.
+    ///
+    /// Returns the new block count, which is zero if the namespace is no
+    /// longer active.
+    pub fn refresh(&self, device: &NVMeDevice<A>) -> Result<u64> {
+        let blocks = device.ident_namespace(self.id)?.map_or(0, |ns| ns.capacity());
+        self.block_count.store(blocks, Ordering::Release);
+        Ok(self.block_count())
+    }
 
-        // Check status
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+    /// Reserve the last `blocks` blocks of the namespace as extra
+    /// overprovisioning, and deallocate them.
+    ///
+    /// Reserved blocks are hidden from `block_count` and from I/O, so the
+    /// controller can use them as spare area as long as they stay
+    /// deallocated. Data in blocks newly taken into the reservation is
+    /// lost. 0 removes the reservation. Fails with `Error::LbaOutOfRange`
+    /// if the reservation would leave no blocks. The reservation belongs
+    /// to this namespace object, and is not carried over when the
+    /// namespace is identified again after a format.
+    pub fn set_reserved_blocks(&self, blocks: u64) -> Result<()> {
+        if blocks >= self.capacity() {
+            return Err(Error::LbaOutOfRange);
         }
+        self.reserved_blocks.store(blocks, Ordering::Release);
+        self.trim_reserved()
+    }
 
-        Ok(())
+    /// Get the number of tail blocks reserved as overprovisioning.
+    pub fn reserved_blocks(&self) -> u64 {
+        self.reserved_blocks.load(Ordering::Acquire)
     }
-}
 
-/// A structure representing an NVMe controller device.
-pub struct NVMeDevice<A: Allocator> {
-    address: *mut u8,
-    inner: Arc<DeviceInner<A>>,
+    /// Deallocate the reserved tail blocks again.
+    ///
+    /// Call it periodically, e.g. after a format or a sanitize that left
+    /// the blocks allocated, to keep them available as spare area.
+    pub fn trim_reserved(&self) -> Result<()> {
+        let end = self.capacity();
+        let mut lba = end - self.reserved_blocks().min(end);
+        while lba < end {
+            let blocks = (end - lba).min(u32::MAX as u64);
+            self.deallocate(lba, blocks, true)?;
+            lba += blocks;
+        }
+        Ok(())
+    }
 
-    // Namespaces
-    namespaces: RwLock<BTreeMap<u32, Arc<Namespace<A>>>>,
+    /// Get the block size (in bytes).
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
 
-    // Admin queues
-    admin_sq: SubQueue,
-    admin_cq: CompQueue,
-    admin_buffer: Dma<u8>,
-    // Mutex to serialize admin commands
-    admin_lock: Mutex<()>,
-}
+    /// Check whether the namespace may be attached to several controllers.
+    ///
+    /// A private namespace is only reachable through one controller, so
+    /// it has a single path.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
 
-unsafe impl<A: Allocator> Send for NVMeDevice<A> {}
-unsafe impl<A: Allocator> Sync for NVMeDevice<A> {}
+    /// Get the ID of the ANA group the namespace belongs to.
+    ///
+    /// 0 if the controller does not report ANA groups.
+    pub fn ana_group_id(&self) -> u32 {
+        self.ana_group_id
+    }
 
-impl<A: Allocator> NVMeDevice<A> {
-    /// Set the number of I/O queue pairs.
-    /// Will add or remove queues to match the target count.
-    /// When removing queues, it will:
-    /// 1. Mark queues for shutdown (no new I/O accepted)
-    /// 2. Wait for outstanding I/O to complete
-    /// 3. Remove the queues from hardware
-    pub fn set_ioq_count(&self, target: usize) -> Result<()> {
-        if target == 0 {
-            return Err(Error::InvalidQueueCount);
+    /// Get what reads of deallocated blocks return.
+    pub fn deallocated_read(&self) -> DeallocatedRead {
+        match self.dlfeat & 0x7 {
+            1 => DeallocatedRead::Zeroes,
+            2 => DeallocatedRead::Ones,
+            _ => DeallocatedRead::Undefined,
         }
+    }
 
-        let hw_limit = {
-            let data = self.inner.data.lock();
-            data.max_io_sq.min(data.max_io_cq) as usize
-        };
+    /// Check whether Write Zeroes with the deallocate bit set is supported.
+    ///
+    /// If so, the blocks are deallocated and read back as zeroes.
+    pub fn write_zeroes_deallocates(&self) -> bool {
+        self.dlfeat & (1 << 3) != 0
+    }
 
-        if target > hw_limit {
-            return Err(Error::TooManyQueues);
-        }
+    /// Get the maximum size of a single transfer (in bytes).
+    pub fn max_transfer_size(&self) -> usize {
+        self.device.data.lock().max_transfer_size
+    }
 
-        let current = self.ioq_count();
+    /// Limit the rate of I/O to this namespace, or remove the limit with `None`.
+    ///
+    /// Blocking operations wait until the limit admits them, and `try_`
+    /// operations fail with `Error::RateLimited`. A clock must be set.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) -> Result<()> {
+        *self.rate_limiter.lock() = self.device.rate_limiter(limit)?;
+        Ok(())
+    }
 
-        if target > current {
-            // Add queues
-            for _ in current..target {
-                self.add_ioq_internal()?;
-            }
-        } else if target < current {
-            // Remove queues safely
-            self.rm_ioq_internal(current - target)?;
-        }
+    /// Set the ordering of commands that touch overlapping block ranges.
+    ///
+    /// With `IoOrdering::Overlapping`, blocking operations wait for the
+    /// overlapping commands in flight, and `try_` operations fail with
+    /// `Error::RangeBusy`.
+    pub fn set_ordering(&self, ordering: IoOrdering) {
+        *self.ordering.lock() = ordering;
+    }
 
-        Ok(())
+    /// Get the ordering of commands that touch overlapping block ranges.
+    pub fn ordering(&self) -> IoOrdering {
+        *self.ordering.lock()
     }
 
-    /// Get the current number of I/O queue pairs.
-    pub fn ioq_count(&self) -> usize {
-        self.inner.ioq.lock().len()
+    /// Read from the namespace.
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_with(lba, buf, true)
     }
 
-    /// Get the current number of active (non-shutdown) I/O queue pairs.
-    pub fn active_ioq_count(&self) -> usize {
-        self.inner.ioq.lock()
-            .iter()
-            .filter(|q| !q.lock().shutdown.load(Ordering::Acquire))
-            .count()
+    /// Read from the namespace without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full or at
+    /// its depth limit, `Error::RangeBusy` if an overlapping command holds
+    /// the blocks and `Error::RateLimited` if a rate limit is exhausted.
+    /// Commands failing with `Error::AnaTransition` are not retried. The
+    /// call still waits for the queue lock and for the command to complete.
+    pub fn try_read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_with(lba, buf, false)
+    }
+
+    /// Read from the namespace with dataset management hints.
+    pub fn read_with_hints(&self, lba: u64, buf: &mut [u8], hints: IoHints) -> Result<()> {
+        self.read_hinted(lba, buf, hints, true)
+    }
+
+    /// Write to the namespace.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.write_with(lba, buf, true)
+    }
+
+    /// Write to the namespace without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full or at
+    /// its depth limit, `Error::RangeBusy` if an overlapping command holds
+    /// the blocks and `Error::RateLimited` if a rate limit is exhausted.
+    /// Commands failing with `Error::AnaTransition` are not retried. The
+    /// call still waits for the queue lock and for the command to complete.
+    pub fn try_write(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.write_with(lba, buf, false)
+    }
+
+    /// Write to the namespace with dataset management hints.
+    pub fn write_with_hints(&self, lba: u64, buf: &[u8], hints: IoHints) -> Result<()> {
+        self.write_hinted(lba, buf, hints, true)
+    }
+
+    /// Submit a read without waiting for it to complete.
+    ///
+    /// The result goes to `sink` with `context` once a poll of the queue
+    /// with `NVMeDevice::poll_completions` finds the completion. Fails with
+    /// `Error::SubQueueFull` instead of waiting if the queue is full.
+    /// Steered commands are not ordered by `IoOrdering::Overlapping`, and
+    /// are submitted as set by the device's `DoorbellPolicy`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid and must not be accessed until `sink` is
+    /// called.
+    pub unsafe fn submit_read(
+        &self,
+        lba: u64,
+        buf: &mut [u8],
+        context: u64,
+        sink: Arc<dyn CompletionSink>,
+    ) -> Result<()> {
+        self.submit_steered(lba, buf.as_mut_ptr() as usize, buf.len(), false, context, sink)
+    }
+
+    /// Submit a write without waiting for it to complete.
+    ///
+    /// See `submit_read`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid and must not be modified until `sink` is
+    /// called.
+    pub unsafe fn submit_write(
+        &self,
+        lba: u64,
+        buf: &[u8],
+        context: u64,
+        sink: Arc<dyn CompletionSink>,
+    ) -> Result<()> {
+        self.submit_steered(lba, buf.as_ptr() as usize, buf.len(), true, context, sink)
+    }
+
+    /// Ring the doorbells of commands queued by `DoorbellPolicy::Batched`.
+    ///
+    /// Same as `NVMeDevice::ring`.
+    pub fn ring(&self) {
+        self.device.ring_pending();
+    }
+
+    /// Read with protection information checks.
+    ///
+    /// On namespaces formatted with extended metadata, each block in `buf`
+    /// is followed by its metadata, unless `pract` is set and the metadata
+    /// only holds the protection information, which the controller strips.
+    pub fn read_protected(&self, lba: u64, buf: &mut [u8], check: &ProtectionCheck) -> Result<()> {
+        let options = IoOptions { protection: Some(*check), ..Default::default() };
+        self.read_opts(lba, buf, options, true)
+    }
+
+    /// Write with protection information checks.
+    ///
+    /// The buffer layout is the same as for `read_protected`. With `pract`
+    /// set, the controller generates the protection information.
+    pub fn write_protected(&self, lba: u64, buf: &[u8], check: &ProtectionCheck) -> Result<()> {
+        let options = IoOptions { protection: Some(*check), ..Default::default() };
+        self.write_opts(lba, buf, options, true)
+    }
+
+    /// Flush - Commit data in the volatile write cache to non-volatile media.
+    pub fn flush(&self) -> Result<()> {
+        self.flush_with(true)
+    }
+
+    /// Flush without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_flush(&self) -> Result<()> {
+        self.flush_with(false)
+    }
+
+    /// Consistency barrier across all I/O queues.
+    ///
+    /// Waits for the commands in flight on every active queue, delivering
+    /// the completions of steered ones, then sends a flush through each
+    /// queue and waits for it, so every write submitted before the call is
+    /// durable when it returns. Returns the first flush error.
+    pub fn barrier(&self) -> Result<()> {
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+
+        let queues: Vec<_> = self.device.ioq.lock()
+            .iter()
+            .filter(|q| !q.lock().shutdown.load(Ordering::Acquire))
+            .cloned()
+            .collect();
+        if queues.is_empty() {
+            return Err(Error::NoActiveQueues);
+        }
+
+        // Synchronous commands hold their queue until they complete, so
+        // taking each queue waits them out. Steered ones are waited for
+        // by their command IDs.
+        let mut steered: Vec<Vec<u16>> = queues.iter()
+            .map(|queue| queue.lock().steered.keys().copied().collect())
+            .collect();
+        let waiter = self.device.waiter();
+        loop {
+            for (queue_arc, ids) in queues.iter().zip(&mut steered) {
+                if ids.is_empty() {
+                    continue;
+                }
+                let Some(mut queue) = queue_arc.try_lock() else {
+                    continue;
+                };
+                // Commands still waiting for their doorbell would never complete
+                queue.ring_pending();
+                let done = self.device.take_steered(&mut queue);
+                ids.retain(|cmd_id| queue.steered.contains_key(cmd_id));
+                drop(queue);
+                for (sink, context, result) in done {
+                    sink.complete(context, result);
+                }
+            }
+            if steered.iter().all(|ids| ids.is_empty()) {
+                break;
+            }
+            waiter.wait();
+        }
+
+        let mut result = Ok(());
+        for queue_arc in &queues {
+            // A queue removed meanwhile was flushed by its removal
+            let select = || {
+                let active = !queue_arc.lock().shutdown.load(Ordering::Acquire);
+                active.then(|| queue_arc.clone())
+            };
+            let flushed = self.with_room_at(select, true, |queue| {
+                let cmd = Command::flush(queue.sq.tail() as u16, self.id);
+                self.submit_iocmd(queue, cmd, 0)
+            });
+            match flushed {
+                Ok(entry) => result = result.and(entry.result()),
+                Err(Error::NoActiveQueues) => {}
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+        result
+    }
+
+    /// TRIM/Discard - Essential for SSD performance and lifetime.
+    /// Informs the controller that specified LBA ranges contain no valid data.
+    pub fn trim(&self, lba: u64, block_count: u64) -> Result<()> {
+        self.trim_with(lba, block_count, true)
+    }
+
+    /// TRIM without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_trim(&self, lba: u64, block_count: u64) -> Result<()> {
+        self.trim_with(lba, block_count, false)
+    }
+
+    /// Write Zeroes - Efficient zeroing without data transfer.
+    /// Much faster than writing actual zero buffers.
+    pub fn write_zeroes(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.write_zeroes_with(lba, block_count, true)
+    }
+
+    /// Write Zeroes without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_write_zeroes(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.write_zeroes_with(lba, block_count, false)
+    }
+
+    /// Compare - Atomically compare data without transferring to host.
+    /// Essential for lock-free algorithms and database implementations.
+    pub fn compare(&self, lba: u64, expected: &[u8]) -> Result<bool> {
+        self.compare_with(lba, expected, true).map(|mismatch| mismatch.is_none())
+    }
+
+    /// Compare without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_compare(&self, lba: u64, expected: &[u8]) -> Result<bool> {
+        self.compare_with(lba, expected, false).map(|mismatch| mismatch.is_none())
+    }
+
+    /// Locate the first block that differs from `expected` by reading it back.
+    ///
+    /// Blocks are read into `scratch`, which must hold at least one block.
+    /// Returns `None` if all blocks match.
+    pub fn locate_mismatch(
+        &self,
+        lba: u64,
+        expected: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<Option<u64>> {
+        let block_size = self.block_size as usize;
+        if !expected.len().is_multiple_of(block_size) || scratch.len() < block_size {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let chunk = scratch.len().min(self.max_transfer_size()) / block_size * block_size;
+        for (i, want) in expected.chunks(chunk).enumerate() {
+            let start = lba + (i * chunk / block_size) as u64;
+            let got = &mut scratch[..want.len()];
+            self.read(start, got)?;
+
+            let position = want.chunks(block_size)
+                .zip(got.chunks(block_size))
+                .position(|(want, got)| want != got);
+            if let Some(position) = position {
+                return Ok(Some(start + position as u64));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Verify - Check data integrity without transferring to host.
+    /// Critical for data scrubbing and integrity verification.
+    pub fn verify(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.verify_with(lba, block_count, true)
+    }
+
+    /// Verify without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_verify(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.verify_with(lba, block_count, false)
+    }
+
+    /// Verify a range of any length.
+    ///
+    /// The range is split into commands of at most 65536 blocks and the
+    /// maximum transfer size.
+    /// `progress` is called with the blocks done and the total after each
+    /// command, and the scan stops early once `cancel` is set. Media errors
+    /// do not stop the scan; the failing command is split until each bad
+    /// block is found and recorded in the report.
+    pub fn verify_range(
+        &self,
+        lba: u64,
+        blocks: u64,
+        mut progress: impl FnMut(u64, u64),
+        cancel: Option<&CancelToken>,
+    ) -> Result<VerifyReport> {
+        self.check_range(lba, blocks)?;
+        let end = lba + blocks;
+
+        // Verify moves no data, but controllers may still hold it to MDTS,
+        // and the 16-bit NLB field limits a command to 65536 blocks
+        let max_transfer_size = self.device.data.lock().max_transfer_size as u64;
+        let chunk = (max_transfer_size / self.block_size).clamp(1, 1 << 16);
+
+        let mut report = VerifyReport::default();
+        let mut current = lba;
+        while current < end {
+            if cancel.is_some_and(|c| c.is_cancelled()) {
+                report.cancelled = true;
+                break;
+            }
+
+            let count = (end - current).min(chunk) as u32;
+            self.verify_chunk(current, count, &mut report.media_errors)?;
+
+            current += count as u64;
+            report.blocks_verified += count as u64;
+            progress(report.blocks_verified, blocks);
+        }
+
+        Ok(report)
+    }
+
+    /// Verify a range like `verify_range`, reporting to `operation`.
+    ///
+    /// The scan stops once the operation is cancelled, and the operation
+    /// is over when this returns.
+    pub fn scrub(&self, lba: u64, blocks: u64, operation: &Operation) -> Result<VerifyReport> {
+        let report = self.verify_range(
+            lba,
+            blocks,
+            |done, total| operation.update(done, total, self.device.now_us()),
+            Some(operation.cancel_token()),
+        );
+        operation.finish(self.device.now_us());
+        report
+    }
+
+    /// Verify one command's worth of blocks, locating media errors.
+    fn verify_chunk(&self, lba: u64, count: u32, errors: &mut Vec<MediaError>) -> Result<()> {
+        let mut pending = alloc::vec![(lba, count)];
+        while let Some((lba, count)) = pending.pop() {
+            let status = StatusCode::from_raw(self.verify_entry(lba, count, true)?.status);
+            if status == StatusCode::new(StatusCodeType::Generic, 0) {
+                continue;
+            }
+            if status.sct != StatusCodeType::MediaError {
+                return Err(Error::NvmeStatus(status));
+            }
+
+            if count == 1 {
+                errors.push(MediaError { lba, status });
+            } else {
+                // Push the upper half first so blocks are reported in order
+                let half = count / 2;
+                pending.push((lba + half as u64, count - half));
+                pending.push((lba, half));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy - Server-side copy without host involvement.
+    /// Essential for efficient data migration and backup.
+    pub fn copy(&self, src_lba: u64, dst_lba: u64, block_count: u16) -> Result<IoResult> {
+        self.copy_with(src_lba, dst_lba, block_count, true)
+    }
+
+    /// Copy without waiting for submission queue space.
+    ///
+    /// Fails with `Error::SubQueueFull` if the selected queue is full.
+    pub fn try_copy(&self, src_lba: u64, dst_lba: u64, block_count: u16) -> Result<IoResult> {
+        self.copy_with(src_lba, dst_lba, block_count, false)
+    }
+
+    /// Select an I/O queue and run `f` on it under its lock.
+    ///
+    /// A queue shut down between selection and locking is passed over, so
+    /// no command reaches a queue that is being removed.
+    fn with_queue<R>(&self, f: impl FnOnce(&mut IoQueuePair) -> Result<R>) -> Result<R> {
+        let selected = with_active_queue(
+            || self.select_queue(),
+            |queue| queue.shutdown.load(Ordering::Acquire),
+            f,
+        );
+        selected.unwrap_or(Err(Error::NoActiveQueues))
+    }
+
+    /// Run `f` on a selected queue, and if `block` is set, again whenever
+    /// it fails with `Error::SubQueueFull` or `Error::RateLimited`, or with
+    /// `Error::AnaTransition` as set by the device's `AnaRetryPolicy`.
+    ///
+    /// The wait happens with the queue unlocked, so polls can reap the
+    /// steered completions that make room on it and other commands are
+    /// not held up by an ANA transition.
+    fn with_room<R>(
+        &self,
+        block: bool,
+        f: impl FnMut(&mut IoQueuePair) -> Result<R>,
+    ) -> Result<R> {
+        self.with_room_at(|| self.select_queue(), block, f)
+    }
+
+    /// Like `with_room`, on the queues picked by `select`.
+    fn with_room_at<R>(
+        &self,
+        mut select: impl FnMut() -> Option<Arc<Mutex<IoQueuePair>>>,
+        block: bool,
+        mut f: impl FnMut(&mut IoQueuePair) -> Result<R>,
+    ) -> Result<R> {
+        let waiter = self.device.waiter();
+        let mut ana_deadline = None;
+        loop {
+            let mut path_error = None;
+            let selected = with_active_queue(
+                &mut select,
+                |queue| queue.shutdown.load(Ordering::Acquire),
+                |queue| {
+                    let result = f(queue);
+                    path_error = queue.path_error.take();
+                    result
+                },
+            );
+            match selected.unwrap_or(Err(Error::NoActiveQueues)) {
+                Err(Error::SubQueueFull | Error::RateLimited) if block => waiter.wait(),
+                Err(e) if block && e.is_transient() => self.wait_ana_retry(&mut ana_deadline, e)?,
+                result => return result.map_err(|e| e.on_path(path_error)),
+            }
+        }
+    }
+
+    /// Wait before retrying a command that failed with the transient `error`.
+    ///
+    /// Fails with the error if the multipath controller, if set, now
+    /// selects another path, so the caller retries there, and with the
+    /// policy's fallback once `deadline` passes.
+    fn wait_ana_retry(&self, deadline: &mut Option<u64>, error: Error) -> Result<()> {
+        let policy = self.device.ana_retry;
+        let now = self.device.now_us();
+        if let Some((multipath, path_id)) = self.device.multipath.read().as_ref()
+            && multipath.select_path(self.id, now).is_ok_and(|selected| selected != *path_id)
+        {
+            return Err(error);
+        }
+
+        let deadline = *deadline.get_or_insert_with(|| now + self.device.ana_retry_wait_us());
+        if now >= deadline {
+            return Err(match policy.fallback {
+                AnaFallback::Timeout => Error::AnaTransitionTimeout,
+                AnaFallback::Transition => error,
+            });
+        }
+
+        let resume = deadline.min(now + policy.retry_interval_us as u64);
+        let waiter = self.device.waiter();
+        while self.device.now_us() < resume {
+            waiter.wait();
+        }
+        Ok(())
+    }
+
+    /// Select the optimal I/O queue for this operation.
+    fn select_queue(&self) -> Option<Arc<Mutex<IoQueuePair>>> {
+        let queues = self.device.ioq.lock();
+        if queues.is_empty() {
+            return None;
+        }
+
+        // Filter out shutdown queues
+        let active_queues: Vec<_> = queues
+            .iter()
+            .filter(|q| !q.lock().shutdown.load(Ordering::Acquire))
+            .cloned()
+            .collect();
+
+        if active_queues.is_empty() {
+            return None;
+        }
+
+        if active_queues.len() == 1 {
+            return Some(active_queues[0].clone());
+        }
+
+        // Try to find least loaded active queue
+        let mut min_outstanding = usize::MAX;
+        let mut selected_queue = None;
+
+        for queue in active_queues.iter() {
+            let outstanding = queue.lock().outstanding.load(Ordering::Relaxed);
+            if outstanding < min_outstanding {
+                min_outstanding = outstanding;
+                selected_queue = Some(queue.clone());
+            }
+        }
+
+        // If all queues are equally loaded, use round-robin
+        selected_queue.or_else(|| {
+            let idx = self.device.queue_selector.fetch_add(1, Ordering::Relaxed) % active_queues.len();
+            Some(active_queues[idx].clone())
+        })
+    }
+
+    /// Take the block ranges of a command if overlapping commands are ordered.
+    ///
+    /// Ranges are given as (LBA, block count, written).
+    fn lock_ranges(
+        &self,
+        ranges: &[(u64, u64, bool)],
+        block: bool,
+    ) -> Result<Option<RangeGuard<'_>>> {
+        match self.ordering() {
+            IoOrdering::Relaxed => Ok(None),
+            IoOrdering::Overlapping => {
+                let waiter = self.device.waiter();
+                self.ranges.lock(ranges, block, || waiter.wait()).map(Some)
+            }
+        }
+    }
+
+    /// Check that a block range lies within the namespace.
+    fn check_range(&self, lba: u64, blocks: u64) -> Result<()> {
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.block_count() => Ok(()),
+            _ => Err(Error::LbaOutOfRange),
+        }
+    }
+
+    fn read_with(&self, lba: u64, buf: &mut [u8], block: bool) -> Result<()> {
+        self.read_hinted(lba, buf, IoHints::default(), block)
+    }
+
+    fn write_with(&self, lba: u64, buf: &[u8], block: bool) -> Result<()> {
+        self.write_hinted(lba, buf, IoHints::default(), block)
+    }
+
+    fn read_hinted(&self, lba: u64, buf: &mut [u8], hints: IoHints, block: bool) -> Result<()> {
+        self.read_opts(lba, buf, IoOptions { hints, ..Default::default() }, block)
+    }
+
+    fn write_hinted(&self, lba: u64, buf: &[u8], hints: IoHints, block: bool) -> Result<()> {
+        self.write_opts(lba, buf, IoOptions { hints, ..Default::default() }, block)
+    }
+
+    fn read_opts(&self, lba: u64, buf: &mut [u8], options: IoOptions, block: bool) -> Result<()> {
+        let blocks = self.transfer_blocks(buf.len(), &options)?;
+        self.check_range(lba, blocks)?;
+        self.do_io(lba, buf.as_mut_ptr() as usize, buf.len(), false, options, block)
+    }
+
+    fn write_opts(&self, lba: u64, buf: &[u8], options: IoOptions, block: bool) -> Result<()> {
+        let blocks = self.transfer_blocks(buf.len(), &options)?;
+        self.check_range(lba, blocks)?;
+        self.do_io(lba, buf.as_ptr() as usize, buf.len(), true, options, block)
+    }
+
+    /// Read or write `bytes` at `address`, which the caller keeps valid.
+    pub(crate) fn transfer(
+        &self,
+        lba: u64,
+        address: usize,
+        bytes: usize,
+        write: bool,
+        hints: IoHints,
+    ) -> Result<()> {
+        let options = IoOptions { hints, ..Default::default() };
+        let blocks = self.transfer_blocks(bytes, &options)?;
+        self.check_range(lba, blocks)?;
+        self.do_io(lba, address, bytes, write, options, true)
+    }
+
+    /// Get the size of a block in a data buffer, with its metadata if
+    /// the namespace is formatted with extended metadata.
+    pub(crate) fn transfer_unit(&self) -> u64 {
+        match self.extended_metadata {
+            true => self.block_size + self.metadata_size as u64,
+            false => self.block_size,
+        }
+    }
+
+    /// Get the number of blocks in a transfer of `bytes`.
+    ///
+    /// Blocks of namespaces with extended metadata carry their metadata,
+    /// except when the controller inserts and strips the protection
+    /// information that makes up all of it.
+    fn transfer_blocks(&self, bytes: usize, options: &IoOptions) -> Result<u64> {
+        let stripped = options.protection.is_some_and(|check| {
+            check.pract && self.metadata_size as usize == check.info.size()
+        });
+        let unit = match self.extended_metadata && !stripped {
+            true => self.block_size + self.metadata_size as u64,
+            false => self.block_size,
+        };
+
+        if !(bytes as u64).is_multiple_of(unit) {
+            return Err(Error::InvalidBufferSize);
+        }
+        Ok(bytes as u64 / unit)
+    }
+
+    fn flush_with(&self, block: bool) -> Result<()> {
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+
+        self.with_room(block, |queue| {
+            let cmd = Command::flush(queue.sq.tail() as u16, self.id);
+            self.submit_iocmd(queue, cmd, 0)?.result()
+        })
+    }
+
+    fn trim_with(&self, lba: u64, block_count: u64, block: bool) -> Result<()> {
+        self.check_range(lba, block_count)?;
+        self.deallocate(lba, block_count, block)
+    }
+
+    /// Deallocate a block range without checking it against `block_count`.
+    fn deallocate(&self, lba: u64, block_count: u64, block: bool) -> Result<()> {
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+        let _range = self.lock_ranges(&[(lba, block_count, true)], block)?;
+
+        let result = self.with_room(block, |queue| {
+            // A single range, in DMA memory the controller can read
+            let range_addr = queue.stage(DsmRange::new(lba, block_count as u32));
+            let cmd = Command::dataset_management(
+                queue.sq.tail() as u16,
+                self.id,
+                range_addr,
+                0, // nr = 0 means 1 range
+                true, // ad = true for deallocate (TRIM)
+                false,
+                false,
+            );
+            self.submit_iocmd(queue, cmd, 0)
+        })?.result();
+        self.stats.record(IoKind::Trim, block_count * self.block_size, result.is_ok());
+        result
+    }
+
+    fn write_zeroes_with(&self, lba: u64, block_count: u16, block: bool) -> Result<IoResult> {
+        self.check_range(lba, block_count as u64)?;
+
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+        let _range = self.lock_ranges(&[(lba, block_count as u64, true)], block)?;
+
+        self.with_room(block, |queue| {
+            let cmd = Command::write_zeroes(
+                queue.sq.tail() as u16,
+                self.id,
+                lba,
+                block_count - 1,
+                false, // deac = deallocate after write
+            );
+            self.submit_iocmd(queue, cmd, 0)
+        })?.io_result()
+    }
+
+    /// Returns the completion of the command if the data did not match.
+    fn compare_with(&self, lba: u64, expected: &[u8], block: bool) -> Result<Option<Completion>> {
+        if !(expected.len() as u64).is_multiple_of(self.block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        let blocks = expected.len() as u64 / self.block_size;
+        self.check_range(lba, blocks)?;
+
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+        let _range = self.lock_ranges(&[(lba, blocks, false)], block)?;
+
+        let entry = self.with_room(block, |queue| {
+            // Create PRP for expected data
+            let prp_result = queue.prp_manager.create(
+                self.device.allocator.as_ref(),
+                expected.as_ptr() as usize,
+                expected.len()
+            )?;
+            let prp = prp_result.get_prp();
+
+            let cmd = Command::compare(
+                queue.sq.tail() as u16,
+                self.id,
+                lba,
+                blocks as u16 - 1,
+                [prp.0, prp.1],
+            );
+
+            let entry = self.submit_iocmd(queue, cmd, expected.len());
+
+            // Release PRP resources
+            queue.prp_manager.release(prp_result, self.device.allocator.as_ref());
+            entry
+        })?;
+        match entry.result() {
+            Ok(()) => Ok(None), // Compare matched
+            // Compare Failure
+            Err(Error::CommandFailed(StatusCode { sct: StatusCodeType::MediaError, sc: 0x85 })) => {
+                Ok(Some(entry)) // Compare didn't match
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn verify_with(&self, lba: u64, block_count: u16, block: bool) -> Result<IoResult> {
+        self.verify_entry(lba, block_count as u32, block)?.io_result()
+    }
+
+    /// Submit a verify command and return its completion.
+    fn verify_entry(&self, lba: u64, block_count: u32, block: bool) -> Result<Completion> {
+        self.check_range(lba, block_count as u64)?;
+
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+        let _range = self.lock_ranges(&[(lba, block_count as u64, false)], block)?;
+
+        self.with_room(block, |queue| {
+            let cmd = Command::verify(
+                queue.sq.tail() as u16,
+                self.id,
+                lba,
+                (block_count - 1) as u16,
+            );
+            self.submit_iocmd(queue, cmd, 0)
+        })
+    }
+
+    fn copy_with(
+        &self,
+        src_lba: u64,
+        dst_lba: u64,
+        block_count: u16,
+        block: bool,
+    ) -> Result<IoResult> {
+        self.check_range(src_lba, block_count as u64)?;
+        self.check_range(dst_lba, block_count as u64)?;
+
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+        let ranges = [(src_lba, block_count as u64, false), (dst_lba, block_count as u64, true)];
+        let _range = self.lock_ranges(&ranges, block)?;
+
+        self.with_room(block, |queue| {
+            // Copy descriptor format 0 (simple copy), in DMA memory
+            let desc_addr = queue.stage(CopyRange::new(src_lba, block_count - 1));
+            let cmd = Command::copy(
+                queue.sq.tail() as u16,
+                self.id,
+                desc_addr,
+                dst_lba,
+                0, // nr = 0 means 1 source range
+                0, // desc_format = 0 for simple copy
+            );
+            self.submit_iocmd(queue, cmd, 0)
+        })?.io_result()
+    }
+
+    /// Submit I/O command to hardware queue and wait for it.
+    ///
+    /// Never waits for room on the queue, as whatever frees it up needs
+    /// the queue lock the caller holds. If the queue has no room for the
+    /// command, it is not submitted and `Error::SubQueueFull` or
+    /// `Error::RateLimited` is returned. A command failing with
+    /// `Error::AnaTransition` returns the error, which `with_room` retries
+    /// with the queue unlocked.
+    fn submit_iocmd(&self, queue: &IoQueuePair, cmd: Command, bytes: usize) -> Result<Completion> {
+        // Respect the queue size and the per-queue depth limit
+        let depth = self.device.queue_depth.load(Ordering::Relaxed);
+        if queue.sq.free_slots() == 0 || queue.outstanding.load(Ordering::Acquire) >= depth {
+            return Err(Error::SubQueueFull);
+        }
+
+        // Every ID in use belongs to steered commands whose completions
+        // only a poll of the queue can deliver
+        let cmd = cmd.with_cmd_id(queue.cids.allocate().ok_or(Error::SubQueueFull)?);
+
+        // Apply namespace and queue rate limits
+        let tail = self.throttle(queue, bytes).and_then(|_| queue.sq.try_push(cmd));
+        let tail = match tail {
+            Ok(tail) => tail,
+            Err(e) => {
+                queue.cids.release(cmd.cmd_id());
+                return Err(e);
+            }
+        };
+
+        let waiter = self.device.waiter();
+        let start = self.device.now_us();
+        queue.outstanding.fetch_add(1, Ordering::Relaxed);
+        self.device.track_path_io(true);
+        queue.ring_sq(tail);
+
+        // Wait for completion
+        let entry = queue.wait_completion(cmd.cmd_id(), || waiter.wait());
+        queue.cids.release(cmd.cmd_id());
+        let end = self.device.now_us();
+        self.device.track_path_io(false);
+        queue.outstanding.fetch_sub(1, Ordering::Relaxed);
+        let entry = entry?;
+        queue.latency_us.fetch_add(end - start, Ordering::Relaxed);
+        queue.completed.fetch_add(1, Ordering::Relaxed);
+        self.device.record_latency(end - start, entry.result().is_ok(), end);
+        queue.path_error.set(self.device.report_path_status(queue.qid, &entry, end));
+        self.device.report_path_error(&entry, end);
+
+        match entry.result() {
+            Err(e) if e.is_transient() => Err(e),
+            _ => Ok(entry),
+        }
+    }
+
+    /// Take the budget of a command from the namespace and queue rate limits.
+    ///
+    /// Fails with `Error::RateLimited` if either limit does not admit it yet.
+    fn throttle(&self, queue: &IoQueuePair, bytes: usize) -> Result<()> {
+        let now = self.device.now_us();
+        let mut ns_limiter = self.rate_limiter.lock();
+        let mut queue_limiter = queue.rate_limiter.lock();
+
+        let ready = ns_limiter.as_mut().is_none_or(|limiter| limiter.ready(now))
+            && queue_limiter.as_mut().is_none_or(|limiter| limiter.ready(now));
+        if !ready {
+            return Err(Error::RateLimited);
+        }
+
+        ns_limiter.iter_mut().for_each(|limiter| limiter.consume(bytes));
+        queue_limiter.iter_mut().for_each(|limiter| limiter.consume(bytes));
+        Ok(())
+    }
+
+    /// Submit a read or write whose completion goes to `sink`.
+    fn submit_steered(
+        &self,
+        lba: u64,
+        address: usize,
+        bytes: usize,
+        write: bool,
+        context: u64,
+        sink: Arc<dyn CompletionSink>,
+    ) -> Result<()> {
+        let io = self.device.begin_io(self.id, &self.stats)?;
+
+        let max_transfer_size = self.device.data.lock().max_transfer_size;
+        if bytes > max_transfer_size {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+        let blocks = self.transfer_blocks(bytes, &IoOptions::default())?;
+        self.check_range(lba, blocks)?;
+
+        self.with_queue(|queue| {
+            // Completions are only delivered by polls, which need the queue
+            // lock, so never wait for space while holding it
+            let depth = self.device.queue_depth.load(Ordering::Relaxed);
+            if queue.sq.free_slots() == 0 || queue.outstanding.load(Ordering::Acquire) >= depth {
+                // Batched commands cannot make room until they are submitted
+                queue.ring_pending();
+                return Err(Error::SubQueueFull);
+            }
+            self.throttle(queue, bytes)?;
+
+            let cmd_id = queue.cids.allocate().ok_or(Error::SubQueueFull)?;
+            let allocator = self.device.allocator.as_ref();
+            let prp = match queue.prp_manager.create(allocator, address, bytes) {
+                Ok(prp) => prp,
+                Err(e) => {
+                    queue.cids.release(cmd_id);
+                    return Err(e);
+                }
+            };
+            let (prp1, prp2) = prp.get_prp();
+            let command = Command::read_write(
+                cmd_id,
+                self.id,
+                lba,
+                blocks as u16 - 1,
+                [prp1, prp2],
+                write,
+                IoHints::default(),
+            );
+            let tail = match queue.sq.try_push(command) {
+                Ok(tail) => tail,
+                Err(e) => {
+                    queue.prp_manager.release(prp, allocator);
+                    queue.cids.release(cmd_id);
+                    return Err(e);
+                }
+            };
+
+            queue.outstanding.fetch_add(1, Ordering::Relaxed);
+            self.device.track_path_io(true);
+            queue.steered.insert(cmd_id, SteeredIo {
+                context,
+                sink,
+                prp,
+                stats: self.stats.clone(),
+                kind: if write { IoKind::Write } else { IoKind::Read },
+                bytes,
+                start_us: self.device.now_us(),
+            });
+            let unrung = queue.unrung.fetch_add(1, Ordering::Relaxed) + 1;
+            match *self.device.doorbell_policy.read() {
+                DoorbellPolicy::Batched { max_pending } if unrung < max_pending as usize => {}
+                _ => queue.ring_sq(tail),
+            }
+            Ok(())
+        })?;
+
+        // The command stays active until its completion is delivered
+        core::mem::forget(io);
+        Ok(())
+    }
+
+    /// Perform I/O operation.
+    fn do_io(
+        &self,
+        lba: u64,
+        address: usize,
+        bytes: usize,
+        write: bool,
+        options: IoOptions,
+        block: bool,
+    ) -> Result<()> {
+        // Check if device is detached or suspended, and hold off teardown
+        let _io = self.device.begin_io(self.id, &self.stats)?;
+
+        let max_transfer_size = self.device.data.lock().max_transfer_size;
+        if bytes > max_transfer_size {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+
+        let blocks = self.transfer_blocks(bytes, &options)?;
+        let _range = self.lock_ranges(&[(lba, blocks, write)], block)?;
+
+        // Select queue and perform I/O
+        let result = self.with_room(block, |queue| {
+            // Small reads land in the queue's pre-mapped buffer, so the
+            // caller's buffer needs no PRP or translation, nor alignment
+            let inline = !write && bytes <= INLINE_READ_SIZE;
+            let allocator = self.device.allocator.as_ref();
+            let prp_result = match inline {
+                true => None,
+                false => Some(queue.prp_manager.create(allocator, address, bytes)?),
+            };
+            let prp = prp_result
+                .as_ref()
+                .map_or((queue.inline_buf.phys_addr, 0), |r| r.get_prp());
+
+            // Create command
+            let command = Command::read_write(
+                queue.sq.tail() as u16,
+                self.id,
+                lba,
+                blocks as u16 - 1,
+                [prp.0, prp.1],
+                write,
+                options.hints,
+            );
+            let command = match &options.protection {
+                Some(check) => command.with_protection(check),
+                None => Ok(command),
+            };
+
+            // Submit command with dynamic queue management
+            let entry =
+                command.and_then(|command| self.submit_iocmd(queue, command, bytes));
+
+            // Release PRP resources
+            if let Some(prp_result) = prp_result {
+                queue.prp_manager.release(prp_result, allocator);
+            }
+
+            // Check status
+            let result = entry?.result();
+            if inline && result.is_ok() {
+                unsafe {
+                    let dst = address as *mut u8;
+                    core::ptr::copy_nonoverlapping(queue.inline_buf.addr, dst, bytes);
+                }
+            }
+            result
+        });
+        let kind = if write { IoKind::Write } else { IoKind::Read };
+        self.stats.record(kind, bytes as u64, result.is_ok());
+        result
+    }
+}
+
+/// A structure representing an NVMe controller device.
+pub struct NVMeDevice<A: Allocator> {
+    address: *mut u8,
+    inner: Arc<DeviceInner<A>>,
+
+    // Namespaces
+    namespaces: RwLock<BTreeMap<u32, Arc<Namespace<A>>>>,
+    // Selects the namespaces to attach (None = all)
+    namespace_filter: Option<fn(u32) -> bool>,
+    // Handling of commands that keep I/O queues from being removed
+    queue_removal: QueueRemovalPolicy,
+    // Arbitrate with weighted round robin (CC.AMS)
+    weighted_round_robin: bool,
+
+    // Admin queues
+    admin_sq: SubQueue,
+    admin_cq: CompQueue,
+    admin_buffers: DmaPool<A>,
+    admin_doorbells: QueueDoorbells,
+    // Serializes admin submissions and holds the next command ID to try
+    admin_lock: Mutex<u16>,
+    // Admin commands in flight by command ID
+    admin_pending: Mutex<BTreeMap<u16, AdminSlot>>,
+    // Ticket of the next admin command
+    admin_tickets: AtomicU64,
+    // Completion dword 0 of Asynchronous Event Requests not yet processed
+    async_events: Mutex<VecDeque<u32>>,
+    // Admin command timeout in microseconds (0 = wait forever)
+    admin_timeout_us: u64,
+
+    // Power state bookkeeping
+    power: Mutex<PowerManager>,
+    // State saved across suspend/resume
+    suspend_state: Mutex<Option<SuspendState>>,
+    // Summary of initialization
+    init_report: InitReport,
+}
+
+unsafe impl<A: Allocator> Send for NVMeDevice<A> {}
+unsafe impl<A: Allocator> Sync for NVMeDevice<A> {}
+
+impl<A: Allocator> PathProbe for NVMeDevice<A> {
+    /// Read the Arbitration feature, which every controller supports.
+    fn probe(&self) -> bool {
+        self.arbitration().is_ok()
+    }
+}
+
+impl<A: Allocator> NVMeDevice<A> {
+    /// Set the number of I/O queue pairs.
+    /// Will add or remove queues to match the target count.
+    /// When removing queues, it will:
+    /// 1. Mark queues for shutdown (no new I/O accepted)
+    /// 2. Flush every namespace through them, and wait for outstanding I/O
+    /// 3. Remove the queues from hardware
+    ///
+    /// If a flush does not complete within the wait, or commands are still
+    /// outstanding after it, and after aborting them when
+    /// `DeviceConfig::queue_removal` asks for it, all queues are kept and
+    /// the removal fails with `Error::QueueRemovalStalled`.
+    pub fn set_ioq_count(&self, target: usize) -> Result<()> {
+        if target == 0 {
+            return Err(Error::InvalidQueueCount);
+        }
+
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+
+        if target > hw_limit {
+            return Err(Error::TooManyQueues);
+        }
+
+        let current = self.ioq_count();
+
+        if target > current {
+            // Add queues
+            for _ in current..target {
+                self.add_ioq_internal(&QueueConfig::default())?;
+            }
+        } else if target < current {
+            // Remove queues safely
+            self.rm_ioq_internal(current - target)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add an I/O queue pair whose memory is placed following `hint`.
+    ///
+    /// Use it to allocate a queue and its PRP lists near the CPU that
+    /// will submit to it. Returns the queue ID.
+    pub fn add_ioq(&self, hint: PlacementHint) -> Result<u16> {
+        self.add_ioq_with(&QueueConfig { hint, ..Default::default() })
+    }
+
+    /// Add an I/O queue pair created with `config`.
+    ///
+    /// Returns the queue ID. Fails with `Error::ContiguousQueuesRequired`
+    /// if the queues are not contiguous and the controller requires it.
+    pub fn add_ioq_with(&self, config: &QueueConfig) -> Result<u16> {
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+
+        if self.ioq_count() >= hw_limit {
+            return Err(Error::TooManyQueues);
+        }
+        // Contiguous Queues Required
+        if !config.contiguous && (self.get_reg::<u64>(Register::CAP) >> 16) & 1 == 1 {
+            return Err(Error::ContiguousQueuesRequired);
+        }
+
+        self.add_ioq_internal(config)
+    }
+
+    /// Deliver the completions of commands submitted with
+    /// `Namespace::submit_read` and `Namespace::submit_write` to their sinks.
+    ///
+    /// Polls every I/O queue, so each completion goes to the sink of its
+    /// own command whichever submitter polls. Returns the number delivered.
+    pub fn poll_completions(&self) -> usize {
+        // Commands still waiting for their doorbell would never complete
+        self.inner.ring_pending();
+
+        let queues = self.inner.ioq.lock().clone();
+        let mut delivered = 0;
+        for queue_arc in queues {
+            let done = self.inner.take_steered(&mut queue_arc.lock());
+            delivered += done.len();
+            for (sink, context, result) in done {
+                sink.complete(context, result);
+            }
+        }
+        delivered
+    }
+
+    /// Ring pending doorbells and deliver steered completions like
+    /// `poll_completions`, skipping the queues a synchronous command is
+    /// waiting on, which rang their doorbells itself.
+    ///
+    /// Steered commands only end once delivered, so every wait for the
+    /// I/O in flight reaps them.
+    fn reap_steered(&self) {
+        let queues = self.inner.ioq.lock().clone();
+        for queue_arc in queues {
+            let Some(mut queue) = queue_arc.try_lock() else {
+                continue;
+            };
+            // Commands still waiting for their doorbell would never complete
+            queue.ring_pending();
+            let done = self.inner.take_steered(&mut queue);
+            drop(queue);
+            for (sink, context, result) in done {
+                sink.complete(context, result);
+            }
+        }
+    }
+
+    /// Get the current number of I/O queue pairs.
+    pub fn ioq_count(&self) -> usize {
+        self.inner.ioq.lock().len()
+    }
+
+    /// Limit the rate of I/O submitted to a queue, or remove the limit with `None`.
+    ///
+    /// A clock must be set. See `Namespace::set_rate_limit`.
+    pub fn set_queue_rate_limit(&self, qid: u16, limit: Option<RateLimit>) -> Result<()> {
+        let queue = self.inner.ioq.lock()
+            .iter()
+            .find(|q| q.lock().qid == qid)
+            .cloned()
+            .ok_or(Error::QueueNotFound)?;
+
+        *queue.lock().rate_limiter.lock() = self.inner.rate_limiter(limit)?;
+        Ok(())
+    }
+
+    /// Set the maximum number of outstanding commands per I/O queue.
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.inner.queue_depth.store(depth.clamp(1, IO_QUEUE_SIZE - 1), Ordering::Relaxed);
+    }
+
+    /// Get the maximum number of outstanding commands per I/O queue.
+    pub fn queue_depth(&self) -> usize {
+        self.inner.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Run one queue auto-tuning step.
+    ///
+    /// Samples the average completion latency and the outstanding commands
+    /// since the previous step, and applies the queue count and depth chosen
+    /// by `tuner`. Call it periodically; latency is only measured once a
+    /// clock is set with `set_clock`.
+    pub fn autotune_queues(&self, tuner: &mut QueueTuner) -> Result<()> {
+        let (latency_us, completed, outstanding) = self.inner.ioq.lock()
+            .iter()
+            .fold((0, 0, 0), |(latency_us, completed, outstanding), q| {
+                let queue = q.lock();
+                (
+                    latency_us + queue.latency_us.swap(0, Ordering::Relaxed),
+                    completed + queue.completed.swap(0, Ordering::Relaxed),
+                    outstanding + queue.outstanding.load(Ordering::Relaxed),
+                )
+            });
+
+        // Nothing to learn from an idle period
+        if completed == 0 {
+            return Ok(());
+        }
+
+        let sample = QueueSample {
+            queue_count: self.ioq_count(),
+            depth: self.queue_depth(),
+            avg_latency_us: latency_us / completed,
+            outstanding,
+        };
+
+        let Some(target) = tuner.evaluate(&sample) else {
+            return Ok(());
+        };
+
+        self.set_queue_depth(target.depth);
+
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+        let queue_count = target.queue_count.min(hw_limit);
+        if queue_count != sample.queue_count {
+            self.set_ioq_count(queue_count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the current number of active (non-shutdown) I/O queue pairs.
+    pub fn active_ioq_count(&self) -> usize {
+        self.inner.ioq.lock()
+            .iter()
+            .filter(|q| !q.lock().shutdown.load(Ordering::Acquire))
+            .count()
     }
 
     /// Get statistics for each queue.
@@ -655,312 +2882,3037 @@ impl<A: Allocator> NVMeDevice<A> {
             .iter()
             .map(|q| {
                 let queue = q.lock();
-                (
-                    queue.qid,
-                    queue.outstanding.load(Ordering::Relaxed),
-                    queue.shutdown.load(Ordering::Relaxed)
-                )
+                (
+                    queue.qid,
+                    queue.outstanding.load(Ordering::Relaxed),
+                    queue.shutdown.load(Ordering::Relaxed)
+                )
+            })
+            .collect()
+    }
+
+    /// Internal method to add a new I/O queue pair.
+    fn add_ioq_internal(&self, config: &QueueConfig) -> Result<u16> {
+        let max_queue_entries = self.inner.data.lock().max_queue_entries;
+        // Use a reasonable I/O queue size, but ensure at least 2 entries
+        let queue_size = IO_QUEUE_SIZE.min(max_queue_entries as usize).max(2);
+
+        let qid = self.inner.next_queue_id.fetch_add(1, Ordering::SeqCst) as u16;
+        // No artificial limit - only hardware limits apply!
+        let doorbells = self.inner.doorbell_helper.queue(qid)?;
+
+        // Create completion queue first
+        let allocator = self.inner.allocator.as_ref();
+        let (cq, prp_list) = match config.contiguous {
+            true => (CompQueue::new(queue_size, allocator, config.hint), None),
+            false => {
+                let cq = CompQueue::scattered(queue_size, allocator, config.hint);
+                let list = self.queue_prp_list(&cq.page_addresses(allocator), config.hint);
+                (cq, Some(list))
+            }
+        };
+        self.exec_admin(Command::create_completion_queue(
+            self.admin_sq.tail() as u16,
+            qid,
+            prp_list.as_ref().map_or(cq.address(), |list| list.phys_addr),
+            (queue_size - 1) as u16,
+            config.contiguous,
+            config.vector,
+        ))?;
+
+        let cq = Arc::new(IoCompQueue {
+            cqid: qid,
+            cq,
+            doorbells,
+            pending: Mutex::new(BTreeMap::new()),
+            prp_list,
+            vector: config.vector,
+            dead: AtomicBool::new(false),
+        });
+
+        self.add_sq_internal(qid, cq, config)
+    }
+
+    /// Add a submission queue bound to an existing completion queue.
+    ///
+    /// Completions of every submission queue bound to `cqid` are posted to
+    /// the same completion queue and routed by submission queue ID, which
+    /// saves interrupt vectors and completion queue memory when many
+    /// submission queues are used. Returns the new queue ID.
+    pub fn add_shared_ioq(&self, cqid: u16, hint: PlacementHint) -> Result<u16> {
+        let max_io_sq = self.inner.data.lock().max_io_sq as usize;
+        if self.ioq_count() >= max_io_sq {
+            return Err(Error::TooManyQueues);
+        }
+
+        let cq = self.inner.ioq.lock()
+            .iter()
+            .map(|q| q.lock().cq.clone())
+            .find(|cq| cq.cqid == cqid)
+            .ok_or(Error::QueueNotFound)?;
+
+        let qid = self.inner.next_queue_id.fetch_add(1, Ordering::SeqCst) as u16;
+        self.add_sq_internal(qid, cq, &QueueConfig { hint, ..Default::default() })
+    }
+
+    /// Build the PRP list of a queue that is not physically contiguous.
+    fn queue_prp_list(&self, pages: &[u64], hint: PlacementHint) -> Dma<u64> {
+        let mut list = Dma::allocate_near(pages.len(), self.inner.allocator.as_ref(), hint);
+        for (entry, &page) in list.iter_mut().zip(pages) {
+            *entry = page.to_le();
+        }
+        list
+    }
+
+    /// Create submission queue `qid` bound to `cq` and add it to the queue list.
+    fn add_sq_internal(&self, qid: u16, cq: Arc<IoCompQueue>, config: &QueueConfig) -> Result<u16> {
+        let max_queue_entries = self.inner.data.lock().max_queue_entries;
+        let queue_size = IO_QUEUE_SIZE.min(max_queue_entries as usize).max(2);
+        let doorbells = self.inner.doorbell_helper.queue(qid)?;
+        let hint = config.hint;
+
+        let cqid = cq.cqid;
+        let allocator = self.inner.allocator.as_ref();
+        let (sq, prp_list) = match config.contiguous {
+            true => (SubQueue::new(queue_size, allocator, hint), None),
+            false => {
+                let sq = SubQueue::scattered(queue_size, allocator, hint);
+                let list = self.queue_prp_list(&sq.page_addresses(allocator), hint);
+                (sq, Some(list))
+            }
+        };
+        self.exec_admin(Command::create_submission_queue(
+            self.admin_sq.tail() as u16,
+            qid,
+            prp_list.as_ref().map_or(sq.address(), |list| list.phys_addr),
+            (queue_size - 1) as u16,
+            cqid,
+            config.contiguous,
+            config.priority,
+        ))?;
+
+        // Add to queue list
+        let cids = Arc::new(CommandIds::new(queue_size));
+        let queue_pair = Arc::new(Mutex::new(IoQueuePair {
+            qid,
+            sq,
+            cq: cq.clone(),
+            prp_manager: PrpManager::with_hint(hint),
+            inline_buf: Dma::allocate_near(INLINE_READ_SIZE, allocator, hint),
+            prp_list,
+            outstanding: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+            rate_limiter: Mutex::new(None),
+            doorbells,
+            steered: BTreeMap::new(),
+            cids: cids.clone(),
+            unrung: AtomicUsize::new(0),
+            path_error: Cell::new(None),
+        }));
+
+        self.inner.ioq.lock().push(queue_pair);
+        self.inner.in_flight.lock().insert(qid, (cids, cq));
+        nvme_log!(Debug, "created I/O queue {} with {} entries on CQ {}", qid, queue_size, cqid);
+        Ok(qid)
+    }
+
+    /// Delete completion queues that are no longer bound to any submission queue.
+    ///
+    /// `removed` are the queues whose submission queues were deleted
+    /// and `remaining` the ones still in use.
+    fn delete_unused_cqs(
+        &self,
+        removed: &[Arc<Mutex<IoQueuePair>>],
+        remaining: &[Arc<Mutex<IoQueuePair>>],
+    ) -> Result<()> {
+        let in_use: Vec<u16> = remaining.iter().map(|q| q.lock().cq.cqid).collect();
+        let mut deleted = Vec::new();
+
+        for queue in removed {
+            let cq = queue.lock().cq.clone();
+            if in_use.contains(&cq.cqid) || deleted.contains(&cq.cqid) {
+                continue;
+            }
+
+            self.exec_admin(Command::delete_completion_queue(
+                self.admin_sq.tail() as u16,
+                cq.cqid,
+            ))?;
+            cq.free_buffers(self.inner.allocator.as_ref());
+            deleted.push(cq.cqid);
+        }
+
+        Ok(())
+    }
+
+    /// Internal method to remove specified number of I/O queues safely.
+    fn rm_ioq_internal(&self, count: usize) -> Result<()> {
+        let queues_to_remove = {
+            let queues = self.inner.ioq.lock();
+
+            // Don't remove if it would leave us with no queues
+            if queues.len() <= count {
+                return Err(Error::LastQueueCannotBeRemoved);
+            }
+
+            // Select queues to remove (prefer queues with least outstanding I/O)
+            let mut queue_stats: Vec<_> = queues.iter()
+                .map(|q| {
+                    let queue = q.lock();
+                    (q.clone(), queue.qid, queue.outstanding.load(Ordering::Relaxed))
+                })
+                .collect();
+
+            // Sort by outstanding I/O count
+            queue_stats.sort_by_key(|&(_, _, outstanding)| outstanding);
+
+            // Take the last 'count' queues (highest load)
+            queue_stats.into_iter()
+                .rev()
+                .take(count)
+                .map(|(arc, qid, _)| (arc, qid))
+                .collect::<Vec<_>>()
+        };
+
+        // Phase 1: Mark queues for shutdown
+        for (queue_arc, _) in &queues_to_remove {
+            queue_arc.lock().shutdown.store(true, Ordering::Release);
+        }
+
+        // Keep every queue on failure, so none is deleted under live commands
+        let keep_queues = || {
+            for (queue_arc, _) in &queues_to_remove {
+                queue_arc.lock().shutdown.store(false, Ordering::Release);
+            }
+        };
+
+        // Phase 2: Flush each namespace through the queues, so the writes
+        // submitted on them are committed before they go away
+        let start = self.inner.now_us();
+        let namespaces: Vec<_> = self.namespaces.read().keys().copied().collect();
+        for (queue_arc, qid) in &queues_to_remove {
+            for &nsid in &namespaces {
+                if !self.flush_removed_queue(queue_arc, nsid, start) {
+                    keep_queues();
+                    let queue = queue_arc.lock();
+                    let outstanding = queue.outstanding.load(Ordering::Acquire);
+                    let cids = queue.cids.iter().collect();
+                    return Err(Error::QueueRemovalStalled(alloc::vec![
+                        StalledQueue { qid: *qid, outstanding, cids },
+                    ]));
+                }
+            }
+        }
+
+        // Wait for all outstanding I/O to complete
+        // This is necessary for controlled removal to avoid data loss
+        let mut stalled = self.drain_queues(&queues_to_remove);
+        if !stalled.is_empty() && self.queue_removal == QueueRemovalPolicy::Abort {
+            let aborted = stalled.iter().try_for_each(|queue| {
+                queue.cids.iter().try_for_each(|&cid| {
+                    let abort = Command::abort(self.admin_sq.tail() as u16, queue.qid, cid);
+                    self.exec_admin(abort).map(|_| ())
+                })
+            });
+            if let Err(e) = aborted {
+                keep_queues();
+                return Err(e);
+            }
+            nvme_log!(Warn, "aborted commands of {} I/O queues being removed", stalled.len());
+            stalled = self.drain_queues(&queues_to_remove);
+        }
+        if !stalled.is_empty() {
+            keep_queues();
+            return Err(Error::QueueRemovalStalled(stalled));
+        }
+
+        // Phase 3: Delete queues from hardware and remove from list
+        for (_, qid) in &queues_to_remove {
+            // Delete submission queue first (NVMe spec requirement)
+            self.exec_admin(Command::delete_submission_queue(
+                self.admin_sq.tail() as u16,
+                *qid,
+            ))?;
+        }
+
+        // Then delete completion queues no other submission queue is bound to
+        let (removed, remaining): (Vec<_>, Vec<_>) = self.inner.ioq.lock()
+            .iter()
+            .cloned()
+            .partition(|q| {
+                let qid = q.lock().qid;
+                queues_to_remove.iter().any(|(_, rm_qid)| *rm_qid == qid)
+            });
+        self.delete_unused_cqs(&removed, &remaining)?;
+
+        // Phase 4: Remove from the queue list
+        let mut queues = self.inner.ioq.lock();
+        queues.retain(|q| {
+            let qid = q.lock().qid;
+            !queues_to_remove.iter().any(|(_, rm_qid)| *rm_qid == qid)
+        });
+        let mut in_flight = self.inner.in_flight.lock();
+        for (_, qid) in &queues_to_remove {
+            in_flight.remove(qid);
+        }
+        for queue in &removed {
+            queue.lock().free_buffers(self.inner.allocator.as_ref());
+        }
+
+        Ok(())
+    }
+
+    /// Flush namespace `nsid` through a queue being removed.
+    ///
+    /// The queue is only locked to submit the flush and to look for its
+    /// completion, delivering steered completions meanwhile, so a full
+    /// queue drains. Returns false if the flush has not completed within
+    /// `QUEUE_DRAIN_TIMEOUT_MS` of `start` while a clock is set. Its
+    /// command ID then stays in use.
+    fn flush_removed_queue(
+        &self,
+        queue_arc: &Arc<Mutex<IoQueuePair>>,
+        nsid: u32,
+        start: u64,
+    ) -> bool {
+        let timed_out = || self.inner.now_us() - start >= QUEUE_DRAIN_TIMEOUT_MS as u64 * 1000;
+        let waiter = self.inner.waiter();
+        let mut submitted = None;
+        loop {
+            let (completed, done) = {
+                let mut queue = queue_arc.lock();
+                let done = self.inner.take_steered(&mut queue);
+                let completed = match submitted {
+                    Some(cmd_id) => {
+                        let entry = queue.cq.pending.lock().remove(&(queue.qid, cmd_id));
+                        entry.inspect(|entry| {
+                            queue.sq.set_head(entry.sq_head as usize);
+                            queue.cids.release(cmd_id);
+                        }).is_some()
+                    }
+                    None => {
+                        submitted = queue.cids.allocate().and_then(|cmd_id| {
+                            match queue.sq.try_push(Command::flush(cmd_id, nsid)) {
+                                Ok(tail) => {
+                                    queue.ring_sq(tail);
+                                    Some(cmd_id)
+                                }
+                                Err(_) => {
+                                    queue.cids.release(cmd_id);
+                                    None
+                                }
+                            }
+                        });
+                        false
+                    }
+                };
+                (completed, done)
+            };
+            for (sink, context, result) in done {
+                sink.complete(context, result);
+            }
+
+            if completed {
+                return true;
+            }
+            if timed_out() {
+                return false;
+            }
+            waiter.wait();
+        }
+    }
+
+    /// Wait a bounded time for the commands on queues being removed.
+    ///
+    /// The wait is bounded by `QUEUE_DRAIN_TIMEOUT_MS` while a clock is set.
+    /// Returns the queues that still have commands outstanding.
+    fn drain_queues(&self, queues: &[(Arc<Mutex<IoQueuePair>>, u16)]) -> Vec<StalledQueue> {
+        let start = self.inner.now_us();
+        let waiter = self.inner.waiter();
+        let mut stalled = Vec::new();
+        for (queue_arc, qid) in queues {
+            loop {
+                let (outstanding, done) = {
+                    let mut queue = queue_arc.lock();
+                    let done = self.inner.take_steered(&mut queue);
+                    (queue.outstanding.load(Ordering::Acquire), done)
+                };
+                for (sink, context, result) in done {
+                    sink.complete(context, result);
+                }
+                if outstanding == 0 {
+                    break;
+                }
+
+                if self.inner.now_us() - start >= QUEUE_DRAIN_TIMEOUT_MS as u64 * 1000 {
+                    let cids = queue_arc.lock().cids.iter().collect();
+                    stalled.push(StalledQueue { qid: *qid, outstanding, cids });
+                    break;
+                }
+
+                waiter.wait();
+            }
+        }
+        stalled
+    }
+
+    /// Initialize a NVMe controller device.
+    ///
+    /// The `address` is the base address of the controller
+    /// constructed by the PCI BAR 0 (lower 32 bits) and BAR 1 (upper 32 bits).
+    ///
+    /// The `allocator` is a DMA allocator that implements
+    /// the `Allocator` trait used for the entire NVMe device.
+    pub fn init(address: usize, allocator: A) -> Result<Self> {
+        Self::init_with_config(address, allocator, DeviceConfig::default())
+    }
+
+    /// Initialize a NVMe controller device with a custom configuration.
+    ///
+    /// See `init` for the meaning of `address` and `allocator`.
+    pub fn init_with_config(address: usize, allocator: A, config: DeviceConfig) -> Result<Self> {
+        Self::init_shared(address, Arc::new(allocator), config)
+    }
+
+    /// Inspect a controller without taking it over.
+    ///
+    /// Reads the capabilities and the controller and namespace identify
+    /// data, then disables the controller again. Identify needs a running
+    /// controller, so a minimal admin queue and one page of DMA memory are
+    /// used meanwhile; they are freed when `ProbedDevice::start` is called.
+    /// No I/O queues are created.
+    pub fn probe(address: usize, allocator: A) -> Result<ProbedDevice<A>> {
+        let config = DeviceConfig {
+            admin_queue_depth: MIN_ADMIN_QUEUE_SIZE,
+            ..Default::default()
+        };
+        let device = Self::new_device(address, Arc::new(allocator), &config)?;
+
+        device.enable_controller();
+        device.identify_controller()?;
+        device.ident_namespaces_all()?;
+        device.reset_controller();
+
+        Ok(ProbedDevice { device })
+    }
+
+    /// Initialize a device using a shared allocator.
+    fn init_shared(address: usize, allocator: Arc<A>, config: DeviceConfig) -> Result<Self> {
+        let mut device = Self::new_device(address, allocator, &config)?;
+
+        let mut report = {
+            let data = device.inner.data.lock();
+            InitReport {
+                version: device.nvme_version(),
+                page_size: data.min_pagesize,
+                max_queue_entries: data.max_queue_entries,
+                doorbell_stride: 4 << device.inner.doorbell_helper.stride,
+                admin_queue_depth: device.admin_sq.len(),
+                ..Default::default()
+            }
+        };
+        let mut phase_start = device.inner.now_us();
+        let mut finish_phase = |phase, device: &Self| {
+            let now = device.inner.now_us();
+            report.record(phase, phase_start, now, config.on_phase);
+            phase_start = now;
+        };
+
+        // Reset and enable controller
+        device.reset_controller();
+        finish_phase(InitPhase::Reset, &device);
+        device.start_controller();
+        if device.get_reg::<u32>(Register::CSTS) & CSTS_NSSRO != 0 {
+            nvme_log!(Info, "NVM subsystem reset occurred before initialization");
+            device.set_reg::<u32>(Register::CSTS, CSTS_NSSRO);
+        }
+        finish_phase(InitPhase::Enable, &device);
+        nvme_log!(Info, "controller enabled, admin queue depth {}", device.admin_sq.len());
+
+        // Identify controller
+        device.identify_controller()?;
+        finish_phase(InitPhase::Identify, &device);
+
+        // Negotiate maximum number of I/O queues with the controller
+        let queues_assumed = device.negotiate_queues()?;
+        finish_phase(InitPhase::QueueNegotiation, &device);
+
+        // Create I/O queues
+        device.create_ioq()?;
+        finish_phase(InitPhase::QueueCreation, &device);
+
+        // Identify all namespaces
+        device.ident_namespaces_all()?;
+        finish_phase(InitPhase::NamespaceScan, &device);
+        nvme_log!(Info, "found {} namespaces", device.namespaces.read().len());
+
+        {
+            let data = device.inner.data.lock();
+            report.max_transfer_size = data.max_transfer_size;
+            report.max_io_sq = data.max_io_sq;
+            report.max_io_cq = data.max_io_cq;
+        }
+        report.queues_assumed = queues_assumed;
+        report.io_queues = device.ioq_count();
+        report.namespaces = device.namespaces.read().len();
+        device.init_report = report;
+
+        Ok(device)
+    }
+
+    /// Set up the driver state for a controller without touching the controller.
+    fn new_device(address: usize, allocator: Arc<A>, config: &DeviceConfig) -> Result<Self> {
+        // Need to read capabilities first to get the doorbell stride and max queue entries
+        let cap = unsafe { ((address + Register::CAP as usize) as *const u64).read_volatile() };
+        let cap = u64::from_le(cap);
+        let doorbell_stride = (cap >> 32) as u8 & 0xF;
+        let max_queue_entries = (cap & 0x7FFF) as usize + 1;
+        let min_pagesize = 1 << (((cap >> 48) as u8 & 0xF) + 12);
+        let wrr_supported = (cap >> 17) & 1 != 0;
+        if config.weighted_round_robin && !wrr_supported {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        // Admin queues are limited to 4096 entries regardless of MQES
+        let admin_queue_size = config.admin_queue_depth
+            .clamp(MIN_ADMIN_QUEUE_SIZE, max_queue_entries.clamp(MIN_ADMIN_QUEUE_SIZE, 4096));
+
+        let doorbell_helper = DoorbellHelper::new(address, doorbell_stride, config.bar_size);
+        let admin_doorbells = doorbell_helper.queue(0)?;
+
+        let inner = Arc::new(DeviceInner {
+            allocator: allocator.clone(),
+            doorbell_helper: doorbell_helper,
+            data: Mutex::new(Default::default()),
+            ioq: Mutex::new(Vec::new()),
+            queue_selector: AtomicUsize::new(0),
+            queue_depth: AtomicUsize::new(IO_QUEUE_SIZE - 1),
+            next_queue_id: AtomicUsize::new(1),
+            shutting_down: AtomicBool::new(false),
+            active_io: AtomicUsize::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+            suspended: AtomicBool::new(false),
+            quiesced: AtomicBool::new(false),
+            clock: RwLock::new(config.clock.clone()),
+            wait_strategy: RwLock::new(config.wait_strategy.clone()),
+            latency: LatencyHistogram::default(),
+            path: RwLock::new(None),
+            multipath: RwLock::new(None),
+            doorbell_policy: RwLock::new(DoorbellPolicy::Immediate),
+            ana_retry: config.ana_retry,
+            operation: Mutex::new(None),
+        });
+
+        let device = Self {
+            address: address as _,
+            inner: inner.clone(),
+            namespaces: RwLock::new(BTreeMap::new()),
+            admin_sq: SubQueue::new(admin_queue_size, allocator.as_ref(), PlacementHint::Any),
+            admin_cq: CompQueue::new(admin_queue_size, allocator.as_ref(), PlacementHint::Any),
+            admin_buffers: DmaPool::new(allocator.clone(), ADMIN_BUFFER_POOL_SIZE),
+            admin_doorbells,
+            admin_lock: Mutex::new(0),
+            admin_pending: Mutex::new(BTreeMap::new()),
+            admin_tickets: AtomicU64::new(0),
+            async_events: Mutex::new(VecDeque::new()),
+            admin_timeout_us: config.admin_timeout_ms as u64 * 1000,
+            namespace_filter: config.namespace_filter,
+            queue_removal: config.queue_removal,
+            weighted_round_robin: config.weighted_round_robin,
+            power: Mutex::new(PowerManager::new()),
+            suspend_state: Mutex::new(None),
+            init_report: InitReport::default(),
+        };
+
+        // Update controller data with capability values
+        {
+            let mut data = device.inner.data.lock();
+            data.min_pagesize = min_pagesize;
+            data.max_queue_entries = max_queue_entries as u16;
+            data.wrr_supported = wrr_supported;
+        }
+
+        Ok(device)
+    }
+
+    /// Identify the controller and record its data.
+    fn identify_controller(&self) -> Result<()> {
+        let buf = self.admin_buffers.get(4096)?;
+        self.exec_admin(Command::identify(
+            self.admin_sq.tail() as u16,
+            buf.phys_addr(),
+            IdentifyType::Controller,
+        ))?;
+        ControllerData::check_identify(&buf)?;
+
+        let extract_string = |start: usize, end: usize| -> String {
+            buf[start..end]
+                .iter()
+                .flat_map(|&b| char::from_u32(b as u32))
+                .collect::<String>()
+                .trim()
+                .to_string()
+        };
+
+        // Update controller data safely using Mutex
+        let mut data = self.inner.data.lock();
+        data.serial_number = extract_string(4, 24);
+        data.model_number = extract_string(24, 64);
+        data.firmware_revision = extract_string(64, 72);
+
+        let max_pages = 1 << buf[77];
+        data.max_transfer_size = max_pages as usize * data.min_pagesize;
+        data.recommended_arbitration_burst = buf[72];
+        data.num_power_states = buf[263] + 1;
+        data.firmware_updates = buf[260];
+        data.firmware_update_granularity = buf[319];
+        data.ana_transition_time_sec = buf[343];
+        data.oncs = u16::from_le_bytes([buf[520], buf[521]]);
+        data.rtd3_resume_latency_us = u32::from_le_bytes(buf[88..92].try_into().unwrap());
+        data.rtd3_entry_latency_us = u32::from_le_bytes(buf[92..96].try_into().unwrap());
+
+        // Power state descriptors start at byte 2048, 32 bytes each
+        let descriptors = buf[2048..]
+            .chunks_exact(size_of::<PowerStateDescriptor>())
+            .take(data.num_power_states as usize)
+            .map(PowerStateDescriptor::from_identify_data)
+            .collect::<Result<Vec<_>>>()?;
+        self.power.lock().init_power_states(&descriptors);
+
+        // The maximum I/O queue counts come from Set Features, not Identify
+        let sanicap = u32::from_le_bytes(buf[328..332].try_into().unwrap());
+        data.sanitize = SanitizeCapabilities::from_sanicap(sanicap);
+        data.sqes = buf[512];
+        data.cqes = buf[513];
+
+        nvme_log!(
+            Info,
+            "identified {} (serial {}, firmware {})",
+            data.model_number,
+            data.serial_number,
+            data.firmware_revision,
+        );
+        drop((data, buf));
+
+        self.configure_entry_sizes()
+    }
+
+    /// Program the I/O queue entry sizes reported by Identify into CC.
+    ///
+    /// Queue entries are laid out as 64-byte commands and 16-byte
+    /// completions, so controllers that cannot use those sizes are
+    /// rejected before any I/O queue is created.
+    fn configure_entry_sizes(&self) -> Result<()> {
+        let entry_sizes = self.entry_size_config();
+        if entry_sizes == 0 {
+            let data = self.inner.data.lock();
+            nvme_log!(
+                Error,
+                "unsupported queue entry sizes (SQES {:#04x}, CQES {:#04x})",
+                data.sqes,
+                data.cqes,
+            );
+            return Err(Error::UnsupportedQueueEntrySize);
+        }
+
+        let cc = self.get_reg::<u32>(Register::CC) & !0x00FF_0000;
+        self.set_reg::<u32>(Register::CC, cc | entry_sizes);
+        Ok(())
+    }
+
+    /// CC.IOSQES and CC.IOCQES, or 0 until the controller is known to support them.
+    fn entry_size_config(&self) -> u32 {
+        match self.inner.data.lock().supports_entry_sizes() {
+            true => (CQ_ENTRY_SIZE_LOG2 as u32) << 20 | (SQ_ENTRY_SIZE_LOG2 as u32) << 16,
+            false => 0,
+        }
+    }
+
+    /// Reset the controller, configure the admin queues and enable it.
+    fn enable_controller(&self) {
+        self.reset_controller();
+        self.start_controller();
+    }
+
+    /// Disable the controller and wait until it is ready to be configured.
+    fn reset_controller(&self) {
+        self.set_reg::<u32>(Register::CC, self.get_reg::<u32>(Register::CC) & !1);
+        let waiter = self.inner.waiter();
+        while self.get_reg::<u32>(Register::CSTS) & 1 == 1 {
+            waiter.wait();
+        }
+    }
+
+    /// Configure the admin queues and enable the controller.
+    fn start_controller(&self) {
+        // Configure admin queues, dropping the commands lost with the old ones
+        self.admin_sq.reset();
+        self.admin_cq.reset();
+        self.admin_pending.lock().clear();
+
+        // A reset aborts formats and self-tests, while a sanitize carries on
+        self.inner.operation.lock().take_if(|op| !matches!(op, DeviceOperation::Sanitize(_)));
+
+        let admin_queue_size = self.admin_sq.len() as u32;
+        self.set_reg::<u64>(Register::ASQ, self.admin_sq.address());
+        self.set_reg::<u64>(Register::ACQ, self.admin_cq.address());
+        let aqa = (admin_queue_size - 1) << 16 | (admin_queue_size - 1);
+        self.set_reg::<u32>(Register::AQA, aqa);
+
+        // Enable controller
+        // I/O queue entry sizes are set once Identify has reported them
+        // The arbitration mechanism can only be changed while disabled
+        let mut cc = self.get_reg::<u32>(Register::CC) & 0xFF00_000F;
+        if self.weighted_round_robin {
+            cc |= CC_AMS_WRR;
+        }
+        self.set_reg::<u32>(Register::CC, cc | self.entry_size_config());
+
+        self.set_reg::<u32>(Register::CC, self.get_reg::<u32>(Register::CC) | 1);
+        let waiter = self.inner.waiter();
+        while self.get_reg::<u32>(Register::CSTS) & 1 == 0 {
+            waiter.wait();
+        }
+    }
+
+    /// Negotiate the number of I/O queues with the controller.
+    ///
+    /// A rejected request is retried with half as many queues. If even a
+    /// single queue pair is rejected, one pair is assumed, which every
+    /// controller supports, and `true` is returned.
+    fn negotiate_queues(&self) -> Result<bool> {
+        // Request a reasonable number of queues (e.g., 64 of each type)
+        // The controller will respond with the actual number it can support
+        let mut requested_queues = 64;
+        let allocated = loop {
+            // Both counts are 0-based
+            let queue_config = (requested_queues - 1) << 16 | (requested_queues - 1);
+            match self.exec_admin(Command::set_features(
+                self.admin_sq.tail() as u16,
+                0,
+                FeatureId::NumberOfQueues,
+                queue_config,
+                false,
+            )) {
+                Ok(result) => break Some(result.command_specific),
+                Err(Error::CommandFailed(status)) if requested_queues > 1 => {
+                    nvme_log!(
+                        Warn,
+                        "Number of Queues for {} queues rejected (status {:#04x}), retrying",
+                        requested_queues,
+                        status.sc,
+                    );
+                    requested_queues /= 2;
+                }
+                Err(Error::CommandFailed(status)) => {
+                    nvme_log!(
+                        Warn,
+                        "Number of Queues rejected (status {:#04x}), assuming one queue pair",
+                        status.sc,
+                    );
+                    break None;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        // Extract actual allocated queue counts from completion entry
+        // Bits 31:16 = Number of I/O Completion Queues Allocated (0-based)
+        // Bits 15:0 = Number of I/O Submission Queues Allocated (0-based)
+        let allocated_sq = allocated.map_or(1, |dw0| (dw0 & 0xFFFF) + 1);
+        let allocated_cq = allocated.map_or(1, |dw0| ((dw0 >> 16) & 0xFFFF) + 1);
+
+        // Only use as many I/O queues as there are mapped doorbells for
+        let doorbell_limit = self.inner.doorbell_helper.max_queues()
+            .map_or(u32::MAX, |max| max.saturating_sub(1) as u32);
+
+        let mut data = self.inner.data.lock();
+        data.max_io_sq = allocated_sq.min(doorbell_limit) as u16;
+        data.max_io_cq = allocated_cq.min(doorbell_limit) as u16;
+        nvme_log!(
+            Info,
+            "controller allows {} I/O submission and {} completion queues",
+            data.max_io_sq,
+            data.max_io_cq,
+        );
+        Ok(allocated.is_none())
+    }
+
+    /// Capture the device's configuration as a profile.
+    ///
+    /// Records the I/O queue count and depth, the current values of the
+    /// controller-wide features the controller supports, the APST table,
+    /// the multipath policy and the device personality.
+    pub fn export_profile(&self) -> Result<DeviceProfile> {
+        let features = PROFILE_FEATURES
+            .into_iter()
+            .filter_map(|feature| {
+                let value = self.get_feature(0, feature, FeatureSelector::Current).ok()?;
+                Some((feature, value))
+            })
+            .collect();
+
+        let buf = self.admin_buffers.get(256)?;
+        let apst = self.exec_admin(Command::get_features_with_data(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::AutonomousPowerState,
+            FeatureSelector::Current as u8,
+            buf.phys_addr(),
+        )).ok().map(|entry| (entry.command_specific, buf.to_vec()));
+
+        let multipath = self.inner.multipath.read().as_ref().map(|(multipath, _)| {
+            (multipath.path_selector(), multipath.get_rpfr_config())
+        });
+
+        Ok(DeviceProfile {
+            io_queues: self.ioq_count() as u16,
+            queue_depth: self.queue_depth() as u16,
+            features,
+            apst,
+            path_selector: multipath.map(|(selector, _)| selector),
+            rpfr: multipath.map(|(_, rpfr)| rpfr),
+            personality: *self.power.lock().get_personality(),
+        })
+    }
+
+    /// Configure the device as captured in `profile`.
+    ///
+    /// The profile may come from this device or another one. The queue
+    /// count is limited to what the controller grants, and the multipath
+    /// policy is only applied if a multipath controller is set. With
+    /// `persist`, the features are also saved across power cycles. Fails
+    /// with the error of the first feature the controller rejects.
+    pub fn apply_profile(&self, profile: &DeviceProfile, persist: bool) -> Result<()> {
+        self.check_persist(persist)?;
+
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+        self.set_ioq_count((profile.io_queues as usize).clamp(1, hw_limit))?;
+        self.set_queue_depth(profile.queue_depth as usize);
+
+        for &(feature, value) in &profile.features {
+            if let Err(e) = self.set_feature(0, feature, value, persist) {
+                nvme_log!(Error, "profile feature {:?} rejected: {}", feature, e);
+                return Err(e);
+            }
+        }
+
+        if let Some((value, table)) = &profile.apst {
+            let buf = self.admin_buffer_with(table)?;
+            self.exec_admin(Command::set_features_with_data(
+                self.admin_sq.tail() as u16,
+                0,
+                FeatureId::AutonomousPowerState,
+                *value,
+                persist,
+                buf.phys_addr(),
+            ))?;
+        }
+
+        if let Some((multipath, _)) = self.inner.multipath.read().as_ref() {
+            if let Some(selector) = profile.path_selector {
+                multipath.set_path_selector(selector);
+            }
+            if let Some(rpfr) = profile.rpfr {
+                multipath.update_rpfr_config(rpfr);
+            }
+        }
+
+        self.power.lock().set_personality(profile.personality);
+        nvme_log!(Info, "applied profile with {} features", profile.features.len());
+        Ok(())
+    }
+
+    /// Suspend the device.
+    ///
+    /// New I/O is rejected, the I/O in flight is given up to 30 seconds
+    /// to complete, all namespaces are flushed, the I/O queues are
+    /// deleted and the controller is shut down. The queue configuration and
+    /// features such as APST and the volatile write cache are saved so
+    /// that `resume` can restore them without re-probing the device.
+    pub fn suspend(&self) -> Result<()> {
+        if self.inner.suspended.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        if !self.drain_io(TEARDOWN_TIMEOUT_MS) {
+            nvme_log!(Warn, "suspending with I/O still in flight");
+        }
+
+        let state = SuspendState {
+            ioq_count: self.ioq_count(),
+            write_cache: self.exec_admin(Command::get_features(
+                self.admin_sq.tail() as u16,
+                0,
+                FeatureId::VolatileWriteCache,
+                0,
+            )).ok().map(|entry| entry.command_specific & 1 != 0),
+            apst: self.admin_buffers.get(256).ok().and_then(|buf| {
+                self.exec_admin(Command::get_features_with_data(
+                    self.admin_sq.tail() as u16,
+                    0,
+                    FeatureId::AutonomousPowerState,
+                    0,
+                    buf.phys_addr(),
+                )).ok().map(|entry| (entry.command_specific, buf.to_vec()))
+            }),
+        };
+
+        // Flush and delete all I/O queues
+        if let Err(e) = self.destroy_ioq() {
+            self.inner.suspended.store(false, Ordering::Release);
+            return Err(e);
+        }
+        *self.suspend_state.lock() = Some(state);
+
+        // Normal shutdown notification, then wait for shutdown complete
+        let cc = self.get_reg::<u32>(Register::CC) & !(0x3 << 14);
+        self.set_reg::<u32>(Register::CC, cc | (0x1 << 14));
+        let waiter = self.inner.waiter();
+        while (self.get_reg::<u32>(Register::CSTS) >> 2) & 0x3 != 0x2 {
+            waiter.wait();
+        }
+
+        Ok(())
+    }
+
+    /// Stop accepting new I/O and drain the commands in flight.
+    ///
+    /// Namespace commands fail with `Error::DeviceQuiesced` until
+    /// `unquiesce` is called. Waits up to `timeout_ms` (0 = forever) for
+    /// the outstanding commands on all queues, then aborts the ones still
+    /// running and waits for them once more. The timeout is only enforced
+    /// while a clock is set. Use it before firmware activation, sanitize,
+    /// format or suspend.
+    pub fn quiesce(&self, timeout_ms: u32) -> Result<QuiesceReport> {
+        self.inner.quiesced.store(true, Ordering::SeqCst);
+
+        let mut report = QuiesceReport {
+            drained: self.inner.active_io.load(Ordering::Acquire),
+            ..Default::default()
+        };
+        if self.drain_io(timeout_ms) {
+            return Ok(report);
+        }
+
+        let stuck: Vec<_> = self.inner.in_flight.lock()
+            .iter()
+            .flat_map(|(&sqid, (cids, _))| cids.iter().map(move |cid| (sqid, cid)))
+            .collect();
+        for (sqid, cid) in stuck {
+            let entry = self.exec_admin(Command::abort(self.admin_sq.tail() as u16, sqid, cid))?;
+            // Bit 0 of DW0 is cleared if the command was aborted
+            if entry.command_specific & 1 == 0 {
+                report.aborted += 1;
+            }
+        }
+        nvme_log!(Warn, "quiesce aborted {} commands", report.aborted);
+
+        self.drain_io(timeout_ms);
+        report.remaining = self.inner.active_io.load(Ordering::Acquire);
+        report.drained = report.drained.saturating_sub(report.aborted + report.remaining);
+        Ok(report)
+    }
+
+    /// Accept new I/O again after `quiesce`.
+    pub fn unquiesce(&self) {
+        self.inner.quiesced.store(false, Ordering::Release);
+    }
+
+    /// Wait up to `timeout_ms` (0 = forever) until no I/O is in flight.
+    ///
+    /// Completions of steered commands are delivered meanwhile. The timeout
+    /// is only enforced while a clock is set. Returns false on timeout.
+    fn drain_io(&self, timeout_ms: u32) -> bool {
+        let start = self.inner.now_us();
+        let waiter = self.inner.waiter();
+        while self.inner.active_io.load(Ordering::Acquire) != 0 {
+            self.reap_steered();
+            if timeout_ms != 0 && self.inner.now_us() - start >= timeout_ms as u64 * 1000 {
+                return false;
+            }
+            waiter.wait();
+        }
+        true
+    }
+
+    /// Resume the device after `suspend`.
+    ///
+    /// Re-enables the controller, recreates the I/O queues and restores
+    /// the features saved at suspend time. If that fails, the saved state
+    /// is kept, so `resume` can be called again.
+    pub fn resume(&self) -> Result<()> {
+        let Some(state) = self.suspend_state.lock().take() else {
+            return Ok(());
+        };
+
+        if let Err(e) = self.restore(&state) {
+            // The next attempt resets the controller, which drops the queues
+            // created so far
+            self.inner.ioq.lock().clear();
+            self.inner.in_flight.lock().clear();
+            self.inner.next_queue_id.store(1, Ordering::SeqCst);
+            *self.suspend_state.lock() = Some(state);
+            return Err(e);
+        }
+
+        // The controller reset returns it to power state 0
+        self.power.lock().record_transition(0, self.inner.now_us());
+        self.inner.suspended.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Bring the controller back up with the state saved by `suspend`.
+    fn restore(&self, state: &SuspendState) -> Result<()> {
+        self.enable_controller();
+        self.negotiate_queues()?;
+
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+        for _ in 0..state.ioq_count.clamp(1, hw_limit) {
+            self.add_ioq_internal(&QueueConfig::default())?;
+        }
+
+        if let Some(enable) = state.write_cache {
+            self.exec_admin(Command::set_features(
+                self.admin_sq.tail() as u16,
+                0,
+                FeatureId::VolatileWriteCache,
+                enable as u32,
+                false,
+            ))?;
+        }
+
+        if let Some((value, table)) = &state.apst {
+            let buf = self.admin_buffer_with(table)?;
+            self.exec_admin(Command::set_features_with_data(
+                self.admin_sq.tail() as u16,
+                0,
+                FeatureId::AutonomousPowerState,
+                *value,
+                false,
+                buf.phys_addr(),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Reset the controller and bring it back up.
+    ///
+    /// New I/O is rejected and the I/O in flight is given up to 30 seconds
+    /// to complete. Then the namespaces are flushed, the I/O queues are
+    /// deleted and the controller is reset. The controller is
+    /// then identified again and the same number of I/O queues is
+    /// recreated. Namespaces and their handles stay valid.
+    pub fn reset(&self) -> Result<()> {
+        self.reset_with(false)
+    }
+
+    /// Reset the NVM subsystem (NSSR) and bring the controller back up.
+    ///
+    /// Behaves like `reset`. Fails with `Error::FeatureNotSupported` if
+    /// the controller does not support NVM subsystem resets.
+    pub fn subsystem_reset(&self) -> Result<()> {
+        // CAP.NSSRS
+        if (self.get_reg::<u64>(Register::CAP) >> 36) & 1 == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.reset_with(true)
+    }
+
+    /// Quiesce I/O, reset the controller or subsystem and re-initialize.
+    fn reset_with(&self, subsystem: bool) -> Result<()> {
+        if self.inner.suspended.swap(true, Ordering::AcqRel) {
+            return Err(Error::DeviceSuspended);
+        }
+        if !self.drain_io(TEARDOWN_TIMEOUT_MS) {
+            nvme_log!(Warn, "resetting with I/O still in flight");
+        }
+
+        let ioq_count = self.ioq_count();
+        let result = self.destroy_ioq().and_then(|_| {
+            if subsystem {
+                // "NVMe" in ASCII
+                self.set_reg::<u32>(Register::NSSR, 0x4E56_4D65);
+            }
+            self.reinitialize(ioq_count)
+        });
+        nvme_log!(Info, "{} reset complete", if subsystem { "subsystem" } else { "controller" });
+
+        // The reset returns the controller to power state 0
+        self.power.lock().record_transition(0, self.inner.now_us());
+        self.inner.suspended.store(false, Ordering::Release);
+        result
+    }
+
+    /// Enable the controller again after a reset, identify it and
+    /// recreate `ioq_count` I/O queues.
+    fn reinitialize(&self, ioq_count: usize) -> Result<()> {
+        self.enable_controller();
+        // Our own subsystem resets set NSSRO too, so it is cleared here
+        self.set_reg::<u32>(Register::CSTS, CSTS_NSSRO);
+        self.identify_controller()?;
+        self.negotiate_queues()?;
+
+        let hw_limit = {
+            let data = self.inner.data.lock();
+            data.max_io_sq.min(data.max_io_cq) as usize
+        };
+        for _ in 0..ioq_count.clamp(1, hw_limit) {
+            self.add_ioq_internal(&QueueConfig::default())?;
+        }
+        Ok(())
+    }
+
+    /// Check whether the controller was reset without the host asking.
+    ///
+    /// An NVM subsystem reset sets CSTS.NSSRO, and a controller that is
+    /// disabled or not ready outside of `suspend` and `reset` has been
+    /// reset on its own. A controller that reads all ones is gone rather
+    /// than reset.
+    pub fn reset_occurred(&self) -> Option<ControllerReset> {
+        let csts = self.get_reg::<u32>(Register::CSTS);
+        if csts == u32::MAX {
+            return None;
+        }
+        if csts & CSTS_NSSRO != 0 {
+            return Some(ControllerReset::Subsystem);
+        }
+        let enabled = self.get_reg::<u32>(Register::CC) & 1 != 0 && csts & 1 != 0;
+        match enabled || self.inner.suspended.load(Ordering::Acquire) {
+            true => None,
+            false => Some(ControllerReset::Controller),
+        }
+    }
+
+    /// Recover from a reset the host did not ask for.
+    ///
+    /// If `reset_occurred` reports one, `events`' reset handlers are told
+    /// about it and the controller is initialized again with the same
+    /// number of I/O queues, like after `reset`. Commands in flight at the
+    /// time of the reset are lost: their waiters and completion sinks get
+    /// `Error::ControllerResetOccurred`. Returns the reset recovered from.
+    pub fn recover_reset(
+        &self,
+        events: Option<&AsyncEventManager>,
+    ) -> Result<Option<ControllerReset>> {
+        let Some(reset) = self.reset_occurred() else {
+            return Ok(None);
+        };
+        if self.inner.suspended.swap(true, Ordering::AcqRel) {
+            return Err(Error::DeviceSuspended);
+        }
+        nvme_log!(Warn, "{:?} reset occurred underneath the host, re-initializing", reset);
+
+        // The queues are gone from the controller, so they are only dropped
+        // here, once the commands waiting on them have given up
+        let ioq_count = self.ioq_count();
+        for (_, cq) in core::mem::take(&mut *self.inner.in_flight.lock()).values() {
+            cq.dead.store(true, Ordering::Release);
+        }
+        let queues = core::mem::take(&mut *self.inner.ioq.lock());
+        for queue_arc in queues {
+            let done = self.inner.fail_steered(&mut queue_arc.lock());
+            for (sink, context, result) in done {
+                sink.complete(context, result);
+            }
+        }
+        self.inner.next_queue_id.store(1, Ordering::SeqCst);
+
+        let result = events
+            .map_or(Ok(()), |events| events.notify_reset(&reset))
+            .and_then(|_| self.reinitialize(ioq_count));
+
+        self.power.lock().record_transition(0, self.inner.now_us());
+        self.inner.suspended.store(false, Ordering::Release);
+        result.map(|_| Some(reset))
+    }
+
+    /// Discover which features the controller supports.
+    ///
+    /// Probes every known feature with Get Features using the supported
+    /// capabilities selector and reports whether each one is saveable,
+    /// namespace specific and changeable. Features the controller rejects
+    /// are left out of the result. `nsid` selects the namespace whose
+    /// namespace-specific features are reported, 0 for controller-wide ones.
+    pub fn supported_features(&self, nsid: u32) -> Result<Vec<FeatureCapabilities>> {
+        // ONCS bit 4: Save field in Set Features and Select field in Get Features
+        if self.inner.data.lock().oncs & (1 << 4) == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let features = FeatureId::ALL
+            .iter()
+            .filter_map(|&fid| {
+                self.exec_admin(Command::get_features(
+                    self.admin_sq.tail() as u16,
+                    nsid,
+                    fid,
+                    FeatureSelector::Supported as u8,
+                ))
+                .ok()
+                .map(|entry| FeatureCapabilities::from_feature_value(fid, entry.command_specific))
+            })
+            .collect();
+
+        Ok(features)
+    }
+
+    /// Configure command arbitration.
+    ///
+    /// Priority weights only take effect with weighted round robin
+    /// arbitration, so they are rejected if the controller lacks it.
+    pub fn set_arbitration(&self, config: &ArbitrationConfig, persist: bool) -> Result<()> {
+        if config.uses_weights() && !self.inner.data.lock().wrr_supported {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        self.set_feature(0, FeatureId::Arbitration, config.to_feature_value(), persist)?;
+        Ok(())
+    }
+
+    /// Read the current command arbitration configuration.
+    pub fn arbitration(&self) -> Result<ArbitrationConfig> {
+        let entry = self.exec_admin(Command::get_features(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::Arbitration,
+            0,
+        ))?;
+
+        Ok(ArbitrationConfig::from_feature_value(entry.command_specific))
+    }
+
+    /// Set a feature and return the completion value.
+    ///
+    /// `nsid` selects the namespace for namespace-specific features. Use 0
+    /// for controller-wide features, or `0xFFFFFFFF` to apply the value to
+    /// all namespaces. Targeting a namespace with a feature that is not
+    /// namespace specific fails with `Error::FeatureNotNamespaceSpecific`.
+    ///
+    /// With `persist` set, the value is also saved so that it survives
+    /// power cycles and controller resets.
+    pub fn set_feature(
+        &self,
+        nsid: u32,
+        feature: FeatureId,
+        value: u32,
+        persist: bool,
+    ) -> Result<u32> {
+        self.check_persist(persist)?;
+
+        let entry = self.exec_admin(Command::set_features(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature,
+            value,
+            persist,
+        ))?;
+
+        Ok(entry.command_specific)
+    }
+
+    /// Get a feature value.
+    ///
+    /// `nsid` selects the namespace for namespace-specific features
+    /// and is 0 for controller-wide features.
+    pub fn get_feature(&self, nsid: u32, feature: FeatureId, sel: FeatureSelector) -> Result<u32> {
+        let entry = self.exec_admin(Command::get_features(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature,
+            sel as u8,
+        ))?;
+
+        Ok(entry.command_specific)
+    }
+
+    /// Get a feature value decoded according to its feature ID.
+    ///
+    /// Features without a typed decoding are returned as `FeatureValue::Raw`.
+    pub fn get_feature_value(
+        &self,
+        nsid: u32,
+        feature: FeatureId,
+        sel: FeatureSelector,
+    ) -> Result<FeatureValue> {
+        let value = self.get_feature(nsid, feature, sel)?;
+        Ok(FeatureValue::decode(feature, value))
+    }
+
+    /// Set a feature and decode the completion value.
+    ///
+    /// See `set_feature` and `FeatureValue::decode_set`.
+    pub fn set_feature_value(
+        &self,
+        nsid: u32,
+        feature: FeatureId,
+        value: u32,
+        persist: bool,
+    ) -> Result<FeatureValue> {
+        let result = self.set_feature(nsid, feature, value, persist)?;
+        Ok(FeatureValue::decode_set(feature, result))
+    }
+
+    /// Get the reservation notifications namespace `nsid` reports.
+    pub fn reservation_notifications(&self, nsid: u32) -> Result<ReservationNotificationConfig> {
+        let feature = FeatureId::ReservationNotificationMask;
+        let value = self.get_feature(nsid, feature, FeatureSelector::Current)?;
+        Ok(ReservationNotificationConfig::from_feature_value(value))
+    }
+
+    /// Choose the reservation notifications namespace `nsid` reports.
+    ///
+    /// Reported notifications are queued in the Reservation Notification
+    /// log page and announced with an asynchronous event, which
+    /// `handle_async_event` passes on to the reservation handlers.
+    pub fn set_reservation_notifications(
+        &self,
+        nsid: u32,
+        config: &ReservationNotificationConfig,
+        persist: bool,
+    ) -> Result<()> {
+        let value = config.to_feature_value();
+        self.set_feature(nsid, FeatureId::ReservationNotificationMask, value, persist)?;
+        Ok(())
+    }
+
+    /// Take the queued reservation notifications, oldest first.
+    ///
+    /// Each read of the log page takes one notification, so the page is
+    /// read until the controller reports no more.
+    pub fn read_reservation_notifications(&self) -> Result<Vec<ReservationNotification>> {
+        let mut notifications = Vec::new();
+        // At most 255 notifications are queued besides the one read
+        for _ in 0..=u8::MAX as usize {
+            let data = self.read_log(LogPageId::ReservationNotification, 0, 64)?;
+            let notification = ReservationNotification::from_log_data(&data)?;
+            if notification.is_empty() {
+                break;
+            }
+            notifications.push(notification);
+            if notification.available == 0 {
+                break;
+            }
+        }
+        Ok(notifications)
+    }
+
+    /// Restore a feature to its default value.
+    ///
+    /// Reads the default with `FeatureSelector::Default` and applies it as
+    /// the current value. With `persist` set, the default also replaces
+    /// the saved value, so the reset survives power cycles.
+    pub fn restore_defaults(&self, nsid: u32, feature: FeatureId, persist: bool) -> Result<()> {
+        self.check_persist(persist)?;
+
+        if !feature.has_data() {
+            let value = self.get_feature(nsid, feature, FeatureSelector::Default)?;
+            self.set_feature(nsid, feature, value, persist)?;
+            return Ok(());
+        }
+
+        // The default data structure is returned in an admin buffer
+        // and is written back from there
+        let buf = self.admin_buffers.get(4096)?;
+        let entry = self.exec_admin(Command::get_features_with_data(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature,
+            FeatureSelector::Default as u8,
+            buf.phys_addr(),
+        ))?;
+
+        self.exec_admin(Command::set_features_with_data(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature,
+            entry.command_specific,
+            persist,
+            buf.phys_addr(),
+        ))?;
+        Ok(())
+    }
+
+    /// Check that feature values can be saved if `persist` is requested.
+    fn check_persist(&self, persist: bool) -> Result<()> {
+        // ONCS bit 4: Save field in Set Features and Select field in Get Features
+        if persist && self.inner.data.lock().oncs & (1 << 4) == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+        Ok(())
+    }
+
+    /// Configure error recovery (TLER and DULBE) for a namespace.
+    pub fn set_error_recovery(
+        &self,
+        nsid: u32,
+        config: &ErrorRecoveryConfig,
+        persist: bool,
+    ) -> Result<()> {
+        self.set_feature(nsid, FeatureId::ErrorRecovery, config.to_feature_value(), persist)?;
+        Ok(())
+    }
+
+    /// Read the current error recovery configuration of a namespace.
+    pub fn error_recovery(&self, nsid: u32) -> Result<ErrorRecoveryConfig> {
+        let value = self.get_feature(nsid, FeatureId::ErrorRecovery, FeatureSelector::Current)?;
+        Ok(ErrorRecoveryConfig::from_feature_value(value))
+    }
+
+    /// Enable or disable interrupt coalescing for an interrupt vector.
+    pub fn set_interrupt_vector_coalescing(
+        &self,
+        vector: u16,
+        enabled: bool,
+        persist: bool,
+    ) -> Result<()> {
+        let value = ((!enabled as u32) << 16) | vector as u32;
+        self.set_feature(0, FeatureId::InterruptVectorConfig, value, persist)?;
+        Ok(())
+    }
+
+    /// Check whether interrupt coalescing is enabled for an interrupt vector.
+    pub fn interrupt_vector_coalescing(&self, vector: u16) -> Result<bool> {
+        let entry = self.exec_admin(Command::get_features_with_value(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::InterruptVectorConfig,
+            0,
+            vector as u32,
+        ))?;
+
+        Ok((entry.command_specific >> 16) & 1 == 0)
+    }
+
+    /// Enable or disable interrupt coalescing for the vector of an I/O queue.
+    ///
+    /// Latency-critical queues can be exempted from coalescing this way
+    /// while the remaining queues keep the device-wide coalescing setting.
+    /// Fails with `Error::SharedInterruptVector` unless the queue was
+    /// created with a vector of its own, see `QueueConfig::vector`, as the
+    /// setting would apply to every queue on the vector.
+    pub fn set_queue_coalescing(&self, qid: u16, enabled: bool, persist: bool) -> Result<()> {
+        let cqs: Vec<_> = self.inner.ioq.lock()
+            .iter()
+            .map(|q| {
+                let queue = q.lock();
+                (queue.qid, queue.cq.cqid, queue.cq.vector)
             })
-            .collect()
+            .collect();
+        let &(_, cqid, vector) = cqs.iter()
+            .find(|&&(sqid, _, _)| sqid == qid)
+            .ok_or(Error::QueueNotFound)?;
+
+        // Vector 0 also serves the admin completion queue
+        let vector = vector.filter(|&vector| vector != 0).ok_or(Error::SharedInterruptVector)?;
+        if cqs.iter().any(|&(_, other, v)| other != cqid && v == Some(vector)) {
+            return Err(Error::SharedInterruptVector);
+        }
+
+        self.set_interrupt_vector_coalescing(vector, enabled, persist)
+    }
+
+    /// Configure predictable latency mode for an NVM set.
+    pub fn set_predictable_latency(
+        &self,
+        nvm_set_id: u16,
+        config: &PredictableLatencyConfig,
+        persist: bool,
+    ) -> Result<()> {
+        self.check_persist(persist)?;
+        let buf = self.admin_buffer_with(&config.to_data())?;
+
+        let value = ((config.enabled as u32) << 16) | nvm_set_id as u32;
+        self.exec_admin(Command::set_features_with_data(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::PredictableLatencyModeConfig,
+            value,
+            persist,
+            buf.phys_addr(),
+        ))?;
+        Ok(())
+    }
+
+    /// Tell the controller which optional behaviors the host supports.
+    ///
+    /// LBA Format Extension (`lbafee`) must be enabled before extended LBA
+    /// formats can be used, and `etdas` before telemetry data area 4 is
+    /// reported.
+    pub fn set_host_behavior(&self, config: &HostBehaviorSupport, persist: bool) -> Result<()> {
+        self.check_persist(persist)?;
+        let buf = self.admin_buffer_with(&config.to_data())?;
+
+        self.exec_admin(Command::set_features_with_data(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::HostBehaviorSupport,
+            0,
+            persist,
+            buf.phys_addr(),
+        ))?;
+        Ok(())
+    }
+
+    /// Read the host behavior settings.
+    pub fn host_behavior(&self, sel: FeatureSelector) -> Result<HostBehaviorSupport> {
+        let buf = self.admin_buffers.get(512)?;
+        self.exec_admin(Command::get_features_with_data(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::HostBehaviorSupport,
+            sel as u8,
+            buf.phys_addr(),
+        ))?;
+
+        Ok(HostBehaviorSupport::from_data(&buf))
+    }
+
+    /// Request a predictable latency window for an NVM set.
+    pub fn set_predictable_latency_window(
+        &self,
+        nvm_set_id: u16,
+        window: PredictableLatencyWindow,
+    ) -> Result<()> {
+        let value = ((window as u32) << 16) | nvm_set_id as u32;
+        self.exec_admin(Command::set_features(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::PredictableLatencyModeWindow,
+            value,
+            false,
+        ))?;
+        Ok(())
+    }
+
+    /// Read the Predictable Latency Per NVM Set log page.
+    ///
+    /// The result reports the window the set is currently operating in
+    /// and the typical and estimated deterministic window budgets.
+    pub fn predictable_latency_status(&self, nvm_set_id: u16) -> Result<PredictableLatencyPerNvmSet> {
+        let data = self.read_log(LogPageId::PredictableLatencyPerNvmSet, nvm_set_id, 512)?;
+        PredictableLatencyPerNvmSet::from_log_data(&data)
+    }
+
+    /// Read the Predictable Latency Event Aggregate log page.
+    pub fn predictable_latency_events(&self) -> Result<PredictableLatencyEventAggregate> {
+        let data = self.read_log(LogPageId::PredictableLatencyEventAggregate, 0, 4096)?;
+        PredictableLatencyEventAggregate::from_log_data(&data)
+    }
+
+    /// Start a sanitize operation.
+    ///
+    /// Fails with `Error::SanitizePerNamespaceNotSupported` if a single
+    /// namespace is targeted and the controller can only sanitize the
+    /// whole subsystem. Progress is reported by the Sanitize Status log page.
+    ///
+    /// I/O already in flight to the sanitized namespaces is waited for
+    /// first. New I/O to them fails with `Error::SanitizeInProgress` until
+    /// `sanitize_status` reports the operation is over, or the controller
+    /// sends a Sanitize Operation Completed event.
+    pub fn sanitize(&self, config: &SanitizePerNamespace) -> Result<()> {
+        let caps = self.inner.data.lock().sanitize;
+        let cmd = config.build_command(self.admin_sq.tail() as u16, &caps)?;
+        let operation = DeviceOperation::Sanitize(config.namespace_id);
+        self.begin_operation(operation)?;
+        if let Err(e) = self.exec_admin(cmd) {
+            self.end_operation(operation);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Send security protocol data to the controller.
+    ///
+    /// `data` is copied into a DMA buffer and sent like a single piece of
+    /// `security_send_stream`.
+    pub fn security_send(
+        &self,
+        manager: &SecurityManager,
+        nsid: u32,
+        protocol: SecurityProtocol,
+        sp_specific: u16,
+        data: &[u8],
+    ) -> Result<()> {
+        let transfer = SecurityTransfer::new(nsid, protocol, sp_specific);
+        self.security_send_stream(manager, &transfer, [data])
+    }
+
+    /// Receive `len` bytes of security protocol data from the controller.
+    ///
+    /// Issues a single Security Receive command, so `len` must not exceed
+    /// the maximum transfer size. Use `security_receive_stream` for
+    /// responses that are returned by several commands.
+    pub fn security_receive(
+        &self,
+        manager: &SecurityManager,
+        nsid: u32,
+        protocol: SecurityProtocol,
+        sp_specific: u16,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let transfer = SecurityTransfer::new(nsid, protocol, sp_specific);
+        Ok(self.security_receive_piece(manager, &transfer, len)?.to_vec())
+    }
+
+    /// Send a security protocol payload given as a stream of pieces.
+    ///
+    /// A payload up to the maximum transfer size is gathered into one DMA
+    /// buffer and sent with a single Security Send command. A larger TCG
+    /// payload (see `SecurityTransfer::carries_compackets`) is split into
+    /// its ComPackets, each sent with its own IF-SEND, and zero padding
+    /// after the last one is dropped. Fails with `Error::IoSizeExceedsMdts`
+    /// if another payload or a single ComPacket exceeds the maximum
+    /// transfer size, and with `Error::InvalidBufferSize` if the TCG
+    /// payload does not end with a complete ComPacket.
+    pub fn security_send_stream<'d>(
+        &self,
+        manager: &SecurityManager,
+        transfer: &SecurityTransfer,
+        pieces: impl IntoIterator<Item = &'d [u8]> + Clone,
+    ) -> Result<()> {
+        let max_transfer_size = self.inner.data.lock().max_transfer_size.max(4096);
+        let len = pieces.clone().into_iter().map(<[u8]>::len).sum::<usize>();
+        if len <= max_transfer_size {
+            return self.security_send_piece(manager, transfer, pieces, len);
+        }
+        if !transfer.carries_compackets() {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+
+        let payload: Vec<u8> = pieces.into_iter().flatten().copied().collect();
+        let mut rest = &payload[..];
+        while rest.iter().any(|&b| b != 0) {
+            let compacket_len = tcg_compacket_len(rest).ok_or(Error::InvalidBufferSize)?;
+            if compacket_len > max_transfer_size {
+                return Err(Error::IoSizeExceedsMdts);
+            }
+            let (compacket, next) = rest.split_at(compacket_len);
+            self.security_send_piece(manager, transfer, [compacket], compacket_len)?;
+            rest = next;
+        }
+        Ok(())
+    }
+
+    /// Receive a security protocol payload as a stream of pieces.
+    ///
+    /// The first Security Receive asks for `piece_size` bytes and hands
+    /// them to `sink`, which returns the allocation length of the next
+    /// one, or 0 to end the payload. For TCG (see
+    /// `SecurityTransfer::carries_compackets`), a ComPacket reporting
+    /// `tcg_outstanding_data` when `sink` returns 0 is followed by another
+    /// IF-RECV on the same ComID, sized for the outstanding data up to the
+    /// maximum transfer size, until none is left. Fails with
+    /// `Error::IoSizeExceedsMdts` if a piece `sink` asks for exceeds the
+    /// maximum transfer size.
+    pub fn security_receive_stream(
+        &self,
+        manager: &SecurityManager,
+        transfer: &SecurityTransfer,
+        piece_size: usize,
+        mut sink: impl FnMut(&[u8]) -> Result<usize>,
+    ) -> Result<()> {
+        let max_transfer_size = self.inner.data.lock().max_transfer_size.max(4096);
+
+        let mut size = piece_size;
+        loop {
+            let buf = self.security_receive_piece(manager, transfer, size)?;
+            size = match sink(&buf)? {
+                0 if transfer.carries_compackets() => match tcg_outstanding_data(&buf) {
+                    Some(outstanding) if outstanding != 0 => {
+                        (TCG_COMPACKET_HEADER + outstanding as usize).min(max_transfer_size)
+                    }
+                    _ => break,
+                },
+                0 => break,
+                size => size,
+            };
+        }
+        Ok(())
+    }
+
+    /// Send `len` bytes gathered from `pieces` with one Security Send command.
+    fn security_send_piece<'d>(
+        &self,
+        manager: &SecurityManager,
+        transfer: &SecurityTransfer,
+        pieces: impl IntoIterator<Item = &'d [u8]>,
+        len: usize,
+    ) -> Result<()> {
+        if len > self.inner.data.lock().max_transfer_size.max(4096) {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+        let mut buf = self.admin_buffers.get(len)?;
+        let mut offset = 0;
+        for piece in pieces {
+            buf[offset..offset + piece.len()].copy_from_slice(piece);
+            offset += piece.len();
+        }
+        let (prp1, prp2) = buf.prp();
+        self.exec_admin(manager.build_security_send_command(
+            self.admin_sq.tail() as u16,
+            transfer.nsid,
+            prp1,
+            transfer.protocol,
+            transfer.sp_specific,
+            len as u32,
+        ).with_prp2(prp2))?;
+        Ok(())
+    }
+
+    /// Receive up to `len` bytes with one Security Receive command.
+    fn security_receive_piece(
+        &self,
+        manager: &SecurityManager,
+        transfer: &SecurityTransfer,
+        len: usize,
+    ) -> Result<PoolBuffer<'_, A>> {
+        if len > self.inner.data.lock().max_transfer_size.max(4096) {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+        let buf = self.admin_buffers.get(len)?;
+        let (prp1, prp2) = buf.prp();
+        self.exec_admin(manager.build_security_receive_command(
+            self.admin_sq.tail() as u16,
+            transfer.nsid,
+            prp1,
+            transfer.protocol,
+            transfer.sp_specific,
+            len as u32,
+        ).with_prp2(prp2))?;
+        Ok(buf)
+    }
+
+    /// Run TCG level 0 discovery and record the drive's features in `manager`.
+    ///
+    /// Use `SecurityManager::check_tcg_operation` before issuing locking
+    /// SP methods.
+    pub fn tcg_discovery(&self, manager: &mut SecurityManager) -> Result<TcgDiscovery> {
+        let data = self.security_receive(manager, 0, SecurityProtocol::Tcg, 0x0001, 2048)?;
+        manager.update_tcg_discovery(&data).cloned()
+    }
+
+    /// Read the Sanitize Status log page into `manager`.
+    pub fn refresh_sanitize_status(&self, manager: &mut SecurityManager) -> Result<()> {
+        manager.update_sanitize_status(&self.read_log(LogPageId::SanitizeStatus, 0, 512)?)
+    }
+
+    /// Download and commit a firmware image.
+    ///
+    /// Before anything is downloaded, the update is checked against the
+    /// controller's firmware slots and update granularity and then by the
+    /// manager's verifier, if one is set. Progress is tracked in `manager`.
+    ///
+    /// With `auto_activate`, a controller or NVM subsystem reset that the
+    /// commit asks for is performed, and the new revision is confirmed
+    /// from the firmware slot log. Returns the reset still needed to
+    /// activate the image, if any.
+    pub fn update_firmware(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+        auto_activate: bool,
+    ) -> Result<FirmwareActivation> {
+        let chunk_size = self.check_firmware_update(manager, config)?;
+        manager.start_update(config)?;
+        self.download_firmware(manager, config, 0, chunk_size, None)?;
+        self.commit_firmware(manager, config, auto_activate)
+    }
+
+    /// Download and commit a firmware image like `update_firmware`,
+    /// reporting to `operation`.
+    ///
+    /// Cancelling the operation stops the download before its next chunk
+    /// with `Error::OperationCancelled`, and the update can be continued
+    /// with `resume_firmware_update`. The commit is not cancelled once sent.
+    pub fn update_firmware_tracked(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+        auto_activate: bool,
+        operation: &Operation,
+    ) -> Result<FirmwareActivation> {
+        let result = self.check_firmware_update(manager, config).and_then(|chunk_size| {
+            manager.start_update(config)?;
+            self.download_firmware(manager, config, 0, chunk_size, Some(operation))?;
+            self.commit_firmware(manager, config, auto_activate)
+        });
+        operation.finish(self.inner.now_us());
+        result
+    }
+
+    /// Continue a firmware update whose download was interrupted.
+    ///
+    /// Downloading restarts from the last chunk the controller accepted,
+    /// as recorded in `manager`. The controller discards partial images
+    /// on reset, so this only works while it has not been reset since.
+    pub fn resume_firmware_update(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+        auto_activate: bool,
+    ) -> Result<FirmwareActivation> {
+        let chunk_size = self.check_firmware_update(manager, config)?;
+        let offset = manager.resume_update(config)?;
+        nvme_log!(Info, "resuming firmware download at offset {}", offset);
+        self.download_firmware(manager, config, offset, chunk_size, None)?;
+        self.commit_firmware(manager, config, auto_activate)
+    }
+
+    /// Run the firmware update checks and the manager's verifier.
+    ///
+    /// Returns the download chunk size.
+    fn check_firmware_update(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+    ) -> Result<usize> {
+        let slot_log = self.read_log(LogPageId::FirmwareSlot, 0, 512)?;
+        manager.update_slot_info(&slot_log)?;
+
+        let (frmw, fwug, max_transfer_size) = {
+            let data = self.inner.data.lock();
+            (data.firmware_updates, data.firmware_update_granularity, data.max_transfer_size)
+        };
+        manager.set_granularity(fwug);
+        let mut checker = FirmwareImageChecker::new(frmw, fwug, manager);
+        let checked = manager.download_chunk_size(max_transfer_size).and_then(|chunk_size| {
+            checker.chunk_size = chunk_size;
+            manager.validate_update(config).and_then(|_| checker.validate(config))
+        });
+
+        if let Err(e) = checked {
+            manager.fail_update(FirmwareUpdateError::InvalidImage);
+            return Err(e);
+        }
+        if let Err(e) = manager.verify_image(config) {
+            manager.fail_update(FirmwareUpdateError::VerificationFailed);
+            return Err(e);
+        }
+        Ok(checker.chunk_size)
+    }
+
+    /// Download a firmware image from `offset` on, reporting to `operation`.
+    fn download_firmware(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+        mut offset: usize,
+        chunk_size: usize,
+        operation: Option<&Operation>,
+    ) -> Result<()> {
+        let total = config.image_size();
+        while let Some(chunk) = config.get_chunk(offset, chunk_size) {
+            if operation.is_some_and(|op| op.is_cancelled()) {
+                nvme_log!(Info, "firmware download cancelled at offset {}", offset);
+                return Err(Error::OperationCancelled);
+            }
+            let buf = self.admin_buffer_with(chunk)?;
+            let (prp1, prp2) = buf.prp();
+            let cmd = manager.build_download_command(
+                self.admin_sq.tail() as u16,
+                prp1,
+                offset as u32,
+                chunk.len() as u32,
+            );
+            if let Err(e) = cmd.and_then(|cmd| self.exec_admin(cmd.with_prp2(prp2))) {
+                manager.fail_update(FirmwareUpdateError::DownloadFailed);
+                return Err(e);
+            }
+            offset += chunk.len();
+            manager.update_progress(offset as u32, total as u32);
+            if let Some(operation) = operation {
+                operation.update(offset as u64, total as u64, self.inner.now_us());
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit a downloaded firmware image and optionally activate it.
+    fn commit_firmware(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+        auto_activate: bool,
+    ) -> Result<FirmwareActivation> {
+        self.check_operation()?;
+        manager.start_commit();
+        let commit = manager.build_commit_command(
+            self.admin_sq.tail() as u16,
+            config.target_slot,
+            config.commit_action,
+            config.boot_partition_id,
+        );
+        let activation = match self.exec_admin(commit) {
+            Ok(_) => manager.check_activation_required(config.commit_action),
+            Err(e) => {
+                // Committed, but activation needs a reset
+                let pending = match e {
+                    Error::CommandFailed(status) => manager.activation_for_status(status),
+                    _ => None,
+                };
+                let Some(activation) = pending else {
+                    manager.fail_update(FirmwareUpdateError::CommitFailed);
+                    manager.record_update(config.target_slot, self.inner.now_us(), false);
+                    return Err(e);
+                };
+                activation
+            }
+        };
+        manager.record_update(config.target_slot, self.inner.now_us(), true);
+
+        let reset = match activation {
+            FirmwareActivation::ControllerReset | FirmwareActivation::MaxTimeViolation
+                if auto_activate => Self::reset,
+            FirmwareActivation::NvmSubsystemReset if auto_activate => Self::subsystem_reset,
+            FirmwareActivation::None => {
+                manager.complete_commit(false);
+                return Ok(activation);
+            }
+            _ => {
+                nvme_log!(Info, "firmware committed, activation needs {:?}", activation);
+                manager.complete_commit(true);
+                return Ok(activation);
+            }
+        };
+
+        if let Err(e) = reset(self).and_then(|_| self.confirm_firmware(manager, config)) {
+            manager.fail_update(FirmwareUpdateError::RequiresReset(activation));
+            return Err(e);
+        }
+        manager.complete_commit(false);
+        Ok(FirmwareActivation::None)
+    }
+
+    /// Check that the firmware in the committed slot is running.
+    fn confirm_firmware(
+        &self,
+        manager: &mut FirmwareManager,
+        config: &FirmwareUpdateConfig,
+    ) -> Result<()> {
+        let slot_log = self.read_log(LogPageId::FirmwareSlot, 0, 512)?;
+        manager.update_slot_info(&slot_log)?;
+
+        let info = manager.get_slot_info().ok_or(Error::FirmwareUpdateFailed)?;
+        let expected = info.get_revision(config.target_slot)
+            .map(|rev| String::from_utf8_lossy(&rev).trim().to_string());
+        let running = self.inner.data.lock().firmware_revision.clone();
+
+        if info.active_slot() != config.target_slot || expected.as_deref() != Some(running.as_str()) {
+            nvme_log!(Error, "firmware slot {} is not active after reset", config.target_slot);
+            return Err(Error::FirmwareUpdateFailed);
+        }
+        nvme_log!(Info, "firmware {} active in slot {}", running, config.target_slot);
+        Ok(())
+    }
+
+    /// Read the Sanitize Status log page.
+    ///
+    /// Releases the fence of a sanitize that is no longer in progress.
+    pub fn sanitize_status(&self) -> Result<SanitizeStatus> {
+        let data = self.read_log(LogPageId::SanitizeStatus, 0, 512)?;
+        let status = SanitizeStatus::from_log_data(&data)?;
+        if !status.is_in_progress() {
+            self.inner.operation.lock().take_if(|op| matches!(op, DeviceOperation::Sanitize(_)));
+        }
+        Ok(status)
     }
 
-    /// Internal method to add a new I/O queue pair.
-    fn add_ioq_internal(&self) -> Result<u16> {
-        let max_queue_entries = self.inner.data.lock().max_queue_entries;
-        // Use a reasonable I/O queue size, but ensure at least 2 entries
-        let queue_size = IO_QUEUE_SIZE.min(max_queue_entries as usize).max(2);
+    /// Read the Device Self-test log page.
+    ///
+    /// Releases the fence of a self-test that is no longer running.
+    pub fn self_test_status(&self) -> Result<SelfTestResult> {
+        let data = self.read_log(LogPageId::DeviceSelfTest, 0, 564)?;
+        let result = SelfTestResult {
+            current_operation: data[0] & 0x0F,
+            current_completion: data[1] & 0x7F,
+            results: data[4..].to_vec(),
+        };
+        if result.current_operation == 0 {
+            self.end_operation(DeviceOperation::SelfTest);
+        }
+        Ok(result)
+    }
 
-        let qid = self.inner.next_queue_id.fetch_add(1, Ordering::SeqCst) as u16;
-        // No artificial limit - only hardware limits apply!
+    /// Create a handle for a host-driven operation on namespace `nsid`.
+    ///
+    /// Pass it to `update_firmware_tracked` or `Namespace::scrub`.
+    pub fn new_operation(&self, kind: OperationKind, nsid: u32) -> Operation {
+        Operation::new(kind, nsid, self.inner.now_us())
+    }
+
+    /// Start a sanitize operation and return its handle.
+    ///
+    /// See `start_sanitize`.
+    pub fn sanitize_operation(&self, config: &SanitizePerNamespace) -> Result<Operation> {
+        let token = self.start_sanitize(config)?;
+        let now = self.inner.now_us();
+        Ok(Operation::started(OperationKind::Sanitize, config.namespace_id, now, token))
+    }
 
-        // Create queue structures
-        let sq = SubQueue::new(queue_size, self.inner.allocator.as_ref());
-        let cq = CompQueue::new(queue_size, self.inner.allocator.as_ref());
-        let sq_addr = sq.address();
-        let cq_addr = cq.address();
+    /// Start formatting namespace `nsid` and return the operation's handle.
+    ///
+    /// See `start_format_namespace`. Progress is reported if the namespace
+    /// supports the Format Progress Indicator.
+    pub fn format_operation(&self, nsid: u32, config: &FormatConfig) -> Result<Operation> {
+        let token = self.start_format_namespace(nsid, config)?;
+        Ok(Operation::started(OperationKind::Format, nsid, self.inner.now_us(), token))
+    }
 
-        // Create completion queue first
-        self.exec_admin(Command::create_completion_queue(
+    /// Start a device self-test on namespace `nsid` and return its handle.
+    ///
+    /// See `start_self_test`.
+    pub fn self_test_operation(&self, nsid: u32, test: SelfTestType) -> Result<Operation> {
+        let token = self.start_self_test(nsid, test)?;
+        Ok(Operation::started(OperationKind::SelfTest, nsid, self.inner.now_us(), token))
+    }
+
+    /// Update the progress of an operation and return a snapshot of it.
+    ///
+    /// Controller-run operations are checked on the controller, while
+    /// host-driven ones report what they recorded so far. Fails with the
+    /// error of a starting command or sanitize that failed, after which
+    /// the operation is over.
+    pub fn poll_operation(&self, operation: &Operation) -> Result<OperationProgress> {
+        if operation.is_done() {
+            return Ok(operation.progress());
+        }
+
+        let now = self.inner.now_us();
+        let started = operation.poll_token(|token| self.poll_admin(token)).inspect_err(|_| {
+            operation.finish(now);
+        })?;
+        match operation.kind() {
+            OperationKind::Format if started => operation.finish(now),
+            OperationKind::Format => {
+                // FPI bit 7 tells whether the remaining percentage is reported
+                if let Ok(data) = self.namespace_data(operation.nsid())
+                    && data.fpi & 0x80 != 0
+                {
+                    operation.update(100 - (data.fpi & 0x7F).min(100) as u64, 100, now);
+                }
+            }
+            OperationKind::Sanitize if started => {
+                let status = self.sanitize_status()?;
+                if status.is_failed() {
+                    operation.finish(now);
+                    return Err(Error::SanitizeFailed);
+                }
+                if !status.is_in_progress() {
+                    operation.update(1, 1, now);
+                    operation.finish(now);
+                } else {
+                    // SPROG is the numerator of a fraction of 65536
+                    operation.update(status.progress as u64, 1 << 16, now);
+                }
+            }
+            OperationKind::SelfTest if started => {
+                let status = self.self_test_status()?;
+                if status.current_operation == 0 {
+                    operation.update(1, 1, now);
+                    operation.finish(now);
+                } else {
+                    operation.update(status.current_completion as u64, 100, now);
+                }
+            }
+            _ => {}
+        }
+        Ok(operation.progress())
+    }
+
+    /// Cancel an operation.
+    ///
+    /// A running self-test is aborted, and host-driven operations stop
+    /// before their next command. Fails with
+    /// `Error::OperationNotCancellable` for sanitize and format.
+    pub fn cancel_operation(&self, operation: &Operation) -> Result<()> {
+        if !operation.kind().is_cancellable() {
+            return Err(Error::OperationNotCancellable);
+        }
+        operation.cancel_token().cancel();
+
+        if operation.kind() == OperationKind::SelfTest && !operation.is_done() {
+            let abort = SelfTestType::Abort.code();
+            self.exec_admin(Command::device_self_test(
+                self.admin_sq.tail() as u16,
+                operation.nsid(),
+                abort,
+            ))?;
+            self.end_operation(DeviceOperation::SelfTest);
+            operation.finish(self.inner.now_us());
+        }
+        Ok(())
+    }
+
+    /// Get the format, sanitize or self-test currently fencing commands.
+    pub fn operation_in_progress(&self) -> Option<DeviceOperation> {
+        *self.inner.operation.lock()
+    }
+
+    /// Leave sanitize failure mode after a failed sanitize.
+    ///
+    /// If the failed sanitize allowed unrestricted exit, issues the Exit
+    /// Failure Mode action and checks in the Sanitize Status log that the
+    /// drive left failure mode, failing with `Error::SanitizeFailed` if it
+    /// did not. Otherwise the drive stays in failure mode and
+    /// `SanitizeRecovery::RequiresSanitize` tells the caller to start a
+    /// new sanitize instead.
+    pub fn exit_sanitize_failure_mode(&self) -> Result<SanitizeRecovery> {
+        let status = self.sanitize_status()?;
+        if !status.is_failed() {
+            return Ok(SanitizeRecovery::NotFailed);
+        }
+        if !status.unrestricted_exit() {
+            nvme_log!(Warn, "sanitize failed in restricted mode, a new sanitize is required");
+            return Ok(SanitizeRecovery::RequiresSanitize);
+        }
+
+        self.exec_admin(Command::sanitize(
             self.admin_sq.tail() as u16,
-            qid,
-            cq_addr,
-            (queue_size - 1) as u16,
+            0,
+            SanitizeAction::ExitFailureMode as u8,
+            false,
+            0,
+            false,
+            false,
         ))?;
 
-        // Create submission queue
-        self.exec_admin(Command::create_submission_queue(
+        if self.sanitize_status()?.is_failed() {
+            nvme_log!(Error, "drive is still in sanitize failure mode");
+            return Err(Error::SanitizeFailed);
+        }
+
+        nvme_log!(Info, "exited sanitize failure mode");
+        Ok(SanitizeRecovery::Exited)
+    }
+
+    /// Read the Media Unit Status log page of domain `domain_id`.
+    ///
+    /// Use 0 if the NVM subsystem does not report domains.
+    pub fn media_unit_status(&self, domain_id: u16) -> Result<MediaUnitStatusLog> {
+        let logs = LogPageManager::new();
+        let header = logs.parse_media_unit_status(
+            &self.read_log(LogPageId::MediaUnitStatus, domain_id, 16)?,
+        )?.header;
+
+        // Each descriptor is 16 bytes followed by up to one identifier per channel
+        let descriptor_size = 16 + header.num_channels as usize * 2;
+        let len = 16 + header.num_mus_descriptors as usize * descriptor_size;
+        logs.parse_media_unit_status(&self.read_log(LogPageId::MediaUnitStatus, domain_id, len)?)
+    }
+
+    /// Read the SMART / Health Information log page.
+    pub fn smart_health(&self) -> Result<SmartHealthInfo> {
+        let data = self.read_log(LogPageId::SmartHealth, 0, 512)?;
+        SmartHealthInfo::from_log_data(&data)
+    }
+
+    /// Get the SMART / Health log page cached in `logs`, reading it if stale.
+    ///
+    /// Freshness follows the maximum ages set with `LogPageManager::set_max_age`
+    /// and the clock set by `set_clock`. Without a clock the log is always read.
+    pub fn get_or_fetch_smart_health(&self, logs: &mut LogPageManager) -> Result<SmartHealthInfo> {
+        let cached = |logs: &LogPageManager| logs.get_smart_health().copied();
+        self.get_or_fetch(logs, CachedLog::SmartHealth, cached, |logs| {
+            logs.parse_smart_health(&self.read_log(LogPageId::SmartHealth, 0, 512)?)
+        })
+    }
+
+    /// Get the Error Information log entries cached in `logs`, reading them if stale.
+    ///
+    /// See `get_or_fetch_smart_health`.
+    pub fn get_or_fetch_error_log(
+        &self,
+        logs: &mut LogPageManager,
+    ) -> Result<Vec<ErrorLogEntry>> {
+        let cached = |logs: &LogPageManager| Some(logs.get_error_log().to_vec());
+        self.get_or_fetch(logs, CachedLog::ErrorInformation, cached, |logs| {
+            logs.parse_error_log(&self.read_log(LogPageId::ErrorInformation, 0, 4096)?)
+        })
+    }
+
+    /// Get the Firmware Slot Information log page cached in `logs`, reading it if stale.
+    ///
+    /// See `get_or_fetch_smart_health`.
+    pub fn get_or_fetch_firmware_slot(
+        &self,
+        logs: &mut LogPageManager,
+    ) -> Result<FirmwareSlotInfo> {
+        let cached = |logs: &LogPageManager| logs.get_firmware_slot().copied();
+        self.get_or_fetch(logs, CachedLog::FirmwareSlot, cached, |logs| {
+            logs.parse_firmware_slot(&self.read_log(LogPageId::FirmwareSlot, 0, 512)?)
+        })
+    }
+
+    /// Get the Endurance Group Information log page of endurance group `id`
+    /// cached in `logs`, reading it if stale or cached for another group.
+    ///
+    /// See `get_or_fetch_smart_health`.
+    pub fn get_or_fetch_endurance_group(
+        &self,
+        logs: &mut LogPageManager,
+        id: u16,
+    ) -> Result<EnduranceGroupInfo> {
+        let cached = |logs: &LogPageManager| logs.get_endurance_group_of(id).copied();
+        self.get_or_fetch(logs, CachedLog::EnduranceGroup, cached, |logs| {
+            let data = self.read_log(LogPageId::EnduranceGroupInformation, id, 512)?;
+            logs.parse_endurance_group_of(id, &data)
+        })
+    }
+
+    /// Reuse a cached log if it is fresh, otherwise fetch it and mark it fetched.
+    fn get_or_fetch<T>(
+        &self,
+        logs: &mut LogPageManager,
+        log: CachedLog,
+        cached: impl FnOnce(&LogPageManager) -> Option<T>,
+        fetch: impl FnOnce(&mut LogPageManager) -> Result<T>,
+    ) -> Result<T> {
+        let now = self.inner.clock.read().as_ref().map(|clock| clock.now_us());
+        if let Some(now) = now
+            && logs.is_fresh(log, now)
+            && let Some(value) = cached(logs)
+        {
+            return Ok(value);
+        }
+
+        let value = fetch(logs)?;
+        if let Some(now) = now {
+            logs.mark_fetched(log, now);
+        }
+        Ok(value)
+    }
+
+    /// Read the SMART / Health log page and record it in `monitor`.
+    ///
+    /// Samples are timestamped with the clock set by `set_clock`.
+    pub fn poll_health(&self, monitor: &mut HealthMonitor) -> Result<SmartHealthInfo> {
+        let info = self.smart_health()?;
+        monitor.record(info, self.inner.now_us());
+        Ok(info)
+    }
+
+    /// Read the self-reported drive power from the Power Measurement log page.
+    ///
+    /// The reading is also recorded in the power manager, timestamped with
+    /// the clock set by `set_clock`.
+    pub fn self_reported_power(&self) -> Result<SelfReportedPower> {
+        let data = self.read_log(LogPageId::PowerMeasurement, 0, 32)?;
+        let power = SelfReportedPower::from_log_data(&data)?;
+        self.power.lock().record_power_sample(power, self.inner.now_us());
+        Ok(power)
+    }
+
+    /// Read the self-reported drive power if a reading is due.
+    ///
+    /// Call it periodically, e.g. from a timer tick. Readings are taken at
+    /// the interval set with `PowerManager::set_power_sampling` and kept in
+    /// the power manager's window. Returns `None` if no reading was due.
+    /// Without a clock, only the first call takes a reading unless the
+    /// interval is 0.
+    pub fn sample_power(&self) -> Result<Option<SelfReportedPower>> {
+        if !self.power.lock().is_power_sample_due(self.inner.now_us()) {
+            return Ok(None);
+        }
+        self.self_reported_power().map(Some)
+    }
+
+    /// Sample the allocation of namespace `nsid`.
+    ///
+    /// Identifies the namespace again for its capacity and allocated blocks,
+    /// and adds the bytes written and deallocated through it from its I/O
+    /// statistics. Samples are timestamped with the clock set by
+    /// `set_clock`. Fails with `Error::InvalidNamespace` if the namespace
+    /// is not attached.
+    pub fn namespace_utilization(&self, nsid: u32) -> Result<UtilizationSample> {
+        let ns = self.get_ns(nsid).ok_or(Error::InvalidNamespace)?;
+        let data = self.namespace_data(nsid)?;
+        let stats = ns.stats();
+        Ok(UtilizationSample {
+            time_us: self.inner.now_us(),
+            nsid,
+            block_size: ns.block_size(),
+            capacity_blocks: data.capacity,
+            used_blocks: data.nuse,
+            written_bytes: stats.write_bytes,
+            trimmed_bytes: stats.trim_bytes,
+        })
+    }
+
+    /// Sample the allocation of namespace `nsid` and record it in `monitor`.
+    pub fn poll_utilization(
+        &self,
+        nsid: u32,
+        monitor: &mut UtilizationMonitor,
+    ) -> Result<UtilizationSample> {
+        let sample = self.namespace_utilization(nsid)?;
+        monitor.record(sample);
+        Ok(sample)
+    }
+
+    /// Check out an admin buffer holding a command payload.
+    fn admin_buffer_with(&self, data: &[u8]) -> Result<PoolBuffer<'_, A>> {
+        let mut buf = self.admin_buffers.get(data.len())?;
+        buf.copy_from_slice(data);
+        Ok(buf)
+    }
+
+    /// Issue an Identify command and return its 4 KiB data structure.
+    ///
+    /// Any CNS value can be requested, including ones the driver does not
+    /// interpret itself, with `IdentifyCns::Raw`.
+    pub fn identify(&self, cns: IdentifyCns, params: IdentifyParams) -> Result<Vec<u8>> {
+        let buf = self.admin_buffers.get(4096)?;
+        self.exec_admin(Command::identify_cns(
             self.admin_sq.tail() as u16,
-            qid,
-            sq_addr,
-            (queue_size - 1) as u16,
-            qid, // Use same ID for CQ
+            buf.phys_addr(),
+            cns,
+            params,
         ))?;
 
-        // Add to queue list
-        let queue_pair = Arc::new(Mutex::new(IoQueuePair {
-            qid,
-            sq,
-            cq,
-            prp_manager: Default::default(),
-            outstanding: AtomicUsize::new(0),
-            shutdown: AtomicBool::new(false),
-        }));
+        Ok(buf.to_vec())
+    }
 
-        self.inner.ioq.lock().push(queue_pair);
-        Ok(qid)
+    /// Get the LBA formats supported by namespace `nsid`.
+    ///
+    /// Storage tag sizes and protection information formats are taken from
+    /// the extended LBA formats if the controller reports them.
+    pub fn lba_formats(&self, nsid: u32) -> Result<Vec<LbaFormat>> {
+        let params = IdentifyParams { nsid, ..Default::default() };
+        let identify = self.identify(IdentifyCns::Namespace, params)?;
+        let nvm = self.identify(IdentifyCns::IoCommandSetNamespace, params).ok();
+
+        Ok(parse_lba_formats(&identify, nvm.as_deref()))
     }
 
-    /// Internal method to remove specified number of I/O queues safely.
-    fn rm_ioq_internal(&self, count: usize) -> Result<()> {
-        let queues_to_remove = {
-            let queues = self.inner.ioq.lock();
+    /// Format namespace `nsid` with Format NVM.
+    ///
+    /// All data on the namespace is lost. I/O already in flight to the
+    /// namespace is waited for first, and new I/O to it fails with
+    /// `Error::FormatInProgress` meanwhile. The namespace is identified
+    /// again afterwards and replaced, so `get_ns` returns it with the new
+    /// block size, while I/O through the old namespace fails.
+    pub fn format_namespace(&self, nsid: u32, config: &FormatConfig) -> Result<()> {
+        let cmd = self.format_command(nsid, config)?;
+        let operation = DeviceOperation::Format(nsid);
+        self.begin_operation(operation)?;
+        let result = self.exec_admin(cmd);
+        self.end_operation(operation);
+        result?;
+        self.replace_namespace(nsid)
+    }
 
-            // Don't remove if it would leave us with no queues
-            if queues.len() <= count {
-                return Err(Error::LastQueueCannotBeRemoved);
+    /// Start formatting namespace `nsid` without waiting for it to finish.
+    ///
+    /// Like `format_namespace`, which blocks the calling thread until the
+    /// format is done. The namespace is replaced when `poll_admin` reports
+    /// the command's completion.
+    pub fn start_format_namespace(&self, nsid: u32, config: &FormatConfig) -> Result<AdminToken> {
+        let cmd = self.format_command(nsid, config)?;
+        self.submit_fenced(cmd, Some(nsid), DeviceOperation::Format(nsid), false)
+    }
+
+    /// Start a sanitize operation without waiting for the command to complete.
+    ///
+    /// See `sanitize`.
+    pub fn start_sanitize(&self, config: &SanitizePerNamespace) -> Result<AdminToken> {
+        let caps = self.inner.data.lock().sanitize;
+        let cmd = config.build_command(self.admin_sq.tail() as u16, &caps)?;
+        self.submit_fenced(cmd, None, DeviceOperation::Sanitize(config.namespace_id), true)
+    }
+
+    /// Start or abort a device self-test on namespace `nsid` (0xFFFFFFFF = all).
+    ///
+    /// Progress is reported by `self_test_status`. Formats and sanitizes
+    /// fail with `Error::SelfTestInProgress` until it reports the test is
+    /// over, or an abort completes.
+    pub fn start_self_test(&self, nsid: u32, test: SelfTestType) -> Result<AdminToken> {
+        let cmd = Command::device_self_test(self.admin_sq.tail() as u16, nsid, test.code());
+        if let SelfTestType::Abort = test {
+            let mut token = self.submit_admin(cmd, None)?;
+            token.fence = Some((DeviceOperation::SelfTest, false));
+            return Ok(token);
+        }
+        self.submit_fenced(cmd, None, DeviceOperation::SelfTest, true)
+    }
+
+    /// Fence the device for `operation` and submit the admin command starting it.
+    ///
+    /// If `background` is set, the fence outlives the command's successful
+    /// completion and is released once the operation's log page reports it
+    /// is over.
+    fn submit_fenced(
+        &self,
+        cmd: Command,
+        rescan: Option<u32>,
+        operation: DeviceOperation,
+        background: bool,
+    ) -> Result<AdminToken> {
+        self.begin_operation(operation)?;
+        match self.submit_admin(cmd, rescan) {
+            Ok(mut token) => {
+                token.fence = Some((operation, background));
+                Ok(token)
             }
+            Err(e) => {
+                self.end_operation(operation);
+                Err(e)
+            }
+        }
+    }
 
-            // Select queues to remove (prefer queues with least outstanding I/O)
-            let mut queue_stats: Vec<_> = queues.iter()
-                .map(|q| {
-                    let queue = q.lock();
-                    (q.clone(), queue.qid, queue.outstanding.load(Ordering::Relaxed))
-                })
-                .collect();
+    /// Fence the device for a long-running operation.
+    ///
+    /// Fails with the error of the operation already running, if any. Then
+    /// waits for the I/O in flight to the namespaces the operation fences.
+    fn begin_operation(&self, operation: DeviceOperation) -> Result<()> {
+        {
+            let mut current = self.inner.operation.lock();
+            if let Some(running) = *current {
+                return Err(running.error());
+            }
+            *current = Some(operation);
+        }
+        if let Err(e) = self.drain_fenced_io(operation) {
+            self.end_operation(operation);
+            return Err(e);
+        }
+        Ok(())
+    }
 
-            // Sort by outstanding I/O count
-            queue_stats.sort_by_key(|&(_, _, outstanding)| outstanding);
+    /// Wait until no I/O to the namespaces `operation` fences is in flight.
+    ///
+    /// Completions of steered commands are delivered meanwhile. Fails with
+    /// `Error::NamespaceBusy` if commands are still running after
+    /// `FENCE_DRAIN_TIMEOUT_MS`, which is only enforced while a clock is set.
+    fn drain_fenced_io(&self, operation: DeviceOperation) -> Result<()> {
+        let fenced: Vec<_> = self.namespaces.read()
+            .values()
+            .filter(|ns| operation.fences_io(ns.id))
+            .cloned()
+            .collect();
+        let busy = || fenced.iter().any(|ns| ns.stats.in_flight.load(Ordering::SeqCst) != 0);
+
+        let start = self.inner.now_us();
+        let waiter = self.inner.waiter();
+        while busy() {
+            self.reap_steered();
+            if self.inner.now_us() - start >= FENCE_DRAIN_TIMEOUT_MS as u64 * 1000 {
+                nvme_log!(Warn, "{:?} timed out waiting for namespace I/O", operation);
+                return Err(Error::NamespaceBusy);
+            }
+            waiter.wait();
+        }
+        Ok(())
+    }
 
-            // Take the last 'count' queues (highest load)
-            queue_stats.into_iter()
-                .rev()
-                .take(count)
-                .map(|(arc, qid, _)| (arc, qid))
-                .collect::<Vec<_>>()
+    /// Fail with the error of a running format or sanitize.
+    ///
+    /// Guards admin commands that must not overlap one, such as namespace
+    /// attachment and firmware commits.
+    fn check_operation(&self) -> Result<()> {
+        match *self.inner.operation.lock() {
+            Some(running) if running != DeviceOperation::SelfTest => Err(running.error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Release the fence of `operation`, if it is the one running.
+    fn end_operation(&self, operation: DeviceOperation) {
+        self.inner.operation.lock().take_if(|running| *running == operation);
+    }
+
+    /// Check whether an admin command submitted with a `start_` method completed.
+    ///
+    /// Returns `None` while it is in flight, and its result once it has
+    /// completed. The token is then used up: polling it again fails with
+    /// `Error::UnknownAdminToken`, as does polling a command lost to a
+    /// controller reset. Never waits for other admin commands.
+    pub fn poll_admin(&self, token: &AdminToken) -> Result<Option<IoResult>> {
+        let Some(entry) = self.take_admin(token.cmd_id, token.ticket)? else {
+            return Ok(None);
         };
 
-        // Phase 1: Mark queues for shutdown
-        for (queue_arc, _) in &queues_to_remove {
-            queue_arc.lock().shutdown.store(true, Ordering::Release);
+        if let Some((operation, background)) = token.fence
+            && (!background || entry.result().is_err())
+        {
+            self.end_operation(operation);
+        }
+        if let Err(e) = entry.result() {
+            nvme_log!(Error, "admin command {:#04x} failed: {}", token.opcode, e);
+            return Err(e);
         }
+        if let Some(nsid) = token.rescan {
+            self.replace_namespace(nsid)?;
+        }
+        entry.io_result().map(Some)
+    }
 
-        // Phase 2: Flush and wait for outstanding I/O to complete
-        // This is important for controlled queue removal to ensure data integrity
-        for (queue_arc, qid) in &queues_to_remove {
-            // Send flush command to ensure all writes are committed
-            for &ns_id in self.namespaces.read().keys() {
-                let queue = queue_arc.lock();
+    /// Build the Format NVM command for `config`, checking the LBA format.
+    fn format_command(&self, nsid: u32, config: &FormatConfig) -> Result<Command> {
+        let formats = self.lba_formats(nsid)?;
+        if !formats.iter().any(|format| format.index == config.lba_format) {
+            return Err(Error::InvalidLbaFormat);
+        }
+        if config.lba_format as usize >= BASIC_LBA_FORMATS
+            && !self.host_behavior(FeatureSelector::Current)?.lbafee
+        {
+            return Err(Error::LbaFormatExtensionDisabled);
+        }
+
+        Ok(Command::format_nvm(
+            self.admin_sq.tail() as u16,
+            nsid,
+            config.lba_format,
+            config.extended_metadata as u8,
+            config.protection_type,
+            config.protection_first as u8,
+            config.secure_erase,
+        ))
+    }
+
+    /// Identify namespace `nsid` again after a format and replace it.
+    ///
+    /// I/O through the old namespace fails from then on.
+    fn replace_namespace(&self, nsid: u32) -> Result<()> {
+        nvme_log!(Info, "formatted namespace {}", nsid);
+        let new = self.ident_namespace(nsid)?;
+        let old = match new {
+            Some(new) => self.insert_namespace(new),
+            None => self.namespaces.write().remove(&nsid),
+        };
+        if let Some(old) = old {
+            old.block_count.store(0, Ordering::Release);
+        }
+        Ok(())
+    }
 
-                // Flush only shutdown queues, but ensure completion
-                if queue.shutdown.load(Ordering::Acquire) {
-                    let flush_cmd = Command::flush(queue.sq.tail() as u16, ns_id);
+    /// Get the controllers attached to namespace `nsid`.
+    pub fn attached_controllers(&self, nsid: u32) -> Result<ControllerList> {
+        self.controller_list(IdentifyCns::NamespaceControllerList, nsid)
+    }
 
-                    // Push flush command (blocking is OK here - controlled removal)
-                    let tail = queue.sq.push(flush_cmd);
-                    self.inner.doorbell_helper.write(Doorbell::SubTail(*qid), tail as u32);
+    /// Attach namespace `nsid` to the controllers in `controllers`.
+    ///
+    /// Fails with `Error::NamespaceAlreadyAttached` if it is attached to
+    /// one of them, and with `Error::NamespaceIsPrivate` if it is private
+    /// and would end up attached to more than one controller.
+    pub fn attach_namespace(&self, nsid: u32, controllers: &ControllerList) -> Result<()> {
+        let attached = self.attached_controllers(nsid)?;
+        if controllers.ids.iter().any(|id| attached.ids.contains(id)) {
+            return Err(Error::NamespaceAlreadyAttached);
+        }
+        if !self.namespace_shared(nsid)? && attached.ids.len() + controllers.ids.len() > 1 {
+            return Err(Error::NamespaceIsPrivate);
+        }
 
-                    // MUST wait for flush completion for data safety
-                    let (head, _entry) = queue.cq.pop();
-                    self.inner.doorbell_helper.write(Doorbell::CompHead(*qid), head as u32);
-                    queue.sq.set_head(_entry.sq_head as usize);
-                }
+        self.namespace_attachment(nsid, 0, controllers)
+    }
+
+    /// Detach namespace `nsid` from the controllers in `controllers`.
+    pub fn detach_namespace(&self, nsid: u32, controllers: &ControllerList) -> Result<()> {
+        self.namespace_attachment(nsid, 1, controllers)
+    }
+
+    /// Issue a Namespace Attachment command.
+    fn namespace_attachment(&self, nsid: u32, sel: u8, controllers: &ControllerList) -> Result<()> {
+        self.check_operation()?;
+        let buf = self.admin_buffer_with(&controllers.to_bytes()?)?;
+        self.exec_admin(Command::namespace_attachment(
+            self.admin_sq.tail() as u16,
+            nsid,
+            sel,
+            buf.phys_addr(),
+        ))?;
+        Ok(())
+    }
+
+    /// Check whether allocated namespace `nsid` may be attached to several controllers.
+    fn namespace_shared(&self, nsid: u32) -> Result<bool> {
+        let params = IdentifyParams { nsid, ..Default::default() };
+        let data = self.identify(IdentifyCns::AllocatedNamespace, params)?;
+        // NMIC bit 0
+        Ok(data[30] & 1 != 0)
+    }
+
+    /// Get all controllers in the NVM subsystem.
+    pub fn subsystem_controllers(&self) -> Result<ControllerList> {
+        self.controller_list(IdentifyCns::ControllerList, 0)
+    }
+
+    /// Collect a controller list, paging through it from controller ID 0.
+    ///
+    /// Each Identify returns up to 2047 IDs greater than or equal to the
+    /// one given, so a full page is followed by a request for the rest.
+    fn controller_list(&self, cns: IdentifyCns, nsid: u32) -> Result<ControllerList> {
+        let mut list = ControllerList::default();
+        let mut start = 0;
+
+        loop {
+            let params = IdentifyParams { nsid, controller_id: start, ..Default::default() };
+            let page = ControllerList::from_bytes(&self.identify(cns, params)?)?;
+            let next = match page.ids.last() {
+                Some(&last) if page.ids.len() == CONTROLLER_LIST_MAX => last.checked_add(1),
+                _ => None,
+            };
+            list.ids.extend(page.ids);
+
+            match next {
+                Some(next) => start = next,
+                None => return Ok(list),
             }
+        }
+    }
 
-            // Wait for all outstanding I/O to complete
-            // This is necessary for controlled removal to avoid data loss
-            let mut wait_count = 0;
-            const MAX_WAIT: usize = 10000; // Prevent infinite wait
+    /// Get the UUID List the controller uses for vendor specific data.
+    ///
+    /// Empty if the controller does not support UUIDs for selection.
+    pub fn uuid_list(&self) -> Result<Vec<UuidEntry>> {
+        let data = self.identify(IdentifyCns::UuidList, IdentifyParams::default())?;
+
+        // 32-byte entries after a 32-byte header, terminated by an all-zero entry
+        Ok(data
+            .get(32..)
+            .unwrap_or_default()
+            .chunks_exact(32)
+            .take_while(|entry| entry.iter().any(|&b| b != 0))
+            .zip(1..)
+            .map(|(entry, index)| UuidEntry {
+                index,
+                association: entry[0] & 0x3,
+                uuid: entry[16..32].try_into().unwrap(),
+            })
+            .collect())
+    }
 
-            loop {
-                let outstanding = queue_arc.lock().outstanding.load(Ordering::Acquire);
-                if outstanding == 0 {
-                    break;
-                }
+    /// Find the UUID index to use for vendor data of `uuid`.
+    pub fn uuid_index(&self, uuid: &[u8; 16]) -> Result<Option<u8>> {
+        Ok(self.uuid_list()?
+            .into_iter()
+            .find(|entry| entry.uuid == *uuid)
+            .map(|entry| entry.index))
+    }
+
+    /// Read `len` bytes of a log page by identifier.
+    ///
+    /// `uuid_index` selects the vendor a vendor specific log belongs to,
+    /// see `uuid_index`. Use 0 for logs defined by the specification.
+    pub fn read_vendor_log(&self, log_id: u8, uuid_index: u8, len: usize) -> Result<Vec<u8>> {
+        self.read_log_raw(log_id, 0, uuid_index, len)
+    }
+
+    /// Get a feature value by identifier, selecting the vendor by UUID index.
+    pub fn get_vendor_feature(
+        &self,
+        nsid: u32,
+        feature_id: u8,
+        uuid_index: u8,
+        sel: FeatureSelector,
+    ) -> Result<IoResult> {
+        self.exec_admin(Command::get_features_raw(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature_id,
+            sel as u8,
+        ).with_uuid_index(uuid_index))?.io_result()
+    }
+
+    /// Set a feature by identifier, selecting the vendor by UUID index.
+    pub fn set_vendor_feature(
+        &self,
+        nsid: u32,
+        feature_id: u8,
+        uuid_index: u8,
+        value: u32,
+        persist: bool,
+    ) -> Result<IoResult> {
+        self.check_persist(persist)?;
+
+        self.exec_admin(Command::set_features_raw(
+            self.admin_sq.tail() as u16,
+            nsid,
+            feature_id,
+            value,
+            persist,
+        ).with_uuid_index(uuid_index))?.io_result()
+    }
+
+    /// Read a log page of `len` bytes.
+    fn read_log(&self, log_id: LogPageId, lsi: u16, len: usize) -> Result<Vec<u8>> {
+        self.read_log_raw(log_id as u8, lsi, 0, len)
+    }
+
+    /// Read the controller-initiated telemetry log.
+    ///
+    /// Data area 4 is included if the controller reports it, see
+    /// `set_host_behavior`. Reading the log clears a Telemetry Log Changed
+    /// event.
+    pub fn controller_telemetry(&self) -> Result<TelemetryLog> {
+        let id = LogPageId::TelemetryControllerInitiated;
+        let header = unsafe { read_le::<TelemetryLogHeader>(&self.read_log(id, 0, 512)?) };
+
+        // Data areas are made of 512-byte blocks, block 0 being the header
+        let last_block = (header.da3_last_block as u32).max(header.da4_last_block);
+        let len = (last_block as usize + 1) * 512;
+        let max_transfer_size = self.inner.data.lock().max_transfer_size.max(4096);
+
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let size = (len - data.len()).min(max_transfer_size);
+            let buf = self.admin_buffers.get(size)?;
+            let (prp1, prp2) = buf.prp();
+            self.exec_admin(Command::get_log_page_raw(
+                self.admin_sq.tail() as u16,
+                prp1,
+                id as u8,
+                0,
+                size.div_ceil(4) as u32,
+                data.len() as u64,
+            ).with_prp2(prp2))?;
+            data.extend_from_slice(&buf);
+        }
+
+        // The generation number of the data read is in its own header
+        let header = unsafe { read_le::<TelemetryLogHeader>(&data) };
+        Ok(TelemetryLog { header, data })
+    }
+
+    /// Read a log page of `len` bytes by identifier and UUID index.
+    fn read_log_raw(&self, log_id: u8, lsi: u16, uuid_index: u8, len: usize) -> Result<Vec<u8>> {
+        let buf = self.admin_buffers.get(len)?;
+        let (prp1, prp2) = buf.prp();
+        self.exec_admin(Command::get_log_page_raw(
+            self.admin_sq.tail() as u16,
+            prp1,
+            log_id,
+            lsi,
+            len.div_ceil(4) as u32,
+            0,
+        ).with_prp2(prp2).with_uuid_index(uuid_index))?;
+
+        Ok(buf.to_vec())
+    }
+
+    /// Compare namespace data and report where it differs.
+    ///
+    /// On a Compare Failure, the first mismatching LBA is taken from the
+    /// controller's Error Information log if it recorded one for the command.
+    /// Otherwise the range is read back into `scratch` and diffed on the host,
+    /// see `Namespace::locate_mismatch`.
+    pub fn compare_detailed(
+        &self,
+        namespace_id: u32,
+        lba: u64,
+        expected: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<CompareOutcome> {
+        let ns = self.get_ns(namespace_id).ok_or(Error::InvalidNamespace)?;
+        let Some(entry) = ns.compare_with(lba, expected, true)? else {
+            return Ok(CompareOutcome::Match);
+        };
 
-                wait_count += 1;
-                if wait_count > MAX_WAIT {
-                    // Log warning or handle timeout
-                    break;
-                }
+        let blocks = expected.len() as u64 / ns.block_size();
+        let (sqid, cmd_id) = (entry.sq_id, entry.cmd_id);
 
-                core::hint::spin_loop();
-            }
-        }
+        let logged = self.read_log(LogPageId::ErrorInformation, 0, 4096)
+            .ok()
+            .and_then(|data| {
+                data.chunks_exact(size_of::<ErrorLogEntry>())
+                    .filter_map(|chunk| ErrorLogEntry::from_log_data(chunk).ok())
+                    .find(|e| e.error_count != 0 && e.sqid == sqid && e.cmdid == cmd_id)
+            })
+            .map(|e| e.lba)
+            .filter(|&failed| failed >= lba && failed < lba + blocks);
 
-        // Phase 3: Delete queues from hardware and remove from list
-        for (_, qid) in &queues_to_remove {
-            // Delete submission queue first (NVMe spec requirement)
-            self.exec_admin(Command::delete_submission_queue(
-                self.admin_sq.tail() as u16,
-                *qid,
-            ))?;
+        let mismatch = match logged {
+            Some(failed) => Some(failed),
+            None => ns.locate_mismatch(lba, expected, scratch)?,
+        };
 
-            // Then delete completion queue
-            self.exec_admin(Command::delete_completion_queue(
-                self.admin_sq.tail() as u16,
-                *qid,
-            ))?;
-        }
+        Ok(CompareOutcome::Mismatch { lba: mismatch })
+    }
 
-        // Phase 4: Remove from the queue list
-        let mut queues = self.inner.ioq.lock();
-        queues.retain(|q| {
-            let qid = q.lock().qid;
-            !queues_to_remove.iter().any(|(_, rm_qid)| *rm_qid == qid)
-        });
+    /// Get a namespace by its ID.
+    ///
+    /// Returns `None` if the namespace doesn't exist.
+    pub fn get_ns(&self, namespace_id: u32) -> Option<Arc<Namespace<A>>> {
+        self.namespaces.read().get(&namespace_id).cloned()
+    }
 
-        Ok(())
+    /// Get controller data.
+    pub fn data(&self) -> ControllerData {
+        self.inner.data.lock().clone()
     }
 
-    /// Initialize a NVMe controller device.
+    /// Describe the device for a device inventory.
     ///
-    /// The `address` is the base address of the controller
-    /// constructed by the PCI BAR 0 (lower 32 bits) and BAR 1 (upper 32 bits).
+    /// Collects the controller identity, firmware slots, namespaces with
+    /// their sizes, formats and identifiers, optional command support,
+    /// power states and queue configuration. Parts the controller fails to
+    /// report are left empty rather than failing the whole report.
+    pub fn describe(&self) -> Result<DeviceReport> {
+        let controller = self.data();
+
+        let firmware = self.read_log(LogPageId::FirmwareSlot, 0, 512)
+            .and_then(|log| FirmwareSlotInfo::from_log_data(&log))
+            .ok()
+            .map(|info| FirmwareReport {
+                active_slot: info.active_slot(),
+                next_slot: Some(info.next_reset_slot()).filter(|&slot| slot != 0),
+                slot_count: (controller.firmware_updates >> 1) & 0x7,
+                slot1_read_only: controller.firmware_updates & 0x1 != 0,
+                revisions: (1..=7)
+                    .filter_map(|slot| {
+                        let revision = info.get_revision(slot)?;
+                        let revision = String::from_utf8_lossy(&revision);
+                        let revision = revision.trim_matches(|c| c == ' ' || c == '\0');
+                        (!revision.is_empty()).then(|| (slot, revision.to_string()))
+                    })
+                    .collect(),
+            });
+
+        let namespaces: Vec<_> = self.namespaces.read().values().cloned().collect();
+        let namespaces = namespaces
+            .into_iter()
+            .map(|ns| {
+                let params = IdentifyParams { nsid: ns.id(), ..Default::default() };
+                NamespaceReport {
+                    info: NamespaceInfo {
+                        id: ns.id(),
+                        block_size: ns.block_size(),
+                        block_count: ns.block_count(),
+                        shared: ns.is_shared(),
+                        ana_group_id: ns.ana_group_id(),
+                    },
+                    reserved_blocks: ns.reserved_blocks(),
+                    formats: self.lba_formats(ns.id()).unwrap_or_default(),
+                    identifiers: self.identify(IdentifyCns::NamespaceDescriptorList, params)
+                        .map(|data| NamespaceIdentifiers::from_descriptor_list(&data))
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        let queues = QueueReport {
+            admin_depth: self.admin_sq.len(),
+            io_queues: self.ioq_count(),
+            queue_depth: self.queue_depth(),
+            max_io_sq: controller.max_io_sq,
+            max_io_cq: controller.max_io_cq,
+            max_queue_entries: controller.max_queue_entries,
+            max_transfer_size: controller.max_transfer_size,
+        };
+
+        Ok(DeviceReport {
+            version: self.nvme_version(),
+            firmware,
+            namespaces,
+            commands: OptionalCommands::from_oncs(controller.oncs),
+            power_states: self.power.lock().get_power_states().to_vec(),
+            queues,
+            controller,
+        })
+    }
+
+    /// Get the power manager.
     ///
-    /// The `allocator` is a DMA allocator that implements
-    /// the `Allocator` trait used for the entire NVMe device.
-    pub fn init(address: usize, allocator: A) -> Result<Self> {
-        let allocator = Arc::new(allocator);
-        // Need to read capabilities first to get the doorbell stride and max queue entries
-        let cap = unsafe { ((address + Register::CAP as usize) as *const u64).read_volatile() };
-        let doorbell_stride = (cap >> 32) as u8 & 0xF;
-        let max_queue_entries = (cap & 0x7FFF) as usize + 1;
-        let min_pagesize = 1 << (((cap >> 48) as u8 & 0xF) + 12);
+    /// It is populated with the power state descriptors
+    /// reported by the controller at initialization.
+    pub fn power(&self) -> MutexGuard<'_, PowerManager> {
+        self.power.lock()
+    }
 
-        // Use hardware maximum for admin queue - software queue handles overflow efficiently
-        // No artificial limits - let hardware capabilities determine the size
-        let admin_queue_size = max_queue_entries.max(MIN_ADMIN_QUEUE_SIZE);
+    /// Get the summary of device initialization.
+    pub fn init_report(&self) -> &InitReport {
+        &self.init_report
+    }
 
-        let doorbell_helper = DoorbellHelper::new(address, doorbell_stride);
+    /// Set the clock used to timestamp driver events.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.inner.clock.write() = Some(clock);
+    }
 
-        let inner = Arc::new(DeviceInner {
-            allocator: allocator.clone(),
-            doorbell_helper: doorbell_helper,
-            data: Mutex::new(Default::default()),
-            ioq: Mutex::new(Vec::new()),
-            queue_selector: AtomicUsize::new(0),
-            next_queue_id: AtomicUsize::new(1),
-            shutting_down: AtomicBool::new(false),
-        });
+    /// Get the latency histogram of I/O commands.
+    ///
+    /// Commands are timestamped at submission and completion while a clock
+    /// is set with `set_clock`.
+    pub fn io_latency(&self) -> LatencyStats {
+        self.inner.latency.snapshot()
+    }
 
-        let device = Self {
-            address: address as _,
-            inner: inner.clone(),
-            namespaces: RwLock::new(BTreeMap::new()),
-            admin_sq: SubQueue::new(admin_queue_size, allocator.as_ref()),
-            admin_cq: CompQueue::new(admin_queue_size, allocator.as_ref()),
-            admin_buffer: Dma::allocate(4096, allocator.as_ref()),
-            admin_lock: Mutex::new(()),
-        };
+    /// Clear the latency histogram of I/O commands.
+    pub fn reset_io_latency(&self) {
+        self.inner.latency.reset();
+    }
 
-        // Update controller data with capability values
-        {
-            let mut data = device.inner.data.lock();
-            data.min_pagesize = min_pagesize;
-            data.max_queue_entries = max_queue_entries as u16;
-        }
+    /// Feed measured I/O latencies and errors to a multipath path.
+    ///
+    /// The path's metrics are then based on real device latency. Paths
+    /// added to a `MultipathController` are fed with `set_multipath`.
+    /// `None` stops feeding.
+    pub fn set_path(&self, path: Option<Arc<ControllerPath>>) {
+        *self.inner.path.write() = path;
+    }
 
-        // Reset controller
-        device.set_reg::<u32>(Register::CC, device.get_reg::<u32>(Register::CC) & !1);
-        while device.get_reg::<u32>(Register::CSTS) & 1 == 1 {
-            spin_loop();
+    /// Report I/O latencies and ANA path errors to a multipath controller.
+    ///
+    /// While a clock is set, the latency and outcome of each I/O command
+    /// update path `path_id` with `MultipathController::record_io`. Commands
+    /// failing with `Error::AnaTransition`, `Error::AnaInaccessible` or
+    /// `Error::AnaPersistentLoss` fail the path over with
+    /// `MultipathController::handle_path_error`. Failed I/O commands are
+    /// recorded with `MultipathController::record_error` and return their
+    /// error as `Error::OnPath`. `None` stops reporting.
+    ///
+    /// The namespaces join the ANA groups they report with
+    /// `MultipathController::assign_ana_group`, now and whenever they are
+    /// identified again.
+    pub fn set_multipath(&self, multipath: Option<(Arc<MultipathController>, u32)>) {
+        if let Some((multipath, _)) = multipath.as_ref() {
+            for ns in self.namespaces.read().values() {
+                multipath.assign_ana_group(ns.id, ns.ana_group_id);
+            }
         }
+        *self.inner.multipath.write() = multipath;
+    }
 
-        // Configure admin queues
-        device.set_reg::<u64>(Register::ASQ, device.admin_sq.address() as u64);
-        device.set_reg::<u64>(Register::ACQ, device.admin_cq.address() as u64);
-        let aqa = (admin_queue_size as u32 - 1) << 16 | (admin_queue_size as u32 - 1);
-        device.set_reg::<u32>(Register::AQA, aqa);
+    /// Install a hook that is called while waiting on the controller.
+    ///
+    /// Use it to yield to a cooperative scheduler instead of spinning.
+    /// Same as setting `WaitStrategy::Yield` with `set_wait_strategy`.
+    pub fn set_yield_hook(&self, hook: Arc<dyn YieldHook>) {
+        self.set_wait_strategy(WaitStrategy::Yield(hook));
+    }
 
-        // Enable controller
-        let cc = device.get_reg::<u32>(Register::CC) & 0xFF00_000F;
-        device.set_reg::<u32>(Register::CC, cc | (4 << 20) | (6 << 16));
+    /// Set when the doorbells of commands not waited for are rung.
+    ///
+    /// Commands still waiting for their doorbell are submitted first.
+    pub fn set_doorbell_policy(&self, policy: DoorbellPolicy) {
+        self.inner.ring_pending();
+        *self.inner.doorbell_policy.write() = policy;
+    }
 
-        device.set_reg::<u32>(Register::CC, device.get_reg::<u32>(Register::CC) | 1);
-        while device.get_reg::<u32>(Register::CSTS) & 1 == 0 {
-            spin_loop();
-        }
+    /// Ring the doorbells of commands queued by `DoorbellPolicy::Batched`.
+    pub fn ring(&self) {
+        self.inner.ring_pending();
+    }
 
-        // Identify controller
-        device.exec_admin(Command::identify(
-            device.admin_sq.tail() as u16,
-            device.admin_buffer.phys_addr,
-            IdentifyType::Controller,
-        ))?;
+    /// Set how the driver waits on the controller.
+    ///
+    /// Applies to wait loops started afterwards.
+    pub fn set_wait_strategy(&self, strategy: WaitStrategy) {
+        *self.inner.wait_strategy.write() = strategy;
+    }
 
-        let extract_string = |start: usize, end: usize| -> String {
-            device.admin_buffer[start..end]
-                .iter()
-                .flat_map(|&b| char::from_u32(b as u32))
-                .collect::<String>()
-                .trim()
-                .to_string()
-        };
+    /// Transition the controller to a power state.
+    ///
+    /// Issues Set Features (Power Management), reads the state back
+    /// with Get Features to confirm it and records the transition.
+    /// With `persist` set, the controller also enters this state after
+    /// a power cycle. The workload hint of the power manager is sent
+    /// along, so it is kept across the transition.
+    pub fn set_power_state(&self, ps: u8, persist: bool) -> Result<()> {
+        let hint = self.power.lock().get_workload_hint();
+        self.set_power_state_with_hint(ps, hint, persist)
+    }
 
-        // Update controller data safely using Mutex
-        {
-            let mut data = device.inner.data.lock();
-            data.serial_number = extract_string(4, 24);
-            data.model_number = extract_string(24, 64);
-            data.firmware_revision = extract_string(64, 72);
+    /// Transition the controller to a power state with a workload hint.
+    ///
+    /// Same as `set_power_state`, but also records `hint` in the power
+    /// manager once the controller has accepted it. With APST enabled, the
+    /// controller may leave the power state on its own later, while the
+    /// hint stays in effect.
+    pub fn set_power_state_with_hint(
+        &self,
+        ps: u8,
+        hint: WorkloadHint,
+        persist: bool,
+    ) -> Result<()> {
+        if ps >= self.inner.data.lock().num_power_states {
+            return Err(Error::InvalidFeatureConfig);
+        }
 
-            let max_pages = 1 << device.admin_buffer.as_ref()[77];
-            data.max_transfer_size = max_pages as usize * data.min_pagesize;
+        self.set_feature(0, FeatureId::PowerManagement, hint.feature_value(ps), persist)?;
 
-            // Note: SQES (bytes 512) and CQES (byte 513) are queue entry sizes, not queue counts
-            // We'll get the actual maximum I/O queue counts via Set Features
+        if self.current_power_state()? != ps {
+            return Err(Error::PowerStateTransitionFailed);
         }
 
-        // Negotiate maximum number of I/O queues with the controller
-        // Request a reasonable number of queues (e.g., 64 of each type)
-        // The controller will respond with the actual number it can support
-        let requested_queues = 63;  // 0-based value (63 means 64 queues)
-        let queue_config = (requested_queues << 16) | requested_queues;
+        let mut power = self.power.lock();
+        power.set_workload_hint(hint);
+        power.record_transition(ps, self.inner.now_us());
+        Ok(())
+    }
 
-        let result = device.exec_admin(Command::set_features(
-            device.admin_sq.tail() as u16,
-            FeatureId::NumberOfQueues,
-            queue_config,
-            false,
+    /// Read the workload hint in effect from the controller.
+    pub fn workload_hint(&self) -> Result<WorkloadHint> {
+        let entry = self.exec_admin(Command::get_features(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::PowerManagement,
+            0,
         ))?;
 
-        // Extract actual allocated queue counts from completion entry
-        // Bits 31:16 = Number of I/O Completion Queues Allocated (0-based)
-        // Bits 15:0 = Number of I/O Submission Queues Allocated (0-based)
-        let allocated_sq = (result.command_specific & 0xFFFF) + 1;
-        let allocated_cq = ((result.command_specific >> 16) & 0xFFFF) + 1;
-
-        {
-            let mut data = device.inner.data.lock();
-            data.max_io_sq = allocated_sq as u16;
-            data.max_io_cq = allocated_cq as u16;
-        }
+        Ok(WorkloadHint::from_bits((entry.command_specific >> 5) as u8))
+    }
 
-        // Create I/O queues
-        device.create_ioq()?;
+    /// Enable or disable Non-Operational Power State Permissive Mode.
+    ///
+    /// When enabled, the controller may temporarily exit a non-operational
+    /// power state to perform controller-initiated background operations.
+    pub fn set_non_operational_permissive(&self, enable: bool, persist: bool) -> Result<()> {
+        self.set_feature(0, FeatureId::NonOperationalPowerState, enable as u32, persist)?;
+        Ok(())
+    }
 
-        // Identify all namespaces
-        device.ident_namespaces_all()?;
+    /// Check whether Non-Operational Power State Permissive Mode is enabled.
+    pub fn non_operational_permissive(&self) -> Result<bool> {
+        let entry = self.exec_admin(Command::get_features(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::NonOperationalPowerState,
+            0,
+        ))?;
 
-        Ok(device)
+        Ok(entry.command_specific & 1 != 0)
     }
 
-    /// Get a namespace by its ID.
+    /// Prepare the controller for runtime suspend.
     ///
-    /// Returns `None` if the namespace doesn't exist.
-    pub fn get_ns(&self, namespace_id: u32) -> Option<Arc<Namespace<A>>> {
-        self.namespaces.read().get(&namespace_id).cloned()
+    /// Transitions into the deepest non-operational power state whose
+    /// exit latency fits within `max_resume_latency_us`, and returns it.
+    pub fn prepare_for_runtime_suspend(&self, max_resume_latency_us: u32) -> Result<u8> {
+        let ps = self.power.lock()
+            .find_deepest_non_operational_state(max_resume_latency_us)
+            .ok_or(Error::NoSuitablePowerState)?;
+
+        self.set_power_state(ps, false)?;
+        Ok(ps)
     }
 
-    /// Get controller data.
-    pub fn data(&self) -> ControllerData {
-        self.inner.data.lock().clone()
+    /// Read the current power state from the controller.
+    pub fn current_power_state(&self) -> Result<u8> {
+        let entry = self.exec_admin(Command::get_features(
+            self.admin_sq.tail() as u16,
+            0,
+            FeatureId::PowerManagement,
+            0,
+        ))?;
+
+        Ok((entry.command_specific & 0x1F) as u8)
     }
 
     /// Create initial I/O queues.
     fn create_ioq(&self) -> Result<()> {
         // Start with one I/O queue pair
-        self.add_ioq_internal()?;
+        self.add_ioq_internal(&QueueConfig::default())?;
         Ok(())
     }
 
@@ -979,20 +5931,23 @@ impl<A: Allocator> NVMeDevice<A> {
 
             // Phase 2: Flush all namespaces and wait for completion
             // This is critical - we MUST ensure flushes complete for data safety
+            let waiter = self.inner.waiter();
             for &ns_id in self.namespaces.read().keys() {
                 let queues = self.inner.ioq.lock().clone();
                 for queue_arc in queues.iter() {
                     let queue = queue_arc.lock();
-                    let flush_cmd = Command::flush(queue.sq.tail() as u16, ns_id);
+                    let Some(cmd_id) = queue.cids.allocate() else {
+                        continue;
+                    };
+                    let flush_cmd = Command::flush(cmd_id, ns_id);
 
                     // Push flush command
-                    let tail = queue.sq.push(flush_cmd);
-                    self.inner.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+                    let tail = queue.sq.push(flush_cmd, || waiter.wait());
+                    queue.ring_sq(tail);
 
                     // Wait for flush completion - this is essential
-                    let (head, _entry) = queue.cq.pop();
-                    self.inner.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-                    queue.sq.set_head(_entry.sq_head as usize);
+                    let _ = queue.wait_completion(cmd_id, || waiter.wait());
+                    queue.cids.release(cmd_id);
                 }
             }
 
@@ -1007,58 +5962,278 @@ impl<A: Allocator> NVMeDevice<A> {
                     self.admin_sq.tail() as u16,
                     qid,
                 ))?;
+            }
 
-                // Then delete completion queue
-                self.exec_admin(Command::delete_completion_queue(
-                    self.admin_sq.tail() as u16,
-                    qid,
-                ))?;
+            // Then delete completion queues
+            self.delete_unused_cqs(&queues, &[])?;
+            for queue in &queues {
+                queue.lock().free_buffers(self.inner.allocator.as_ref());
             }
         }
 
         self.inner.ioq.lock().clear();
+        self.inner.in_flight.lock().clear();
         self.inner.next_queue_id.store(1, Ordering::SeqCst);
         Ok(())
     }
 
-    /// Identify all namespaces on the device.
+    /// Identify all selected namespaces on the device.
     fn ident_namespaces_all(&self) -> Result<()> {
-        // Get namespace list
+        for id in self.active_ns_ids()? {
+            if !self.attaches(id) {
+                continue;
+            }
+            if let Some(namespace) = self.ident_namespace(id)? {
+                self.insert_namespace(namespace);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add an identified namespace, returning the one it replaces.
+    ///
+    /// The namespace joins its ANA group on the multipath controller, if set.
+    fn insert_namespace(&self, namespace: Namespace<A>) -> Option<Arc<Namespace<A>>> {
+        if let Some((multipath, _)) = self.inner.multipath.read().as_ref() {
+            multipath.assign_ana_group(namespace.id, namespace.ana_group_id);
+        }
+        self.namespaces.write().insert(namespace.id, Arc::new(namespace))
+    }
+
+    /// Check whether namespace `id` is selected by the namespace filter.
+    fn attaches(&self, id: u32) -> bool {
+        self.namespace_filter.is_none_or(|filter| filter(id))
+    }
+
+    /// Get the IDs of the active namespaces.
+    ///
+    /// Each Identify returns up to 1024 active NSIDs greater than the one
+    /// given, in increasing order, so a full page is followed by another
+    /// request starting from its last NSID. NSIDs need not be contiguous.
+    fn active_ns_ids(&self) -> Result<Vec<u32>> {
+        let buf = self.admin_buffers.get(4096)?;
+        let mut ids = Vec::new();
+        let mut start = 0;
+
+        loop {
+            self.exec_admin(Command::identify(
+                self.admin_sq.tail() as u16,
+                buf.phys_addr(),
+                IdentifyType::NamespaceList(start),
+            ))?;
+
+            let page = buf
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .take_while(|&id| id != 0)
+                .filter(|&id| id > start)
+                .collect::<Vec<_>>();
+
+            // Stop on a short page, or when the list reaches the last valid NSID
+            let full = page.len() == buf.len() / 4;
+            let next = page.last().copied().filter(|&last| full && last < 0xFFFF_FFFE);
+            ids.extend(page);
+
+            match next {
+                Some(last) => start = last,
+                None => return Ok(ids),
+            }
+        }
+    }
+
+    /// Read the Identify Namespace data structure of namespace `id`.
+    fn namespace_data(&self, id: u32) -> Result<NamespaceData> {
+        let buf = self.admin_buffers.get(4096)?;
         self.exec_admin(Command::identify(
             self.admin_sq.tail() as u16,
-            self.admin_buffer.phys_addr,
-            IdentifyType::NamespaceList(0),
+            buf.phys_addr(),
+            IdentifyType::Namespace(id),
         ))?;
+        Ok(unsafe { read_le::<NamespaceData>(&buf) })
+    }
+
+    /// Identify a namespace, or `None` if it is not active.
+    fn ident_namespace(&self, id: u32) -> Result<Option<Namespace<A>>> {
+        // Inactive namespaces identify as all zeroes
+        let data = self.namespace_data(id)?;
+        if data.capacity == 0 {
+            return Ok(None);
+        }
+        if let Err(e) = data.check() {
+            nvme_log!(Error, "identify namespace {} data failed validation: {}", id, e);
+            return Err(e);
+        }
+
+        let flba_index = active_format_index(data.lba_size);
+        let flba_data = (data.lba_format_support[flba_index] >> 16) & 0xFF;
+
+        Ok(Some(Namespace {
+            id,
+            block_size: 1 << flba_data,
+            block_count: AtomicU64::new(data.capacity),
+            metadata_size: data.lba_format_support[flba_index] as u16,
+            extended_metadata: data.lba_size & (1 << 4) != 0,
+            shared: data.nmic & 1 != 0,
+            ana_group_id: data.anagrpid,
+            dlfeat: data.dlfeat,
+            device: self.inner.clone(),
+            rate_limiter: Mutex::new(None),
+            stats: Arc::new(IoCounters::default()),
+            opens: OpenTracker::default(),
+            ordering: Mutex::new(IoOrdering::Relaxed),
+            ranges: RangeLocks::default(),
+            reserved_blocks: AtomicU64::new(0),
+        }))
+    }
+
+    /// Submit Asynchronous Event Requests until `events` has its maximum outstanding.
+    ///
+    /// The requests complete once the controller reports an event, while
+    /// other admin commands go on. Their completions are collected whenever
+    /// the admin completion queue is reaped and handled by
+    /// `process_async_events`.
+    pub fn submit_async_events(&self, events: &mut AsyncEventManager) -> Result<()> {
+        let outstanding = self.admin_pending.lock()
+            .values()
+            .filter(|slot| slot.async_event)
+            .count();
+        events.sync_outstanding(outstanding as u32);
+
+        let waiter = self.inner.waiter();
+        while events.needs_aer_submission() {
+            let cmd = events.build_aer_command(self.admin_sq.tail() as u16);
+            self.push_admin(cmd, self.inner.now_us(), &waiter, true)?;
+            events.aer_submitted();
+        }
+        Ok(())
+    }
+
+    /// Handle the asynchronous events reported since the last call.
+    ///
+    /// Each event is passed to `handle_async_event`, then the requests
+    /// used up are submitted again. Returns the namespace changes found.
+    /// If handling an event fails, the events after it stay queued.
+    pub fn process_async_events(
+        &self,
+        events: &mut AsyncEventManager,
+    ) -> Result<Vec<NamespaceChange>> {
+        self.reap_admin();
+
+        let mut changes = Vec::new();
+        loop {
+            let Some(dw0) = self.async_events.lock().pop_front() else {
+                break;
+            };
+            changes.extend(self.handle_async_event(events, dw0)?);
+        }
+
+        self.submit_async_events(events)?;
+        Ok(changes)
+    }
 
-        let ids = self.admin_buffer
+    /// Handle an Asynchronous Event Request completion.
+    ///
+    /// The event is recorded in `events` and passed to its handlers. A
+    /// Sanitize Operation Completed event reads the Sanitize Status log,
+    /// which releases the sanitize fence. For a Namespace Attribute Changed
+    /// notice, the Changed Namespace List log is
+    /// read, only the listed namespaces are identified again, and each
+    /// change is reported to the namespace change handlers.
+    pub fn handle_async_event(
+        &self,
+        events: &mut AsyncEventManager,
+        completion_dw0: u32,
+    ) -> Result<Vec<NamespaceChange>> {
+        events.process_event(completion_dw0)?;
+
+        let event = AsyncEvent::from_completion(completion_dw0);
+        nvme_log!(Info, "async event {:?}", event.event_info);
+        if matches!(event.event_info, AsyncEventInfo::TelemetryLogChanged) {
+            let log = self.controller_telemetry()?;
+            if events.notify_telemetry(&log)? {
+                nvme_log!(Info, "telemetry data generation {}", log.generation());
+            }
+            return Ok(Vec::new());
+        }
+        if matches!(event.event_info, AsyncEventInfo::ReservationLogPageAvailable) {
+            for notification in self.read_reservation_notifications()? {
+                nvme_log!(Info, "reservation notification {:?}", notification.kind);
+                events.notify_reservation(&notification)?;
+            }
+            return Ok(Vec::new());
+        }
+        if matches!(
+            event.event_info,
+            AsyncEventInfo::SanitizeOperationCompleted
+                | AsyncEventInfo::SanitizeCompletedWithUnexpectedDeallocation
+        ) {
+            // Reading the log releases the fence and re-enables the event
+            let status = self.sanitize_status()?;
+            nvme_log!(Info, "sanitize completed, failed {}", status.is_failed());
+            return Ok(Vec::new());
+        }
+        if !matches!(event.event_info, AsyncEventInfo::NamespaceAttributeChanged) {
+            return Ok(Vec::new());
+        }
+
+        let changes = self.rescan_changed_namespaces()?;
+        for change in &changes {
+            nvme_log!(Info, "namespace change {:?}", change);
+            events.notify_namespace_change(change)?;
+        }
+        Ok(changes)
+    }
+
+    /// Re-identify the namespaces in the Changed Namespace List log.
+    fn rescan_changed_namespaces(&self) -> Result<Vec<NamespaceChange>> {
+        let data = self.read_log(LogPageId::ChangedNamespaceList, 0, 4096)?;
+        let mut ids = data
             .chunks_exact(4)
             .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
-            .filter(|&id| id != 0)
+            .take_while(|&id| id != 0)
             .collect::<Vec<u32>>();
 
-        // Identify each namespace
-        for id in ids {
-            self.exec_admin(Command::identify(
-                self.admin_sq.tail() as u16,
-                self.admin_buffer.phys_addr,
-                IdentifyType::Namespace(id),
-            ))?;
+        // More than 1024 namespaces changed, so check all of them
+        if ids.first() == Some(&0xFFFF_FFFF) {
+            ids = self.active_ns_ids()?;
+            ids.extend(self.namespaces.read().keys());
+            ids.sort_unstable();
+            ids.dedup();
+        }
 
-            let data = unsafe { &*(self.admin_buffer.addr as *const NamespaceData) };
-            let flba_index = (data.lba_size & 0xF) as usize;
-            let flba_data = (data.lba_format_support[flba_index] >> 16) & 0xFF;
+        let mut changes = Vec::new();
+        for id in ids.into_iter().filter(|&id| self.attaches(id)) {
+            let old = self.get_ns(id);
+            let new = self.ident_namespace(id)?;
 
-            let namespace = Namespace {
-                id,
-                block_size: 1 << flba_data,
-                block_count: data.capacity,
-                device: self.inner.clone(),
+            let change = match (old, new) {
+                (None, Some(new)) => {
+                    self.insert_namespace(new);
+                    NamespaceChange::Added(id)
+                }
+                (Some(old), None) => {
+                    // Fail I/O through handles that are still held
+                    old.block_count.store(0, Ordering::Release);
+                    self.namespaces.write().remove(&id);
+                    NamespaceChange::Removed(id)
+                }
+                (Some(old), Some(new)) if old.capacity() != new.capacity() => {
+                    // Reported like `block_count`, without the reserved blocks
+                    let old_blocks = old.block_count();
+                    old.block_count.store(new.capacity(), Ordering::Release);
+                    NamespaceChange::Resized {
+                        nsid: id,
+                        old_blocks,
+                        new_blocks: old.block_count(),
+                    }
+                }
+                _ => continue,
             };
-
-            self.namespaces.write().insert(id, Arc::new(namespace));
+            changes.push(change);
         }
 
-        Ok(())
+        Ok(changes)
     }
 
     /// Get the list of all namespaces on the device.
@@ -1067,39 +6242,178 @@ impl<A: Allocator> NVMeDevice<A> {
     }
 
     /// Helper function to read a NVMe register.
-    fn get_reg<T>(&self, reg: Register) -> T {
+    fn get_reg<T: Le>(&self, reg: Register) -> T {
         let address = self.address as usize + reg as usize;
-        unsafe { (address as *const T).read_volatile() }
+        unsafe { (address as *const T).read_volatile() }.swap_le()
     }
 
     /// Helper function to write a NVMe register.
-    fn set_reg<T>(&self, reg: Register, value: T) {
+    fn set_reg<T: Le>(&self, reg: Register, value: T) {
         let address = self.address as usize + reg as usize;
-        unsafe { (address as *mut T).write_volatile(value) }
+        unsafe { (address as *mut T).write_volatile(value.swap_le()) }
     }
 
     /// Execute an admin command.
+    ///
+    /// Other admin commands may be in flight at the same time, each matched
+    /// to its completion by command ID.
     fn exec_admin(&self, cmd: Command) -> Result<Completion> {
-        // Serialize admin commands to prevent race conditions
-        let _guard = self.admin_lock.lock();
+        let start = self.inner.now_us();
+        let waiter = self.inner.waiter();
+        let (cmd_id, ticket) = self.push_admin(cmd, start, &waiter, false)?;
+
+        // Wait for the completion, reaped by whichever admin user gets to it first
+        let entry = loop {
+            if let Some(entry) = self.take_admin(cmd_id, ticket)? {
+                break entry;
+            }
+            let timeout = Error::AdminCommandTimeout;
+            if let Err(e) = self.admin_wait(&waiter, cmd.opcode(), start, timeout) {
+                self.abandon_admin(cmd_id, ticket);
+                return Err(e);
+            }
+        };
 
-        // Push command to submission queue (will spin if full)
-        let tail = self.admin_sq.push(cmd);
-        self.inner.doorbell_helper.write(Doorbell::SubTail(0), tail as u32);
+        if let Err(e) = entry.result() {
+            nvme_log!(Error, "admin command {:#04x} failed: {}", cmd.opcode(), e);
+            return Err(e);
+        }
+        Ok(entry)
+    }
 
-        // Wait for completion
-        let (head, entry) = self.admin_cq.pop();
-        self.inner.doorbell_helper.write(Doorbell::CompHead(0), head as u32);
+    /// Submit an admin command without waiting for its completion.
+    ///
+    /// `rescan` is a namespace to identify again once the command succeeds.
+    fn submit_admin(&self, cmd: Command, rescan: Option<u32>) -> Result<AdminToken> {
+        let start = self.inner.now_us();
+        let (cmd_id, ticket) = self.push_admin(cmd, start, &self.inner.waiter(), false)?;
+        Ok(AdminToken { cmd_id, opcode: cmd.opcode(), ticket, rescan, fence: None })
+    }
 
-        // Update submission queue head from completion entry
-        self.admin_sq.set_head(entry.sq_head as usize);
+    /// Give `cmd` a free command ID, register it and submit it.
+    ///
+    /// Returns the command ID and the ticket of the submission.
+    fn push_admin(
+        &self,
+        cmd: Command,
+        start: u64,
+        waiter: &Waiter,
+        async_event: bool,
+    ) -> Result<(u16, u64)> {
+        let mut next_cid = self.admin_lock.lock();
+
+        let (cmd, ticket) = {
+            let mut pending = self.admin_pending.lock();
+            while pending.contains_key(&next_cid) {
+                *next_cid = next_cid.wrapping_add(1);
+            }
+            let cmd = cmd.with_cmd_id(*next_cid);
+            *next_cid = next_cid.wrapping_add(1);
+
+            let ticket = self.admin_tickets.fetch_add(1, Ordering::Relaxed);
+            let slot = AdminSlot { ticket, completion: None, abandoned: false, async_event };
+            pending.insert(cmd.cmd_id(), slot);
+            (cmd, ticket)
+        };
+
+        // Wait for space, reaping completions to learn how far the controller got
+        let tail = loop {
+            match self.admin_sq.try_push(cmd) {
+                Ok(tail) => break tail,
+                Err(_) => {
+                    self.reap_admin();
+                    let full = Error::AdminQueueFull;
+                    if let Err(e) = self.admin_wait(waiter, cmd.opcode(), start, full) {
+                        self.admin_pending.lock().remove(&cmd.cmd_id());
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        self.admin_doorbells.ring_sq(tail);
+
+        Ok((cmd.cmd_id(), ticket))
+    }
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+    /// Take the completion of admin command `cmd_id` if it has arrived.
+    ///
+    /// Fails with `Error::UnknownAdminToken` if the submission with `ticket`
+    /// is not in flight.
+    fn take_admin(&self, cmd_id: u16, ticket: u64) -> Result<Option<Completion>> {
+        self.reap_admin();
+
+        let mut pending = self.admin_pending.lock();
+        let completion = match pending.get(&cmd_id) {
+            Some(slot) if slot.ticket == ticket => slot.completion,
+            _ => return Err(Error::UnknownAdminToken),
+        };
+        if completion.is_some() {
+            pending.remove(&cmd_id);
         }
+        Ok(completion)
+    }
 
-        Ok(entry)
+    /// Stop waiting for admin command `cmd_id`.
+    ///
+    /// Its command ID stays reserved until the late completion arrives, so
+    /// that completion is not taken for a later command's.
+    fn abandon_admin(&self, cmd_id: u16, ticket: u64) {
+        let mut pending = self.admin_pending.lock();
+        match pending.get_mut(&cmd_id) {
+            Some(slot) if slot.ticket == ticket && slot.completion.is_none() => {
+                slot.abandoned = true;
+            }
+            Some(slot) if slot.ticket == ticket => {
+                pending.remove(&cmd_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Collect the admin completions posted so far for their submitters.
+    fn reap_admin(&self) {
+        // Reap under the lock, so completion queue doorbells are rung in order
+        let mut pending = self.admin_pending.lock();
+        while let Some((head, entry)) = self.admin_cq.try_pop() {
+            self.admin_doorbells.ring_cq(head);
+
+            // Update submission queue head from completion entry
+            self.admin_sq.set_head(entry.sq_head as usize);
+
+            let cmd_id = entry.cmd_id;
+            match pending.get_mut(&cmd_id) {
+                Some(slot) if slot.abandoned => {
+                    pending.remove(&cmd_id);
+                }
+                Some(slot) if slot.async_event => {
+                    pending.remove(&cmd_id);
+                    match entry.result() {
+                        Ok(()) => self.async_events.lock().push_back(entry.command_specific),
+                        Err(e) => nvme_log!(Warn, "asynchronous event request failed: {}", e),
+                    }
+                }
+                Some(slot) => slot.completion = Some(entry),
+                // Completions of commands lost to a controller reset
+                None => {}
+            }
+        }
+    }
+
+    /// Wait step for the admin command with `opcode` started at `start`.
+    ///
+    /// Fails with `error` once the admin timeout has elapsed.
+    fn admin_wait(&self, waiter: &Waiter, opcode: u8, start: u64, error: Error) -> Result<()> {
+        if self.admin_timeout_us != 0 && self.inner.now_us() - start >= self.admin_timeout_us {
+            // The command may have been lost to a reset the host did not ask for
+            let error = match self.reset_occurred() {
+                Some(_) => Error::ControllerResetOccurred,
+                None => error,
+            };
+            nvme_log!(Error, "admin command {:#04x} failed: {}", opcode, error);
+            return Err(error);
+        }
+        waiter.wait();
+        Ok(())
     }
 }
 
@@ -1116,8 +6430,12 @@ impl<A: Allocator> NVMeDevice<A> {
 
 impl<A: Allocator> Drop for NVMeDevice<A> {
     fn drop(&mut self) {
-        // 1. Set global shutdown flag
-        self.inner.shutting_down.store(true, Ordering::Release);
+        // 1. Detach namespaces and wait for their in-flight commands
+        self.inner.shutting_down.store(true, Ordering::SeqCst);
+        if !self.drain_io(TEARDOWN_TIMEOUT_MS) {
+            nvme_log!(Warn, "dropping the device with I/O still in flight");
+        }
+        let waiter = self.inner.waiter();
 
         // 2. Flush each namespace on each queue
         for &ns_id in self.namespaces.read().keys() {
@@ -1128,14 +6446,16 @@ impl<A: Allocator> Drop for NVMeDevice<A> {
                 // Mark shutdown and send flush
                 queue.shutdown.store(true, Ordering::Release);
 
-                let flush_cmd = Command::flush(queue.sq.tail() as u16, ns_id);
-                let tail = queue.sq.push(flush_cmd);
-                self.inner.doorbell_helper.write(Doorbell::SubTail(queue.qid), tail as u32);
+                let Some(cmd_id) = queue.cids.allocate() else {
+                    continue;
+                };
+                let flush_cmd = Command::flush(cmd_id, ns_id);
+                let tail = queue.sq.push(flush_cmd, || waiter.wait());
+                queue.ring_sq(tail);
 
                 // Wait for flush completion
-                let (head, entry) = queue.cq.pop();
-                self.inner.doorbell_helper.write(Doorbell::CompHead(queue.qid), head as u32);
-                queue.sq.set_head(entry.sq_head as usize);
+                let _ = queue.wait_completion(cmd_id, || waiter.wait());
+                queue.cids.release(cmd_id);
             }
         }
 
@@ -0,0 +1,70 @@
+//! Little-endian conversion of controller data structures.
+//!
+//! The controller reads and writes all multi-byte fields in little-endian
+//! order. Structures shared with it are converted with `Le::swap_le` right
+//! before they are handed to the controller and right after they are read
+//! back, which compiles to nothing on little-endian hosts.
+
+/// A value that is stored little-endian in controller memory.
+pub(crate) trait Le: Copy {
+    /// Convert between host and little-endian byte order.
+    ///
+    /// The conversion is its own inverse.
+    fn swap_le(self) -> Self;
+}
+
+macro_rules! le_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Le for $ty {
+                fn swap_le(self) -> Self {
+                    <$ty>::to_le(self)
+                }
+            }
+        )*
+    };
+}
+
+le_int!(u8, u16, u32, u64, u128);
+
+impl<T: Le, const N: usize> Le for [T; N] {
+    fn swap_le(self) -> Self {
+        self.map(Le::swap_le)
+    }
+}
+
+/// Implement `Le` for a packed structure by converting the listed fields.
+///
+/// Fields not listed are single bytes or byte arrays, so a structure made
+/// of nothing else lists none.
+macro_rules! le_struct {
+    ($ty:ty {}) => {
+        impl $crate::endian::Le for $ty {
+            fn swap_le(self) -> Self {
+                self
+            }
+        }
+    };
+    ($ty:ty { $($field:ident),+ $(,)? }) => {
+        impl $crate::endian::Le for $ty {
+            fn swap_le(mut self) -> Self {
+                $(
+                    self.$field = $crate::endian::Le::swap_le(self.$field);
+                )*
+                self
+            }
+        }
+    };
+}
+
+pub(crate) use le_struct;
+
+/// Read a little-endian structure from the start of `data`.
+///
+/// # Safety
+///
+/// `data` must hold at least `size_of::<T>()` bytes and any bit pattern
+/// must be a valid `T`.
+pub(crate) unsafe fn read_le<T: Le>(data: &[u8]) -> T {
+    unsafe { core::ptr::read_unaligned(data.as_ptr() as *const T) }.swap_le()
+}
@@ -1,5 +1,10 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt::{self, Display};
 
+use crate::device::StalledQueue;
+use crate::multipath::{AnaState, PathErrorRecord};
+
 /// NVMe status code type.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StatusCodeType {
@@ -16,7 +21,7 @@ pub enum StatusCodeType {
 }
 
 /// NVMe command status codes.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StatusCode {
     /// Status code type
     pub sct: StatusCodeType,
@@ -167,7 +172,7 @@ pub enum Error {
     /// The queue size exceeds the maximum queue entry size (MQES).
     QueueSizeExceedsMqes,
     /// Command failed with a specific status code.
-    CommandFailed(u16),
+    CommandFailed(StatusCode),
     /// Invalid namespace ID.
     InvalidNamespace,
     /// Feature configuration not set.
@@ -182,8 +187,25 @@ pub enum Error {
     PowerLimitExceeded,
     /// Sanitize operation in progress.
     SanitizeInProgress,
+    /// Format NVM operation in progress.
+    FormatInProgress,
+    /// Device self-test operation in progress.
+    SelfTestInProgress,
+    /// The sanitize operation failed.
+    SanitizeFailed,
+    /// The operation cannot be cancelled once started.
+    OperationNotCancellable,
+    /// The operation was cancelled.
+    OperationCancelled,
+    /// Identify data failed validation; names the implausible field.
+    InvalidIdentifyData(&'static str),
+    /// The data is not a device profile of a known version.
+    InvalidProfile,
     /// Firmware update failed.
     FirmwareUpdateFailed,
+    /// A firmware download range is not aligned to the controller's firmware
+    /// update granularity (FWUG).
+    FirmwareGranularityViolation,
     /// Security command failed.
     SecurityCommandFailed,
     /// NVMe status code error.
@@ -198,12 +220,135 @@ pub enum Error {
     QueueNotFound,
     /// Cannot remove the last queue.
     LastQueueCannotBeRemoved,
+    /// I/O queues being removed still have commands outstanding.
+    QueueRemovalStalled(Vec<StalledQueue>),
     /// Invalid queue count.
     InvalidQueueCount,
     /// Too many queues requested.
     TooManyQueues,
+    /// The I/O queue has no interrupt vector of its own.
+    SharedInterruptVector,
     /// No active queues available.
     NoActiveQueues,
+    /// The controller did not enter the requested power state.
+    PowerStateTransitionFailed,
+    /// No power state satisfies the requested constraints.
+    NoSuitablePowerState,
+    /// Device is suspended.
+    DeviceSuspended,
+    /// Device is quiesced and does not accept new I/O.
+    DeviceQuiesced,
+    /// The controller does not support the requested feature.
+    FeatureNotSupported,
+    /// Read of a deallocated or unwritten logical block (DULBE).
+    DeallocatedOrUnwrittenBlock,
+    /// Command rejected by an I/O rate limit.
+    RateLimited,
+    /// An overlapping command is in flight on the namespace.
+    RangeBusy,
+    /// Operation requires a clock, but none is set.
+    ClockNotSet,
+    /// Doorbell register lies beyond the mapped controller registers.
+    DoorbellOutOfRange,
+    /// Timed out waiting for space in the admin submission queue.
+    AdminQueueFull,
+    /// Admin command did not complete within the admin timeout.
+    AdminCommandTimeout,
+    /// The controller was reset underneath the host; recover with
+    /// `NVMeDevice::recover_reset`.
+    ControllerResetOccurred,
+    /// The feature value cannot be saved.
+    FeatureNotSaveable,
+    /// The feature does not apply to a specific namespace.
+    FeatureNotNamespaceSpecific,
+    /// The block range is beyond the end of the namespace.
+    LbaOutOfRange,
+    /// The controller does not support 64-byte submission or 16-byte completion queue entries.
+    UnsupportedQueueEntrySize,
+    /// The device was dropped, so the namespace no longer accepts I/O.
+    NamespaceDetached,
+    /// The namespace is open exclusively, cannot be opened exclusively, or
+    /// still has I/O in flight when a format or sanitize starts.
+    NamespaceBusy,
+    /// The namespace handle is read-only.
+    NamespaceReadOnly,
+    /// The controller cannot sanitize a single namespace.
+    SanitizePerNamespaceNotSupported,
+    /// A controller list holds more than 2047 identifiers.
+    ControllerListTooLong,
+    /// The namespace does not support the requested LBA format.
+    InvalidLbaFormat,
+    /// LBA formats above 15 need LBA Format Extension to be enabled.
+    LbaFormatExtensionDisabled,
+    /// A protection information tag does not fit its field.
+    InvalidProtectionTag,
+    /// The admin command of a token is not in flight.
+    UnknownAdminToken,
+    /// The controller requires physically contiguous queues (CAP.CQR).
+    ContiguousQueuesRequired,
+    /// The namespace is already attached to the controller.
+    NamespaceAlreadyAttached,
+    /// The namespace is private and can only be attached to one controller.
+    NamespaceIsPrivate,
+    /// The path is in an ANA transition, so the command may succeed when
+    /// retried once it ends or on another path.
+    AnaTransition,
+    /// The namespace is inaccessible through the path's controller.
+    AnaInaccessible,
+    /// The namespace is permanently lost through the path's controller.
+    AnaPersistentLoss,
+    /// The path stayed in an ANA transition for longer than the retries
+    /// of the command were allowed to wait.
+    AnaTransitionTimeout,
+    /// A command failed on a multipath path, with the path, controller and
+    /// queue it took.
+    OnPath(PathErrorRecord, Box<Error>),
+    /// The drive's TCG security subsystem class does not offer the operation.
+    TcgOperationNotSupported,
+}
+
+impl Error {
+    /// Get the ANA state a path error reports for the path the command took.
+    pub fn ana_state(&self) -> Option<AnaState> {
+        match self.without_path() {
+            Error::AnaTransition => Some(AnaState::Change),
+            Error::AnaInaccessible => Some(AnaState::Inaccessible),
+            Error::AnaPersistentLoss => Some(AnaState::PersistentLoss),
+            _ => None,
+        }
+    }
+
+    /// Check whether the error is a transient path error.
+    ///
+    /// Persistent losses and inaccessible paths need another path or an
+    /// ANA state change before commands succeed again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.without_path(), Error::AnaTransition)
+    }
+
+    /// Get the path, controller and queue a failed command took, if recorded.
+    pub fn path_record(&self) -> Option<&PathErrorRecord> {
+        match self {
+            Error::OnPath(record, _) => Some(record),
+            _ => None,
+        }
+    }
+
+    /// Attach the path a failed command took, if one was recorded.
+    pub(crate) fn on_path(self, record: Option<PathErrorRecord>) -> Error {
+        match record {
+            Some(record) => Error::OnPath(record, Box::new(self)),
+            None => self,
+        }
+    }
+
+    /// Get the error without the path it occurred on.
+    pub fn without_path(&self) -> &Error {
+        match self {
+            Error::OnPath(_, error) => error,
+            error => error,
+        }
+    }
 }
 
 impl core::error::Error for Error {}
@@ -232,8 +377,14 @@ impl Display for Error {
             Error::QueueSizeExceedsMqes => {
                 write!(f, "The queue size exceeds the maximum queue entry size")
             }
-            Error::CommandFailed(code) => {
-                write!(f, "Command failed with status code: {}", code)
+            Error::CommandFailed(status) => {
+                write!(
+                    f,
+                    "Command failed with status code: {:?} {:#04x} ({})",
+                    status.sct,
+                    status.sc,
+                    status.description(),
+                )
             }
             Error::InvalidNamespace => {
                 write!(f, "Invalid namespace ID")
@@ -256,9 +407,33 @@ impl Display for Error {
             Error::SanitizeInProgress => {
                 write!(f, "Sanitize operation in progress")
             }
+            Error::FormatInProgress => {
+                write!(f, "Format NVM operation in progress")
+            }
+            Error::SelfTestInProgress => {
+                write!(f, "Device self-test operation in progress")
+            }
+            Error::SanitizeFailed => {
+                write!(f, "Sanitize operation failed")
+            }
+            Error::OperationNotCancellable => {
+                write!(f, "Operation cannot be cancelled")
+            }
+            Error::OperationCancelled => {
+                write!(f, "Operation was cancelled")
+            }
+            Error::InvalidIdentifyData(field) => {
+                write!(f, "Invalid identify data: implausible {}", field)
+            }
+            Error::InvalidProfile => {
+                write!(f, "Invalid device profile")
+            }
             Error::FirmwareUpdateFailed => {
                 write!(f, "Firmware update failed")
             }
+            Error::FirmwareGranularityViolation => {
+                write!(f, "Firmware download range violates the update granularity")
+            }
             Error::SecurityCommandFailed => {
                 write!(f, "Security command failed")
             }
@@ -280,15 +455,131 @@ impl Display for Error {
             Error::LastQueueCannotBeRemoved => {
                 write!(f, "Cannot remove the last I/O queue")
             }
+            Error::QueueRemovalStalled(queues) => {
+                let outstanding: usize = queues.iter().map(|queue| queue.outstanding).sum();
+                write!(f, "{} commands outstanding on I/O queues being removed", outstanding)
+            }
             Error::InvalidQueueCount => {
                 write!(f, "Invalid queue count")
             }
             Error::TooManyQueues => {
                 write!(f, "Too many queues requested")
             }
+            Error::SharedInterruptVector => {
+                write!(f, "The I/O queue shares its interrupt vector")
+            }
             Error::NoActiveQueues => {
                 write!(f, "No active I/O queues available")
             }
+            Error::PowerStateTransitionFailed => {
+                write!(f, "Controller did not enter the requested power state")
+            }
+            Error::NoSuitablePowerState => {
+                write!(f, "No power state satisfies the requested constraints")
+            }
+            Error::DeviceSuspended => {
+                write!(f, "Device is suspended")
+            }
+            Error::DeviceQuiesced => {
+                write!(f, "Device is quiesced")
+            }
+            Error::FeatureNotSupported => {
+                write!(f, "Feature not supported by the controller")
+            }
+            Error::DeallocatedOrUnwrittenBlock => {
+                write!(f, "Read of a deallocated or unwritten logical block")
+            }
+            Error::RateLimited => {
+                write!(f, "I/O rate limit exceeded")
+            }
+            Error::RangeBusy => {
+                write!(f, "An overlapping command is in flight")
+            }
+            Error::ClockNotSet => {
+                write!(f, "No clock is set")
+            }
+            Error::DoorbellOutOfRange => {
+                write!(f, "Doorbell register is outside the mapped region")
+            }
+            Error::AdminQueueFull => {
+                write!(f, "Admin submission queue is full")
+            }
+            Error::AdminCommandTimeout => {
+                write!(f, "Admin command timed out")
+            }
+            Error::ControllerResetOccurred => {
+                write!(f, "The controller was reset underneath the host")
+            }
+            Error::FeatureNotSaveable => {
+                write!(f, "Feature is not saveable")
+            }
+            Error::FeatureNotNamespaceSpecific => {
+                write!(f, "Feature is not namespace specific")
+            }
+            Error::LbaOutOfRange => {
+                write!(f, "Block range is beyond the end of the namespace")
+            }
+            Error::UnsupportedQueueEntrySize => {
+                write!(f, "Controller does not support the driver's queue entry sizes")
+            }
+            Error::NamespaceDetached => {
+                write!(f, "Namespace is detached from its device")
+            }
+            Error::NamespaceBusy => {
+                write!(f, "Namespace is in use by another handle or has I/O in flight")
+            }
+            Error::NamespaceReadOnly => {
+                write!(f, "Namespace handle is read-only")
+            }
+            Error::SanitizePerNamespaceNotSupported => {
+                write!(f, "Controller does not support sanitize per namespace")
+            }
+            Error::ControllerListTooLong => {
+                write!(f, "Controller list holds more than 2047 identifiers")
+            }
+            Error::InvalidLbaFormat => {
+                write!(f, "Namespace does not support the LBA format")
+            }
+            Error::LbaFormatExtensionDisabled => {
+                write!(f, "LBA format needs LBA Format Extension to be enabled")
+            }
+            Error::InvalidProtectionTag => {
+                write!(f, "Protection information tag does not fit its field")
+            }
+            Error::UnknownAdminToken => {
+                write!(f, "The admin command is not in flight")
+            }
+            Error::ContiguousQueuesRequired => {
+                write!(f, "The controller requires physically contiguous queues")
+            }
+            Error::NamespaceAlreadyAttached => {
+                write!(f, "The namespace is already attached to the controller")
+            }
+            Error::NamespaceIsPrivate => {
+                write!(f, "The namespace is private to another controller")
+            }
+            Error::AnaTransition => {
+                write!(f, "The path is in an ANA transition")
+            }
+            Error::AnaInaccessible => {
+                write!(f, "The namespace is inaccessible through this path")
+            }
+            Error::AnaPersistentLoss => {
+                write!(f, "The namespace is persistently lost through this path")
+            }
+            Error::AnaTransitionTimeout => {
+                write!(f, "The path did not leave its ANA transition in time")
+            }
+            Error::OnPath(record, error) => {
+                write!(
+                    f,
+                    "{} (path {}, controller {}, queue {})",
+                    error, record.path_id, record.controller_id, record.qid
+                )
+            }
+            Error::TcgOperationNotSupported => {
+                write!(f, "The TCG security subsystem class does not support the operation")
+            }
         }
     }
 }
@@ -6,6 +6,7 @@ use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::cmd::Command;
 use crate::error::Result;
+use crate::log::{ReservationNotification, TelemetryLog};
 
 /// Asynchronous event type.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -47,6 +48,11 @@ pub enum AsyncEventInfo {
     LbaStatusInformationAlert,
     EnduranceGroupEventAggregateLogChange,
 
+    // I/O command set specific events
+    ReservationLogPageAvailable,
+    SanitizeOperationCompleted,
+    SanitizeCompletedWithUnexpectedDeallocation,
+
     // Vendor specific
     VendorSpecific(u8),
 }
@@ -101,6 +107,13 @@ impl AsyncEvent {
             (AsyncEventType::Notice, 5) => AsyncEventInfo::LbaStatusInformationAlert,
             (AsyncEventType::Notice, 6) => AsyncEventInfo::EnduranceGroupEventAggregateLogChange,
 
+            // I/O command set specific events
+            (AsyncEventType::IoCommandSet, 0) => AsyncEventInfo::ReservationLogPageAvailable,
+            (AsyncEventType::IoCommandSet, 1) => AsyncEventInfo::SanitizeOperationCompleted,
+            (AsyncEventType::IoCommandSet, 2) => {
+                AsyncEventInfo::SanitizeCompletedWithUnexpectedDeallocation
+            }
+
             // Vendor specific
             (AsyncEventType::VendorSpecific, val) => AsyncEventInfo::VendorSpecific(val),
 
@@ -153,21 +166,73 @@ impl AsyncEvent {
             AsyncEventInfo::PredictableLatencyEventAggregateLogChange => Some(0x0B),
             AsyncEventInfo::LbaStatusInformationAlert => Some(0x0E),
             AsyncEventInfo::EnduranceGroupEventAggregateLogChange => Some(0x0F),
+            AsyncEventInfo::ReservationLogPageAvailable => Some(0x80),
+            AsyncEventInfo::SanitizeOperationCompleted
+            | AsyncEventInfo::SanitizeCompletedWithUnexpectedDeallocation => Some(0x81),
 
             _ => None,
         })
     }
 }
 
+/// A namespace change reported by the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceChange {
+    /// A namespace became active
+    Added(u32),
+    /// A namespace is no longer active
+    Removed(u32),
+    /// A namespace changed capacity
+    Resized {
+        /// Namespace ID
+        nsid: u32,
+        /// Previous capacity in blocks, as `Namespace::block_count` reported it
+        old_blocks: u64,
+        /// New capacity in blocks, as `Namespace::block_count` reports it
+        new_blocks: u64,
+    },
+}
+
+/// A reset of the controller that the host did not ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerReset {
+    /// An NVM subsystem reset occurred (CSTS.NSSRO)
+    Subsystem,
+    /// The controller was disabled on its own
+    Controller,
+}
+
 /// Event handler callback type.
 pub type EventHandler = fn(&AsyncEvent) -> Result<()>;
 
+/// Namespace change handler callback type.
+pub type NamespaceChangeHandler = fn(&NamespaceChange) -> Result<()>;
+
+/// Controller-initiated telemetry consumer callback type.
+pub type TelemetryHandler = fn(&TelemetryLog) -> Result<()>;
+
+/// Unexpected controller reset handler callback type.
+pub type ResetHandler = fn(&ControllerReset) -> Result<()>;
+
+/// Reservation notification handler callback type.
+pub type ReservationHandler = fn(&ReservationNotification) -> Result<()>;
+
 /// Asynchronous event manager.
 pub struct AsyncEventManager {
     /// Pending events queue
     pending_events: VecDeque<AsyncEvent>,
     /// Event handlers
     handlers: Vec<EventHandler>,
+    /// Namespace change handlers
+    ns_handlers: Vec<NamespaceChangeHandler>,
+    /// Controller-initiated telemetry consumers
+    telemetry_handlers: Vec<TelemetryHandler>,
+    /// Unexpected controller reset handlers
+    reset_handlers: Vec<ResetHandler>,
+    /// Reservation notification handlers
+    reservation_handlers: Vec<ReservationHandler>,
+    /// Generation number of the last telemetry data handed to consumers
+    telemetry_generation: Option<u8>,
     /// Maximum outstanding AERs
     max_aers: u8,
     /// Current outstanding AERs
@@ -183,6 +248,11 @@ impl Default for AsyncEventManager {
         Self {
             pending_events: VecDeque::new(),
             handlers: Vec::new(),
+            ns_handlers: Vec::new(),
+            telemetry_handlers: Vec::new(),
+            reset_handlers: Vec::new(),
+            reservation_handlers: Vec::new(),
+            telemetry_generation: None,
             max_aers: 4, // Default to 4 outstanding AERs
             outstanding_aers: AtomicU32::new(0),
             event_history: Vec::new(),
@@ -205,9 +275,33 @@ impl AsyncEventManager {
         self.handlers.push(handler);
     }
 
+    /// Register a namespace change handler.
+    pub fn register_namespace_handler(&mut self, handler: NamespaceChangeHandler) {
+        self.ns_handlers.push(handler);
+    }
+
+    /// Register a consumer of controller-initiated telemetry data.
+    pub fn register_telemetry_handler(&mut self, handler: TelemetryHandler) {
+        self.telemetry_handlers.push(handler);
+    }
+
+    /// Register a handler of unexpected controller resets.
+    pub fn register_reset_handler(&mut self, handler: ResetHandler) {
+        self.reset_handlers.push(handler);
+    }
+
+    /// Register a handler of reservation notifications.
+    pub fn register_reservation_handler(&mut self, handler: ReservationHandler) {
+        self.reservation_handlers.push(handler);
+    }
+
     /// Clear all event handlers.
     pub fn clear_handlers(&mut self) {
         self.handlers.clear();
+        self.ns_handlers.clear();
+        self.telemetry_handlers.clear();
+        self.reset_handlers.clear();
+        self.reservation_handlers.clear();
     }
 
     /// Process an async event from completion.
@@ -223,8 +317,9 @@ impl AsyncEventManager {
         // Queue the event
         self.pending_events.push_back(event);
 
-        // Decrement outstanding AERs
-        self.outstanding_aers.fetch_sub(1, Ordering::SeqCst);
+        // Decrement outstanding AERs, which may already have been resynchronized
+        let _ = self.outstanding_aers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n.saturating_sub(1)));
 
         // Call handlers
         for handler in &self.handlers {
@@ -234,6 +329,50 @@ impl AsyncEventManager {
         Ok(())
     }
 
+    /// Report a namespace change to the namespace change handlers.
+    pub fn notify_namespace_change(&self, change: &NamespaceChange) -> Result<()> {
+        for handler in &self.ns_handlers {
+            handler(change)?;
+        }
+        Ok(())
+    }
+
+    /// Report an unexpected controller reset to the reset handlers.
+    pub fn notify_reset(&self, reset: &ControllerReset) -> Result<()> {
+        for handler in &self.reset_handlers {
+            handler(reset)?;
+        }
+        Ok(())
+    }
+
+    /// Report a reservation notification to the reservation handlers.
+    pub fn notify_reservation(&self, notification: &ReservationNotification) -> Result<()> {
+        for handler in &self.reservation_handlers {
+            handler(notification)?;
+        }
+        Ok(())
+    }
+
+    /// Hand controller-initiated telemetry data to the telemetry consumers.
+    ///
+    /// Data of a generation already handed over is skipped. Returns whether
+    /// the consumers were called.
+    pub fn notify_telemetry(&mut self, log: &TelemetryLog) -> Result<bool> {
+        if !log.available() || self.telemetry_generation == Some(log.generation()) {
+            return Ok(false);
+        }
+        for handler in &self.telemetry_handlers {
+            handler(log)?;
+        }
+        self.telemetry_generation = Some(log.generation());
+        Ok(true)
+    }
+
+    /// Get the generation number of the last telemetry data handed over.
+    pub fn telemetry_generation(&self) -> Option<u8> {
+        self.telemetry_generation
+    }
+
     /// Get pending events.
     pub fn get_pending_events(&mut self) -> Vec<AsyncEvent> {
         self.pending_events.drain(..).collect()
@@ -254,6 +393,13 @@ impl AsyncEventManager {
         self.outstanding_aers.load(Ordering::SeqCst)
     }
 
+    /// Set the number of outstanding AERs to the count the device tracks.
+    ///
+    /// AERs are lost when the controller is reset.
+    pub(crate) fn sync_outstanding(&self, count: u32) {
+        self.outstanding_aers.store(count, Ordering::SeqCst);
+    }
+
     /// Build Async Event Request command.
     pub fn build_aer_command(&self, cmd_id: u16) -> Command {
         Command::async_event_request(cmd_id)
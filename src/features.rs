@@ -1,15 +1,19 @@
 //! NVMe Feature management module for NVMe 2.3 specification.
 
+use alloc::collections::BTreeMap;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::mem::size_of;
 
 use crate::cmd::{Command, FeatureId};
+use crate::endian::{le_struct, read_le};
 use crate::error::{Error, Result};
 
 /// Power state descriptor.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct PowerStateDescriptor {
-    /// Maximum power in centiwatts (100mW units)
+    /// Maximum power in 0.01 W units, or 0.0001 W units if MXPS is set in `flags`
     pub max_power: u16,
     /// Reserved
     _rsvd1: u8,
@@ -27,31 +31,111 @@ pub struct PowerStateDescriptor {
     pub write_throughput: u8,
     /// Relative write latency
     pub write_latency: u8,
-    /// Idle power in centiwatts
+    /// Idle power in the units of `idle_power_scale`
     pub idle_power: u16,
-    /// Idle power scale
+    /// Idle power scale in bits 7:6 (01b = 0.0001 W, 10b = 0.01 W)
     pub idle_power_scale: u8,
     /// Reserved
     _rsvd2: u8,
-    /// Active power in centiwatts
+    /// Active power in the units of `active_power_scale`
     pub active_power: u16,
-    /// Active power scale
+    /// Active power scale in bits 7:6 (01b = 0.0001 W, 10b = 0.01 W)
     pub active_power_scale: u8,
     /// Reserved
     _rsvd3: [u8; 9],
 }
 
+le_struct!(PowerStateDescriptor {
+    max_power, entry_latency, exit_latency, idle_power, active_power,
+});
+
+impl PowerStateDescriptor {
+    /// Parse from Identify Controller data.
+    pub fn from_identify_data(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<Self>() {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let desc = unsafe {
+            read_le::<Self>(data)
+        };
+
+        Ok(desc)
+    }
+}
+
+/// Workload hint (WH) of the Power Management feature.
+///
+/// Tells the controller what the host's upcoming work looks like, so it
+/// can pick its internal power behaviour. The hint is independent of the
+/// power state: with APST enabled, the controller still leaves and
+/// re-enters power states on its own after idle periods, and the hint
+/// stays in effect across those transitions until it is set again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WorkloadHint {
+    /// No workload is predicted
+    #[default]
+    None,
+    /// Workload #1: extended idle periods, each followed by a burst of
+    /// random writes, as when APST puts the controller to sleep between
+    /// bursts
+    IdleBurstRandomWrites,
+    /// Workload #2: heavy sequential writes
+    HeavySequentialWrites,
+    /// Reserved value
+    Reserved(u8),
+}
+
+impl WorkloadHint {
+    /// Parse the 3-bit WH field.
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0x7 {
+            0 => Self::None,
+            1 => Self::IdleBurstRandomWrites,
+            2 => Self::HeavySequentialWrites,
+            other => Self::Reserved(other),
+        }
+    }
+
+    /// Encode the 3-bit WH field.
+    pub fn bits(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::IdleBurstRandomWrites => 1,
+            Self::HeavySequentialWrites => 2,
+            Self::Reserved(bits) => bits & 0x7,
+        }
+    }
+
+    /// Encode the Power Management feature value of `power_state` with
+    /// this hint.
+    pub fn feature_value(&self, power_state: u8) -> u32 {
+        (self.bits() as u32) << 5 | (power_state & 0x1F) as u32
+    }
+}
+
 /// Power management configuration for NVMe 2.3.
 #[derive(Debug, Clone, Copy)]
 pub struct PowerManagementConfig {
     /// Power state to transition to
     pub power_state: u8,
     /// Workload hint
-    pub workload_hint: u8,
+    pub workload_hint: WorkloadHint,
     /// Non-operational power state permissive mode
     pub non_op_permissive: bool,
 }
 
+impl PowerManagementConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        Self {
+            power_state: (value & 0x1F) as u8,
+            workload_hint: WorkloadHint::from_bits((value >> 5) as u8),
+            non_op_permissive: false,
+        }
+    }
+}
+
 /// Temperature threshold configuration.
 #[derive(Debug, Clone, Copy)]
 pub struct TemperatureThreshold {
@@ -63,6 +147,17 @@ pub struct TemperatureThreshold {
     pub threshold_type: u8,
 }
 
+impl TemperatureThreshold {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        Self {
+            threshold: value as u16,
+            select: ((value >> 16) & 0xF) as u8,
+            threshold_type: ((value >> 20) & 0x3) as u8,
+        }
+    }
+}
+
 /// Autonomous Power State Transition (APST) configuration entry.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -118,6 +213,80 @@ pub struct InterruptCoalescingConfig {
     pub time: u8,
 }
 
+impl InterruptCoalescingConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        Self {
+            threshold: value as u8,
+            time: (value >> 8) as u8,
+        }
+    }
+}
+
+/// Arbitration configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArbitrationConfig {
+    /// Arbitration burst as a power of two (7 = no limit)
+    pub burst: u8,
+    /// Low priority weight (0-based)
+    pub low_priority_weight: u8,
+    /// Medium priority weight (0-based)
+    pub medium_priority_weight: u8,
+    /// High priority weight (0-based)
+    pub high_priority_weight: u8,
+}
+
+impl ArbitrationConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        Self {
+            burst: (value & 0x7) as u8,
+            low_priority_weight: (value >> 8) as u8,
+            medium_priority_weight: (value >> 16) as u8,
+            high_priority_weight: (value >> 24) as u8,
+        }
+    }
+
+    /// Convert to feature value for Set Features command.
+    pub fn to_feature_value(&self) -> u32 {
+        (self.burst as u32 & 0x7)
+            | (self.low_priority_weight as u32) << 8
+            | (self.medium_priority_weight as u32) << 16
+            | (self.high_priority_weight as u32) << 24
+    }
+
+    /// Check if any weighted round robin weight is set.
+    pub fn uses_weights(&self) -> bool {
+        self.low_priority_weight != 0
+            || self.medium_priority_weight != 0
+            || self.high_priority_weight != 0
+    }
+}
+
+/// Error recovery configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorRecoveryConfig {
+    /// Time limited error recovery in 100 millisecond increments (0 = no limit)
+    pub time_limit: u16,
+    /// Deallocated or Unwritten Logical Block Error Enable
+    pub dulbe: bool,
+}
+
+impl ErrorRecoveryConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        Self {
+            time_limit: value as u16,
+            dulbe: (value >> 16) & 1 != 0,
+        }
+    }
+
+    /// Convert to feature value for Set Features command.
+    pub fn to_feature_value(&self) -> u32 {
+        ((self.dulbe as u32) << 16) | self.time_limit as u32
+    }
+}
+
 /// Asynchronous Event configuration.
 #[derive(Debug, Clone, Copy)]
 pub struct AsyncEventConfig {
@@ -141,6 +310,67 @@ pub struct AsyncEventConfig {
     pub endurance_group_enable: bool,
 }
 
+impl AsyncEventConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        let bit = |n: u32| (value >> n) & 1 != 0;
+        Self {
+            critical_warning_mask: value as u8,
+            smart_health_enable: bit(8),
+            namespace_attribute_enable: bit(9),
+            firmware_activation_enable: bit(10),
+            telemetry_enable: bit(11),
+            ana_change_enable: bit(12),
+            predictable_latency_enable: bit(13),
+            lba_status_enable: bit(14),
+            endurance_group_enable: bit(15),
+        }
+    }
+}
+
+/// Reservation notifications a namespace reports.
+///
+/// The Reservation Notification Mask feature holds the inverse: events
+/// whose bit is set are not reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservationNotificationConfig {
+    /// This host's registration was preempted (REGPRE)
+    pub registration_preempted: bool,
+    /// A reservation was released (RESREL)
+    pub reservation_released: bool,
+    /// A reservation was preempted (RESPRE)
+    pub reservation_preempted: bool,
+}
+
+impl Default for ReservationNotificationConfig {
+    fn default() -> Self {
+        Self {
+            registration_preempted: true,
+            reservation_released: true,
+            reservation_preempted: true,
+        }
+    }
+}
+
+impl ReservationNotificationConfig {
+    /// Parse from a Get Features completion value.
+    pub fn from_feature_value(value: u32) -> Self {
+        let notified = |n: u32| (value >> n) & 1 == 0;
+        Self {
+            registration_preempted: notified(1),
+            reservation_released: notified(2),
+            reservation_preempted: notified(3),
+        }
+    }
+
+    /// Convert to a Set Features value.
+    pub fn to_feature_value(&self) -> u32 {
+        (!self.registration_preempted as u32) << 1
+            | (!self.reservation_released as u32) << 2
+            | (!self.reservation_preempted as u32) << 3
+    }
+}
+
 /// Keep Alive Timer configuration.
 #[derive(Debug, Clone, Copy)]
 pub struct KeepAliveTimerConfig {
@@ -157,6 +387,93 @@ pub struct SanitizeConfig {
     pub no_dealloc_modifies_media: bool,
 }
 
+/// Decoded value of a Get or Set Features completion.
+#[derive(Debug, Clone, Copy)]
+pub enum FeatureValue {
+    /// Arbitration burst and weights
+    Arbitration(ArbitrationConfig),
+    /// Power state and workload hint
+    PowerManagement(PowerManagementConfig),
+    /// Temperature threshold selected by the command
+    TemperatureThreshold(TemperatureThreshold),
+    /// Error recovery time limit and DULBE
+    ErrorRecovery(ErrorRecoveryConfig),
+    /// The volatile write cache is enabled
+    VolatileWriteCache(bool),
+    /// Number of I/O queues allocated by the controller
+    NumberOfQueues {
+        /// Submission queues (1-based)
+        submission: u32,
+        /// Completion queues (1-based)
+        completion: u32,
+    },
+    /// Interrupt coalescing threshold and time
+    InterruptCoalescing(InterruptCoalescingConfig),
+    /// Atomic write normal is disabled (DN)
+    WriteAtomicityNormal(bool),
+    /// Enabled asynchronous event notices
+    AsyncEventConfig(AsyncEventConfig),
+    /// Autonomous power state transitions are enabled
+    AutonomousPowerState(bool),
+    /// Keep alive timeout
+    KeepAliveTimer(KeepAliveTimerConfig),
+    /// Reported reservation notifications
+    ReservationNotification(ReservationNotificationConfig),
+    /// Value of a feature without a typed decoding
+    Raw(u32),
+}
+
+impl FeatureValue {
+    /// Decode the completion value of a Get or Set Features command.
+    pub fn decode(feature_id: FeatureId, value: u32) -> Self {
+        match feature_id {
+            FeatureId::Arbitration => {
+                Self::Arbitration(ArbitrationConfig::from_feature_value(value))
+            }
+            FeatureId::PowerManagement => {
+                Self::PowerManagement(PowerManagementConfig::from_feature_value(value))
+            }
+            FeatureId::TemperatureThreshold => {
+                Self::TemperatureThreshold(TemperatureThreshold::from_feature_value(value))
+            }
+            FeatureId::ErrorRecovery => {
+                Self::ErrorRecovery(ErrorRecoveryConfig::from_feature_value(value))
+            }
+            FeatureId::VolatileWriteCache => Self::VolatileWriteCache(value & 1 != 0),
+            FeatureId::NumberOfQueues => Self::NumberOfQueues {
+                submission: (value & 0xFFFF) + 1,
+                completion: (value >> 16) + 1,
+            },
+            FeatureId::InterruptCoalescing => {
+                Self::InterruptCoalescing(InterruptCoalescingConfig::from_feature_value(value))
+            }
+            FeatureId::WriteAtomicityNormal => Self::WriteAtomicityNormal(value & 1 != 0),
+            FeatureId::AsyncEventConfig => {
+                Self::AsyncEventConfig(AsyncEventConfig::from_feature_value(value))
+            }
+            FeatureId::AutonomousPowerState => Self::AutonomousPowerState(value & 1 != 0),
+            FeatureId::KeepAliveTimer => {
+                Self::KeepAliveTimer(KeepAliveTimerConfig { timeout_ms: value })
+            }
+            FeatureId::ReservationNotificationMask => Self::ReservationNotification(
+                ReservationNotificationConfig::from_feature_value(value),
+            ),
+            _ => Self::Raw(value),
+        }
+    }
+
+    /// Decode the completion value of a Set Features command.
+    ///
+    /// Only features that define it report a value, like the queues
+    /// allocated for Number of Queues. The others are returned as `Raw`.
+    pub fn decode_set(feature_id: FeatureId, value: u32) -> Self {
+        match feature_id {
+            FeatureId::NumberOfQueues => Self::decode(feature_id, value),
+            _ => Self::Raw(value),
+        }
+    }
+}
+
 /// Feature configuration selector.
 #[derive(Debug, Clone, Copy)]
 pub enum FeatureSelector {
@@ -170,6 +487,31 @@ pub enum FeatureSelector {
     Supported = 3,
 }
 
+/// Capabilities of a feature as reported with `FeatureSelector::Supported`.
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureCapabilities {
+    /// Feature ID
+    pub feature_id: FeatureId,
+    /// The feature value may be saved across power cycles
+    pub saveable: bool,
+    /// The feature is namespace specific
+    pub namespace_specific: bool,
+    /// The feature value may be changed
+    pub changeable: bool,
+}
+
+impl FeatureCapabilities {
+    /// Parse from a Get Features (Supported) completion value.
+    pub fn from_feature_value(feature_id: FeatureId, value: u32) -> Self {
+        Self {
+            feature_id,
+            saveable: value & (1 << 0) != 0,
+            namespace_specific: value & (1 << 1) != 0,
+            changeable: value & (1 << 2) != 0,
+        }
+    }
+}
+
 /// Feature configuration result.
 #[derive(Debug, Clone)]
 pub struct FeatureResult {
@@ -227,18 +569,45 @@ pub enum DevicePersonality {
 pub struct PredictableLatencyConfig {
     /// Enable predictable latency mode
     pub enabled: bool,
-    /// Window select
-    pub window: u8,
-    /// Target read latency in microseconds
-    pub target_read_latency_us: u32,
-    /// Target write latency in microseconds
-    pub target_write_latency_us: u32,
+    /// Report an event when the DTWIN reads threshold is exceeded
+    pub dtwin_reads_warning: bool,
+    /// Report an event when the DTWIN writes threshold is exceeded
+    pub dtwin_writes_warning: bool,
+    /// Report an event when the DTWIN time threshold is exceeded
+    pub dtwin_time_warning: bool,
+    /// DTWIN reads threshold (in units of 512 bytes)
+    pub dtwin_reads_threshold: u64,
+    /// DTWIN writes threshold (in units of 512 bytes)
+    pub dtwin_writes_threshold: u64,
+    /// DTWIN time threshold in milliseconds
+    pub dtwin_time_threshold_ms: u64,
+}
+
+impl PredictableLatencyConfig {
+    /// Build the 512-byte data structure for Set Features.
+    pub fn to_data(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+
+        let mut events = 0u16;
+        if self.dtwin_reads_warning { events |= 1 << 0; }
+        if self.dtwin_writes_warning { events |= 1 << 1; }
+        if self.dtwin_time_warning { events |= 1 << 2; }
+
+        data[0..2].copy_from_slice(&events.to_le_bytes());
+        data[32..40].copy_from_slice(&self.dtwin_reads_threshold.to_le_bytes());
+        data[40..48].copy_from_slice(&self.dtwin_writes_threshold.to_le_bytes());
+        data[48..56].copy_from_slice(&self.dtwin_time_threshold_ms.to_le_bytes());
+        data
+    }
 }
 
 /// Host Behavior Support for NVMe 2.3.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct HostBehaviorSupport {
     /// Host supports 128-bit CAS
+    ///
+    /// Not part of the Host Behavior Support data structure, so it is
+    /// neither sent to nor read back from the controller.
     pub cas_128bit: bool,
     /// Advanced Command Retry Enable
     pub acre: bool,
@@ -248,6 +617,27 @@ pub struct HostBehaviorSupport {
     pub lbafee: bool,
 }
 
+impl HostBehaviorSupport {
+    /// Build the 512-byte data structure for Set Features.
+    pub fn to_data(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+        data[0] = self.acre as u8;
+        data[1] = self.etdas as u8;
+        data[2] = self.lbafee as u8;
+        data
+    }
+
+    /// Parse the data structure returned by Get Features.
+    pub fn from_data(data: &[u8]) -> Self {
+        Self {
+            cas_128bit: false,
+            acre: data[0] & 1 != 0,
+            etdas: data[1] & 1 != 0,
+            lbafee: data[2] & 1 != 0,
+        }
+    }
+}
+
 /// Endurance Group Event configuration for NVMe 2.3.
 #[derive(Debug, Clone, Copy)]
 pub struct EnduranceGroupEventConfig {
@@ -271,6 +661,8 @@ pub struct FeatureManager {
     predictable_latency: Option<PredictableLatencyConfig>,
     host_behavior: Option<HostBehaviorSupport>,
     endurance_group_event: Option<EnduranceGroupEventConfig>,
+    /// Namespace-specific error recovery configurations
+    error_recovery: BTreeMap<u32, ErrorRecoveryConfig>,
 }
 
 impl Default for FeatureManager {
@@ -287,6 +679,7 @@ impl Default for FeatureManager {
             predictable_latency: None,
             host_behavior: None,
             endurance_group_event: None,
+            error_recovery: BTreeMap::new(),
         }
     }
 }
@@ -357,13 +750,23 @@ impl FeatureManager {
         self.endurance_group_event = Some(config);
     }
 
+    /// Configure error recovery for a namespace.
+    pub fn set_error_recovery(&mut self, nsid: u32, config: ErrorRecoveryConfig) {
+        self.error_recovery.insert(nsid, config);
+    }
+
+    /// Get error recovery configuration for a namespace.
+    pub fn get_error_recovery(&self, nsid: u32) -> Option<&ErrorRecoveryConfig> {
+        self.error_recovery.get(&nsid)
+    }
+
     /// Build Set Features command for power management.
     pub fn build_power_management_command(&self, cmd_id: u16) -> Result<Command> {
         let config = self.power_management
             .ok_or(Error::InvalidFeatureConfig)?;
 
-        let value = (config.workload_hint as u32) << 5 | config.power_state as u32;
-        Ok(Command::set_features(cmd_id, FeatureId::PowerManagement, value, false))
+        let value = config.workload_hint.feature_value(config.power_state);
+        Ok(Command::set_features(cmd_id, 0, FeatureId::PowerManagement, value, false))
     }
 
     /// Build Set Features command for async events.
@@ -381,6 +784,6 @@ impl FeatureManager {
         if config.lba_status_enable { value |= 1 << 14; }
         if config.endurance_group_enable { value |= 1 << 15; }
 
-        Ok(Command::set_features(cmd_id, FeatureId::AsyncEventConfig, value, false))
+        Ok(Command::set_features(cmd_id, 0, FeatureId::AsyncEventConfig, value, false))
     }
 }
@@ -1,10 +1,12 @@
 //! NVMe Firmware Update module for NVMe 2.3 specification.
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::mem::size_of;
 
 use crate::cmd::Command;
-use crate::error::{Error, Result};
+use crate::endian::{le_struct, read_le};
+use crate::error::{Error, Result, StatusCode, StatusCodeType};
 
 /// Firmware slot information.
 #[derive(Debug, Clone, Copy)]
@@ -20,6 +22,8 @@ pub struct FirmwareSlotInfo {
     _rsvd2: [u8; 448],
 }
 
+le_struct!(FirmwareSlotInfo {});
+
 impl FirmwareSlotInfo {
     /// Parse from log page data.
     pub fn from_log_data(data: &[u8]) -> Result<Self> {
@@ -28,7 +32,7 @@ impl FirmwareSlotInfo {
         }
 
         let info = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const Self)
+            read_le::<Self>(data)
         };
 
         Ok(info)
@@ -136,6 +140,90 @@ impl FirmwareUpdateConfig {
     }
 }
 
+/// Checks a firmware update before any of it is downloaded.
+///
+/// Set a verifier with `FirmwareManager::set_verifier` to check image
+/// signatures or checksums as part of `NVMeDevice::update_firmware`.
+pub trait FirmwareValidator: Send + Sync {
+    /// Fail if the update must not be downloaded.
+    fn validate(&self, config: &FirmwareUpdateConfig) -> Result<()>;
+}
+
+/// Built-in firmware update checker.
+///
+/// Checks the target slot against the controller's firmware slots and the
+/// slot log, and the image and download chunk sizes against the firmware
+/// update granularity (FWUG), so download ranges never overlap or
+/// straddle a granularity boundary.
+#[derive(Debug, Clone, Copy)]
+pub struct FirmwareImageChecker {
+    /// Number of firmware slots
+    pub slots: u8,
+    /// Whether slot 1 is read-only
+    pub slot1_read_only: bool,
+    /// Download size and offset granularity in bytes
+    pub granularity: usize,
+    /// Maximum image size in bytes
+    pub max_image_size: usize,
+    /// Download chunk size in bytes
+    pub chunk_size: usize,
+    /// Active slot, if the slot log has been read
+    pub active_slot: Option<u8>,
+}
+
+impl FirmwareImageChecker {
+    /// Create a checker from the Identify Controller FRMW and FWUG fields
+    /// and the limits and slot log held by `manager`.
+    pub fn new(frmw: u8, fwug: u8, manager: &FirmwareManager) -> Self {
+        Self {
+            slots: ((frmw >> 1) & 0x7).max(1),
+            slot1_read_only: frmw & 1 != 0,
+            granularity: granularity_bytes(fwug),
+            max_image_size: manager.get_max_image_size(),
+            chunk_size: manager.get_chunk_size(),
+            active_slot: manager.get_slot_info().map(FirmwareSlotInfo::active_slot),
+        }
+    }
+}
+
+impl FirmwareValidator for FirmwareImageChecker {
+    fn validate(&self, config: &FirmwareUpdateConfig) -> Result<()> {
+        let slot = config.target_slot;
+        if slot == 0 || slot > self.slots || (slot == 1 && self.slot1_read_only) {
+            return Err(Error::FirmwareUpdateFailed);
+        }
+
+        let size = config.image_size();
+        if size == 0 || size > self.max_image_size || !size.is_multiple_of(self.granularity) {
+            return Err(Error::FirmwareUpdateFailed);
+        }
+
+        // Every chunk starts on a granularity boundary
+        if self.chunk_size == 0 || !self.chunk_size.is_multiple_of(self.granularity) {
+            return Err(Error::FirmwareUpdateFailed);
+        }
+
+        // The running image can only be replaced by one that gets activated
+        if self.active_slot == Some(slot)
+            && matches!(config.commit_action, FirmwareCommitAction::ReplaceNoActivate)
+        {
+            return Err(Error::FirmwareUpdateFailed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the firmware download granularity in bytes from the Identify
+/// Controller FWUG field.
+fn granularity_bytes(fwug: u8) -> usize {
+    match fwug {
+        // No information or no restriction beyond dword alignment
+        0 | 0xFF => 4,
+        units => units as usize * 4096,
+    }
+}
+
 /// Firmware activation method.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FirmwareActivation {
@@ -145,6 +233,8 @@ pub enum FirmwareActivation {
     NvmSubsystemReset,
     /// Controller reset required
     ControllerReset,
+    /// Conventional (PCIe hot or warm) reset required, which only the platform can do
+    ConventionalReset,
     /// Maximum time violation
     MaxTimeViolation,
 }
@@ -196,6 +286,17 @@ pub enum FirmwareUpdateError {
     CommitFailed,
 }
 
+/// Progress of an interrupted firmware download.
+#[derive(Debug, Clone, Copy)]
+struct DownloadCheckpoint {
+    /// Target slot of the download
+    slot: u8,
+    /// Size of the image being downloaded
+    size: usize,
+    /// Bytes successfully downloaded
+    offset: usize,
+}
+
 /// Firmware update manager.
 pub struct FirmwareManager {
     /// Current firmware slot info
@@ -204,10 +305,16 @@ pub struct FirmwareManager {
     max_image_size: usize,
     /// Firmware download chunk size
     chunk_size: usize,
+    /// Download size and offset granularity in bytes
+    granularity: usize,
     /// Current update status
     update_status: FirmwareUpdateStatus,
     /// Update history
     update_history: Vec<(u8, u64, bool)>, // (slot, timestamp, success)
+    /// Caller-supplied image verifier
+    verifier: Option<Box<dyn FirmwareValidator>>,
+    /// Progress of the download in flight or interrupted
+    checkpoint: Option<DownloadCheckpoint>,
 }
 
 impl Default for FirmwareManager {
@@ -216,8 +323,11 @@ impl Default for FirmwareManager {
             slot_info: None,
             max_image_size: 16 * 1024 * 1024, // Default 16MB
             chunk_size: 4096,                  // Default 4KB chunks
+            granularity: 4,
             update_status: FirmwareUpdateStatus::NotStarted,
             update_history: Vec::new(),
+            verifier: None,
+            checkpoint: None,
         }
     }
 }
@@ -234,6 +344,30 @@ impl FirmwareManager {
         self.chunk_size = chunk_size;
     }
 
+    /// Set the download granularity from the Identify Controller FWUG field.
+    pub fn set_granularity(&mut self, fwug: u8) {
+        self.granularity = granularity_bytes(fwug);
+    }
+
+    /// Get the download size and offset granularity in bytes.
+    pub fn get_granularity(&self) -> usize {
+        self.granularity
+    }
+
+    /// Get the largest download chunk size that keeps to the granularity.
+    ///
+    /// The chunk size is rounded down to a multiple of the granularity and
+    /// capped at `max_transfer_size`. Fails with
+    /// `Error::FirmwareGranularityViolation` if not even one granule fits.
+    pub fn download_chunk_size(&self, max_transfer_size: usize) -> Result<usize> {
+        let limit = self.chunk_size.min(max_transfer_size);
+        match limit - limit % self.granularity {
+            0 if max_transfer_size >= self.granularity => Ok(self.granularity),
+            0 => Err(Error::FirmwareGranularityViolation),
+            size => Ok(size),
+        }
+    }
+
     /// Update slot information from log page.
     pub fn update_slot_info(&mut self, log_data: &[u8]) -> Result<()> {
         self.slot_info = Some(FirmwareSlotInfo::from_log_data(log_data)?);
@@ -274,6 +408,16 @@ impl FirmwareManager {
         Ok(())
     }
 
+    /// Set the image verifier, e.g. a signature or CRC check, or remove it with `None`.
+    pub fn set_verifier(&mut self, verifier: Option<Box<dyn FirmwareValidator>>) {
+        self.verifier = verifier;
+    }
+
+    /// Run the image verifier, if one is set.
+    pub fn verify_image(&self, config: &FirmwareUpdateConfig) -> Result<()> {
+        self.verifier.as_ref().map_or(Ok(()), |verifier| verifier.validate(config))
+    }
+
     /// Start firmware update.
     pub fn start_update(&mut self, config: &FirmwareUpdateConfig) -> Result<()> {
         self.validate_update(config)?;
@@ -281,15 +425,47 @@ impl FirmwareManager {
             progress: 0,
             total: config.image_size() as u32,
         };
+        self.checkpoint = Some(DownloadCheckpoint {
+            slot: config.target_slot,
+            size: config.image_size(),
+            offset: 0,
+        });
         Ok(())
     }
 
+    /// Resume an interrupted firmware update.
+    ///
+    /// Returns the offset to continue downloading from. Fails if no
+    /// download of the same image to the same slot was interrupted.
+    pub fn resume_update(&mut self, config: &FirmwareUpdateConfig) -> Result<usize> {
+        self.validate_update(config)?;
+        let checkpoint = self.checkpoint
+            .filter(|c| c.slot == config.target_slot && c.size == config.image_size())
+            .ok_or(Error::FirmwareUpdateFailed)?;
+
+        self.update_status = FirmwareUpdateStatus::Downloading {
+            progress: checkpoint.offset as u32,
+            total: checkpoint.size as u32,
+        };
+        Ok(checkpoint.offset)
+    }
+
+    /// Get the number of bytes downloaded by the current or interrupted update.
+    pub fn downloaded_offset(&self) -> Option<usize> {
+        self.checkpoint.map(|c| c.offset)
+    }
+
     /// Update download progress.
+    ///
+    /// `bytes_downloaded` is also the checkpoint a resumed update starts from.
     pub fn update_progress(&mut self, bytes_downloaded: u32, total_bytes: u32) {
         self.update_status = FirmwareUpdateStatus::Downloading {
             progress: bytes_downloaded,
             total: total_bytes,
         };
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.offset = bytes_downloaded as usize;
+        }
     }
 
     /// Mark download complete and start verification.
@@ -304,6 +480,7 @@ impl FirmwareManager {
 
     /// Mark commit complete.
     pub fn complete_commit(&mut self, requires_activation: bool) {
+        self.checkpoint = None;
         if requires_activation {
             self.update_status = FirmwareUpdateStatus::PendingActivation;
         } else {
@@ -337,15 +514,24 @@ impl FirmwareManager {
     }
 
     /// Build firmware download command.
+    ///
+    /// Fails with `Error::FirmwareGranularityViolation` if `offset` or
+    /// `length` is not a multiple of the download granularity, which the
+    /// controller would reject with an Overlapping Range error.
     pub fn build_download_command(
         &self,
         cmd_id: u16,
-        address: usize,
+        address: u64,
         offset: u32,
         length: u32,
-    ) -> Command {
-        let num_dwords = (length + 3) / 4; // Convert bytes to dwords
-        Command::firmware_image_download(cmd_id, address, num_dwords, offset / 4)
+    ) -> Result<Command> {
+        let granularity = self.granularity as u32;
+        if length == 0 || !offset.is_multiple_of(granularity) || !length.is_multiple_of(granularity)
+        {
+            return Err(Error::FirmwareGranularityViolation);
+        }
+        let num_dwords = length / 4; // Convert bytes to dwords
+        Ok(Command::firmware_image_download(cmd_id, address, num_dwords, offset / 4))
     }
 
     /// Build firmware commit command.
@@ -359,13 +545,31 @@ impl FirmwareManager {
         Command::firmware_commit(cmd_id, slot, action as u8, bpid.unwrap_or(0))
     }
 
-    /// Check if firmware activation is required.
+    /// Check if firmware activation is required after a successful commit.
+    ///
+    /// Immediate activation needs no reset unless the commit status asks for one.
     pub fn check_activation_required(&self, action: FirmwareCommitAction) -> FirmwareActivation {
         match action {
-            FirmwareCommitAction::ReplaceNoActivate => FirmwareActivation::None,
+            FirmwareCommitAction::ReplaceNoActivate
+            | FirmwareCommitAction::ReplaceActivateNow => FirmwareActivation::None,
             FirmwareCommitAction::ReplaceActivateNextReset
             | FirmwareCommitAction::ActivateNextReset => FirmwareActivation::ControllerReset,
-            FirmwareCommitAction::ReplaceActivateNow => FirmwareActivation::NvmSubsystemReset,
+        }
+    }
+
+    /// Map a Firmware Commit status code to the reset it asks for, if any.
+    ///
+    /// Only command specific status codes ask for a reset.
+    pub fn activation_for_status(&self, status: StatusCode) -> Option<FirmwareActivation> {
+        if status.sct != StatusCodeType::CommandSpecific {
+            return None;
+        }
+        match status.sc {
+            0x0B => Some(FirmwareActivation::ConventionalReset),
+            0x10 => Some(FirmwareActivation::NvmSubsystemReset),
+            0x11 => Some(FirmwareActivation::ControllerReset),
+            0x12 => Some(FirmwareActivation::MaxTimeViolation),
+            _ => None,
         }
     }
 
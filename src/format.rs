@@ -0,0 +1,111 @@
+//! Logical block formats and Format NVM parameters.
+
+use alloc::vec::Vec;
+
+/// Number of LBA formats with LBA Format Extension enabled.
+pub(crate) const MAX_LBA_FORMATS: usize = 64;
+
+/// Number of LBA formats without LBA Format Extension.
+pub(crate) const BASIC_LBA_FORMATS: usize = 16;
+
+/// Protection information format of an LBA format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PiFormat {
+    /// 16-bit guard, the format of NVMe 1.x
+    #[default]
+    Guard16,
+    /// 32-bit CRC guard
+    Guard32,
+    /// 64-bit CRC guard
+    Guard64,
+    /// Reserved value
+    Reserved(u8),
+}
+
+impl PiFormat {
+    /// Parse the PIF field of an extended LBA format.
+    fn from_pif(pif: u8) -> Self {
+        match pif {
+            0 => Self::Guard16,
+            1 => Self::Guard32,
+            2 => Self::Guard64,
+            other => Self::Reserved(other),
+        }
+    }
+}
+
+/// A logical block format supported by a namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbaFormat {
+    /// Format index, used to select the format with Format NVM
+    pub index: u8,
+    /// Logical block size in bytes
+    pub block_size: u64,
+    /// Metadata bytes per logical block
+    pub metadata_size: u16,
+    /// Relative performance (0 = best, 3 = degraded)
+    pub relative_performance: u8,
+    /// Storage tag size in bits (0 without an extended format)
+    pub storage_tag_size: u8,
+    /// Protection information format
+    pub pi_format: PiFormat,
+    /// The namespace is formatted with this format
+    pub in_use: bool,
+}
+
+/// Parse the LBA formats of an Identify Namespace data structure.
+///
+/// `nvm` is the NVM command set specific Identify Namespace data structure
+/// holding the extended formats, if the controller returned one.
+pub(crate) fn parse_lba_formats(identify: &[u8], nvm: Option<&[u8]>) -> Vec<LbaFormat> {
+    // NLBAF is 0-based
+    let count = (identify[25] as usize + 1).min(MAX_LBA_FORMATS);
+    let active = active_format_index(identify[26]);
+
+    (0..count)
+        .map(|index| {
+            let offset = 128 + index * 4;
+            let lbaf = u32::from_le_bytes(identify[offset..offset + 4].try_into().unwrap());
+            let elbaf = nvm
+                .and_then(|nvm| nvm.get(12 + index * 4..16 + index * 4))
+                .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+
+            LbaFormat {
+                index: index as u8,
+                block_size: 1 << ((lbaf >> 16) & 0xFF),
+                metadata_size: lbaf as u16,
+                relative_performance: ((lbaf >> 24) & 0x3) as u8,
+                storage_tag_size: (elbaf & 0x7F) as u8,
+                pi_format: PiFormat::from_pif(((elbaf >> 7) & 0x3) as u8),
+                in_use: index == active,
+            }
+        })
+        .collect()
+}
+
+/// Get the format index selected by the FLBAS field.
+///
+/// Bits 3:0 hold the low four bits of the index and bits 6:5 the upper
+/// two, which are only used with LBA Format Extension.
+pub(crate) fn active_format_index(flbas: u8) -> usize {
+    (flbas & 0xF) as usize | (((flbas >> 5) & 0x3) as usize) << 4
+}
+
+/// Parameters of a Format NVM command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FormatConfig {
+    /// LBA format index (0-63)
+    ///
+    /// Indices above 15 need LBA Format Extension to be enabled with
+    /// `NVMeDevice::set_host_behavior`.
+    pub lba_format: u8,
+    /// Transfer metadata at the end of each logical block instead of in
+    /// a separate buffer
+    pub extended_metadata: bool,
+    /// Protection information type (0 = disabled, 1-3 = type 1-3)
+    pub protection_type: u8,
+    /// Protection information is in the first bytes of the metadata
+    pub protection_first: bool,
+    /// Secure erase setting (0 = none, 1 = user data erase, 2 = cryptographic erase)
+    pub secure_erase: u8,
+}
@@ -0,0 +1,212 @@
+//! SMART / Health sampling and endurance estimation.
+
+use alloc::collections::VecDeque;
+
+use crate::log::SmartHealthInfo;
+
+/// Bytes per SMART data unit (1000 512-byte units).
+const DATA_UNIT_BYTES: u128 = 512_000;
+
+/// A SMART / Health snapshot taken at a point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthSample {
+    /// Host clock time of the sample in microseconds
+    pub time_us: u64,
+    /// SMART / Health information
+    pub info: SmartHealthInfo,
+}
+
+/// Projected drive endurance.
+#[derive(Debug, Clone, Copy)]
+pub struct EnduranceEstimate {
+    /// Percentage of the rated endurance used
+    pub percentage_used: u8,
+    /// Bytes written by the host so far
+    pub bytes_written: u128,
+    /// Projected total bytes written at 100% used
+    pub projected_total_bytes: Option<u128>,
+    /// Projected bytes that can still be written
+    pub remaining_bytes: Option<u128>,
+    /// Host write rate in bytes per power-on hour
+    pub write_rate_per_hour: u128,
+    /// Power-on hours until the drive reaches 100% used
+    pub remaining_hours: Option<u128>,
+    /// Power-on hours value at which the drive reaches 100% used
+    pub wear_out_power_on_hours: Option<u128>,
+}
+
+/// Counter changes between two samples.
+///
+/// Rates are zero if the samples were taken without a clock.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthDelta {
+    /// Time between the samples in microseconds
+    pub interval_us: u64,
+    /// Bytes read by the host
+    pub bytes_read: u128,
+    /// Bytes written by the host
+    pub bytes_written: u128,
+    /// Host read commands completed
+    pub read_commands: u128,
+    /// Host write commands completed
+    pub write_commands: u128,
+    /// Minutes the controller was busy with I/O commands
+    pub busy_minutes: u128,
+    /// New media and data integrity errors
+    pub media_errors: u128,
+    /// Change in the percentage used estimate
+    pub percentage_used: u8,
+    /// Average host read throughput in MB/s
+    pub read_mb_per_sec: f64,
+    /// Average host write throughput in MB/s
+    pub write_mb_per_sec: f64,
+    /// Media errors per hour
+    pub media_errors_per_hour: f64,
+    /// Write amplification proxy: percentage used consumed per TB written
+    ///
+    /// `None` if nothing was written. Only meaningful over long intervals,
+    /// since the percentage used estimate moves in whole steps.
+    pub wear_per_tb_written: Option<f64>,
+}
+
+/// Keeps a bounded history of SMART / Health samples.
+///
+/// Feed it with `NVMeDevice::poll_health`, or with `record` if the log
+/// page is read some other way.
+#[derive(Debug, Clone)]
+pub struct HealthMonitor {
+    /// Samples, oldest first
+    samples: VecDeque<HealthSample>,
+    /// Maximum number of samples kept
+    capacity: usize,
+}
+
+impl HealthMonitor {
+    /// Create a new monitor keeping at most `capacity` samples.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2);
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record a sample, dropping the oldest one if full.
+    pub fn record(&mut self, info: SmartHealthInfo, time_us: u64) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(HealthSample { time_us, info });
+    }
+
+    /// Get the most recent sample.
+    pub fn latest(&self) -> Option<&HealthSample> {
+        self.samples.back()
+    }
+
+    /// Iterate over the samples, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = &HealthSample> {
+        self.samples.iter()
+    }
+
+    /// Drop all samples.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Compute counter changes between the two most recent samples.
+    ///
+    /// Counters are differenced with wraparound, so a counter that rolls
+    /// over between polls still yields the right delta.
+    pub fn delta_since_last_poll(&self) -> Option<HealthDelta> {
+        let len = self.samples.len();
+        if len < 2 {
+            return None;
+        }
+        let prev = &self.samples[len - 2];
+        let curr = &self.samples[len - 1];
+        let (old, new) = (prev.info, curr.info);
+
+        let interval_us = curr.time_us.saturating_sub(prev.time_us);
+        let bytes_read = new
+            .data_units_read
+            .wrapping_sub(old.data_units_read)
+            .wrapping_mul(DATA_UNIT_BYTES);
+        let bytes_written = new
+            .data_units_written
+            .wrapping_sub(old.data_units_written)
+            .wrapping_mul(DATA_UNIT_BYTES);
+        let media_errors = new.media_errors.wrapping_sub(old.media_errors);
+        let percentage_used = new.percentage_used.saturating_sub(old.percentage_used);
+
+        // Bytes per microsecond is MB/s
+        let per_sec = |bytes: u128| match interval_us {
+            0 => 0.0,
+            us => bytes as f64 / us as f64,
+        };
+        let per_hour = |count: u128| match interval_us {
+            0 => 0.0,
+            us => count as f64 * 3_600_000_000.0 / us as f64,
+        };
+
+        Some(HealthDelta {
+            interval_us,
+            bytes_read,
+            bytes_written,
+            read_commands: new.host_read_commands.wrapping_sub(old.host_read_commands),
+            write_commands: new
+                .host_write_commands
+                .wrapping_sub(old.host_write_commands),
+            busy_minutes: new.controller_busy_time.wrapping_sub(old.controller_busy_time),
+            media_errors,
+            percentage_used,
+            read_mb_per_sec: per_sec(bytes_read),
+            write_mb_per_sec: per_sec(bytes_written),
+            media_errors_per_hour: per_hour(media_errors),
+            wear_per_tb_written: (bytes_written > 0)
+                .then(|| percentage_used as f64 * 1e12 / bytes_written as f64),
+        })
+    }
+
+    /// Estimate the remaining endurance.
+    ///
+    /// The write rate is taken over the power-on hours spanned by the
+    /// samples, or over the drive's whole life if they span less than an
+    /// hour. Projections are `None` until the drive reports any wear or
+    /// while nothing is being written.
+    pub fn endurance(&self) -> Option<EnduranceEstimate> {
+        let oldest = self.samples.front()?.info;
+        let latest = self.samples.back()?.info;
+
+        let bytes_written = latest.data_units_written.saturating_mul(DATA_UNIT_BYTES);
+        let power_on_hours = latest.power_on_hours;
+        let percentage_used = latest.percentage_used;
+
+        let span_hours = power_on_hours.saturating_sub(oldest.power_on_hours);
+        let span_units = latest
+            .data_units_written
+            .saturating_sub(oldest.data_units_written);
+        let write_rate_per_hour = span_units
+            .saturating_mul(DATA_UNIT_BYTES)
+            .checked_div(span_hours)
+            .or_else(|| bytes_written.checked_div(power_on_hours))
+            .unwrap_or(0);
+
+        let projected_total_bytes = (percentage_used > 0)
+            .then(|| bytes_written.saturating_mul(100) / percentage_used as u128);
+        let remaining_bytes =
+            projected_total_bytes.map(|total| total.saturating_sub(bytes_written));
+        let remaining_hours =
+            remaining_bytes.and_then(|bytes| bytes.checked_div(write_rate_per_hour));
+
+        Some(EnduranceEstimate {
+            percentage_used,
+            bytes_written,
+            projected_total_bytes,
+            remaining_bytes,
+            write_rate_per_hour,
+            remaining_hours,
+            wear_out_power_on_hours: remaining_hours.map(|h| power_on_hours.saturating_add(h)),
+        })
+    }
+}
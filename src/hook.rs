@@ -0,0 +1,154 @@
+//! Host-provided wait, memory barrier and completion hooks.
+
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::hint::spin_loop;
+use core::sync::atomic::{Ordering, fence};
+
+use spin::RwLock;
+
+use crate::error::Result;
+use crate::queues::IoResult;
+
+/// Lets the host run other work while the driver waits on the controller.
+///
+/// The driver busy-waits for submission queue space and for completions.
+/// On a single-threaded executor this starves every other task, so the
+/// host can install a hook that yields to its scheduler instead with
+/// `WaitStrategy::Yield`.
+pub trait YieldHook: Send + Sync {
+    /// Called repeatedly while the driver waits.
+    fn yield_now(&self);
+}
+
+/// How the driver waits on the controller.
+///
+/// Used by every wait loop: queue space, completions, controller enable,
+/// reset and shutdown, and draining I/O.
+#[derive(Clone, Default)]
+pub enum WaitStrategy {
+    /// Spin with `core::hint::spin_loop` on each step
+    #[default]
+    Spin,
+    /// Spin for `spins` steps, then pause for a doubling number of spin
+    /// hints per step, up to `max_pause`
+    ///
+    /// Polls queue memory less often during long waits, which leaves more
+    /// time to SMT siblings and other virtual CPUs.
+    Backoff {
+        /// Steps that spin once before backing off
+        spins: u32,
+        /// Most spin hints per step
+        max_pause: u32,
+    },
+    /// Call a yield hook on each step
+    Yield(Arc<dyn YieldHook>),
+    /// Call a platform wait hint on each step, e.g. one that runs `wfe`
+    /// on ARM or `tpause` on x86
+    Hint(fn()),
+}
+
+impl core::fmt::Debug for WaitStrategy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Spin => write!(f, "Spin"),
+            Self::Backoff { spins, max_pause } => f
+                .debug_struct("Backoff")
+                .field("spins", spins)
+                .field("max_pause", max_pause)
+                .finish(),
+            Self::Yield(_) => write!(f, "Yield"),
+            Self::Hint(_) => write!(f, "Hint"),
+        }
+    }
+}
+
+/// State of one wait loop.
+pub(crate) struct Waiter {
+    strategy: WaitStrategy,
+    /// Steps taken so far
+    steps: Cell<u32>,
+}
+
+impl Waiter {
+    /// Start a wait loop following `strategy`.
+    pub(crate) fn new(strategy: WaitStrategy) -> Self {
+        Self { strategy, steps: Cell::new(0) }
+    }
+
+    /// Take one wait step.
+    pub(crate) fn wait(&self) {
+        match &self.strategy {
+            WaitStrategy::Spin => spin_loop(),
+            WaitStrategy::Backoff { spins, max_pause } => {
+                let step = self.steps.get();
+                self.steps.set(step.saturating_add(1));
+
+                let pauses = match step.checked_sub(*spins) {
+                    None => 1,
+                    Some(past) => {
+                        2u32.saturating_pow(past.saturating_add(1)).min(*max_pause).max(1)
+                    }
+                };
+                for _ in 0..pauses {
+                    spin_loop();
+                }
+            }
+            WaitStrategy::Yield(hook) => hook.yield_now(),
+            WaitStrategy::Hint(hint) => hint(),
+        }
+    }
+}
+
+/// Receives the completions of commands submitted without waiting.
+///
+/// Several submitters can share an I/O queue, each passing its own sink
+/// and context ID, e.g. a task's waker or a request's callback. Whoever
+/// polls the queue, each completion goes to the sink of its command.
+pub trait CompletionSink: Send + Sync {
+    /// Called once per command with its submitter's context ID and result.
+    fn complete(&self, context: u64, result: Result<IoResult>);
+}
+
+/// Orders CPU accesses to queue memory against the controller.
+///
+/// Submission entries must be visible to the controller before the
+/// doorbell write that announces them, and completion entries must not be
+/// read before their phase bit. By default the driver uses a sequentially
+/// consistent fence, which may not order device accesses on weakly-ordered
+/// architectures. Install a hook with `set_barrier_hook` to use the
+/// platform's DMA barriers instead (e.g. `dmb oshst`/`dmb oshld` on ARM or
+/// `fence ow, ow`/`fence ir, ir` on RISC-V).
+pub trait BarrierHook: Send + Sync {
+    /// Order prior writes to DMA memory before a following doorbell write.
+    fn write_barrier(&self);
+
+    /// Order a read of DMA memory before the reads that follow it.
+    fn read_barrier(&self);
+}
+
+/// The installed barrier hook.
+static BARRIER_HOOK: RwLock<Option<&'static dyn BarrierHook>> = RwLock::new(None);
+
+/// Install the barrier hook used by all devices, or remove it with `None`.
+pub fn set_barrier_hook(hook: Option<&'static dyn BarrierHook>) {
+    *BARRIER_HOOK.write() = hook;
+}
+
+/// Order prior writes to DMA memory before a following doorbell write.
+pub(crate) fn write_barrier() {
+    let hook = *BARRIER_HOOK.read();
+    match hook {
+        Some(hook) => hook.write_barrier(),
+        None => fence(Ordering::SeqCst),
+    }
+}
+
+/// Order a read of DMA memory before the reads that follow it.
+pub(crate) fn read_barrier() {
+    let hook = *BARRIER_HOOK.read();
+    match hook {
+        Some(hook) => hook.read_barrier(),
+        None => fence(Ordering::SeqCst),
+    }
+}
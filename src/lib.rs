@@ -18,48 +18,111 @@
 
 extern crate alloc;
 
+mod cache;
+mod clock;
 mod cmd;
 mod device;
+mod endian;
 mod error;
+mod health;
+mod hook;
+mod logger;
 mod memory;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod open;
+mod operation;
+mod ordering;
+mod profile;
+mod provisioning;
+mod qos;
 mod queues;
+mod report;
+mod request;
+mod tuning;
 
 // NVMe 2.3 modules
 mod events;
 mod features;
 mod firmware;
+mod format;
 mod log;
 mod multipath;
 mod power;
+mod protection;
 mod security;
 
 // Core exports
-pub use device::{ControllerData, NVMeDevice, Namespace};
+pub use cache::{CacheConfig, CachedNamespace};
+pub use clock::Clock;
+pub use cmd::{
+    AccessFrequency, AccessLatency, FeatureId, IdentifyCns, IdentifyParams, IoHints, QueuePriority,
+};
+pub use device::{
+    AdminToken, AnaFallback, AnaRetryPolicy, CancelToken, CompareOutcome, ControllerData,
+    ControllerList, DeallocatedRead, DeviceConfig, DeviceOperation, DoorbellPolicy, InitPhase,
+    InitReport, MediaError, NVMeDevice, Namespace, NamespaceInfo, NamespaceStats, PhaseTiming,
+    ProbedDevice, QueueConfig, QueueRemovalPolicy, QuiesceReport, SelfTestResult, SelfTestType,
+    StalledQueue, UuidEntry, VerifyReport,
+};
 pub use error::{Error, StatusCode, StatusCodeType};
-pub use memory::Allocator;
+pub use health::{EnduranceEstimate, HealthDelta, HealthMonitor, HealthSample};
+pub use hook::{BarrierHook, CompletionSink, WaitStrategy, YieldHook, set_barrier_hook};
+pub use logger::{Level, Logger, set_logger};
+pub use memory::{Allocator, PlacementHint};
+pub use metrics::{LATENCY_BUCKETS, LatencyStats};
+pub use open::{NamespaceHandle, OpenMode};
+pub use operation::{Operation, OperationKind, OperationProgress};
+pub use ordering::IoOrdering;
+pub use profile::DeviceProfile;
+pub use provisioning::{UtilizationMonitor, UtilizationSample, UtilizationTrend};
+pub use qos::RateLimit;
+pub use queues::IoResult;
+pub use report::{
+    DeviceReport, FirmwareReport, NamespaceIdentifiers, NamespaceReport, OptionalCommands,
+    QueueReport,
+};
+pub use request::{Elevator, Request, RequestCompletion, RequestFlags, RequestOp};
+pub use tuning::{QueueSample, QueueTarget, QueueTuner, QueueTuningConfig};
 
 // NVMe 2.3 feature exports
-pub use events::{AsyncEvent, AsyncEventManager, AsyncEventType, CriticalWarning};
+pub use events::{
+    AsyncEvent, AsyncEventManager, AsyncEventType, ControllerReset, CriticalWarning,
+    NamespaceChange, NamespaceChangeHandler, ReservationHandler, ResetHandler, TelemetryHandler,
+};
 pub use features::{
-    AsyncEventConfig, AutonomousPowerStateConfig, DevicePersonality, FeatureManager,
-    HostBehaviorSupport, InterruptCoalescingConfig, KeepAliveTimerConfig,
-    PowerManagementConfig, PredictableLatencyConfig, SanitizeConfig, TemperatureThreshold,
+    ArbitrationConfig, AsyncEventConfig, AutonomousPowerStateConfig, DevicePersonality,
+    ErrorRecoveryConfig, FeatureCapabilities, FeatureManager, FeatureSelector, FeatureValue,
+    HostBehaviorSupport, InterruptCoalescingConfig, KeepAliveTimerConfig, PowerManagementConfig,
+    PowerStateDescriptor, PredictableLatencyConfig, ReservationNotificationConfig, SanitizeConfig,
+    TemperatureThreshold, WorkloadHint,
 };
 pub use firmware::{
-    FirmwareCommitAction, FirmwareManager, FirmwareSlotInfo, FirmwareUpdateConfig,
-    FirmwareUpdateStatus,
+    FirmwareActivation, FirmwareCommitAction, FirmwareImageChecker, FirmwareManager,
+    FirmwareSlotInfo, FirmwareUpdateConfig, FirmwareUpdateStatus, FirmwareValidator,
+};
+pub use format::{FormatConfig, LbaFormat, PiFormat};
+pub use log::{
+    CachedLog, EnduranceGroupInfo, ErrorLogEntry, LogPageManager, MediaUnit, MediaUnitStatus,
+    MediaUnitStatusLog, PredictableLatencyEventAggregate, PredictableLatencyPerNvmSet,
+    PredictableLatencyWindow, ReservationNotification, ReservationNotificationType,
+    SmartHealthInfo, TelemetryLog, TelemetryLogHeader,
 };
-pub use log::{LogPageManager, SmartHealthInfo};
 pub use multipath::{
-    AnaState, ControllerPath, MultipathController, PathSelector, PathState, RpfrConfig,
+    AnaState, ControllerPath, MultipathController, PathErrorKind, PathErrorRecord, PathProbe,
+    PathSelector, PathSelectorPolicy, PathState, PathStats, PathWeights, RpfrConfig,
 };
 pub use power::{
-    ApstConfig, PersonalityConfig, PowerLimitConfig, PowerManager, PowerState,
-    SelfReportedPower,
+    ApstConfig, EnergyEstimate, PersonalityConfig, PowerLimitConfig, PowerManager, PowerSample,
+    PowerState, SelfReportedPower,
 };
+pub use protection::{ProtectionCheck, ProtectionInfo};
 pub use security::{
-    CryptoEraseConfig, SanitizeAction, SanitizeOptions, SanitizePerNamespace,
-    SanitizeStatus, SecurityManager,
+    CryptoEraseConfig, LockingFeature, SanitizeAction, SanitizeCapabilities, SanitizeOptions,
+    SanitizePerNamespace, SanitizeRecovery, SanitizeStatus, SecurityManager, SecurityProtocol,
+    SecurityTransfer, TcgDiscovery, TcgOperation, TcgSsc, TCG_COMPACKET_HEADER, tcg_compacket_len,
+    tcg_outstanding_data,
 };
 
 /// NVMe 2.3 specification version
@@ -1,10 +1,13 @@
 //! NVMe Log Page management module for NVMe 2.3 specification.
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::mem::size_of;
 
 use crate::cmd::{Command, LogPageId};
-use crate::error::Result;
+use crate::endian::{le_struct, read_le};
+use crate::error::{Error, Result};
+use crate::firmware::FirmwareSlotInfo;
 
 /// Error log entry structure.
 #[derive(Debug, Clone, Copy)]
@@ -38,6 +41,26 @@ pub struct ErrorLogEntry {
     _rsvd2: [u8; 22],
 }
 
+le_struct!(ErrorLogEntry {
+    error_count, sqid, cmdid, status, param_error_location, lba, nsid, cs_info,
+    trtype_specific,
+});
+
+impl ErrorLogEntry {
+    /// Parse from log page data.
+    pub fn from_log_data(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<Self>() {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let entry = unsafe {
+            read_le::<Self>(data)
+        };
+
+        Ok(entry)
+    }
+}
+
 /// SMART / Health Information log page.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -56,9 +79,9 @@ pub struct SmartHealthInfo {
     pub endurance_critical_warning: u8,
     /// Reserved
     _rsvd1: [u8; 25],
-    /// Data units read (128KB units)
+    /// Data units read (1000 512-byte units)
     pub data_units_read: u128,
-    /// Data units written (128KB units)
+    /// Data units written (1000 512-byte units)
     pub data_units_written: u128,
     /// Host read commands
     pub host_read_commands: u128,
@@ -94,18 +117,26 @@ pub struct SmartHealthInfo {
     _rsvd2: [u8; 280],
 }
 
-/// Firmware slot information.
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct FirmwareSlotInfo {
-    /// Active firmware info
-    pub afi: u8,
-    /// Reserved
-    _rsvd1: [u8; 7],
-    /// Firmware revision for slot 1-7 (8 bytes each)
-    pub firmware_revision: [[u8; 8]; 7],
-    /// Reserved
-    _rsvd2: [u8; 448],
+le_struct!(SmartHealthInfo {
+    temperature, data_units_read, data_units_written, host_read_commands,
+    host_write_commands, controller_busy_time, power_cycles, power_on_hours, unsafe_shutdowns,
+    media_errors, num_error_log_entries, warning_temp_time, critical_temp_time, temp_sensor,
+    tmt1_transition_count, tmt2_transition_count, tmt1_total_time, tmt2_total_time,
+});
+
+impl SmartHealthInfo {
+    /// Parse from log page data.
+    pub fn from_log_data(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<Self>() {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let info = unsafe {
+            read_le::<Self>(data)
+        };
+
+        Ok(info)
+    }
 }
 
 /// Changed namespace list entry.
@@ -116,6 +147,8 @@ pub struct ChangedNamespaceList {
     pub nsid_list: [u32; 1024],
 }
 
+le_struct!(ChangedNamespaceList { nsid_list });
+
 /// Commands supported and effects log page entry.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -168,6 +201,29 @@ pub struct TelemetryLogHeader {
     pub reason_id: [u8; 128],
 }
 
+le_struct!(TelemetryLogHeader { da1_last_block, da2_last_block, da3_last_block, da4_last_block });
+
+/// A telemetry log read from the controller.
+#[derive(Debug, Clone)]
+pub struct TelemetryLog {
+    /// Log header
+    pub header: TelemetryLogHeader,
+    /// The whole log, including the header and all reported data areas
+    pub data: Vec<u8>,
+}
+
+impl TelemetryLog {
+    /// Get the controller-initiated data generation number.
+    pub fn generation(&self) -> u8 {
+        self.header.controller_initiated_data_gen
+    }
+
+    /// Check whether the controller has controller-initiated data available.
+    pub fn available(&self) -> bool {
+        self.header.controller_initiated_data_avail == 1
+    }
+}
+
 /// Endurance group information.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -204,18 +260,24 @@ pub struct EnduranceGroupInfo {
     _rsvd3: [u8; 352],
 }
 
+le_struct!(EnduranceGroupInfo {
+    endurance_estimate, data_units_read, data_units_written, media_units_written,
+    host_read_commands, host_write_commands, media_data_integrity_errors,
+    num_error_info_log_entries,
+});
+
 /// Predictable latency per NVM set.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct PredictableLatencyPerNvmSet {
     /// Status
     pub status: u8,
-    /// Event type
-    pub event_type: u8,
     /// Reserved
     _rsvd1: u8,
+    /// Event type
+    pub event_type: u16,
     /// Reserved
-    _rsvd2: [u8; 61],
+    _rsvd2: [u8; 28],
     /// DTWIN reads typical
     pub dtwin_reads_typical: u64,
     /// DTWIN writes typical
@@ -227,7 +289,140 @@ pub struct PredictableLatencyPerNvmSet {
     /// NDWIN time minimum low
     pub ndwin_time_minimum_low: u64,
     /// Reserved
-    _rsvd3: [u8; 968],
+    _rsvd3: [u8; 56],
+    /// DTWIN reads estimate
+    pub dtwin_reads_estimate: u64,
+    /// DTWIN writes estimate
+    pub dtwin_writes_estimate: u64,
+    /// DTWIN time estimate
+    pub dtwin_time_estimate: u64,
+    /// Reserved
+    _rsvd4: [u8; 360],
+}
+
+le_struct!(PredictableLatencyPerNvmSet {
+    event_type, dtwin_reads_typical, dtwin_writes_typical, dtwin_time_maximum,
+    ndwin_time_minimum_high, ndwin_time_minimum_low, dtwin_reads_estimate, dtwin_writes_estimate,
+    dtwin_time_estimate,
+});
+
+impl PredictableLatencyPerNvmSet {
+    /// Parse from log page data.
+    pub fn from_log_data(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<Self>() {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let info = unsafe {
+            read_le::<Self>(data)
+        };
+
+        Ok(info)
+    }
+
+    /// Get the window the NVM set is currently operating in.
+    ///
+    /// Returns `None` if predictable latency mode is not enabled for the set.
+    pub fn window(&self) -> Option<PredictableLatencyWindow> {
+        match self.status & 0x07 {
+            0x01 => Some(PredictableLatencyWindow::Deterministic),
+            0x02 => Some(PredictableLatencyWindow::NonDeterministic),
+            _ => None,
+        }
+    }
+}
+
+/// Predictable latency mode window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PredictableLatencyWindow {
+    /// Deterministic window (DTWIN)
+    Deterministic = 0x01,
+    /// Non-deterministic window (NDWIN)
+    NonDeterministic = 0x02,
+}
+
+/// Predictable latency event aggregate log page.
+#[derive(Debug, Clone)]
+pub struct PredictableLatencyEventAggregate {
+    /// NVM sets that have a pending predictable latency event
+    pub nvm_sets: Vec<u16>,
+}
+
+impl PredictableLatencyEventAggregate {
+    /// Parse from log page data.
+    pub fn from_log_data(data: &[u8]) -> Result<Self> {
+        if data.len() < 8 {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let count = u64::from_le_bytes(data[0..8].try_into().unwrap()) as usize;
+        let nvm_sets = data[8..]
+            .chunks_exact(2)
+            .take(count)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+
+        Ok(Self { nvm_sets })
+    }
+}
+
+/// Kind of a reservation notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationNotificationType {
+    /// No notification is queued
+    Empty,
+    /// This host's registration was preempted by another host
+    RegistrationPreempted,
+    /// A reservation held by this host's registrant was released
+    ReservationReleased,
+    /// A reservation held by this host's registrant was preempted
+    ReservationPreempted,
+    /// Reserved value
+    Reserved(u8),
+}
+
+/// Reservation Notification log page.
+///
+/// The controller queues one notification per event and reading the log
+/// page takes the oldest one.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservationNotification {
+    /// Log page count, increasing with each notification (0 if empty)
+    pub count: u64,
+    /// Kind of the notification
+    pub kind: ReservationNotificationType,
+    /// Number of further notifications queued
+    pub available: u8,
+    /// Namespace the notification is about
+    pub nsid: u32,
+}
+
+impl ReservationNotification {
+    /// Parse from log page data.
+    pub fn from_log_data(data: &[u8]) -> Result<Self> {
+        if data.len() < 16 {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let kind = match data[8] {
+            0 => ReservationNotificationType::Empty,
+            1 => ReservationNotificationType::RegistrationPreempted,
+            2 => ReservationNotificationType::ReservationReleased,
+            3 => ReservationNotificationType::ReservationPreempted,
+            other => ReservationNotificationType::Reserved(other),
+        };
+        Ok(Self {
+            count: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+            kind,
+            available: data[9],
+            nsid: u32::from_le_bytes(data[12..16].try_into().unwrap()),
+        })
+    }
+
+    /// Check whether no notification was queued.
+    pub fn is_empty(&self) -> bool {
+        self.kind == ReservationNotificationType::Empty
+    }
 }
 
 /// Persistent event log header.
@@ -270,6 +465,11 @@ pub struct PersistentEventLogHeader {
     pub supported_events: [u8; 32],
 }
 
+le_struct!(PersistentEventLogHeader {
+    total_events, total_log_length, header_length, timestamp, power_on_hours,
+    power_cycle_count, pci_vid, pci_ssvid,
+});
+
 /// LBA status information.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -281,21 +481,73 @@ pub struct LbaStatusInfo {
     // LBA range entries follow
 }
 
-/// Media unit status.
+/// Media unit status log header.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct MediaUnitStatus {
     /// Number of media unit status descriptors
     pub num_mus_descriptors: u16,
+    /// Number of channels
+    pub num_channels: u16,
+    /// Selected configuration
+    pub selected_config: u16,
     /// Reserved
-    _rsvd1: [u8; 2],
-    /// Media unit capacity adjustment factor
-    pub mucaf: u16,
-    /// Reserved
-    _rsvd2: [u8; 10],
+    _rsvd: [u8; 10],
     // Media unit status descriptors follow
 }
 
+le_struct!(MediaUnitStatus { num_mus_descriptors, num_channels, selected_config });
+
+/// A media unit status descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaUnit {
+    /// Media unit identifier
+    pub id: u16,
+    /// Domain identifier
+    pub domain_id: u16,
+    /// Endurance group identifier
+    pub endurance_group_id: u16,
+    /// NVM set identifier
+    pub nvm_set_id: u16,
+    /// Capacity adjustment factor
+    pub capacity_adjustment_factor: u16,
+    /// Available spare percentage
+    pub available_spare: u8,
+    /// Percentage used, may exceed 100
+    pub percentage_used: u8,
+    /// Channels attached to the media unit
+    pub channels: Vec<u16>,
+}
+
+impl MediaUnit {
+    /// Check whether the media unit is degraded.
+    ///
+    /// A unit is degraded once its available spare drops below
+    /// `spare_threshold` or its rated endurance is used up.
+    pub fn is_degraded(&self, spare_threshold: u8) -> bool {
+        self.available_spare < spare_threshold || self.percentage_used >= 100
+    }
+}
+
+/// Media unit status log page.
+#[derive(Debug, Clone)]
+pub struct MediaUnitStatusLog {
+    /// Log header
+    pub header: MediaUnitStatus,
+    /// Media unit status descriptors
+    pub units: Vec<MediaUnit>,
+}
+
+impl MediaUnitStatusLog {
+    /// Size of the header and of a descriptor without channel identifiers.
+    const ENTRY_SIZE: usize = 16;
+
+    /// Get the media units that are degraded, see `MediaUnit::is_degraded`.
+    pub fn degraded(&self, spare_threshold: u8) -> impl Iterator<Item = &MediaUnit> {
+        self.units.iter().filter(move |unit| unit.is_degraded(spare_threshold))
+    }
+}
+
 /// Supported log pages.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -304,6 +556,21 @@ pub struct SupportedLogPages {
     pub supported: [u8; 256],
 }
 
+/// A log page whose parsed contents `LogPageManager` caches.
+///
+/// The Changed Namespace List is cleared when read, so it is never cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CachedLog {
+    /// Error Information
+    ErrorInformation,
+    /// SMART / Health Information
+    SmartHealth,
+    /// Firmware Slot Information
+    FirmwareSlot,
+    /// Endurance Group Information
+    EnduranceGroup,
+}
+
 /// Log page manager for handling various log pages.
 pub struct LogPageManager {
     /// Error log entries cache
@@ -312,15 +579,19 @@ pub struct LogPageManager {
     smart_health: Option<SmartHealthInfo>,
     /// Firmware slot info cache
     firmware_slot: Option<FirmwareSlotInfo>,
-    /// Changed namespace list cache
-    changed_namespaces: Vec<u32>,
     /// Telemetry data cache
     telemetry_host: Vec<u8>,
     telemetry_controller: Vec<u8>,
     /// Endurance group info cache
     endurance_group: Option<EnduranceGroupInfo>,
+    /// Endurance group the cached info belongs to, if known
+    endurance_group_id: Option<u16>,
     /// Persistent event log cache
     persistent_events: Vec<u8>,
+    /// Time each cached log was fetched, in microseconds
+    fetched_at: BTreeMap<CachedLog, u64>,
+    /// How long each cached log stays fresh, in microseconds
+    max_age_us: BTreeMap<CachedLog, u64>,
 }
 
 impl Default for LogPageManager {
@@ -329,11 +600,13 @@ impl Default for LogPageManager {
             error_log: Vec::new(),
             smart_health: None,
             firmware_slot: None,
-            changed_namespaces: Vec::new(),
             telemetry_host: Vec::new(),
             telemetry_controller: Vec::new(),
             endurance_group: None,
+            endurance_group_id: None,
             persistent_events: Vec::new(),
+            fetched_at: BTreeMap::new(),
+            max_age_us: BTreeMap::new(),
         }
     }
 }
@@ -354,7 +627,7 @@ impl LogPageManager {
             let start = i * entry_size;
             let entry_data = &data[start..start + entry_size];
             let entry = unsafe {
-                core::ptr::read_unaligned(entry_data.as_ptr() as *const ErrorLogEntry)
+                read_le::<ErrorLogEntry>(entry_data)
             };
             entries.push(entry);
         }
@@ -366,7 +639,7 @@ impl LogPageManager {
     /// Parse SMART/Health information.
     pub fn parse_smart_health(&mut self, data: &[u8]) -> Result<SmartHealthInfo> {
         let info = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const SmartHealthInfo)
+            read_le::<SmartHealthInfo>(data)
         };
         self.smart_health = Some(info);
         Ok(info)
@@ -374,17 +647,17 @@ impl LogPageManager {
 
     /// Parse firmware slot information.
     pub fn parse_firmware_slot(&mut self, data: &[u8]) -> Result<FirmwareSlotInfo> {
-        let info = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const FirmwareSlotInfo)
-        };
+        let info = FirmwareSlotInfo::from_log_data(data)?;
         self.firmware_slot = Some(info);
         Ok(info)
     }
 
     /// Parse changed namespace list.
-    pub fn parse_changed_namespaces(&mut self, data: &[u8]) -> Result<Vec<u32>> {
+    ///
+    /// The list is not cached, since reading it clears it.
+    pub fn parse_changed_namespaces(&self, data: &[u8]) -> Result<Vec<u32>> {
         let list = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const ChangedNamespaceList)
+            read_le::<ChangedNamespaceList>(data)
         };
 
         let mut namespaces = Vec::new();
@@ -395,31 +668,87 @@ impl LogPageManager {
             namespaces.push(nsid);
         }
 
-        self.changed_namespaces = namespaces.clone();
         Ok(namespaces)
     }
 
     /// Parse telemetry log header.
     pub fn parse_telemetry_header(&self, data: &[u8]) -> Result<TelemetryLogHeader> {
         let header = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const TelemetryLogHeader)
+            read_le::<TelemetryLogHeader>(data)
         };
         Ok(header)
     }
 
+    /// Parse a media unit status log page.
+    ///
+    /// Descriptors that do not fully fit in `data` are left out.
+    pub fn parse_media_unit_status(&self, data: &[u8]) -> Result<MediaUnitStatusLog> {
+        if data.len() < size_of::<MediaUnitStatus>() {
+            return Err(Error::InvalidBufferSize);
+        }
+        let header = unsafe {
+            read_le::<MediaUnitStatus>(data)
+        };
+
+        let u16_at = |bytes: &[u8], offset: usize| {
+            u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+        };
+        let mut units = Vec::new();
+        let mut offset = MediaUnitStatusLog::ENTRY_SIZE;
+        for _ in 0..header.num_mus_descriptors {
+            let Some(desc) = data.get(offset..offset + MediaUnitStatusLog::ENTRY_SIZE) else {
+                break;
+            };
+            // Channel identifiers start CIO bytes into the descriptor
+            let channel_count = desc[12] as usize;
+            let channel_offset = (desc[13] as usize).max(MediaUnitStatusLog::ENTRY_SIZE);
+            let len = match channel_count {
+                0 => MediaUnitStatusLog::ENTRY_SIZE,
+                _ => channel_offset + channel_count * 2,
+            };
+            let Some(desc) = data.get(offset..offset + len) else {
+                break;
+            };
+
+            units.push(MediaUnit {
+                id: u16_at(desc, 0),
+                domain_id: u16_at(desc, 2),
+                endurance_group_id: u16_at(desc, 4),
+                nvm_set_id: u16_at(desc, 6),
+                capacity_adjustment_factor: u16_at(desc, 8),
+                available_spare: desc[10],
+                percentage_used: desc[11],
+                channels: (0..channel_count)
+                    .map(|index| u16_at(desc, channel_offset + index * 2))
+                    .collect(),
+            });
+            offset += len;
+        }
+
+        Ok(MediaUnitStatusLog { header, units })
+    }
+
     /// Parse endurance group information.
     pub fn parse_endurance_group(&mut self, data: &[u8]) -> Result<EnduranceGroupInfo> {
         let info = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const EnduranceGroupInfo)
+            read_le::<EnduranceGroupInfo>(data)
         };
         self.endurance_group = Some(info);
+        self.endurance_group_id = None;
+        Ok(info)
+    }
+
+    /// Parse the endurance group information of endurance group `id`.
+    pub fn parse_endurance_group_of(&mut self, id: u16, data: &[u8]) -> Result<EnduranceGroupInfo> {
+        let info = self.parse_endurance_group(data)?;
+        self.endurance_group_id = Some(id);
         Ok(info)
     }
 
     /// Parse persistent event log header.
     pub fn parse_persistent_event_header(&self, data: &[u8]) -> Result<PersistentEventLogHeader> {
         let header = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const PersistentEventLogHeader)
+            read_le::<PersistentEventLogHeader>(data)
         };
         Ok(header)
     }
@@ -447,13 +776,39 @@ impl LogPageManager {
         &self,
         cmd_id: u16,
         log_id: LogPageId,
-        address: usize,
+        address: u64,
         num_dwords: u32,
         offset: u64,
     ) -> Command {
         Command::get_log_page(cmd_id, address, log_id, num_dwords, offset)
     }
 
+    /// Set how long a cached log stays fresh.
+    ///
+    /// Logs without a maximum age are never fresh, so they are read again
+    /// on every `NVMeDevice::get_or_fetch_*` call.
+    pub fn set_max_age(&mut self, log: CachedLog, max_age_us: u64) {
+        self.max_age_us.insert(log, max_age_us);
+    }
+
+    /// Record that `log` was fetched and parsed at `now_us`.
+    pub fn mark_fetched(&mut self, log: CachedLog, now_us: u64) {
+        self.fetched_at.insert(log, now_us);
+    }
+
+    /// Check whether the cached `log` is younger than its maximum age at `now_us`.
+    pub fn is_fresh(&self, log: CachedLog, now_us: u64) -> bool {
+        match (self.fetched_at.get(&log), self.max_age_us.get(&log)) {
+            (Some(&fetched), Some(&max_age)) => now_us.saturating_sub(fetched) <= max_age,
+            _ => false,
+        }
+    }
+
+    /// Mark the cached `log` stale, so the next fetch reads it again.
+    pub fn invalidate(&mut self, log: CachedLog) {
+        self.fetched_at.remove(&log);
+    }
+
     /// Get cached SMART/Health info.
     pub fn get_smart_health(&self) -> Option<&SmartHealthInfo> {
         self.smart_health.as_ref()
@@ -469,13 +824,13 @@ impl LogPageManager {
         self.firmware_slot.as_ref()
     }
 
-    /// Get cached changed namespaces.
-    pub fn get_changed_namespaces(&self) -> &[u32] {
-        &self.changed_namespaces
-    }
-
     /// Get cached endurance group info.
     pub fn get_endurance_group(&self) -> Option<&EnduranceGroupInfo> {
         self.endurance_group.as_ref()
     }
+
+    /// Get cached endurance group info of endurance group `id`.
+    pub fn get_endurance_group_of(&self, id: u16) -> Option<&EnduranceGroupInfo> {
+        self.endurance_group.as_ref().filter(|_| self.endurance_group_id == Some(id))
+    }
 }
@@ -0,0 +1,64 @@
+//! Host-provided logging facade.
+
+use core::fmt;
+
+use spin::RwLock;
+
+/// Log message severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// The driver or controller failed
+    Error,
+    /// Something unexpected that the driver recovered from
+    Warn,
+    /// Major milestones such as initialization steps
+    Info,
+    /// Detailed state changes
+    Debug,
+}
+
+/// Receives diagnostic messages from the driver.
+///
+/// The driver is silent by default. Installing a logger with
+/// `set_logger` shows what it is doing during bring-up, such as
+/// initialization milestones, queue creation, failed commands and
+/// asynchronous events.
+pub trait Logger: Send + Sync {
+    /// Check whether messages at `level` are wanted.
+    ///
+    /// Messages are only formatted if this returns true.
+    fn enabled(&self, level: Level) -> bool {
+        let _ = level;
+        true
+    }
+
+    /// Write a message.
+    fn log(&self, level: Level, args: fmt::Arguments);
+}
+
+/// The installed logger.
+static LOGGER: RwLock<Option<&'static dyn Logger>> = RwLock::new(None);
+
+/// Install the logger used by all devices, or remove it with `None`.
+pub fn set_logger(logger: Option<&'static dyn Logger>) {
+    *LOGGER.write() = logger;
+}
+
+/// Pass a message to the installed logger.
+pub(crate) fn log(level: Level, args: fmt::Arguments) {
+    let logger = *LOGGER.read();
+    if let Some(logger) = logger
+        && logger.enabled(level)
+    {
+        logger.log(level, args);
+    }
+}
+
+/// Log a message through the installed logger.
+macro_rules! nvme_log {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::$level, format_args!($($arg)+))
+    };
+}
+
+pub(crate) use nvme_log;
@@ -1,7 +1,23 @@
 use crate::error::{Error, Result};
-use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
 use core::ops::{Deref, DerefMut};
 use core::slice::{from_raw_parts, from_raw_parts_mut};
+use spin::Mutex;
+
+/// Placement hint for DMA allocations.
+///
+/// Tells the allocator where the memory will be used so that it can
+/// place it close to that CPU, e.g. on the same NUMA node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlacementHint {
+    /// No placement preference
+    #[default]
+    Any,
+    /// Allocate on the given memory node
+    Node(u32),
+    /// Allocate near the CPU identified by an allocator-defined token
+    Affinity(usize),
+}
 
 /// Allocates physically contiguous memory mapped into virtual address space.
 ///
@@ -9,11 +25,14 @@ use core::slice::{from_raw_parts, from_raw_parts_mut};
 pub trait Allocator {
     /// Translates a virtual address to a physical address.
     ///
+    /// Physical addresses are 64-bit regardless of the pointer width, so
+    /// 32-bit hosts can use memory above 4 GiB.
+    ///
     /// You may want to use your page table to translate the address
     /// instead of just subtracting an offset (e.g., `virt - HHDM_OFFSET`)
     /// if the address is allocated by a allocator based on virtual memory
     /// (e.g., kernel heap) rather than a frame allocator.
-    fn translate(&self, addr: usize) -> usize;
+    fn translate(&self, addr: usize) -> u64;
 
     /// Allocates a `size` byte region of memory.
     ///
@@ -27,6 +46,33 @@ pub trait Allocator {
     /// - It must be correctly mapped to virtual memory
     unsafe fn allocate(&self, size: usize) -> usize;
 
+    /// Allocates a `size` byte region of memory following a placement hint.
+    ///
+    /// The hint is advisory. The default implementation ignores it
+    /// and calls `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `allocate`.
+    unsafe fn allocate_near(&self, size: usize, hint: PlacementHint) -> usize {
+        let _ = hint;
+        unsafe { self.allocate(size) }
+    }
+
+    /// Allocates a `size` byte region that only needs to be contiguous in
+    /// virtual memory, following a placement hint.
+    ///
+    /// Its 4 KiB pages may lie anywhere in physical memory; `translate` is
+    /// called for each of them. Used for I/O queues that are not physically
+    /// contiguous. The default implementation calls `allocate_near`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `allocate`, except for physical contiguity.
+    unsafe fn allocate_scattered(&self, size: usize, hint: PlacementHint) -> usize {
+        unsafe { self.allocate_near(size, hint) }
+    }
+
     /// Deallocates a previously allocated region of memory.
     ///
     /// The address must be the virtual address returned by `allocate`.
@@ -47,7 +93,7 @@ pub trait Allocator {
 /// The `T` stored in memory is page-aligned.
 pub(crate) struct Dma<T> {
     pub addr: *mut T,
-    pub phys_addr: usize,
+    pub phys_addr: u64,
     count: usize,
     size: usize,
 }
@@ -83,9 +129,34 @@ impl<T> Dma<T> {
     /// The allocated memory is page-aligned and sized to fit the type T,
     /// rounded up to the nearest page boundary.
     pub fn allocate<A: Allocator>(count: usize, allocator: &A) -> Dma<T> {
+        Self::allocate_near(count, allocator, PlacementHint::Any)
+    }
+
+    /// Allocates a new DMA buffer following a placement hint.
+    pub fn allocate_near<A: Allocator>(count: usize, allocator: &A, hint: PlacementHint) -> Dma<T> {
+        let size = core::mem::size_of::<T>() * count;
+        let aligned = size.div_ceil(4096) * 4096;
+        let addr = unsafe { allocator.allocate_near(aligned, hint) };
+
+        Self {
+            addr: addr as *mut T,
+            phys_addr: allocator.translate(addr),
+            count, size: aligned,
+        }
+    }
+
+    /// Allocates a new DMA buffer whose pages may be physically scattered.
+    ///
+    /// `phys_addr` is then only the address of the first page; use
+    /// `page_addresses` for the others.
+    pub fn allocate_scattered<A: Allocator>(
+        count: usize,
+        allocator: &A,
+        hint: PlacementHint,
+    ) -> Dma<T> {
         let size = core::mem::size_of::<T>() * count;
         let aligned = size.div_ceil(4096) * 4096;
-        let addr = unsafe { allocator.allocate(aligned) };
+        let addr = unsafe { allocator.allocate_scattered(aligned, hint) };
 
         Self {
             addr: addr as *mut T,
@@ -94,6 +165,13 @@ impl<T> Dma<T> {
         }
     }
 
+    /// Get the physical address of each 4 KiB page of the buffer.
+    pub fn page_addresses<A: Allocator>(&self, allocator: &A) -> Vec<u64> {
+        (0..self.size / 4096)
+            .map(|page| allocator.translate(self.addr as usize + page * 4096))
+            .collect()
+    }
+
     /// Deallocates the DMA buffer using the provided allocator.
     ///
     /// # Safety
@@ -110,18 +188,18 @@ impl<T> Dma<T> {
 /// Represents the result of the creation of a PRP.
 pub(crate) enum PrpResult {
     /// Address of PRP1
-    Single(usize),
+    Single(u64),
     /// Addresses of PRP1 and PRP2
-    Double(usize, usize),
+    Double(u64, u64),
     /// Address of PRP1 and a list of PRP2s
-    List(usize, Vec<Dma<u64>>),
+    List(u64, Vec<Dma<u64>>),
 }
 
 impl PrpResult {
     /// Get real address from the PRP result.
     ///
-    /// Returns a tuple `(usize, usize)` containing the first and second PRP addresses.
-    pub fn get_prp(&self) -> (usize, usize) {
+    /// Returns a tuple `(u64, u64)` containing the first and second PRP addresses.
+    pub fn get_prp(&self) -> (u64, u64) {
         match self {
             Self::Single(prp) => (*prp, 0),
             Self::Double(prp1, prp2) => (*prp1, *prp2),
@@ -166,6 +244,7 @@ impl<T> FixedSizeQueue<T> {
 /// It will cache a number of PRP lists to avoid frequent allocations.
 pub(crate) struct PrpManager {
     list_pool: FixedSizeQueue<Dma<u64>>,
+    hint: PlacementHint,
 }
 
 impl Default for PrpManager {
@@ -175,11 +254,20 @@ impl Default for PrpManager {
     fn default() -> Self {
         Self {
             list_pool: FixedSizeQueue::new(32),
+            hint: PlacementHint::Any,
         }
     }
 }
 
 impl PrpManager {
+    /// Creates a new `PrpManager` that allocates PRP lists following a placement hint.
+    pub(crate) fn with_hint(hint: PlacementHint) -> Self {
+        Self {
+            hint,
+            ..Default::default()
+        }
+    }
+
     /// Creates a PRP result for the given address and byte count.
     ///
     /// The NVMe controller will read or write data starting from this address directly.
@@ -188,9 +276,9 @@ impl PrpManager {
     ///
     /// The start address must be aligned to a 4-byte boundary in all situations.
     ///
-    /// And it must be aligned to a page boundary if read or write
-    /// more than a page (currently always 4096 bytes) because the NVMe controller
-    /// reads or writes data in block size which will cause unexpected memory access.
+    /// It may start anywhere within a page (currently always 4096 bytes):
+    /// PRP entry 1 keeps the offset and covers the rest of the first page,
+    /// and every further entry points to the start of a following page.
     pub(crate) fn create<A: Allocator>(
         &mut self,
         allocator: &A,
@@ -201,18 +289,16 @@ impl PrpManager {
             return Err(Error::NotAlignedToDword);
         }
 
+        let offset = address & 0xfff;
         let prp1 = allocator.translate(address);
-        let count = ((address & 0xfff) + bytes).div_ceil(4096);
+        // Pages touched, including the partial first one
+        let count = (offset + bytes).div_ceil(4096);
 
-        if count == 1 {
+        if count <= 1 {
             return Ok(PrpResult::Single(prp1));
         }
 
-        if (address & 0xfff) != 0 {
-            return Err(Error::NotAlignedToPage);
-        }
-
-        let prp2_start = allocator.translate(address + 4096);
+        let prp2_start = allocator.translate(address - offset + 4096);
 
         if count == 2 {
             return Ok(PrpResult::Double(prp1, prp2_start));
@@ -231,15 +317,15 @@ impl PrpManager {
             let mut prp_list = self
                 .list_pool
                 .pop()
-                .unwrap_or_else(|| Dma::allocate(512, allocator));
+                .unwrap_or_else(|| Dma::allocate_near(512, allocator, self.hint));
             for i in 0..entries {
-                prp_list[i] = (prp2_start + (list_idx * 511 + i) * 4096) as u64;
+                prp_list[i] = (prp2_start + ((list_idx * 511 + i) * 4096) as u64).to_le();
             }
             prp_lists.push(prp_list);
         }
 
         for index in 0..prp_lists.len() - 1 {
-            prp_lists[index][511] = prp_lists[index + 1].phys_addr as u64;
+            prp_lists[index][511] = prp_lists[index + 1].phys_addr.to_le();
         }
 
         Ok(PrpResult::List(prp1, prp_lists))
@@ -263,3 +349,225 @@ impl PrpManager {
         }
     }
 }
+
+/// A pool of DMA buffers for admin command data.
+///
+/// Each admin command checks out its own buffer, so concurrent admin users
+/// never share memory, and transfers may span several pages. Returned
+/// buffers are cached for reuse up to the pool capacity.
+pub(crate) struct DmaPool<A: Allocator> {
+    allocator: Arc<A>,
+    free: Mutex<Vec<Dma<u8>>>,
+    prp_manager: Mutex<PrpManager>,
+    capacity: usize,
+}
+
+impl<A: Allocator> DmaPool<A> {
+    /// Creates a pool caching at most `capacity` free buffers.
+    pub(crate) fn new(allocator: Arc<A>, capacity: usize) -> Self {
+        Self {
+            allocator,
+            free: Mutex::new(Vec::with_capacity(capacity)),
+            prp_manager: Mutex::new(PrpManager::default()),
+            capacity,
+        }
+    }
+
+    /// Checks out a buffer of `len` bytes.
+    ///
+    /// The smallest cached buffer that fits is reused, otherwise a new one
+    /// is allocated.
+    pub(crate) fn get(&self, len: usize) -> Result<PoolBuffer<'_, A>> {
+        let size = len.max(1).div_ceil(4096) * 4096;
+        let cached = {
+            let mut free = self.free.lock();
+            free.iter()
+                .enumerate()
+                .filter(|(_, dma)| dma.len() >= size)
+                .min_by_key(|(_, dma)| dma.len())
+                .map(|(index, _)| index)
+                .map(|index| free.swap_remove(index))
+        };
+        let dma = cached.unwrap_or_else(|| Dma::allocate(size, self.allocator.as_ref()));
+
+        let prp = self.prp_manager.lock().create(self.allocator.as_ref(), dma.addr as usize, size);
+        match prp {
+            Ok(prp) => Ok(PoolBuffer { pool: self, dma: Some(dma), prp: Some(prp), len }),
+            Err(error) => {
+                self.put(dma);
+                Err(error)
+            }
+        }
+    }
+
+    /// Frees all cached buffers.
+    pub(crate) fn clear(&self) {
+        for dma in self.free.lock().drain(..) {
+            dma.deallocate(self.allocator.as_ref());
+        }
+    }
+
+    /// Returns a buffer to the cache, or frees it if the cache is full.
+    fn put(&self, dma: Dma<u8>) {
+        let mut free = self.free.lock();
+        if free.len() < self.capacity {
+            free.push(dma);
+        } else {
+            dma.deallocate(self.allocator.as_ref());
+        }
+    }
+}
+
+/// A DMA buffer checked out of a `DmaPool`.
+///
+/// Dereferences to the requested length and goes back to the pool on drop.
+pub(crate) struct PoolBuffer<'a, A: Allocator> {
+    pool: &'a DmaPool<A>,
+    dma: Option<Dma<u8>>,
+    prp: Option<PrpResult>,
+    len: usize,
+}
+
+impl<A: Allocator> PoolBuffer<'_, A> {
+    /// Physical address of the buffer.
+    pub(crate) fn phys_addr(&self) -> u64 {
+        self.prp().0
+    }
+
+    /// PRP entries describing the buffer.
+    pub(crate) fn prp(&self) -> (u64, u64) {
+        self.prp.as_ref().map_or((0, 0), PrpResult::get_prp)
+    }
+}
+
+impl<A: Allocator> Deref for PoolBuffer<'_, A> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.dma.as_ref().map_or(&[], |dma| &dma[..self.len])
+    }
+}
+
+impl<A: Allocator> DerefMut for PoolBuffer<'_, A> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let len = self.len;
+        self.dma.as_mut().map_or(&mut [], |dma| &mut dma[..len])
+    }
+}
+
+impl<A: Allocator> Drop for PoolBuffer<'_, A> {
+    fn drop(&mut self) {
+        if let Some(prp) = self.prp.take() {
+            self.pool.prp_manager.lock().release(prp, self.pool.allocator.as_ref());
+        }
+        if let Some(dma) = self.dma.take() {
+            self.pool.put(dma);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::mock::{MockAllocator, PHYS_OFFSET};
+
+    /// Page-aligned address the data buffers are placed relative to.
+    const PAGE: usize = 0x10_0000;
+
+    /// Collect every PRP entry of a result in order.
+    fn entries(result: &PrpResult) -> Vec<u64> {
+        match result {
+            PrpResult::Single(prp1) => vec![*prp1],
+            PrpResult::Double(prp1, prp2) => vec![*prp1, *prp2],
+            PrpResult::List(prp1, lists) => {
+                let mut entries = vec![*prp1];
+                for (index, list) in lists.iter().enumerate() {
+                    // Every list but the last ends with a pointer to the next one
+                    let used = if index + 1 < lists.len() {
+                        assert_eq!(u64::from_le(list[511]), lists[index + 1].phys_addr);
+                        511
+                    } else {
+                        512
+                    };
+                    // Unused entries of the zeroed test lists stay zero
+                    let filled = list[..used].iter().map(|&e| u64::from_le(e)).filter(|&e| e != 0);
+                    entries.extend(filled);
+                }
+                entries
+            }
+        }
+    }
+
+    /// Expected entries of a transfer of `bytes` from `address`.
+    fn expected(address: usize, bytes: usize) -> Vec<u64> {
+        let first_page = address & !0xfff;
+        let pages = ((address & 0xfff) + bytes).div_ceil(4096);
+        let mut entries = vec![address as u64 + PHYS_OFFSET];
+        entries.extend((1..pages).map(|page| (first_page + page * 4096) as u64 + PHYS_OFFSET));
+        entries
+    }
+
+    fn create(address: usize, bytes: usize) -> PrpResult {
+        PrpManager::default().create(&MockAllocator, address, bytes).unwrap()
+    }
+
+    #[test]
+    fn unaligned_start_within_one_page() {
+        let result = create(PAGE + 0x200, 0xe00);
+        assert!(matches!(result, PrpResult::Single(_)));
+        assert_eq!(entries(&result), expected(PAGE + 0x200, 0xe00));
+    }
+
+    #[test]
+    fn unaligned_start_crossing_a_page() {
+        // One block starting mid-page touches two pages
+        let result = create(PAGE + 0x200, 4096);
+        assert!(matches!(result, PrpResult::Double(..)));
+        assert_eq!(entries(&result), expected(PAGE + 0x200, 4096));
+    }
+
+    #[test]
+    fn aligned_two_pages() {
+        let result = create(PAGE, 8192);
+        assert!(matches!(result, PrpResult::Double(..)));
+        assert_eq!(entries(&result), expected(PAGE, 8192));
+    }
+
+    #[test]
+    fn unaligned_start_needs_one_more_entry() {
+        // Three pages of data starting mid-page span four pages
+        let result = create(PAGE + 0x800, 3 * 4096);
+        assert!(matches!(&result, PrpResult::List(_, lists) if lists.len() == 1));
+        assert_eq!(entries(&result), expected(PAGE + 0x800, 3 * 4096));
+    }
+
+    #[test]
+    fn unaligned_start_chains_lists() {
+        // 512 pages from mid-page need 512 list entries, more than a list holds
+        // once it has to point to the next one
+        let bytes = 512 * 4096;
+        let result = create(PAGE + 4, bytes);
+        assert!(matches!(&result, PrpResult::List(_, lists) if lists.len() == 1));
+        assert_eq!(entries(&result), expected(PAGE + 4, bytes));
+
+        let bytes = 513 * 4096;
+        let result = create(PAGE + 4, bytes);
+        assert!(matches!(&result, PrpResult::List(_, lists) if lists.len() == 2));
+        assert_eq!(entries(&result), expected(PAGE + 4, bytes));
+    }
+
+    #[test]
+    fn unaligned_end_is_not_padded() {
+        // Ending mid-page does not add an entry
+        let result = create(PAGE + 0x800, 4096 - 0x800 + 4096 + 0x10);
+        assert_eq!(entries(&result).len(), 3);
+    }
+
+    #[test]
+    fn rejects_unaligned_dword() {
+        let result = PrpManager::default().create(&MockAllocator, PAGE + 2, 512);
+        assert!(matches!(result, Err(Error::NotAlignedToDword)));
+    }
+}
@@ -0,0 +1,97 @@
+//! Command latency histograms.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of latency buckets.
+///
+/// Bucket `i` counts latencies in `[2^i, 2^(i+1))` microseconds, except
+/// bucket 0, which also counts zero. The last bucket is open ended.
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Snapshot of a latency histogram.
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyStats {
+    /// Command counts per power-of-two microsecond bucket
+    pub buckets: [u64; LATENCY_BUCKETS],
+    /// Number of commands measured
+    pub count: u64,
+    /// Sum of all latencies in microseconds
+    pub total_us: u64,
+    /// Highest latency in microseconds
+    pub max_us: u64,
+}
+
+impl LatencyStats {
+    /// Average latency in microseconds.
+    pub fn average_us(&self) -> u64 {
+        self.total_us.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Latency below which `percent` of the commands completed.
+    ///
+    /// Reported as the upper bound of the bucket the percentile falls in,
+    /// capped at the highest latency seen.
+    pub fn percentile_us(&self, percent: f64) -> u64 {
+        let exact = self.count as f64 * percent.clamp(0.0, 100.0) / 100.0;
+        // Round up without `f64::ceil`, which needs std
+        let target = exact as u64 + u64::from((exact as u64 as f64) < exact);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return ((1u64 << (index + 1)) - 1).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+/// Lock-free latency histogram.
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+            count: AtomicU64::new(0),
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Record a command latency.
+    pub(crate) fn record(&self, latency_us: u64) {
+        let index = (u64::BITS - latency_us.leading_zeros()).saturating_sub(1) as usize;
+        self.buckets[index.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(latency_us, Ordering::Relaxed);
+        self.max_us.fetch_max(latency_us, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the histogram.
+    pub(crate) fn snapshot(&self) -> LatencyStats {
+        LatencyStats {
+            buckets: core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Clear the histogram.
+    pub(crate) fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.total_us.store(0, Ordering::Relaxed);
+        self.max_us.store(0, Ordering::Relaxed);
+    }
+}
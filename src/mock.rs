@@ -0,0 +1,25 @@
+//! Test doubles shared by the unit tests.
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc};
+
+use crate::memory::Allocator;
+
+/// Distance between the mock virtual and physical address spaces.
+pub(crate) const PHYS_OFFSET: u64 = 0x1_0000_0000;
+
+/// Heap memory standing in for DMA memory, mapped at `PHYS_OFFSET`.
+pub(crate) struct MockAllocator;
+
+impl Allocator for MockAllocator {
+    fn translate(&self, addr: usize) -> u64 {
+        addr as u64 + PHYS_OFFSET
+    }
+
+    unsafe fn allocate(&self, size: usize) -> usize {
+        unsafe { alloc_zeroed(Layout::from_size_align(size, 4096).unwrap()) as usize }
+    }
+
+    unsafe fn deallocate(&self, addr: usize, size: usize) {
+        unsafe { dealloc(addr as *mut u8, Layout::from_size_align(size, 4096).unwrap()) }
+    }
+}
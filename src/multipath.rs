@@ -1,11 +1,13 @@
 //! NVMe Multipath and Rapid Path Failure Recovery (RPFR) module for NVMe 2.3.
 
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
-use crate::error::{Error, Result};
+use crate::clock::Clock;
+use crate::error::{Error, Result, StatusCode, StatusCodeType};
 
 /// Path state for multipath.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -39,6 +41,46 @@ pub enum AnaState {
     Change = 0x0F,
 }
 
+/// Origin of a failed command's status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathErrorKind {
+    /// The media or data integrity check failed
+    Media,
+    /// The path or transport between host and controller failed, including
+    /// ANA path errors
+    Transport,
+    /// The command itself failed
+    Command,
+}
+
+impl PathErrorKind {
+    /// Classify a command status.
+    pub fn classify(status: StatusCode) -> Self {
+        match (status.sct, status.sc) {
+            (StatusCodeType::MediaError, _) => Self::Media,
+            (StatusCodeType::PathError, _) | (StatusCodeType::Generic, 0x21) => Self::Transport,
+            _ => Self::Command,
+        }
+    }
+}
+
+/// A failed command and where it was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathErrorRecord {
+    /// Path ID
+    pub path_id: u32,
+    /// Controller ID of the path
+    pub controller_id: u16,
+    /// Submission queue ID
+    pub qid: u16,
+    /// Command status
+    pub status: StatusCode,
+    /// Origin of the status
+    pub kind: PathErrorKind,
+    /// Time the error was recorded
+    pub timestamp: u64,
+}
+
 /// Controller path information.
 #[derive(Debug)]
 pub struct ControllerPath {
@@ -62,6 +104,15 @@ pub struct ControllerPath {
     pub error_count: AtomicU32,
     /// Last access timestamp
     pub last_access: AtomicU64,
+    /// Number of I/Os in flight through this path
+    pub outstanding: AtomicU32,
+    /// Number of media errors on this path
+    pub media_errors: AtomicU32,
+    /// Number of transport errors on this path
+    pub transport_errors: AtomicU32,
+    /// Most recent error recorded with `MultipathController::record_error`,
+    /// read through `PathStats`
+    last_error: Option<PathErrorRecord>,
 }
 
 impl ControllerPath {
@@ -78,6 +129,10 @@ impl ControllerPath {
             io_count: AtomicU64::new(0),
             error_count: AtomicU32::new(0),
             last_access: AtomicU64::new(0),
+            outstanding: AtomicU32::new(0),
+            media_errors: AtomicU32::new(0),
+            transport_errors: AtomicU32::new(0),
+            last_error: None,
         }
     }
 
@@ -106,8 +161,66 @@ impl ControllerPath {
         self.last_access.store(timestamp, Ordering::Relaxed);
     }
 
+    /// Get the latency estimate at `timestamp`.
+    ///
+    /// The estimate halves for each `half_life_us` the path has been idle,
+    /// so a path once slow is tried again instead of keeping its score.
+    /// A half-life of 0 turns the decay off.
+    pub fn latency_at(&self, timestamp: u64, half_life_us: u64) -> u32 {
+        let latency = self.latency_us.load(Ordering::Relaxed);
+        if half_life_us == 0 {
+            return latency;
+        }
+        let idle_us = timestamp.saturating_sub(self.last_access.load(Ordering::Relaxed));
+        latency.checked_shr((idle_us / half_life_us).min(32) as u32).unwrap_or(0)
+    }
+
+    /// Get the statistics of the path.
+    fn stats(&self) -> PathStats {
+        PathStats {
+            path_id: self.path_id,
+            controller_id: self.controller_id,
+            state: self.state,
+            ana_state: self.ana_state,
+            io_count: self.io_count.load(Ordering::Relaxed),
+            error_count: self.error_count.load(Ordering::Relaxed),
+            media_errors: self.media_errors.load(Ordering::Relaxed),
+            transport_errors: self.transport_errors.load(Ordering::Relaxed),
+            last_error: self.last_error,
+            average_latency_us: self.latency_us.load(Ordering::Relaxed),
+        }
+    }
+
     /// Get path score for selection (lower is better).
     pub fn get_score(&self) -> u32 {
+        self.score(self.latency_us.load(Ordering::Relaxed))
+    }
+
+    /// Get path score at `timestamp`, with the latency decayed as in
+    /// `latency_at`.
+    pub fn score_at(&self, timestamp: u64, half_life_us: u64) -> u32 {
+        self.score(self.latency_at(timestamp, half_life_us))
+    }
+
+    /// Get the weighted cost of the path at `timestamp` (lower is better).
+    ///
+    /// Adds the weighted queue depth, latency decayed as in `latency_at`,
+    /// and ANA state, saturating instead of overflowing.
+    pub fn weighted_cost(&self, weights: &PathWeights, timestamp: u64, half_life_us: u64) -> u64 {
+        let ana_steps: u64 = match self.ana_state {
+            AnaState::Optimized => 0,
+            AnaState::NonOptimized => 1,
+            _ => 2,
+        };
+        let outstanding = self.outstanding.load(Ordering::Relaxed) as u64;
+        let latency = self.latency_at(timestamp, half_life_us) as u64;
+
+        (weights.queue_depth as u64 * outstanding)
+            .saturating_add(weights.latency as u64 * latency)
+            .saturating_add(weights.ana as u64 * ana_steps)
+    }
+
+    fn score(&self, latency_us: u32) -> u32 {
         if !self.is_usable() {
             return u32::MAX;
         }
@@ -115,7 +228,7 @@ impl ControllerPath {
         let mut score = self.priority as u32 * 1000;
 
         // Add latency component
-        score += self.latency_us.load(Ordering::Relaxed);
+        score += latency_us;
 
         // Add error rate component
         let io_count = self.io_count.load(Ordering::Relaxed);
@@ -136,8 +249,23 @@ impl ControllerPath {
     }
 }
 
+/// Checks whether a failed path works again.
+///
+/// Implemented by `NVMeDevice`, which sends a Get Features command to the
+/// path's controller.
+pub trait PathProbe: Send + Sync {
+    /// Send a command through the path and report whether it succeeded.
+    fn probe(&self) -> bool;
+}
+
+/// A path's probe and when it last ran.
+struct PathProbeState {
+    probe: Arc<dyn PathProbe>,
+    last_run: Option<u64>,
+}
+
 /// Path failure recovery configuration.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct RpfrConfig {
     /// Enable Rapid Path Failure Recovery
     pub enabled: bool,
@@ -150,7 +278,12 @@ pub struct RpfrConfig {
     /// Enable automatic path failback
     pub auto_failback: bool,
     /// Path health check interval in seconds
+    ///
+    /// A failed path is probed at most once per interval.
     pub health_check_interval_sec: u32,
+    /// Time in milliseconds after which an idle path's latency estimate
+    /// halves (0 = never)
+    pub latency_half_life_ms: u32,
 }
 
 impl Default for RpfrConfig {
@@ -162,6 +295,7 @@ impl Default for RpfrConfig {
             recovery_timeout_ms: 30000,
             auto_failback: true,
             health_check_interval_sec: 60,
+            latency_half_life_ms: 10000,
         }
     }
 }
@@ -205,6 +339,45 @@ impl AnaGroup {
     }
 }
 
+/// Weights of `PathSelector::Weighted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathWeights {
+    /// Cost of each I/O in flight through the path
+    pub queue_depth: u32,
+    /// Cost of each microsecond of latency
+    pub latency: u32,
+    /// Cost of a non-optimized path; a path in an ANA change costs twice this
+    pub ana: u32,
+}
+
+impl Default for PathWeights {
+    fn default() -> Self {
+        Self {
+            queue_depth: 100,
+            latency: 1,
+            ana: 10_000,
+        }
+    }
+}
+
+/// A path selection strategy supplied by the user.
+///
+/// Used by `PathSelector::Custom` once set with
+/// `MultipathController::set_selector_policy`.
+pub trait PathSelectorPolicy: Send + Sync {
+    /// Pick one of the usable `paths` for namespace `namespace_id`.
+    ///
+    /// Returns the index of the chosen path in `paths`, or `None` if none
+    /// is acceptable. Called with the paths locked, so it must not call
+    /// back into the multipath controller.
+    fn select(
+        &self,
+        namespace_id: u32,
+        paths: &[&ControllerPath],
+        timestamp: u64,
+    ) -> Option<usize>;
+}
+
 /// Path selector strategy.
 #[derive(Debug, Clone, Copy)]
 pub enum PathSelector {
@@ -218,6 +391,11 @@ pub enum PathSelector {
     BestScore,
     /// Use priority-based selection
     Priority,
+    /// Select the path with the lowest `ControllerPath::weighted_cost`
+    Weighted(PathWeights),
+    /// Use the policy set with `MultipathController::set_selector_policy`,
+    /// or `BestScore` while none is set
+    Custom,
 }
 
 /// Multipath I/O controller.
@@ -227,15 +405,25 @@ pub struct MultipathController {
     /// Active path index
     active_path: AtomicU32,
     /// RPFR configuration
-    rpfr_config: RpfrConfig,
+    rpfr_config: RwLock<RpfrConfig>,
     /// Path selection strategy
-    path_selector: PathSelector,
+    path_selector: RwLock<PathSelector>,
+    /// Path selection strategies overriding `path_selector` by namespace ID
+    namespace_selectors: RwLock<BTreeMap<u32, PathSelector>>,
     /// ANA groups
     ana_groups: Mutex<BTreeMap<u32, AnaGroup>>,
     /// Failed paths pending recovery
     failed_paths: Mutex<Vec<u32>>,
     /// Last path selection timestamp
     last_selection: AtomicU64,
+    /// Private namespaces and the controller each is attached to
+    private_namespaces: Mutex<BTreeMap<u32, u16>>,
+    /// Probes of the paths by path ID
+    probes: Mutex<BTreeMap<u32, PathProbeState>>,
+    /// Policy of `PathSelector::Custom`
+    policy: RwLock<Option<Arc<dyn PathSelectorPolicy>>>,
+    /// Time source of the health checks
+    clock: Mutex<Option<Arc<dyn Clock>>>,
 }
 
 impl MultipathController {
@@ -244,11 +432,16 @@ impl MultipathController {
         Self {
             paths: Mutex::new(Vec::new()),
             active_path: AtomicU32::new(0),
-            rpfr_config,
-            path_selector,
+            rpfr_config: RwLock::new(rpfr_config),
+            path_selector: RwLock::new(path_selector),
+            namespace_selectors: RwLock::new(BTreeMap::new()),
             ana_groups: Mutex::new(BTreeMap::new()),
             failed_paths: Mutex::new(Vec::new()),
             last_selection: AtomicU64::new(0),
+            private_namespaces: Mutex::new(BTreeMap::new()),
+            probes: Mutex::new(BTreeMap::new()),
+            policy: RwLock::new(None),
+            clock: Mutex::new(None),
         }
     }
 
@@ -269,25 +462,52 @@ impl MultipathController {
         }
     }
 
+    /// Register a namespace reached through controller `controller_id`.
+    ///
+    /// `shared` is `Namespace::is_shared`. A private namespace is not
+    /// aggregated across paths: only paths to its controller are selected
+    /// for it, and it is left out of ANA groups.
+    pub fn add_namespace(&self, namespace_id: u32, controller_id: u16, shared: bool) {
+        let mut private = self.private_namespaces.lock();
+        if shared {
+            private.remove(&namespace_id);
+        } else {
+            private.insert(namespace_id, controller_id);
+            for group in self.ana_groups.lock().values_mut() {
+                group.namespaces.retain(|&nsid| nsid != namespace_id);
+            }
+        }
+    }
+
     /// Select the best path based on configured strategy.
-    pub fn select_path(&self, _namespace_id: u32, timestamp: u64) -> Result<u32> {
+    ///
+    /// Uses the namespace's selector override if it has one.
+    pub fn select_path(&self, namespace_id: u32, timestamp: u64) -> Result<u32> {
         let paths = self.paths.lock();
         if paths.is_empty() {
             return Err(Error::PathFailure);
         }
 
-        // Filter usable paths
+        // Filter usable paths, only to the owning controller for private namespaces
+        let owner = self.private_namespaces.lock().get(&namespace_id).copied();
         let usable_paths: Vec<_> = paths
             .iter()
-            .enumerate()
-            .filter(|(_, p)| p.is_usable())
+            .filter(|p| p.is_usable())
+            .filter(|p| owner.is_none_or(|id| p.controller_id == id))
             .collect();
 
         if usable_paths.is_empty() {
             return Err(Error::PathFailure);
         }
 
-        let selected_idx = match self.path_selector {
+        let half_life_us = self.rpfr_config.read().latency_half_life_ms as u64 * 1000;
+        let selector = self.namespace_selectors.read().get(&namespace_id).copied();
+        let selector = selector.unwrap_or_else(|| *self.path_selector.read());
+        let policy = match selector {
+            PathSelector::Custom => self.policy.read().clone(),
+            _ => None,
+        };
+        let selected_idx = match selector {
             PathSelector::RoundRobin => {
                 let current = self.active_path.load(Ordering::Relaxed) as usize;
                 (current + 1) % usable_paths.len()
@@ -295,43 +515,124 @@ impl MultipathController {
             PathSelector::LowestLatency => {
                 usable_paths
                     .iter()
-                    .min_by_key(|(_, p)| p.latency_us.load(Ordering::Relaxed))
-                    .map(|(idx, _)| *idx)
+                    .enumerate()
+                    .min_by_key(|(_, p)| p.latency_at(timestamp, half_life_us))
+                    .map(|(idx, _)| idx)
                     .unwrap_or(0)
             }
             PathSelector::LeastIo => {
                 usable_paths
                     .iter()
+                    .enumerate()
                     .min_by_key(|(_, p)| p.io_count.load(Ordering::Relaxed))
-                    .map(|(idx, _)| *idx)
+                    .map(|(idx, _)| idx)
                     .unwrap_or(0)
             }
-            PathSelector::BestScore => {
+            PathSelector::Custom if let Some(policy) = policy => {
+                policy
+                    .select(namespace_id, &usable_paths, timestamp)
+                    .filter(|&idx| idx < usable_paths.len())
+                    .ok_or(Error::PathFailure)?
+            }
+            PathSelector::BestScore | PathSelector::Custom => {
                 usable_paths
                     .iter()
-                    .min_by_key(|(_, p)| p.get_score())
-                    .map(|(idx, _)| *idx)
+                    .enumerate()
+                    .min_by_key(|(_, p)| p.score_at(timestamp, half_life_us))
+                    .map(|(idx, _)| idx)
                     .unwrap_or(0)
             }
             PathSelector::Priority => {
                 usable_paths
                     .iter()
+                    .enumerate()
                     .min_by_key(|(_, p)| p.priority)
-                    .map(|(idx, _)| *idx)
+                    .map(|(idx, _)| idx)
+                    .unwrap_or(0)
+            }
+            PathSelector::Weighted(weights) => {
+                usable_paths
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, p)| p.weighted_cost(&weights, timestamp, half_life_us))
+                    .map(|(idx, _)| idx)
                     .unwrap_or(0)
             }
         };
 
-        let selected_path = &usable_paths[selected_idx].1;
+        // Every selector picks a position within the usable paths
+        let selected_path = usable_paths[selected_idx];
         self.active_path.store(selected_path.path_id, Ordering::Relaxed);
         self.last_selection.store(timestamp, Ordering::Relaxed);
 
         Ok(selected_path.path_id)
     }
 
+    /// Count an I/O command submitted through a path as in flight.
+    ///
+    /// `NVMeDevice` calls it for each command once the multipath controller
+    /// is set with `NVMeDevice::set_multipath`, and `finish_io` when the
+    /// command completes.
+    pub fn start_io(&self, path_id: u32) {
+        if let Some(path) = self.paths.lock().iter().find(|p| p.path_id == path_id) {
+            path.outstanding.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count an I/O command started with `start_io` as completed.
+    pub fn finish_io(&self, path_id: u32) {
+        if let Some(path) = self.paths.lock().iter().find(|p| p.path_id == path_id) {
+            let _ = path.outstanding.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                n.checked_sub(1)
+            });
+        }
+    }
+
+    /// Record the latency and outcome of an I/O command sent through a path.
+    ///
+    /// `NVMeDevice` calls it for each command once a clock and the
+    /// multipath controller are set with `NVMeDevice::set_multipath`.
+    pub fn record_io(&self, path_id: u32, latency_us: u32, success: bool, timestamp: u64) {
+        if let Some(path) = self.paths.lock().iter().find(|p| p.path_id == path_id) {
+            path.update_metrics(latency_us, success, timestamp);
+        }
+    }
+
+    /// Record a failed command sent through a path on queue `qid`.
+    ///
+    /// Counts the error by its kind and keeps it as the path's last error.
+    /// Returns the error with its origin, or `None` for an unknown path.
+    /// `NVMeDevice` calls it for each failed I/O command once the multipath
+    /// controller is set with `NVMeDevice::set_multipath`.
+    pub fn record_error(
+        &self,
+        path_id: u32,
+        qid: u16,
+        status: StatusCode,
+        timestamp: u64,
+    ) -> Option<PathErrorRecord> {
+        let mut paths = self.paths.lock();
+        let path = paths.iter_mut().find(|p| p.path_id == path_id)?;
+        let record = PathErrorRecord {
+            path_id,
+            controller_id: path.controller_id,
+            qid,
+            status,
+            kind: PathErrorKind::classify(status),
+            timestamp,
+        };
+        match record.kind {
+            PathErrorKind::Media => path.media_errors.fetch_add(1, Ordering::Relaxed),
+            PathErrorKind::Transport => path.transport_errors.fetch_add(1, Ordering::Relaxed),
+            PathErrorKind::Command => 0,
+        };
+        path.last_error = Some(record);
+        Some(record)
+    }
+
     /// Handle path failure with RPFR.
     pub fn handle_path_failure(&self, path_id: u32, timestamp: u64) -> Result<u32> {
-        if !self.rpfr_config.enabled {
+        if !self.rpfr_config.read().enabled {
             return Err(Error::PathFailure);
         }
 
@@ -356,41 +657,141 @@ impl MultipathController {
         self.select_path(0, timestamp)
     }
 
+    /// Handle an ANA path error reported by a command on a path.
+    ///
+    /// Records the ANA state the error implies for the path, then fails
+    /// the path over with `handle_path_failure`. A path lost persistently
+    /// stays unusable after recovery, until an ANA group update.
+    pub fn handle_path_error(&self, path_id: u32, state: AnaState, timestamp: u64) -> Result<u32> {
+        if let Some(path) = self.paths.lock().iter_mut().find(|p| p.path_id == path_id) {
+            path.ana_state = state;
+        }
+        self.handle_path_failure(path_id, timestamp)
+    }
+
+    /// Set the probe that checks path `path_id` before it is recovered.
+    pub fn set_probe(&self, path_id: u32, probe: Arc<dyn PathProbe>) {
+        self.probes.lock().insert(path_id, PathProbeState { probe, last_run: None });
+    }
+
+    /// Set the policy of `PathSelector::Custom`, returning the previous one.
+    pub fn set_selector_policy(
+        &self,
+        policy: Option<Arc<dyn PathSelectorPolicy>>,
+    ) -> Option<Arc<dyn PathSelectorPolicy>> {
+        core::mem::replace(&mut *self.policy.write(), policy)
+    }
+
+    /// Set the time source of `check_path_health`.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock() = Some(clock);
+    }
+
     /// Attempt to recover failed paths.
+    ///
+    /// A failed path is recovered once the recovery timeout has passed and,
+    /// if it has a probe, the probe succeeds. Probes run at most once per
+    /// health check interval. A path that failed during an ANA transition
+    /// is assumed optimized again once recovered.
     pub fn recover_failed_paths(&self, timestamp: u64) -> Vec<u32> {
-        let mut recovered = Vec::new();
+        let recovery_timeout_ms = self.rpfr_config.read().recovery_timeout_ms as u64;
+
+        // Failed paths that still exist and whose recovery timeout has passed
+        let due: Vec<u32> = {
+            let mut failed_paths = self.failed_paths.lock();
+            let paths = self.paths.lock();
+            failed_paths.retain(|&path_id| paths.iter().any(|p| p.path_id == path_id));
+            failed_paths
+                .iter()
+                .copied()
+                .filter(|&path_id| {
+                    paths.iter().find(|p| p.path_id == path_id).is_some_and(|path| {
+                        let last_access = path.last_access.load(Ordering::Relaxed);
+                        let elapsed_ms = timestamp.saturating_sub(last_access) / 1000;
+                        elapsed_ms >= recovery_timeout_ms
+                    })
+                })
+                .collect()
+        };
+
+        // Probe without holding the path locks, as probes send commands
+        let recovered: Vec<u32> =
+            due.into_iter().filter(|&path_id| self.probe_path(path_id, timestamp)).collect();
+
         let mut failed_paths = self.failed_paths.lock();
         let mut paths = self.paths.lock();
-
-        failed_paths.retain(|&path_id| {
+        for &path_id in &recovered {
             if let Some(path) = paths.iter_mut().find(|p| p.path_id == path_id) {
-                // Check if enough time has passed for recovery
-                let last_access = path.last_access.load(Ordering::Relaxed);
-                let elapsed_ms = (timestamp - last_access) / 1000;
-
-                if elapsed_ms >= self.rpfr_config.recovery_timeout_ms as u64 {
-                    // Attempt recovery
-                    path.state = PathState::Active;
-                    path.error_count.store(0, Ordering::Relaxed);
-                    recovered.push(path_id);
-                    false // Remove from failed list
-                } else {
-                    true // Keep in failed list
+                path.state = PathState::Active;
+                if path.ana_state == AnaState::Change {
+                    path.ana_state = AnaState::Optimized;
                 }
-            } else {
-                false // Path no longer exists
+                path.error_count.store(0, Ordering::Relaxed);
             }
-        });
+        }
+        failed_paths.retain(|path_id| !recovered.contains(path_id));
 
         recovered
     }
 
+    /// Probe failed paths whose health check is due and recover the
+    /// working ones, timed by the clock set with `set_clock`.
+    ///
+    /// Returns the recovered paths. Fails with `Error::ClockNotSet`
+    /// without a clock.
+    pub fn check_path_health(&self) -> Result<Vec<u32>> {
+        let clock = self.clock.lock().clone().ok_or(Error::ClockNotSet)?;
+        Ok(self.recover_failed_paths(clock.now_us()))
+    }
+
+    /// Run the probe of a path if its health check is due.
+    ///
+    /// A path without a probe passes. A path whose probe ran less than a
+    /// health check interval ago fails until the next one.
+    fn probe_path(&self, path_id: u32, timestamp: u64) -> bool {
+        let interval_us = self.rpfr_config.read().health_check_interval_sec as u64 * 1_000_000;
+        let probe = {
+            let mut probes = self.probes.lock();
+            let Some(state) = probes.get_mut(&path_id) else {
+                return true;
+            };
+            if state.last_run.is_some_and(|at| timestamp.saturating_sub(at) < interval_us) {
+                return false;
+            }
+            state.last_run = Some(timestamp);
+            state.probe.clone()
+        };
+        probe.probe()
+    }
+
     /// Update ANA group information.
-    pub fn update_ana_group(&self, group: AnaGroup) {
+    ///
+    /// Namespaces registered as private are left out of the group.
+    pub fn update_ana_group(&self, mut group: AnaGroup) {
+        let private = self.private_namespaces.lock();
+        group.namespaces.retain(|nsid| !private.contains_key(nsid));
         let mut groups = self.ana_groups.lock();
         groups.insert(group.group_id, group);
     }
 
+    /// Place a namespace in ANA group `group_id`, taking it out of any other.
+    ///
+    /// The group is created if it does not exist. Private namespaces and
+    /// group ID 0, which means the namespace reports no group, are ignored.
+    pub fn assign_ana_group(&self, namespace_id: u32, group_id: u32) {
+        if group_id == 0 || self.private_namespaces.lock().contains_key(&namespace_id) {
+            return;
+        }
+        let mut groups = self.ana_groups.lock();
+        for group in groups.values_mut().filter(|group| group.group_id != group_id) {
+            group.namespaces.retain(|&nsid| nsid != namespace_id);
+        }
+        groups
+            .entry(group_id)
+            .or_insert_with(|| AnaGroup::new(group_id))
+            .add_namespace(namespace_id);
+    }
+
     /// Get ANA state for namespace and controller.
     pub fn get_ana_state(&self, namespace_id: u32, controller_id: u16) -> Option<AnaState> {
         let groups = self.ana_groups.lock();
@@ -407,42 +808,59 @@ impl MultipathController {
     /// Get path statistics.
     pub fn get_path_stats(&self, path_id: u32) -> Option<PathStats> {
         let paths = self.paths.lock();
-        paths.iter().find(|p| p.path_id == path_id).map(|p| PathStats {
-            path_id: p.path_id,
-            controller_id: p.controller_id,
-            state: p.state,
-            ana_state: p.ana_state,
-            io_count: p.io_count.load(Ordering::Relaxed),
-            error_count: p.error_count.load(Ordering::Relaxed),
-            average_latency_us: p.latency_us.load(Ordering::Relaxed),
-        })
+        paths.iter().find(|p| p.path_id == path_id).map(ControllerPath::stats)
     }
 
     /// Get all path statistics.
     pub fn get_all_path_stats(&self) -> Vec<PathStats> {
         let paths = self.paths.lock();
-        paths
-            .iter()
-            .map(|p| PathStats {
-                path_id: p.path_id,
-                controller_id: p.controller_id,
-                state: p.state,
-                ana_state: p.ana_state,
-                io_count: p.io_count.load(Ordering::Relaxed),
-                error_count: p.error_count.load(Ordering::Relaxed),
-                average_latency_us: p.latency_us.load(Ordering::Relaxed),
-            })
-            .collect()
+        paths.iter().map(ControllerPath::stats).collect()
     }
 
     /// Get RPFR configuration.
-    pub fn get_rpfr_config(&self) -> &RpfrConfig {
-        &self.rpfr_config
+    pub fn get_rpfr_config(&self) -> RpfrConfig {
+        *self.rpfr_config.read()
+    }
+
+    /// Update RPFR configuration, returning the previous one.
+    ///
+    /// Takes effect for path selections and recoveries started afterwards.
+    pub fn update_rpfr_config(&self, config: RpfrConfig) -> RpfrConfig {
+        core::mem::replace(&mut *self.rpfr_config.write(), config)
+    }
+
+    /// Get the path selection strategy.
+    pub fn path_selector(&self) -> PathSelector {
+        *self.path_selector.read()
     }
 
-    /// Update RPFR configuration.
-    pub fn update_rpfr_config(&mut self, config: RpfrConfig) {
-        self.rpfr_config = config;
+    /// Set the path selection strategy, returning the previous one.
+    ///
+    /// Namespaces with a selector override keep theirs.
+    pub fn set_path_selector(&self, selector: PathSelector) -> PathSelector {
+        core::mem::replace(&mut *self.path_selector.write(), selector)
+    }
+
+    /// Get the path selection strategy override of a namespace.
+    pub fn namespace_selector(&self, namespace_id: u32) -> Option<PathSelector> {
+        self.namespace_selectors.read().get(&namespace_id).copied()
+    }
+
+    /// Override the path selection strategy of a namespace, returning the
+    /// previous override.
+    ///
+    /// `None` removes the override, so the namespace uses the controller's
+    /// strategy again.
+    pub fn set_namespace_selector(
+        &self,
+        namespace_id: u32,
+        selector: Option<PathSelector>,
+    ) -> Option<PathSelector> {
+        let mut selectors = self.namespace_selectors.write();
+        match selector {
+            Some(selector) => selectors.insert(namespace_id, selector),
+            None => selectors.remove(&namespace_id),
+        }
     }
 }
 
@@ -461,6 +879,56 @@ pub struct PathStats {
     pub io_count: u64,
     /// Total error count
     pub error_count: u32,
+    /// Media errors
+    pub media_errors: u32,
+    /// Transport errors
+    pub transport_errors: u32,
+    /// Most recent error
+    pub last_error: Option<PathErrorRecord>,
     /// Average latency in microseconds
     pub average_latency_us: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Paths to controllers 1 and 2, with IDs 10 and 20.
+    fn controller(selector: PathSelector) -> MultipathController {
+        let multipath = MultipathController::new(RpfrConfig::default(), selector);
+        multipath.add_path(ControllerPath::new(1, 10, 0));
+        multipath.add_path(ControllerPath::new(2, 20, 0));
+        multipath
+    }
+
+    #[test]
+    fn private_namespace_selects_owner_path() {
+        let selectors = [
+            PathSelector::RoundRobin,
+            PathSelector::LowestLatency,
+            PathSelector::LeastIo,
+            PathSelector::BestScore,
+            PathSelector::Priority,
+            PathSelector::Weighted(PathWeights::default()),
+            PathSelector::Custom,
+        ];
+        for selector in selectors {
+            let multipath = controller(selector);
+            multipath.add_namespace(5, 2, false);
+            assert_eq!(multipath.select_path(5, 0).unwrap(), 20, "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn unusable_path_is_skipped() {
+        let multipath = MultipathController::new(RpfrConfig::default(), PathSelector::LeastIo);
+        let mut failed = ControllerPath::new(1, 10, 0);
+        failed.state = PathState::Failed;
+        multipath.add_path(failed);
+        multipath.add_path(ControllerPath::new(2, 20, 0));
+        multipath.add_path(ControllerPath::new(3, 30, 0));
+        multipath.paths.lock()[1].io_count.store(5, Ordering::Relaxed);
+
+        assert_eq!(multipath.select_path(1, 0).unwrap(), 30);
+    }
+}
@@ -0,0 +1,165 @@
+//! Software-enforced namespace open modes.
+
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use crate::cmd::IoHints;
+use crate::device::Namespace;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+use crate::queues::IoResult;
+
+/// How a namespace is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Reads only, shared with other non-exclusive handles
+    ReadOnly,
+    /// Reads and writes, shared with other non-exclusive handles
+    ReadWrite,
+    /// Reads and writes, with no other handle open
+    Exclusive,
+}
+
+/// Open handle bookkeeping of a namespace.
+#[derive(Default)]
+pub(crate) struct OpenTracker {
+    /// Number of open handles and whether the one handle is exclusive
+    state: Mutex<(usize, bool)>,
+}
+
+impl OpenTracker {
+    /// Register a new handle.
+    pub(crate) fn acquire(&self, mode: OpenMode) -> Result<()> {
+        let mut state = self.state.lock();
+        let (count, exclusive) = *state;
+        if exclusive || (mode == OpenMode::Exclusive && count != 0) {
+            return Err(Error::NamespaceBusy);
+        }
+        *state = (count + 1, mode == OpenMode::Exclusive);
+        Ok(())
+    }
+
+    /// Unregister a handle.
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock();
+        *state = (state.0.saturating_sub(1), false);
+    }
+}
+
+/// A namespace opened with `Namespace::open`.
+///
+/// Writes through a read-only handle fail with `Error::NamespaceReadOnly`.
+/// The mode is enforced by the driver only, so code that uses the
+/// `Namespace` directly is not restricted by it.
+pub struct NamespaceHandle<A: Allocator> {
+    ns: Arc<Namespace<A>>,
+    mode: OpenMode,
+}
+
+impl<A: Allocator> NamespaceHandle<A> {
+    /// Wrap a namespace whose open count already includes this handle.
+    pub(crate) fn new(ns: Arc<Namespace<A>>, mode: OpenMode) -> Self {
+        Self { ns, mode }
+    }
+
+    /// Get the mode the namespace was opened in.
+    pub fn mode(&self) -> OpenMode {
+        self.mode
+    }
+
+    /// Get the namespace ID.
+    pub fn id(&self) -> u32 {
+        self.ns.id()
+    }
+
+    /// Get the block size (in bytes).
+    pub fn block_size(&self) -> u64 {
+        self.ns.block_size()
+    }
+
+    /// Get the block count.
+    pub fn block_count(&self) -> u64 {
+        self.ns.block_count()
+    }
+
+    /// Read from the namespace.
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.ns.read(lba, buf)
+    }
+
+    /// Read from the namespace without waiting for submission queue space.
+    pub fn try_read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.ns.try_read(lba, buf)
+    }
+
+    /// Read from the namespace with dataset management hints.
+    pub fn read_with_hints(&self, lba: u64, buf: &mut [u8], hints: IoHints) -> Result<()> {
+        self.ns.read_with_hints(lba, buf, hints)
+    }
+
+    /// Write to the namespace.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.ns.write(lba, buf)
+    }
+
+    /// Write to the namespace without waiting for submission queue space.
+    pub fn try_write(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.ns.try_write(lba, buf)
+    }
+
+    /// Write to the namespace with dataset management hints.
+    pub fn write_with_hints(&self, lba: u64, buf: &[u8], hints: IoHints) -> Result<()> {
+        self.check_writable()?;
+        self.ns.write_with_hints(lba, buf, hints)
+    }
+
+    /// Commit data in the volatile write cache to non-volatile media.
+    pub fn flush(&self) -> Result<()> {
+        self.ns.flush()
+    }
+
+    /// Deallocate a block range.
+    pub fn trim(&self, lba: u64, block_count: u64) -> Result<()> {
+        self.check_writable()?;
+        self.ns.trim(lba, block_count)
+    }
+
+    /// Zero a block range without transferring data.
+    pub fn write_zeroes(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.check_writable()?;
+        self.ns.write_zeroes(lba, block_count)
+    }
+
+    /// Compare namespace data with `expected`.
+    pub fn compare(&self, lba: u64, expected: &[u8]) -> Result<bool> {
+        self.ns.compare(lba, expected)
+    }
+
+    /// Check the integrity of a block range without transferring data.
+    pub fn verify(&self, lba: u64, block_count: u16) -> Result<IoResult> {
+        self.ns.verify(lba, block_count)
+    }
+
+    /// Copy blocks within the namespace.
+    pub fn copy(&self, src_lba: u64, dst_lba: u64, block_count: u16) -> Result<IoResult> {
+        self.check_writable()?;
+        self.ns.copy(src_lba, dst_lba, block_count)
+    }
+
+    /// Fail if the handle does not allow writes.
+    fn check_writable(&self) -> Result<()> {
+        match self.mode {
+            OpenMode::ReadOnly => Err(Error::NamespaceReadOnly),
+            OpenMode::ReadWrite | OpenMode::Exclusive => Ok(()),
+        }
+    }
+}
+
+impl<A: Allocator> Drop for NamespaceHandle<A> {
+    fn drop(&mut self) {
+        self.ns.close();
+    }
+}
@@ -0,0 +1,199 @@
+//! Progress and cancellation of long-running operations.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::device::{AdminToken, CancelToken};
+use crate::error::Result;
+use crate::queues::IoResult;
+
+/// Progress is kept in hundredths of a percent.
+const PROGRESS_SCALE: u32 = 10000;
+
+/// Kind of a long-running operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    /// Sanitize, run by the controller
+    Sanitize,
+    /// Format NVM, run by the controller
+    Format,
+    /// Device self-test, run by the controller
+    SelfTest,
+    /// Firmware download and commit, driven by the host
+    FirmwareUpdate,
+    /// Verification of a namespace range, driven by the host
+    Scrub,
+}
+
+impl OperationKind {
+    /// Check whether the operation can be cancelled once started.
+    ///
+    /// Sanitize and Format NVM cannot be aborted, a self-test is aborted
+    /// with the Device Self-test command, and the host-driven operations
+    /// stop before their next command.
+    pub fn is_cancellable(self) -> bool {
+        !matches!(self, Self::Sanitize | Self::Format)
+    }
+}
+
+/// Snapshot of an operation's progress.
+#[derive(Debug, Clone, Copy)]
+pub struct OperationProgress {
+    /// Kind of the operation
+    pub kind: OperationKind,
+    /// Completion percentage, 0 while not reported
+    pub percent: f32,
+    /// Estimated time until completion in microseconds, `None` without a
+    /// clock or before any progress is reported
+    pub remaining_us: Option<u64>,
+    /// The operation is over
+    pub done: bool,
+    /// Cancellation was requested
+    pub cancelled: bool,
+}
+
+/// Shared state of an operation.
+struct OperationState {
+    kind: OperationKind,
+    /// Namespace the operation targets
+    nsid: u32,
+    /// Start time in microseconds
+    started_us: u64,
+    /// Time of the last progress update in microseconds
+    updated_us: AtomicU64,
+    /// Progress in hundredths of a percent
+    progress: AtomicU32,
+    done: AtomicBool,
+    cancel: CancelToken,
+    /// Admin command that started a controller-run operation, until it completes
+    token: Mutex<Option<AdminToken>>,
+}
+
+/// Handle to a long-running operation.
+///
+/// Sanitize, format and self-test return one when started; poll it with
+/// `NVMeDevice::poll_operation`. Firmware updates and scrubs run on the
+/// caller's thread and update a handle created with
+/// `NVMeDevice::new_operation`, so a clone of it can be watched and
+/// cancelled from elsewhere meanwhile.
+#[derive(Clone)]
+pub struct Operation {
+    state: Arc<OperationState>,
+}
+
+impl Operation {
+    /// Create a handle for an operation on namespace `nsid` started at `now_us`.
+    pub(crate) fn new(kind: OperationKind, nsid: u32, now_us: u64) -> Self {
+        Self {
+            state: Arc::new(OperationState {
+                kind,
+                nsid,
+                started_us: now_us,
+                updated_us: AtomicU64::new(now_us),
+                progress: AtomicU32::new(0),
+                done: AtomicBool::new(false),
+                cancel: CancelToken::new(),
+                token: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Create a handle for an operation started by an admin command.
+    pub(crate) fn started(kind: OperationKind, nsid: u32, now_us: u64, token: AdminToken) -> Self {
+        let operation = Self::new(kind, nsid, now_us);
+        *operation.state.token.lock() = Some(token);
+        operation
+    }
+
+    /// Get the kind of the operation.
+    pub fn kind(&self) -> OperationKind {
+        self.state.kind
+    }
+
+    /// Get the namespace the operation targets.
+    pub fn nsid(&self) -> u32 {
+        self.state.nsid
+    }
+
+    /// Get the completion percentage.
+    pub fn percent(&self) -> f32 {
+        self.state.progress.load(Ordering::Acquire) as f32 * 100.0 / PROGRESS_SCALE as f32
+    }
+
+    /// Check whether the operation is over.
+    pub fn is_done(&self) -> bool {
+        self.state.done.load(Ordering::Acquire)
+    }
+
+    /// Check whether cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancel.is_cancelled()
+    }
+
+    /// Get the cancellation token checked by host-driven operations.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.state.cancel
+    }
+
+    /// Estimate the time until completion, extrapolating the progress so far.
+    pub fn remaining_us(&self) -> Option<u64> {
+        let progress = self.state.progress.load(Ordering::Acquire) as u64;
+        let updated = self.state.updated_us.load(Ordering::Acquire);
+        let elapsed = updated.saturating_sub(self.state.started_us);
+        if self.is_done() {
+            return Some(0);
+        }
+        if progress == 0 || elapsed == 0 {
+            return None;
+        }
+        Some(elapsed * (PROGRESS_SCALE as u64 - progress) / progress)
+    }
+
+    /// Take a snapshot of the progress.
+    pub fn progress(&self) -> OperationProgress {
+        OperationProgress {
+            kind: self.kind(),
+            percent: self.percent(),
+            remaining_us: self.remaining_us(),
+            done: self.is_done(),
+            cancelled: self.is_cancelled(),
+        }
+    }
+
+    /// Record `done` of `total` units completed at `now_us`.
+    pub(crate) fn update(&self, done: u64, total: u64, now_us: u64) {
+        let progress = match total {
+            0 => PROGRESS_SCALE,
+            total => (done.min(total) * PROGRESS_SCALE as u64 / total) as u32,
+        };
+        self.state.progress.store(progress, Ordering::Release);
+        self.state.updated_us.fetch_max(now_us, Ordering::AcqRel);
+    }
+
+    /// Mark the operation as over at `now_us`.
+    pub(crate) fn finish(&self, now_us: u64) {
+        self.state.updated_us.fetch_max(now_us, Ordering::AcqRel);
+        self.state.done.store(true, Ordering::Release);
+    }
+
+    /// Poll the admin command that started the operation with `poll`.
+    ///
+    /// Returns whether the command has completed. The token is dropped
+    /// once `poll` reports its completion or fails.
+    pub(crate) fn poll_token(
+        &self,
+        poll: impl FnOnce(&AdminToken) -> Result<Option<IoResult>>,
+    ) -> Result<bool> {
+        let mut token = self.state.token.lock();
+        let Some(current) = token.as_ref() else {
+            return Ok(true);
+        };
+        let result = poll(current);
+        if !matches!(result, Ok(None)) {
+            *token = None;
+        }
+        result.map(|entry| entry.is_some())
+    }
+}
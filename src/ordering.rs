@@ -0,0 +1,88 @@
+//! Ordering of overlapping namespace commands.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::error::{Error, Result};
+
+/// Ordering of commands that touch overlapping LBA ranges.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IoOrdering {
+    /// Commands go to any queue and may complete in any order
+    #[default]
+    Relaxed,
+    /// A command waits while another command touching an overlapping
+    /// range is in flight, unless both only read it
+    ///
+    /// Overlapping requests never run concurrently and take effect one
+    /// after another, without locking by the caller.
+    Overlapping,
+}
+
+/// A block range in use by a command.
+struct Range {
+    ticket: u64,
+    start: u64,
+    end: u64,
+    write: bool,
+}
+
+/// Block ranges of the commands in flight on a namespace.
+#[derive(Default)]
+pub(crate) struct RangeLocks {
+    /// Ranges in use and the next ticket
+    state: Mutex<(Vec<Range>, u64)>,
+}
+
+impl RangeLocks {
+    /// Take block ranges for a command, calling `wait` while any is in use.
+    ///
+    /// Each range is given as its first LBA, its block count and whether
+    /// the command writes it. The ranges are taken together, so commands
+    /// that touch several ranges cannot deadlock each other. If `block` is
+    /// false, fails with `Error::RangeBusy` instead of waiting.
+    pub(crate) fn lock(
+        &self,
+        ranges: &[(u64, u64, bool)],
+        block: bool,
+        wait: impl Fn(),
+    ) -> Result<RangeGuard<'_>> {
+        loop {
+            let mut state = self.state.lock();
+            let busy = ranges.iter().any(|&(lba, blocks, write)| {
+                let end = lba.saturating_add(blocks);
+                state.0.iter().any(|range| {
+                    range.start < end && lba < range.end && (write || range.write)
+                })
+            });
+
+            if !busy {
+                let ticket = state.1;
+                state.1 += 1;
+                for &(start, blocks, write) in ranges {
+                    state.0.push(Range { ticket, start, end: start.saturating_add(blocks), write });
+                }
+                return Ok(RangeGuard { locks: self, ticket });
+            }
+
+            if !block {
+                return Err(Error::RangeBusy);
+            }
+
+            drop(state);
+            wait();
+        }
+    }
+}
+
+/// Releases a range taken with `RangeLocks::lock` when dropped.
+pub(crate) struct RangeGuard<'a> {
+    locks: &'a RangeLocks,
+    ticket: u64,
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.state.lock().0.retain(|range| range.ticket != self.ticket);
+    }
+}
@@ -1,20 +1,22 @@
 //! NVMe Power Management module for NVMe 2.3 specification.
 
+use alloc::collections::VecDeque;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::time::Duration;
 
 use crate::cmd::{Command, FeatureId};
 use crate::error::{Error, Result};
-use crate::features::{DevicePersonality, PowerStateDescriptor};
+use crate::features::{DevicePersonality, PowerStateDescriptor, WorkloadHint};
+use crate::health::HealthDelta;
 
 /// Power state information.
 #[derive(Debug, Clone, Copy)]
 pub struct PowerState {
     /// Power state ID (0-31)
     pub id: u8,
-    /// Maximum power in centiwatts
-    pub max_power_cw: u16,
+    /// Maximum power in microwatts
+    pub max_power_uw: u32,
     /// Entry latency in microseconds
     pub entry_latency_us: u32,
     /// Exit latency in microseconds
@@ -27,27 +29,38 @@ pub struct PowerState {
     pub write_throughput: u8,
     /// Relative write latency
     pub write_latency: u8,
-    /// Idle power in centiwatts
-    pub idle_power_cw: u16,
-    /// Active power in centiwatts
-    pub active_power_cw: u16,
+    /// Idle power in microwatts (0 if not reported)
+    pub idle_power_uw: u32,
+    /// Active power in microwatts (0 if not reported)
+    pub active_power_uw: u32,
     /// Non-operational state
     pub non_operational: bool,
 }
 
+/// Convert an idle or active power value to microwatts by its scale (IPS/APS).
+fn scaled_power_uw(power: u16, scale: u8) -> u32 {
+    match scale >> 6 {
+        0b01 => power as u32 * 100,
+        0b10 => power as u32 * 10_000,
+        _ => 0, // Not reported
+    }
+}
+
 impl From<&PowerStateDescriptor> for PowerState {
     fn from(desc: &PowerStateDescriptor) -> Self {
+        // MXPS selects 0.0001 W instead of 0.01 W units for the maximum power
+        let max_power_unit_uw = if desc.flags & 0x01 != 0 { 100 } else { 10_000 };
         Self {
             id: 0, // Will be set externally
-            max_power_cw: desc.max_power,
+            max_power_uw: desc.max_power as u32 * max_power_unit_uw,
             entry_latency_us: desc.entry_latency,
             exit_latency_us: desc.exit_latency,
             read_throughput: desc.read_throughput,
             read_latency: desc.read_latency,
             write_throughput: desc.write_throughput,
             write_latency: desc.write_latency,
-            idle_power_cw: desc.idle_power,
-            active_power_cw: desc.active_power,
+            idle_power_uw: scaled_power_uw(desc.idle_power, desc.idle_power_scale),
+            active_power_uw: scaled_power_uw(desc.active_power, desc.active_power_scale),
             non_operational: (desc.flags & 0x02) != 0,
         }
     }
@@ -142,6 +155,15 @@ impl SelfReportedPower {
     }
 }
 
+/// A self-reported power reading and when it was taken.
+#[derive(Debug, Clone, Copy)]
+pub struct PowerSample {
+    /// Time of the reading in microseconds
+    pub time_us: u64,
+    /// Reported power
+    pub power: SelfReportedPower,
+}
+
 /// Configurable Device Personality (CDP) configuration for NVMe 2.3.
 #[derive(Debug, Clone, Copy)]
 pub struct PersonalityConfig {
@@ -270,16 +292,39 @@ impl ApstConfig {
     }
 }
 
+/// Storage energy use estimated over an interval between two SMART samples.
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyEstimate {
+    /// Length of the interval in microseconds
+    pub interval_us: u64,
+    /// Fraction of the interval the controller was busy (0.0-1.0)
+    pub duty_cycle: f64,
+    /// Estimated energy used in joules
+    pub energy_joules: f64,
+    /// Average power in watts
+    pub average_power_watts: f64,
+    /// Estimated energy per byte written in nanojoules, `None` if nothing was written
+    pub energy_per_byte_written_nj: Option<f64>,
+}
+
 /// Power management controller.
 pub struct PowerManager {
     /// Available power states
     power_states: Vec<PowerState>,
     /// Current power state
     current_power_state: u8,
+    /// Workload hint sent with the power state
+    workload_hint: WorkloadHint,
     /// Power limit configuration
     power_limit: Option<PowerLimitConfig>,
     /// Self-reported power data
     self_reported_power: Option<SelfReportedPower>,
+    /// Recent self-reported power readings, oldest first
+    power_samples: VecDeque<PowerSample>,
+    /// Maximum number of power readings kept
+    power_window: usize,
+    /// Time between power readings in microseconds
+    power_interval_us: u64,
     /// Device personality configuration
     personality: PersonalityConfig,
     /// APST configuration
@@ -293,8 +338,12 @@ impl Default for PowerManager {
         Self {
             power_states: Vec::new(),
             current_power_state: 0,
+            workload_hint: WorkloadHint::None,
             power_limit: None,
             self_reported_power: None,
+            power_samples: VecDeque::new(),
+            power_window: 60,
+            power_interval_us: 1_000_000,
             personality: PersonalityConfig::balanced(),
             apst_config: ApstConfig::new(),
             transition_history: Vec::new(),
@@ -339,6 +388,48 @@ impl PowerManager {
         self.self_reported_power.as_ref()
     }
 
+    /// Set how often `NVMeDevice::sample_power` reads the self-reported
+    /// power and how many readings are kept.
+    ///
+    /// Readings beyond the new window are dropped, oldest first.
+    pub fn set_power_sampling(&mut self, interval_us: u64, window: usize) {
+        self.power_interval_us = interval_us;
+        self.power_window = window.max(1);
+        while self.power_samples.len() > self.power_window {
+            self.power_samples.pop_front();
+        }
+    }
+
+    /// Check whether a power reading is due at `time_us`.
+    pub fn is_power_sample_due(&self, time_us: u64) -> bool {
+        self.power_samples
+            .back()
+            .is_none_or(|last| time_us.saturating_sub(last.time_us) >= self.power_interval_us)
+    }
+
+    /// Record a self-reported power reading, dropping the oldest one if
+    /// the window is full.
+    pub fn record_power_sample(&mut self, power: SelfReportedPower, time_us: u64) {
+        self.self_reported_power = Some(power);
+        if self.power_samples.len() == self.power_window {
+            self.power_samples.pop_front();
+        }
+        self.power_samples.push_back(PowerSample { time_us, power });
+    }
+
+    /// Iterate over the recent power readings, oldest first.
+    pub fn power_samples(&self) -> impl Iterator<Item = &PowerSample> {
+        self.power_samples.iter()
+    }
+
+    /// Get the mean current power of the recent readings in watts.
+    pub fn mean_sampled_power_watts(&self) -> Option<f64> {
+        let count = self.power_samples.len();
+        let total: f64 =
+            self.power_samples.iter().map(|sample| sample.power.current_power_watts as f64).sum();
+        (count > 0).then(|| total / count as f64)
+    }
+
     /// Set device personality.
     pub fn set_personality(&mut self, config: PersonalityConfig) {
         self.personality = config;
@@ -366,17 +457,28 @@ impl PowerManager {
         max_entry_latency_us: u32,
         max_exit_latency_us: u32,
     ) -> Option<u8> {
-        let max_power_cw = max_power_watts * 100; // Convert to centiwatts
+        let max_power_uw = max_power_watts as u64 * 1_000_000;
 
         self.power_states
             .iter()
             .filter(|ps| {
-                ps.max_power_cw <= max_power_cw
+                ps.max_power_uw as u64 <= max_power_uw
                     && ps.entry_latency_us <= max_entry_latency_us
                     && ps.exit_latency_us <= max_exit_latency_us
                     && !ps.non_operational
             })
-            .min_by_key(|ps| ps.idle_power_cw) // Choose lowest idle power
+            .min_by_key(|ps| ps.idle_power_uw) // Choose lowest idle power
+            .map(|ps| ps.id)
+    }
+
+    /// Find the deepest non-operational power state whose exit latency fits the budget.
+    ///
+    /// Depth is judged by idle power; ties prefer the higher state ID.
+    pub fn find_deepest_non_operational_state(&self, max_exit_latency_us: u32) -> Option<u8> {
+        self.power_states
+            .iter()
+            .filter(|ps| ps.non_operational && ps.exit_latency_us <= max_exit_latency_us)
+            .min_by_key(|ps| (ps.idle_power_uw, u8::MAX - ps.id))
             .map(|ps| ps.id)
     }
 
@@ -386,6 +488,15 @@ impl PowerManager {
             return Err(Error::InvalidFeatureConfig);
         }
 
+        self.record_transition(power_state, timestamp);
+        Ok(())
+    }
+
+    /// Record a power state transition confirmed by the controller.
+    ///
+    /// Unlike `transition_to`, the state is not validated against the known
+    /// power states because the controller has already accepted it.
+    pub fn record_transition(&mut self, power_state: u8, timestamp: u64) {
         // Record transition
         self.transition_history.push((
             self.current_power_state,
@@ -399,7 +510,6 @@ impl PowerManager {
         }
 
         self.current_power_state = power_state;
-        Ok(())
     }
 
     /// Get current power state.
@@ -407,6 +517,21 @@ impl PowerManager {
         self.current_power_state
     }
 
+    /// Set the workload hint sent with power state changes.
+    ///
+    /// Takes effect with the next Power Management command built by
+    /// `build_power_management_command` or sent by
+    /// `NVMeDevice::set_power_state`. See `WorkloadHint` for how it
+    /// interacts with APST.
+    pub fn set_workload_hint(&mut self, hint: WorkloadHint) {
+        self.workload_hint = hint;
+    }
+
+    /// Get the workload hint sent with power state changes.
+    pub fn get_workload_hint(&self) -> WorkloadHint {
+        self.workload_hint
+    }
+
     /// Get power state information.
     pub fn get_power_state_info(&self, state_id: u8) -> Option<&PowerState> {
         self.power_states.get(state_id as usize)
@@ -417,20 +542,57 @@ impl PowerManager {
         &self.power_states
     }
 
-    /// Calculate current power consumption estimate.
-    pub fn estimate_current_power(&self) -> u16 {
+    /// Estimate storage energy use over the interval of a SMART delta.
+    ///
+    /// The controller is taken to draw the active power of the current
+    /// power state (its maximum power if none is reported) while busy and
+    /// its idle power otherwise. Busy time is reported in whole minutes, so
+    /// the estimate is only meaningful over intervals of many minutes.
+    /// Returns `None` for an empty interval or an unknown power state.
+    pub fn estimate_energy(&self, delta: &HealthDelta) -> Option<EnergyEstimate> {
+        let state = self.power_states.get(self.current_power_state as usize)?;
+        if delta.interval_us == 0 {
+            return None;
+        }
+
+        let interval_sec = delta.interval_us as f64 / 1_000_000.0;
+        let busy_sec = (delta.busy_minutes as f64 * 60.0).min(interval_sec);
+        let active_power_uw = match state.active_power_uw {
+            0 => state.max_power_uw,
+            power => power,
+        };
+        let energy_joules = (active_power_uw as f64 * busy_sec
+            + state.idle_power_uw as f64 * (interval_sec - busy_sec))
+            / 1_000_000.0;
+
+        Some(EnergyEstimate {
+            interval_us: delta.interval_us,
+            duty_cycle: busy_sec / interval_sec,
+            energy_joules,
+            average_power_watts: energy_joules / interval_sec,
+            energy_per_byte_written_nj: (delta.bytes_written > 0)
+                .then(|| energy_joules * 1e9 / delta.bytes_written as f64),
+        })
+    }
+
+    /// Calculate current power consumption estimate in microwatts.
+    pub fn estimate_current_power(&self) -> u32 {
         self.power_states
             .get(self.current_power_state as usize)
-            .map(|ps| ps.active_power_cw)
+            .map(|ps| ps.active_power_uw)
             .unwrap_or(0)
     }
 
     /// Build Set Features command for power management.
+    ///
+    /// The command carries the workload hint set with `set_workload_hint`,
+    /// as a Power Management command without one clears it.
     pub fn build_power_management_command(&self, cmd_id: u16, power_state: u8) -> Command {
         Command::set_features(
             cmd_id,
+            0,
             FeatureId::PowerManagement,
-            power_state as u32,
+            self.workload_hint.feature_value(power_state),
             false,
         )
     }
@@ -440,6 +602,7 @@ impl PowerManager {
         let config = self.power_limit.ok_or(Error::InvalidFeatureConfig)?;
         Ok(Command::set_features(
             cmd_id,
+            0,
             FeatureId::PowerManagement,
             config.to_feature_value(),
             false,
@@ -0,0 +1,254 @@
+//! Snapshots of device configuration that can be stored and replayed.
+
+use alloc::vec::Vec;
+
+use crate::cmd::FeatureId;
+use crate::error::{Error, Result};
+use crate::features::DevicePersonality;
+use crate::multipath::{PathSelector, PathWeights, RpfrConfig};
+use crate::power::{CustomPersonalityParams, PersonalityConfig};
+
+/// Start of an encoded profile.
+const PROFILE_MAGIC: [u8; 4] = *b"NVPR";
+
+/// Version of the profile encoding.
+const PROFILE_VERSION: u8 = 1;
+
+/// Features captured by `NVMeDevice::export_profile`.
+///
+/// Only changeable, controller-wide features whose value fits in the
+/// command are included; APST is captured with its table separately.
+pub(crate) const PROFILE_FEATURES: [FeatureId; 9] = [
+    FeatureId::Arbitration,
+    FeatureId::PowerManagement,
+    FeatureId::TemperatureThreshold,
+    FeatureId::VolatileWriteCache,
+    FeatureId::InterruptCoalescing,
+    FeatureId::WriteAtomicityNormal,
+    FeatureId::AsyncEventConfig,
+    FeatureId::HostControlledThermal,
+    FeatureId::NonOperationalPowerState,
+];
+
+/// Driver and controller configuration of a device.
+///
+/// Captured with `NVMeDevice::export_profile` and replayed with
+/// `NVMeDevice::apply_profile`, after a reboot or on a replacement drive.
+/// `to_bytes` and `from_bytes` convert it to a compact form for storage.
+#[derive(Debug, Clone)]
+pub struct DeviceProfile {
+    /// Number of I/O queue pairs
+    pub io_queues: u16,
+    /// Maximum outstanding commands per I/O queue
+    pub queue_depth: u16,
+    /// Current values of the captured features the controller supports
+    pub features: Vec<(FeatureId, u32)>,
+    /// APST feature value and transition table, if supported
+    pub apst: Option<(u32, Vec<u8>)>,
+    /// Path selector of the multipath controller, if one is set
+    ///
+    /// A `PathSelector::Custom` policy object is not captured and has to
+    /// be set again on the multipath controller.
+    pub path_selector: Option<PathSelector>,
+    /// Path failure recovery configuration of the multipath controller
+    pub rpfr: Option<RpfrConfig>,
+    /// Device personality
+    pub personality: PersonalityConfig,
+}
+
+impl DeviceProfile {
+    /// Encode the profile.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&PROFILE_MAGIC);
+        out.push(PROFILE_VERSION);
+        put_u16(&mut out, self.io_queues);
+        put_u16(&mut out, self.queue_depth);
+
+        out.push(self.features.len() as u8);
+        for &(feature, value) in &self.features {
+            out.push(feature as u8);
+            put_u32(&mut out, value);
+        }
+
+        out.push(self.apst.is_some() as u8);
+        if let Some((value, table)) = &self.apst {
+            put_u32(&mut out, *value);
+            put_u16(&mut out, table.len() as u16);
+            out.extend_from_slice(table);
+        }
+
+        out.push(self.path_selector.is_some() as u8);
+        if let Some(selector) = self.path_selector {
+            let tag = match selector {
+                PathSelector::RoundRobin => 0,
+                PathSelector::LowestLatency => 1,
+                PathSelector::LeastIo => 2,
+                PathSelector::BestScore => 3,
+                PathSelector::Priority => 4,
+                PathSelector::Weighted(_) => 5,
+                PathSelector::Custom => 6,
+            };
+            out.push(tag);
+            if let PathSelector::Weighted(weights) = selector {
+                put_u32(&mut out, weights.queue_depth);
+                put_u32(&mut out, weights.latency);
+                put_u32(&mut out, weights.ana);
+            }
+        }
+
+        out.push(self.rpfr.is_some() as u8);
+        if let Some(rpfr) = &self.rpfr {
+            out.push(rpfr.enabled as u8);
+            put_u32(&mut out, rpfr.max_retries);
+            put_u32(&mut out, rpfr.failure_timeout_ms);
+            put_u32(&mut out, rpfr.recovery_timeout_ms);
+            out.push(rpfr.auto_failback as u8);
+            put_u32(&mut out, rpfr.health_check_interval_sec);
+            put_u32(&mut out, rpfr.latency_half_life_ms);
+        }
+
+        out.push(self.personality.to_feature_value() as u8);
+        out.push(self.personality.custom_params.is_some() as u8);
+        if let Some(params) = &self.personality.custom_params {
+            put_u32(&mut out, params.target_iops);
+            put_u32(&mut out, params.target_bandwidth_mbps);
+            put_u32(&mut out, params.target_latency_us);
+            put_u16(&mut out, params.power_budget_watts);
+        }
+        out
+    }
+
+    /// Decode a profile encoded with `to_bytes`.
+    ///
+    /// Fails with `Error::InvalidProfile` if the data is not a profile of
+    /// a known version or is cut short.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader { data };
+        if reader.take(4)? != PROFILE_MAGIC || reader.u8()? != PROFILE_VERSION {
+            return Err(Error::InvalidProfile);
+        }
+        let io_queues = reader.u16()?;
+        let queue_depth = reader.u16()?;
+
+        let mut features = Vec::new();
+        for _ in 0..reader.u8()? {
+            let id = reader.u8()?;
+            let feature = FeatureId::ALL
+                .into_iter()
+                .find(|&feature| feature as u8 == id)
+                .ok_or(Error::InvalidProfile)?;
+            features.push((feature, reader.u32()?));
+        }
+
+        let apst = match reader.flag()? {
+            true => {
+                let value = reader.u32()?;
+                let len = reader.u16()? as usize;
+                Some((value, reader.take(len)?.to_vec()))
+            }
+            false => None,
+        };
+
+        let path_selector = match reader.flag()? {
+            true => Some(match reader.u8()? {
+                0 => PathSelector::RoundRobin,
+                1 => PathSelector::LowestLatency,
+                2 => PathSelector::LeastIo,
+                3 => PathSelector::BestScore,
+                4 => PathSelector::Priority,
+                5 => PathSelector::Weighted(PathWeights {
+                    queue_depth: reader.u32()?,
+                    latency: reader.u32()?,
+                    ana: reader.u32()?,
+                }),
+                6 => PathSelector::Custom,
+                _ => return Err(Error::InvalidProfile),
+            }),
+            false => None,
+        };
+
+        let rpfr = match reader.flag()? {
+            true => Some(RpfrConfig {
+                enabled: reader.flag()?,
+                max_retries: reader.u32()?,
+                failure_timeout_ms: reader.u32()?,
+                recovery_timeout_ms: reader.u32()?,
+                auto_failback: reader.flag()?,
+                health_check_interval_sec: reader.u32()?,
+                latency_half_life_ms: reader.u32()?,
+            }),
+            false => None,
+        };
+
+        let personality = match reader.u8()? {
+            0 => DevicePersonality::Balanced,
+            1 => DevicePersonality::HighPerformance,
+            2 => DevicePersonality::LowPower,
+            3 => DevicePersonality::LowLatency,
+            4 => DevicePersonality::HighCapacity,
+            code => DevicePersonality::Custom(code),
+        };
+        let custom_params = match reader.flag()? {
+            true => Some(CustomPersonalityParams {
+                target_iops: reader.u32()?,
+                target_bandwidth_mbps: reader.u32()?,
+                target_latency_us: reader.u32()?,
+                power_budget_watts: reader.u16()?,
+            }),
+            false => None,
+        };
+
+        Ok(Self {
+            io_queues,
+            queue_depth,
+            features,
+            apst,
+            path_selector,
+            rpfr,
+            personality: PersonalityConfig { personality, custom_params },
+        })
+    }
+}
+
+/// Append a little-endian u16.
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Append a little-endian u32.
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Reads the fields of an encoded profile in order.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::InvalidProfile);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
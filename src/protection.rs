@@ -0,0 +1,210 @@
+//! End-to-end data protection information (PI).
+
+use crate::error::{Error, Result};
+use crate::format::{LbaFormat, PiFormat};
+
+/// Protection information layout of a namespace format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionInfo {
+    /// Guard format
+    pub format: PiFormat,
+    /// Storage tag size in bits
+    pub storage_tag_size: u8,
+}
+
+impl ProtectionInfo {
+    /// Get the protection information layout of an LBA format.
+    pub fn from_format(format: &LbaFormat) -> Self {
+        Self {
+            format: format.pi_format,
+            storage_tag_size: format.storage_tag_size,
+        }
+    }
+
+    /// Size of the protection information in bytes.
+    pub fn size(&self) -> usize {
+        match self.format {
+            PiFormat::Guard16 => 8,
+            PiFormat::Guard32 | PiFormat::Guard64 | PiFormat::Reserved(_) => 16,
+        }
+    }
+
+    /// Size of the combined storage and reference tag field in bits.
+    ///
+    /// With the 16-bit guard format, the storage tag is sent separately
+    /// and the field only holds the 32-bit reference tag.
+    fn tag_field_bits(&self) -> u32 {
+        match self.format {
+            PiFormat::Guard16 => 32,
+            PiFormat::Guard32 => 80,
+            PiFormat::Guard64 | PiFormat::Reserved(_) => 48,
+        }
+    }
+
+    /// Size of the reference tag in bits.
+    ///
+    /// With the 32 and 64-bit guard formats, the storage tag takes the
+    /// upper bits of the combined tag field and the reference tag the rest.
+    /// Reference tags wider than 64 bits are handled as their low 64 bits.
+    pub fn reference_tag_bits(&self) -> u32 {
+        match self.format {
+            PiFormat::Guard16 => 32,
+            _ => self.tag_field_bits().saturating_sub(self.storage_tag_size as u32),
+        }
+    }
+
+    /// Encode the initial storage and reference tags of a command.
+    ///
+    /// Returns command dwords 2 and 3 and command dword 14. Fails with
+    /// `Error::InvalidProtectionTag` if a tag does not fit its field.
+    pub fn encode_tags(&self, storage_tag: u64, reference_tag: u64) -> Result<(u64, u32)> {
+        let storage_bits = self.storage_tag_size as u32;
+        let reference_bits = self.reference_tag_bits();
+        if !fits(storage_tag, storage_bits) || !fits(reference_tag, reference_bits) {
+            return Err(Error::InvalidProtectionTag);
+        }
+
+        match self.format {
+            // The storage tag goes to dwords 2 and 3, the reference tag to dword 14
+            PiFormat::Guard16 => Ok((storage_tag, reference_tag as u32)),
+            // The low 32 bits of the combined field go to dword 14, the rest
+            // to dwords 2 and 3
+            _ => {
+                let field = ((storage_tag as u128) << reference_bits) | reference_tag as u128;
+                Ok(((field >> 32) as u64, field as u32))
+            }
+        }
+    }
+
+    /// Compute the guard of a logical block's data.
+    ///
+    /// CRC-16 T10-DIF, CRC-32C or the NVMe CRC-64, depending on the format.
+    pub fn guard(&self, data: &[u8]) -> u64 {
+        match self.format {
+            PiFormat::Guard16 => crc16_t10dif(data) as u64,
+            PiFormat::Guard32 => crc32c(data) as u64,
+            PiFormat::Guard64 | PiFormat::Reserved(_) => crc64_nvme(data),
+        }
+    }
+
+    /// Check the guard in protection information `pi` against `data`.
+    pub fn check_guard(&self, data: &[u8], pi: &[u8]) -> bool {
+        let guard_bytes = match self.format {
+            PiFormat::Guard16 => 2,
+            PiFormat::Guard32 => 4,
+            PiFormat::Guard64 | PiFormat::Reserved(_) => 8,
+        };
+        be_bits(&pi[..guard_bytes]) as u64 == self.guard(data)
+    }
+
+    /// Get the application tag from protection information `pi`.
+    pub fn application_tag(&self, pi: &[u8]) -> u16 {
+        let offset = match self.format {
+            PiFormat::Guard16 => 2,
+            PiFormat::Guard32 => 4,
+            PiFormat::Guard64 | PiFormat::Reserved(_) => 8,
+        };
+        u16::from_be_bytes([pi[offset], pi[offset + 1]])
+    }
+
+    /// Get the reference tag from protection information `pi`.
+    pub fn reference_tag(&self, pi: &[u8]) -> u64 {
+        let mask = u128::MAX.checked_shr(128 - self.reference_tag_bits()).unwrap_or(0);
+        (self.tag_field(pi) & mask) as u64
+    }
+
+    /// Get the storage tag from protection information `pi`.
+    ///
+    /// Always 0 with the 16-bit guard format, which does not store it.
+    pub fn storage_tag(&self, pi: &[u8]) -> u64 {
+        match self.format {
+            PiFormat::Guard16 => 0,
+            _ => (self.tag_field(pi) >> self.reference_tag_bits()) as u64,
+        }
+    }
+
+    /// Read the combined storage and reference tag field.
+    fn tag_field(&self, pi: &[u8]) -> u128 {
+        let start = self.size() - self.tag_field_bits() as usize / 8;
+        be_bits(&pi[start..self.size()])
+    }
+}
+
+/// Protection information checks and tags of a read or write.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionCheck {
+    /// Protection information layout of the namespace format
+    pub info: ProtectionInfo,
+    /// The controller inserts protection information on writes and strips
+    /// it on reads (PRACT)
+    pub pract: bool,
+    /// Check the guard
+    pub check_guard: bool,
+    /// Check the application tag
+    pub check_application_tag: bool,
+    /// Check the reference tag
+    pub check_reference_tag: bool,
+    /// Check the storage tag
+    pub check_storage_tag: bool,
+    /// Expected application tag
+    pub application_tag: u16,
+    /// Bits of the application tag to check
+    pub application_tag_mask: u16,
+    /// Expected storage tag
+    pub storage_tag: u64,
+    /// Expected reference tag of the first block
+    pub reference_tag: u64,
+}
+
+impl ProtectionCheck {
+    /// Encode the PRINFO field of command dword 12.
+    pub(crate) fn prinfo(&self) -> u8 {
+        ((self.pract as u8) << 3)
+            | ((self.check_guard as u8) << 2)
+            | ((self.check_application_tag as u8) << 1)
+            | self.check_reference_tag as u8
+    }
+}
+
+/// Check whether `value` fits in `bits` bits.
+fn fits(value: u64, bits: u32) -> bool {
+    bits >= 64 || value >> bits == 0
+}
+
+/// Read a big-endian field of up to 16 bytes.
+fn be_bits(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0, |acc, &byte| (acc << 8) | byte as u128)
+}
+
+/// CRC-16 T10-DIF (polynomial 0x8BB7).
+fn crc16_t10dif(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8BB7 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// CRC-32C (Castagnoli, reflected polynomial 0x82F63B78).
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |mut crc, &byte| {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+        crc
+    })
+}
+
+/// NVMe CRC-64 (reflected polynomial 0x9A6C9329AC4BC9B5).
+fn crc64_nvme(data: &[u8]) -> u64 {
+    !data.iter().fold(!0u64, |mut crc, &byte| {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x9A6C_9329_AC4B_C9B5 } else { crc >> 1 };
+        }
+        crc
+    })
+}
@@ -0,0 +1,134 @@
+//! Thin-provisioning utilization of namespaces.
+
+use alloc::collections::VecDeque;
+
+/// Allocation of a namespace at a point in time.
+///
+/// Block counts come from Identify Namespace, byte counts from the host's
+/// own I/O statistics, which `Namespace::reset_stats` clears.
+#[derive(Debug, Clone, Copy)]
+pub struct UtilizationSample {
+    /// Time of the sample in microseconds
+    pub time_us: u64,
+    /// Namespace ID
+    pub nsid: u32,
+    /// Block size in bytes
+    pub block_size: u64,
+    /// Blocks the namespace may allocate (NCAP)
+    pub capacity_blocks: u64,
+    /// Blocks currently allocated (NUSE)
+    pub used_blocks: u64,
+    /// Bytes written by the host
+    pub written_bytes: u64,
+    /// Bytes deallocated by the host
+    pub trimmed_bytes: u64,
+}
+
+impl UtilizationSample {
+    /// Get the number of blocks that may still be allocated.
+    pub fn free_blocks(&self) -> u64 {
+        self.capacity_blocks.saturating_sub(self.used_blocks)
+    }
+
+    /// Get the allocated fraction of the capacity, from 0.0 to 1.0.
+    pub fn used_fraction(&self) -> f64 {
+        match self.capacity_blocks {
+            0 => 0.0,
+            capacity => self.used_blocks.min(capacity) as f64 / capacity as f64,
+        }
+    }
+}
+
+/// Change in allocation over the samples of a `UtilizationMonitor`.
+#[derive(Debug, Clone, Copy)]
+pub struct UtilizationTrend {
+    /// Time between the oldest and latest sample in microseconds
+    pub interval_us: u64,
+    /// Change in allocated blocks
+    pub used_blocks_delta: i64,
+    /// Bytes written by the host over the interval
+    pub written_bytes: u64,
+    /// Bytes deallocated by the host over the interval
+    pub trimmed_bytes: u64,
+    /// Allocated blocks gained per hour
+    pub blocks_per_hour: f64,
+    /// Hours until the capacity is allocated at the current rate, `None`
+    /// while allocation is not growing
+    pub hours_to_full: Option<f64>,
+}
+
+/// Keeps a bounded history of namespace utilization samples.
+///
+/// Feed it with `NVMeDevice::poll_utilization`, or with `record` if the
+/// samples are taken some other way. Keep one monitor per namespace.
+#[derive(Debug, Clone)]
+pub struct UtilizationMonitor {
+    /// Samples, oldest first
+    samples: VecDeque<UtilizationSample>,
+    /// Maximum number of samples kept
+    capacity: usize,
+}
+
+impl UtilizationMonitor {
+    /// Create a new monitor keeping at most `capacity` samples.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2);
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record a sample, dropping the oldest one if full.
+    pub fn record(&mut self, sample: UtilizationSample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Get the most recent sample.
+    pub fn latest(&self) -> Option<&UtilizationSample> {
+        self.samples.back()
+    }
+
+    /// Iterate over the samples, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = &UtilizationSample> {
+        self.samples.iter()
+    }
+
+    /// Drop all samples.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Compute the change in allocation between the oldest and latest
+    /// sample.
+    ///
+    /// Host byte counts that went down, because the statistics were reset,
+    /// count as zero.
+    pub fn trend(&self) -> Option<UtilizationTrend> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let oldest = self.samples.front()?;
+        let latest = self.samples.back()?;
+
+        let interval_us = latest.time_us.saturating_sub(oldest.time_us);
+        let used_blocks_delta = latest.used_blocks as i64 - oldest.used_blocks as i64;
+        let blocks_per_hour = match interval_us {
+            0 => 0.0,
+            us => used_blocks_delta as f64 * 3_600_000_000.0 / us as f64,
+        };
+
+        Some(UtilizationTrend {
+            interval_us,
+            used_blocks_delta,
+            written_bytes: latest.written_bytes.saturating_sub(oldest.written_bytes),
+            trimmed_bytes: latest.trimmed_bytes.saturating_sub(oldest.trimmed_bytes),
+            blocks_per_hour,
+            hours_to_full: (blocks_per_hour > 0.0)
+                .then(|| latest.free_blocks() as f64 / blocks_per_hour),
+        })
+    }
+}
@@ -0,0 +1,122 @@
+//! Token bucket based I/O rate limiting.
+
+/// I/O rate limit.
+///
+/// A rate of 0 leaves that dimension unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Maximum commands per second
+    pub iops: u32,
+    /// Maximum bytes transferred per second
+    pub bytes_per_sec: u64,
+    /// Burst allowance in milliseconds of the configured rates
+    pub burst_ms: u32,
+}
+
+impl RateLimit {
+    /// Create a rate limit on commands per second.
+    pub fn iops(iops: u32) -> Self {
+        Self {
+            iops,
+            bytes_per_sec: 0,
+            burst_ms: 100,
+        }
+    }
+
+    /// Create a rate limit on bandwidth.
+    pub fn bandwidth(bytes_per_sec: u64) -> Self {
+        Self {
+            iops: 0,
+            bytes_per_sec,
+            burst_ms: 100,
+        }
+    }
+
+    /// Set the burst allowance.
+    pub fn with_burst(mut self, burst_ms: u32) -> Self {
+        self.burst_ms = burst_ms;
+        self
+    }
+}
+
+/// A token bucket refilled at a fixed rate.
+///
+/// Credit is kept in rate-microseconds so that refills are exact. A
+/// command is admitted while the bucket holds any credit and may drive
+/// it negative, so commands larger than the burst still get through.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    /// Refill rate per second (0 = unlimited)
+    rate: u64,
+    /// Maximum credit
+    capacity: i64,
+    /// Current credit
+    credit: i64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst_ms: u32) -> Self {
+        let capacity = rate
+            .saturating_mul(burst_ms.max(1) as u64 * 1000)
+            .min(i64::MAX as u64) as i64;
+        Self {
+            rate,
+            capacity,
+            credit: capacity,
+        }
+    }
+
+    fn refill(&mut self, elapsed_us: u64) {
+        let refill = elapsed_us.saturating_mul(self.rate).min(i64::MAX as u64) as i64;
+        self.credit = self.credit.saturating_add(refill).min(self.capacity);
+    }
+
+    fn ready(&self) -> bool {
+        self.rate == 0 || self.credit > 0
+    }
+
+    fn take(&mut self, amount: u64) {
+        if self.rate != 0 {
+            let cost = amount.saturating_mul(1_000_000).min(i64::MAX as u64) as i64;
+            self.credit = self.credit.saturating_sub(cost);
+        }
+    }
+}
+
+/// Enforces a `RateLimit` at submission time.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    /// Command bucket
+    iops: TokenBucket,
+    /// Bandwidth bucket
+    bytes: TokenBucket,
+    /// Time of the last refill in microseconds
+    last_us: u64,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter starting with full buckets.
+    pub fn new(limit: RateLimit, now_us: u64) -> Self {
+        Self {
+            iops: TokenBucket::new(limit.iops as u64, limit.burst_ms),
+            bytes: TokenBucket::new(limit.bytes_per_sec, limit.burst_ms),
+            last_us: now_us,
+        }
+    }
+
+    /// Refill the buckets and check whether a command can be admitted.
+    pub fn ready(&mut self, now_us: u64) -> bool {
+        let elapsed_us = now_us.saturating_sub(self.last_us);
+        self.last_us = now_us;
+        self.iops.refill(elapsed_us);
+        self.bytes.refill(elapsed_us);
+
+        self.iops.ready() && self.bytes.ready()
+    }
+
+    /// Charge an admitted command transferring `bytes`.
+    pub fn consume(&mut self, bytes: usize) {
+        self.iops.take(1);
+        self.bytes.take(bytes as u64);
+    }
+}
@@ -1,23 +1,76 @@
-use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use spin::Mutex;
 
 use crate::cmd::Command;
-use crate::error::{Error, Result};
-use crate::memory::{Dma, Allocator};
+use crate::endian::{Le, le_struct};
+use crate::error::{Error, Result, StatusCode};
+use crate::hook::read_barrier;
+use crate::memory::{Dma, Allocator, PlacementHint};
 
 /// Completion entry in the NVMe completion queue.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub(crate) struct Completion {
     pub command_specific: u32,
-    _rsvd: u32,
+    pub dw1: u32,
     pub sq_head: u16,
     pub sq_id: u16,
     pub cmd_id: u16,
     pub status: u16,
 }
 
+le_struct!(Completion { command_specific, dw1, sq_head, sq_id, cmd_id, status });
+
+impl Completion {
+    /// Convert the status field into a result.
+    ///
+    /// Reads of deallocated or unwritten blocks with DULBE enabled are
+    /// reported as `Error::DeallocatedOrUnwrittenBlock`. Feature errors
+    /// are reported as `Error::FeatureNotSaveable` and
+    /// `Error::FeatureNotNamespaceSpecific`, and Namespace Attachment errors
+    /// as `Error::NamespaceAlreadyAttached` and `Error::NamespaceIsPrivate`.
+    /// ANA path errors are reported as `Error::AnaPersistentLoss`,
+    /// `Error::AnaInaccessible` and `Error::AnaTransition`.
+    pub fn result(&self) -> Result<()> {
+        let status = self.status;
+        let sc = (status >> 1) & 0xff;
+        let sct = (status >> 9) & 0x7;
+
+        match (sct, sc) {
+            (0, 0) => Ok(()),
+            (1, 0x0D) => Err(Error::FeatureNotSaveable),
+            (1, 0x0F) => Err(Error::FeatureNotNamespaceSpecific),
+            (1, 0x18) => Err(Error::NamespaceAlreadyAttached),
+            (1, 0x19) => Err(Error::NamespaceIsPrivate),
+            (2, 0x87) => Err(Error::DeallocatedOrUnwrittenBlock),
+            (3, 0x01) => Err(Error::AnaPersistentLoss),
+            (3, 0x02) => Err(Error::AnaInaccessible),
+            (3, 0x03) => Err(Error::AnaTransition),
+            _ => Err(Error::CommandFailed(StatusCode::from_raw(status))),
+        }
+    }
+
+    /// Convert into the command specific dwords if the command succeeded.
+    pub fn io_result(&self) -> Result<IoResult> {
+        self.result().map(|_| IoResult { dw0: self.command_specific, dw1: self.dw1 })
+    }
+}
+
+/// Command specific dwords of a successful completion.
+///
+/// Commands such as Copy, Write Zeroes and vendor specific commands may
+/// report data here. The meaning depends on the command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoResult {
+    /// Dword 0 of the completion queue entry
+    pub dw0: u32,
+    /// Dword 1 of the completion queue entry
+    pub dw1: u32,
+}
+
 /// Represents an NVMe submission queue.
 ///
 /// The submission queue holds commands that are
@@ -42,43 +95,97 @@ impl SubQueue {
     /// Creates a new submission queue.
     ///
     /// The allocator should implement the `Allocator` trait.
-    pub fn new<A: Allocator>(len: usize, allocator: &A) -> Self {
+    /// The slots are placed following `hint`.
+    pub fn new<A: Allocator>(len: usize, allocator: &A, hint: PlacementHint) -> Self {
+        Self::with_slots(Dma::allocate_near(len, allocator, hint), len)
+    }
+
+    /// Creates a new submission queue whose pages may be physically scattered.
+    pub fn scattered<A: Allocator>(len: usize, allocator: &A, hint: PlacementHint) -> Self {
+        Self::with_slots(Dma::allocate_scattered(len, allocator, hint), len)
+    }
+
+    fn with_slots(slots: Dma<Command>, len: usize) -> Self {
         Self {
-            inner: Mutex::new(SubQueueInner {
-                slots: Dma::allocate(len, allocator),
-                head: 0,
-                tail: 0,
-            }),
+            inner: Mutex::new(SubQueueInner { slots, head: 0, tail: 0 }),
             len,
         }
     }
 
+    /// Returns the physical address of each page of the queue.
+    pub fn page_addresses<A: Allocator>(&self, allocator: &A) -> Vec<u64> {
+        self.inner.lock().slots.page_addresses(allocator)
+    }
+
     /// Returns the physical address of the submission queue.
     ///
     /// It is usually used to configure the admin queues.
-    pub fn address(&self) -> usize {
+    pub fn address(&self) -> u64 {
         self.inner.lock().slots.phys_addr
     }
 
+    /// Returns the number of slots in the queue.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Resets the head and tail positions.
+    ///
+    /// Used when the controller is reset and the queue is reconfigured.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+        inner.head = 0;
+        inner.tail = 0;
+    }
+
+    /// Free the queue memory.
+    ///
+    /// The queue must not be used afterwards.
+    pub fn deallocate<A: Allocator>(&self, allocator: &A) {
+        self.inner.lock().slots.deallocate(allocator);
+    }
+
     /// Get current tail position (for admin commands)
     pub fn tail(&self) -> usize {
         self.inner.lock().tail
     }
 
     /// Set head position (from completion entry)
+    ///
+    /// The head only moves forward over submitted commands. Completions
+    /// reaped out of order carry older head positions, which are ignored,
+    /// as are positions past the tail.
     pub fn set_head(&self, head: usize) {
-        self.inner.lock().head = head;
+        let mut inner = self.inner.lock();
+        let consumed = (head + self.len - inner.head) % self.len;
+        if head < self.len && consumed <= self.occupied(&inner) {
+            inner.head = head;
+        }
+    }
+
+    /// Returns the number of commands that can be pushed without waiting.
+    ///
+    /// One slot always stays empty to tell a full queue from an empty one.
+    pub fn free_slots(&self) -> usize {
+        let inner = self.inner.lock();
+        self.len - 1 - self.occupied(&inner)
+    }
+
+    /// Number of commands not yet fetched by the controller.
+    fn occupied(&self, inner: &SubQueueInner) -> usize {
+        (inner.tail + self.len - inner.head) % self.len
     }
 
     /// Pushes a command to the submission queue
     ///
-    /// It blocks until there is space available in the queue.
-    pub fn push(&self, entry: Command) -> usize {
+    /// It blocks until there is space available in the queue,
+    /// calling `wait` between attempts.
+    pub fn push(&self, entry: Command, wait: impl Fn()) -> usize {
         loop {
             if let Ok(tail) = self.try_push(entry) {
                 return tail;
             }
-            spin_loop();
+            wait();
         }
     }
 
@@ -87,11 +194,12 @@ impl SubQueue {
     /// It does not block if the queue is full.
     pub fn try_push(&self, entry: Command) -> Result<usize> {
         let mut inner = self.inner.lock();
-        if inner.head == (inner.tail + 1) % self.len {
+        if self.occupied(&inner) == self.len - 1 {
             Err(Error::SubQueueFull)
         } else {
             let tail = inner.tail;
-            inner.slots[tail] = entry;
+            // The controller reads the slot, so the write must not be elided
+            unsafe { inner.slots.addr.add(tail).write_volatile(entry.swap_le()) };
             inner.tail = (inner.tail + 1) % self.len;
             Ok(inner.tail)
         }
@@ -112,58 +220,95 @@ pub(crate) struct CompQueue {
 struct CompQueueInner {
     /// The completion slots
     slots: Dma<Completion>,
-    /// Current head position of the queue
+    /// Head position and expected phase
+    cursor: CqCursor,
+}
+
+/// Head position and expected phase tag of a completion queue.
+///
+/// The controller inverts the phase tag it writes each time it wraps
+/// around the queue, so the host expects the inverted tag from the pass
+/// after each wrap as well. Entries still carrying the old tag have not
+/// been posted yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CqCursor {
+    /// Next slot to consume
     head: usize,
-    /// Used to determine if an entry is valid
+    /// Phase tag of entries posted in the current pass
     phase: bool,
+    /// Number of slots
+    len: usize,
+}
+
+impl CqCursor {
+    /// Cursor of an empty queue with `len` slots.
+    fn new(len: usize) -> Self {
+        Self { head: 0, phase: true, len }
+    }
+
+    /// Check whether an entry with `status` was posted in the current pass.
+    fn is_posted(&self, status: u16) -> bool {
+        ((status & 1) == 1) == self.phase
+    }
+
+    /// Consume `count` entries, flipping the phase once per wrap.
+    fn advance(&mut self, count: usize) {
+        let end = self.head + count;
+        if (end / self.len) % 2 == 1 {
+            self.phase = !self.phase;
+        }
+        self.head = end % self.len;
+    }
 }
 
 impl CompQueue {
     /// Creates a new completion queue.
     ///
     /// The allocator should implement the `Allocator` trait.
-    pub fn new<A: Allocator>(len: usize, allocator: &A) -> Self {
+    /// The slots are placed following `hint`.
+    pub fn new<A: Allocator>(len: usize, allocator: &A, hint: PlacementHint) -> Self {
+        Self::with_slots(Dma::allocate_near(len, allocator, hint), len)
+    }
+
+    /// Creates a new completion queue whose pages may be physically scattered.
+    pub fn scattered<A: Allocator>(len: usize, allocator: &A, hint: PlacementHint) -> Self {
+        Self::with_slots(Dma::allocate_scattered(len, allocator, hint), len)
+    }
+
+    fn with_slots(slots: Dma<Completion>, len: usize) -> Self {
         Self {
-            inner: Mutex::new(CompQueueInner {
-                slots: Dma::allocate(len, allocator),
-                head: 0,
-                phase: true,
-            }),
+            inner: Mutex::new(CompQueueInner { slots, cursor: CqCursor::new(len) }),
             len,
         }
     }
 
+    /// Returns the physical address of each page of the queue.
+    pub fn page_addresses<A: Allocator>(&self, allocator: &A) -> Vec<u64> {
+        self.inner.lock().slots.page_addresses(allocator)
+    }
+
     /// Returns the physical address of the completion queue.
     ///
     /// It is usually used to configure the admin queues.
-    pub fn address(&self) -> usize {
+    pub fn address(&self) -> u64 {
         self.inner.lock().slots.phys_addr
     }
 
-    /// Pops a completion entry from the queue.
+    /// Resets the head position and phase and clears all entries.
     ///
-    /// It blocks until there is a valid entry available.
-    pub fn pop(&self) -> (usize, Completion) {
-        loop {
-            if let Some(val) = self.try_pop() {
-                return val;
-            }
-            spin_loop();
-        }
+    /// Used when the controller is reset and the queue is reconfigured,
+    /// so that stale entries are not mistaken for new completions.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+        unsafe { core::ptr::write_bytes(inner.slots.addr, 0, self.len) };
+        inner.cursor = CqCursor::new(self.len);
     }
 
-    /// Pops a step of completion entries from the queue.
+    /// Free the queue memory.
     ///
-    /// It returns the final head position and the completion entry.
-    pub fn pop_n(&self, step: usize) -> (usize, Completion) {
-        let mut inner = self.inner.lock();
-        inner.head += step - 1;
-        if inner.head >= self.len {
-            inner.phase = !inner.phase;
-        }
-        inner.head %= self.len;
-        drop(inner); // Release lock before calling pop()
-        self.pop()
+    /// The queue must not be used afterwards.
+    pub fn deallocate<A: Allocator>(&self, allocator: &A) {
+        self.inner.lock().slots.deallocate(allocator);
     }
 
     /// Attempts to pop a completion entry from the queue.
@@ -173,15 +318,417 @@ impl CompQueue {
     /// with the new head position.
     pub fn try_pop(&self) -> Option<(usize, Completion)> {
         let mut inner = self.inner.lock();
-        let entry_clone = inner.slots[inner.head].clone();
-        let status = entry_clone.status;
+        let slot = unsafe { inner.slots.addr.add(inner.cursor.head) };
+
+        // Check the phase bit before reading the rest of the entry
+        let status = unsafe { (slot as *const u16).add(7).read_volatile() };
+        if !inner.cursor.is_posted(u16::from_le(status)) {
+            return None;
+        }
+        read_barrier();
+        let entry = unsafe { slot.read_volatile() }.swap_le();
+
+        inner.cursor.advance(1);
+        Some((inner.cursor.head, entry))
+    }
+}
+
+/// Command IDs in use on an I/O submission queue.
+///
+/// A command keeps its ID until its completion is taken, which may be
+/// long after the queue has wrapped, so IDs are not taken from the tail.
+/// IDs are taken under the queue lock, but can be listed without it.
+pub(crate) struct CommandIds {
+    /// One bit per command ID, set while it is in use
+    used: Vec<AtomicU64>,
+    /// Number of command IDs
+    len: usize,
+    /// Where the search for a free ID starts
+    next: AtomicUsize,
+}
+
+impl CommandIds {
+    /// Create a map of `len` command IDs, all of them free.
+    pub fn new(len: usize) -> Self {
+        let used = (0..len.div_ceil(64)).map(|_| AtomicU64::new(0)).collect();
+        Self { used, len, next: AtomicUsize::new(0) }
+    }
+
+    /// Take a free command ID, or `None` if all are in use.
+    ///
+    /// The search starts past the last ID taken, so a freed ID is not
+    /// reused right away.
+    pub fn allocate(&self) -> Option<u16> {
+        let next = self.next.load(Ordering::Relaxed);
+        let cid = (0..self.len).map(|i| (next + i) % self.len).find(|&cid| {
+            let bit = 1 << (cid % 64);
+            self.used[cid / 64].fetch_or(bit, Ordering::AcqRel) & bit == 0
+        })?;
+        self.next.store((cid + 1) % self.len, Ordering::Relaxed);
+        Some(cid as u16)
+    }
+
+    /// Free a command ID taken with `allocate`.
+    pub fn release(&self, cid: u16) {
+        let cid = cid as usize;
+        if cid < self.len {
+            self.used[cid / 64].fetch_and(!(1 << (cid % 64)), Ordering::AcqRel);
+        }
+    }
+
+    /// Iterate over the command IDs in use.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len)
+            .filter(|&cid| self.used[cid / 64].load(Ordering::Acquire) & (1 << (cid % 64)) != 0)
+            .map(|cid| cid as u16)
+    }
+}
+
+/// Lock the queue picked by `select` and run `f` on it.
+///
+/// Queues are only shut down under their lock, so one picked before a
+/// shutdown is found out once locked and `select` is asked again. `f`
+/// therefore never runs on a shut down queue. Returns `None` once
+/// `select` finds no queue.
+pub(crate) fn with_active_queue<Q, R>(
+    mut select: impl FnMut() -> Option<Arc<Mutex<Q>>>,
+    is_shutdown: impl Fn(&Q) -> bool,
+    f: impl FnOnce(&mut Q) -> R,
+) -> Option<R> {
+    loop {
+        let queue_arc = select()?;
+        let mut queue = queue_arc.lock();
+        if !is_shutdown(&queue) {
+            return Some(f(&mut queue));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::mock::{MockAllocator, PHYS_OFFSET};
+
+    /// Controller side of a completion queue.
+    struct MockController {
+        slots: *mut Completion,
+        len: usize,
+        tail: usize,
+        phase: bool,
+    }
+
+    impl MockController {
+        fn new(cq: &CompQueue) -> Self {
+            let slots = (cq.address() - PHYS_OFFSET) as *mut Completion;
+            Self { slots, len: cq.len, tail: 0, phase: true }
+        }
+
+        /// Post the completion of `cmd_id`, inverting the phase tag on wrap.
+        fn post(&mut self, cmd_id: u16) {
+            self.write(self.tail, cmd_id, self.phase);
+            self.tail = (self.tail + 1) % self.len;
+            if self.tail == 0 {
+                self.phase = !self.phase;
+            }
+        }
+
+        /// Write an entry into any slot with any phase tag.
+        fn write(&self, slot: usize, cmd_id: u16, phase: bool) {
+            let entry = Completion {
+                command_specific: 0,
+                dw1: 0,
+                sq_head: 0,
+                sq_id: 1,
+                cmd_id,
+                status: phase as u16,
+            };
+            unsafe { self.slots.add(slot).write_volatile(entry.swap_le()) };
+        }
+    }
+
+    /// A submission queue that can be shut down, like an I/O queue pair.
+    struct TestQueue {
+        sq: SubQueue,
+        shutdown: bool,
+    }
+
+    fn test_queue(allocator: &MockAllocator) -> Arc<Mutex<TestQueue>> {
+        let sq = SubQueue::new(4, allocator, PlacementHint::Any);
+        Arc::new(Mutex::new(TestQueue { sq, shutdown: false }))
+    }
+
+    fn push(sq: &SubQueue, cmd_id: u16) -> Result<usize> {
+        sq.try_push(Command::flush(cmd_id, 1))
+    }
+
+    fn drain(cq: &CompQueue) -> Vec<(usize, u16)> {
+        core::iter::from_fn(|| cq.try_pop())
+            .map(|(head, entry)| (head, entry.cmd_id))
+            .collect()
+    }
+
+    #[test]
+    fn queue_shut_down_after_selection_is_passed_over() {
+        let allocator = MockAllocator;
+        let removed = test_queue(&allocator);
+        let remaining = test_queue(&allocator);
+        let cq = CompQueue::new(4, &allocator, PlacementHint::Any);
+        let mut controller = MockController::new(&cq);
 
-        (((status & 1) == 1) == inner.phase).then(|| {
-            inner.head = (inner.head + 1) % self.len;
-            if inner.head == 0 {
-                inner.phase = !inner.phase;
+        // The first queue is shut down right after being picked, as queue
+        // removal may do before the submitter takes the lock
+        let mut picks = 0;
+        let select = || {
+            picks += 1;
+            let queue = if picks == 1 { &removed } else { &remaining };
+            let picked = queue.clone();
+            if picks == 1 {
+                removed.lock().shutdown = true;
             }
-            (inner.head, entry_clone)
-        })
+            Some(picked)
+        };
+        let cmd_id = with_active_queue(select, |queue| queue.shutdown, |queue| {
+            push(&queue.sq, 7).map(|_| 7)
+        });
+
+        assert!(matches!(cmd_id, Some(Ok(7))));
+        assert_eq!(picks, 2);
+        assert_eq!(removed.lock().sq.tail(), 0);
+        assert_eq!(remaining.lock().sq.tail(), 1);
+
+        controller.post(7);
+        assert_eq!(drain(&cq), [(1, 7)]);
+    }
+
+    #[test]
+    fn no_active_queue_runs_nothing() {
+        let allocator = MockAllocator;
+        let queue = test_queue(&allocator);
+        queue.lock().shutdown = true;
+
+        let mut picks = 0;
+        let select = || {
+            picks += 1;
+            (picks == 1).then(|| queue.clone())
+        };
+        let ran = with_active_queue(select, |queue| queue.shutdown, |queue| {
+            push(&queue.sq, 1).unwrap();
+        });
+
+        assert_eq!(ran, None);
+        assert_eq!(queue.lock().sq.tail(), 0);
+    }
+
+    #[test]
+    fn cursor_flips_phase_on_wrap() {
+        let mut cursor = CqCursor::new(4);
+        for _ in 0..3 {
+            cursor.advance(1);
+        }
+        assert_eq!((cursor.head, cursor.phase), (3, true));
+
+        cursor.advance(1);
+        assert_eq!((cursor.head, cursor.phase), (0, false));
+        assert!(cursor.is_posted(0));
+        assert!(!cursor.is_posted(1));
+    }
+
+    #[test]
+    fn cursor_advance_across_wrap_point() {
+        let mut cursor = CqCursor::new(4);
+        cursor.advance(3);
+        cursor.advance(2);
+        assert_eq!((cursor.head, cursor.phase), (1, false));
+
+        // Two full passes restore the phase
+        cursor.advance(8);
+        assert_eq!((cursor.head, cursor.phase), (1, false));
+
+        cursor.advance(7);
+        assert_eq!((cursor.head, cursor.phase), (0, false));
+    }
+
+    #[test]
+    fn cursor_advance_matches_single_steps() {
+        for len in 1..6 {
+            for start in 0..len * 2 {
+                for count in 0..len * 3 {
+                    let mut bulk = CqCursor::new(len);
+                    bulk.advance(start);
+                    let mut steps = bulk;
+                    bulk.advance(count);
+                    (0..count).for_each(|_| steps.advance(1));
+                    assert_eq!(bulk, steps, "len {len} start {start} count {count}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pop_follows_controller_across_wraps() {
+        let allocator = MockAllocator;
+        let cq = CompQueue::new(4, &allocator, PlacementHint::Any);
+        let mut controller = MockController::new(&cq);
+
+        let mut next = 0;
+        for batch in [1, 3, 2, 4, 3] {
+            for _ in 0..batch {
+                controller.post(next);
+                next += 1;
+            }
+            let popped = drain(&cq);
+            let ids: Vec<_> = popped.iter().map(|&(_, id)| id).collect();
+            assert_eq!(ids, (next - batch..next).collect::<Vec<_>>());
+            assert_eq!(popped.last().unwrap().0, controller.tail);
+        }
+        cq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn try_pop_follows_entries_across_wraps() {
+        let allocator = MockAllocator;
+        let cq = CompQueue::new(4, &allocator, PlacementHint::Any);
+        let mut controller = MockController::new(&cq);
+
+        for cmd_id in 0..3 {
+            controller.post(cmd_id);
+        }
+        let popped: Vec<_> = (0..3).map_while(|_| cq.try_pop()).collect();
+        let (head, entry) = popped[2];
+        assert_eq!((popped.len(), head, { entry.cmd_id }), (3, 3, 2));
+
+        for cmd_id in 3..6 {
+            controller.post(cmd_id);
+        }
+        let popped: Vec<_> = (0..3).map_while(|_| cq.try_pop()).collect();
+        let ids: Vec<_> = popped.iter().map(|(_, entry)| entry.cmd_id).collect();
+        assert_eq!(ids, [3, 4, 5]);
+        assert_eq!(popped[2].0, 2);
+        assert!(cq.try_pop().is_none());
+        cq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn stale_entries_are_not_consumed() {
+        let allocator = MockAllocator;
+        let cq = CompQueue::new(4, &allocator, PlacementHint::Any);
+        let mut controller = MockController::new(&cq);
+
+        // Zeroed slots carry phase 0 and are not completions
+        assert!(cq.try_pop().is_none());
+
+        for id in 0..4 {
+            controller.post(id);
+        }
+        assert_eq!(drain(&cq).len(), 4);
+
+        // After the wrap, the entries of the first pass are stale
+        assert!(cq.try_pop().is_none());
+
+        // An entry posted past the head is not consumed before the head
+        controller.write(1, 9, false);
+        assert!(cq.try_pop().is_none());
+        controller.post(4);
+        assert_eq!(drain(&cq), [(1, 4), (2, 9)]);
+        cq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn reset_discards_posted_entries() {
+        let allocator = MockAllocator;
+        let cq = CompQueue::new(4, &allocator, PlacementHint::Any);
+        let mut controller = MockController::new(&cq);
+
+        controller.post(0);
+        controller.post(1);
+        cq.reset();
+        assert!(cq.try_pop().is_none());
+
+        let mut controller = MockController::new(&cq);
+        controller.post(2);
+        assert_eq!(drain(&cq), [(1, 2)]);
+        cq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn sq_full_detection_across_wraps() {
+        let allocator = MockAllocator;
+        let sq = SubQueue::new(4, &allocator, PlacementHint::Any);
+        assert_eq!(sq.free_slots(), 3);
+
+        let mut next = 0;
+        for _ in 0..5 {
+            while sq.free_slots() > 0 {
+                push(&sq, next).unwrap();
+                next += 1;
+            }
+            assert!(matches!(push(&sq, next), Err(Error::SubQueueFull)));
+
+            // The controller fetches all but the last command
+            sq.set_head((sq.tail() + 3) % 4);
+            assert_eq!(sq.free_slots(), 2);
+        }
+        sq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn sq_push_returns_wrapped_tail() {
+        let allocator = MockAllocator;
+        let sq = SubQueue::new(4, &allocator, PlacementHint::Any);
+
+        let tails: Vec<_> = (0..3).map(|id| push(&sq, id).unwrap()).collect();
+        assert_eq!(tails, [1, 2, 3]);
+        sq.set_head(3);
+        assert_eq!(push(&sq, 3).unwrap(), 0);
+        assert_eq!(push(&sq, 4).unwrap(), 1);
+        assert_eq!(sq.free_slots(), 1);
+        sq.deallocate(&allocator);
+    }
+
+    #[test]
+    fn command_ids_outlive_queue_wraps() {
+        let cids = CommandIds::new(4);
+        let long_running = cids.allocate().unwrap();
+        for _ in 0..10 {
+            let cid = cids.allocate().unwrap();
+            assert_ne!(cid, long_running);
+            cids.release(cid);
+        }
+
+        let taken: Vec<_> = (0..3).map(|_| cids.allocate().unwrap()).collect();
+        assert!(!taken.contains(&long_running));
+        assert_eq!(cids.allocate(), None);
+        assert_eq!(cids.iter().count(), 4);
+
+        cids.release(long_running);
+        assert_eq!(cids.allocate(), Some(long_running));
+    }
+
+    #[test]
+    fn sq_ignores_stale_and_bogus_heads() {
+        let allocator = MockAllocator;
+        let sq = SubQueue::new(4, &allocator, PlacementHint::Any);
+        for id in 0..3 {
+            push(&sq, id).unwrap();
+        }
+        sq.set_head(2);
+        assert_eq!(sq.free_slots(), 2);
+
+        // An older head from a completion reaped late
+        sq.set_head(1);
+        assert_eq!(sq.free_slots(), 2);
+
+        // Past the tail, or outside the queue
+        push(&sq, 3).unwrap();
+        sq.set_head(1);
+        sq.set_head(7);
+        assert_eq!(sq.free_slots(), 1);
+
+        // Caught up with the tail
+        sq.set_head(0);
+        assert_eq!(sq.free_slots(), 3);
+        sq.deallocate(&allocator);
     }
 }
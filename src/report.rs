@@ -0,0 +1,165 @@
+//! Identity and topology reports of a device.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::device::{ControllerData, NamespaceInfo};
+use crate::format::LbaFormat;
+use crate::power::PowerState;
+
+/// Namespace identifier types in the Namespace Identification Descriptor list.
+const NIDT_EUI64: u8 = 1;
+const NIDT_NGUID: u8 = 2;
+const NIDT_UUID: u8 = 3;
+
+/// Structured description of a device, returned by `NVMeDevice::describe`.
+///
+/// Meant for an operating system's device inventory: everything needed to
+/// name, list and match the device and its namespaces.
+#[derive(Debug, Clone)]
+pub struct DeviceReport {
+    /// NVMe version as (major, minor, tertiary)
+    pub version: (u16, u8, u8),
+    /// Identify Controller data
+    pub controller: ControllerData,
+    /// Firmware slots, `None` if the Firmware Slot log could not be read
+    pub firmware: Option<FirmwareReport>,
+    /// Active namespaces, by ascending ID
+    pub namespaces: Vec<NamespaceReport>,
+    /// Optional NVM commands the controller supports
+    pub commands: OptionalCommands,
+    /// Power states reported by the controller
+    pub power_states: Vec<PowerState>,
+    /// Queue configuration and limits
+    pub queues: QueueReport,
+}
+
+/// Firmware slots of a controller.
+#[derive(Debug, Clone)]
+pub struct FirmwareReport {
+    /// Slot of the running firmware
+    pub active_slot: u8,
+    /// Slot activated at the next reset, if one is pending
+    pub next_slot: Option<u8>,
+    /// Number of slots the controller supports
+    pub slot_count: u8,
+    /// Slot 1 is read-only
+    pub slot1_read_only: bool,
+    /// Revision of each populated slot
+    pub revisions: Vec<(u8, String)>,
+}
+
+/// Description of a namespace.
+#[derive(Debug, Clone)]
+pub struct NamespaceReport {
+    /// ID, size and sharing of the namespace
+    pub info: NamespaceInfo,
+    /// Tail blocks kept unwritten by the host
+    pub reserved_blocks: u64,
+    /// Supported LBA formats, empty if they could not be read
+    pub formats: Vec<LbaFormat>,
+    /// Globally unique identifiers of the namespace
+    pub identifiers: NamespaceIdentifiers,
+}
+
+/// Globally unique identifiers of a namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceIdentifiers {
+    /// IEEE Extended Unique Identifier
+    pub eui64: Option<[u8; 8]>,
+    /// Namespace Globally Unique Identifier
+    pub nguid: Option<[u8; 16]>,
+    /// Namespace UUID
+    pub uuid: Option<[u8; 16]>,
+}
+
+impl NamespaceIdentifiers {
+    /// Parse a Namespace Identification Descriptor list (Identify CNS 03h).
+    ///
+    /// Descriptors of other types and all-zero identifiers are skipped.
+    pub fn from_descriptor_list(data: &[u8]) -> Self {
+        let mut ids = Self::default();
+        let mut offset = 0;
+        while offset + 4 <= data.len() {
+            let kind = data[offset];
+            let len = data[offset + 1] as usize;
+            if kind == 0 || len == 0 {
+                break;
+            }
+            let start = offset + 4;
+            let Some(id) = data.get(start..start + len) else {
+                break;
+            };
+            if id.iter().any(|&b| b != 0) {
+                match (kind, len) {
+                    (NIDT_EUI64, 8) => ids.eui64 = id.try_into().ok(),
+                    (NIDT_NGUID, 16) => ids.nguid = id.try_into().ok(),
+                    (NIDT_UUID, 16) => ids.uuid = id.try_into().ok(),
+                    _ => {}
+                }
+            }
+            offset = start + len;
+        }
+        ids
+    }
+}
+
+/// Optional NVM commands and features reported in ONCS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptionalCommands {
+    /// Compare
+    pub compare: bool,
+    /// Write Uncorrectable
+    pub write_uncorrectable: bool,
+    /// Dataset Management
+    pub dataset_management: bool,
+    /// Write Zeroes
+    pub write_zeroes: bool,
+    /// Save field of Set Features and Select field of Get Features
+    pub save_select_features: bool,
+    /// Reservations
+    pub reservations: bool,
+    /// Timestamp feature
+    pub timestamp: bool,
+    /// Verify
+    pub verify: bool,
+    /// Copy
+    pub copy: bool,
+}
+
+impl OptionalCommands {
+    /// Decode the ONCS field of Identify Controller.
+    pub fn from_oncs(oncs: u16) -> Self {
+        let bit = |n: u16| oncs & (1 << n) != 0;
+        Self {
+            compare: bit(0),
+            write_uncorrectable: bit(1),
+            dataset_management: bit(2),
+            write_zeroes: bit(3),
+            save_select_features: bit(4),
+            reservations: bit(5),
+            timestamp: bit(6),
+            verify: bit(7),
+            copy: bit(8),
+        }
+    }
+}
+
+/// Queue configuration of a device and the controller's limits.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueReport {
+    /// Entries in the admin queues
+    pub admin_depth: usize,
+    /// I/O queue pairs in use
+    pub io_queues: usize,
+    /// Maximum outstanding commands per I/O queue
+    pub queue_depth: usize,
+    /// I/O submission queues the controller allows
+    pub max_io_sq: u16,
+    /// I/O completion queues the controller allows
+    pub max_io_cq: u16,
+    /// Maximum entries per queue
+    pub max_queue_entries: u16,
+    /// Maximum transfer size in bytes
+    pub max_transfer_size: usize,
+}
@@ -0,0 +1,337 @@
+//! Block-layer requests with merging and splitting.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::cmd::{IoHints, QueuePriority};
+use crate::device::Namespace;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Largest block count of a Read, Write or Write Zeroes command.
+const MAX_COMMAND_BLOCKS: u64 = u16::MAX as u64;
+
+/// Largest block count of a dataset management range.
+const MAX_TRIM_BLOCKS: u64 = u32::MAX as u64;
+
+/// Operation of a block request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestOp {
+    /// Read blocks into the request's buffers
+    Read,
+    /// Write blocks from the request's buffers
+    Write,
+    /// Deallocate blocks
+    Trim,
+    /// Zero blocks without a data transfer
+    WriteZeroes,
+    /// Commit the volatile write cache, ordered after all earlier requests
+    Flush,
+}
+
+/// Flags of a block request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RequestFlags {
+    /// Dataset management hints of reads and writes
+    pub hints: IoHints,
+    /// Never merge the request with its neighbours
+    pub no_merge: bool,
+}
+
+/// A buffer segment of a request.
+enum Segment<'a> {
+    /// Destination of a read
+    In(&'a mut [u8]),
+    /// Source of a write
+    Out(&'a [u8]),
+}
+
+impl Segment<'_> {
+    /// Address and length of the segment.
+    fn span(&self) -> (usize, usize) {
+        match self {
+            Self::In(buf) => (buf.as_ptr() as usize, buf.len()),
+            Self::Out(buf) => (buf.as_ptr() as usize, buf.len()),
+        }
+    }
+}
+
+/// A block request queued on an `Elevator`.
+pub struct Request<'a> {
+    /// Operation
+    pub op: RequestOp,
+    /// First block
+    pub lba: u64,
+    /// Number of blocks, taken from the buffer for reads and writes
+    pub blocks: u64,
+    /// Flags
+    pub flags: RequestFlags,
+    /// Dispatch priority, more urgent requests go first
+    pub priority: QueuePriority,
+    /// Caller's identifier, reported in the completion
+    pub tag: u64,
+    /// Buffer segments in block order
+    segments: Vec<Segment<'a>>,
+}
+
+impl<'a> Request<'a> {
+    fn new(op: RequestOp, lba: u64, blocks: u64, segments: Vec<Segment<'a>>) -> Self {
+        Self {
+            op,
+            lba,
+            blocks,
+            flags: RequestFlags::default(),
+            priority: QueuePriority::default(),
+            tag: 0,
+            segments,
+        }
+    }
+
+    /// Read blocks from `lba` into `buf`.
+    pub fn read(lba: u64, buf: &'a mut [u8]) -> Self {
+        Self::new(RequestOp::Read, lba, 0, alloc::vec![Segment::In(buf)])
+    }
+
+    /// Write blocks from `buf` to `lba`.
+    pub fn write(lba: u64, buf: &'a [u8]) -> Self {
+        Self::new(RequestOp::Write, lba, 0, alloc::vec![Segment::Out(buf)])
+    }
+
+    /// Deallocate `blocks` blocks from `lba`.
+    pub fn trim(lba: u64, blocks: u64) -> Self {
+        Self::new(RequestOp::Trim, lba, blocks, Vec::new())
+    }
+
+    /// Zero `blocks` blocks from `lba`.
+    pub fn write_zeroes(lba: u64, blocks: u64) -> Self {
+        Self::new(RequestOp::WriteZeroes, lba, blocks, Vec::new())
+    }
+
+    /// Flush the volatile write cache.
+    pub fn flush() -> Self {
+        Self::new(RequestOp::Flush, 0, 0, Vec::new())
+    }
+
+    /// Set the flags.
+    pub fn with_flags(mut self, flags: RequestFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the dispatch priority.
+    pub fn with_priority(mut self, priority: QueuePriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set the caller's identifier.
+    pub fn with_tag(mut self, tag: u64) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    /// Check whether `next` continues this request and may join it.
+    fn can_merge(&self, next: &Request<'_>) -> bool {
+        !self.flags.no_merge
+            && !next.flags.no_merge
+            && self.op == next.op
+            && self.op != RequestOp::Flush
+            && self.flags.hints == next.flags.hints
+            && self.priority == next.priority
+            && self.lba.checked_add(self.blocks) == Some(next.lba)
+    }
+
+    /// Check whether the blocks of this request and `other` overlap.
+    fn overlaps(&self, other: &Request<'_>) -> bool {
+        self.lba < other.lba.saturating_add(other.blocks)
+            && other.lba < self.lba.saturating_add(self.blocks)
+    }
+}
+
+/// Outcome of a dispatched request, or of several merged ones.
+#[derive(Debug)]
+pub struct RequestCompletion {
+    /// Tags of the requests, in block order
+    pub tags: Vec<u64>,
+    /// Result of the commands; the first error stops the rest
+    pub result: Result<()>,
+}
+
+/// Queues block requests, merges adjacent ones and splits them at
+/// command limits on dispatch.
+///
+/// Between flushes, requests are dispatched by priority and then by
+/// operation and block address, except that a request is never moved
+/// ahead of an earlier one whose blocks it overlaps. Read and
+/// write buffers are split at the maximum transfer size; merged requests
+/// whose buffers follow each other in memory share commands.
+pub struct Elevator<'a, A: Allocator> {
+    /// Namespace the requests go to
+    ns: Arc<Namespace<A>>,
+    /// Queued requests in arrival order
+    requests: Vec<Request<'a>>,
+}
+
+impl<'a, A: Allocator> Elevator<'a, A> {
+    /// Create an empty elevator for a namespace.
+    pub fn new(ns: Arc<Namespace<A>>) -> Self {
+        Self { ns, requests: Vec::new() }
+    }
+
+    /// Queue a request.
+    ///
+    /// Fails with `Error::InvalidBufferSize` if a buffer is not a whole
+    /// number of blocks, and with `Error::LbaOutOfRange` if the request
+    /// runs past the end of the namespace.
+    pub fn push(&mut self, mut request: Request<'a>) -> Result<()> {
+        if matches!(request.op, RequestOp::Read | RequestOp::Write) {
+            let unit = self.ns.transfer_unit();
+            let bytes = request.segments.iter().map(|segment| segment.span().1 as u64).sum::<u64>();
+            if bytes % unit != 0 {
+                return Err(Error::InvalidBufferSize);
+            }
+            request.blocks = bytes / unit;
+        }
+        if request.op != RequestOp::Flush {
+            match request.lba.checked_add(request.blocks) {
+                Some(end) if end <= self.ns.block_count() => {}
+                _ => return Err(Error::LbaOutOfRange),
+            }
+        }
+
+        self.requests.push(request);
+        Ok(())
+    }
+
+    /// Get the number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Check whether no requests are queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Merge and submit all queued requests, waiting for each to complete.
+    pub fn dispatch(&mut self) -> Vec<RequestCompletion> {
+        let mut completions = Vec::new();
+        let mut batch = Vec::new();
+        let mut flushed = false;
+
+        for request in core::mem::take(&mut self.requests) {
+            if request.op != RequestOp::Flush {
+                batch.push(request);
+                continue;
+            }
+            if !batch.is_empty() {
+                completions.extend(self.submit(core::mem::take(&mut batch)));
+                flushed = false;
+            }
+            match completions.last_mut() {
+                // Consecutive flushes collapse into one
+                Some(last) if flushed => last.tags.push(request.tag),
+                _ => {
+                    let result = self.ns.flush();
+                    completions.push(RequestCompletion { tags: alloc::vec![request.tag], result });
+                    flushed = true;
+                }
+            }
+        }
+        completions.extend(self.submit(batch));
+
+        completions
+    }
+
+    /// Submit a batch without flushes.
+    fn submit(&self, batch: Vec<Request<'a>>) -> impl Iterator<Item = RequestCompletion> + '_ {
+        Self::merge(batch).into_iter().map(|(request, tags)| RequestCompletion {
+            tags,
+            result: self.execute(&request),
+        })
+    }
+
+    /// Sort a batch without flushes and merge adjacent requests.
+    ///
+    /// Requests are only reordered within runs that do not overlap, so a
+    /// request never passes an earlier one touching the same blocks.
+    fn merge(batch: Vec<Request<'a>>) -> Vec<(Request<'a>, Vec<u64>)> {
+        let mut runs: Vec<Vec<Request<'a>>> = Vec::new();
+        for request in batch {
+            match runs.last_mut() {
+                Some(run) if !run.iter().any(|queued| queued.overlaps(&request)) => {
+                    run.push(request)
+                }
+                _ => runs.push(alloc::vec![request]),
+            }
+        }
+
+        let mut merged: Vec<(Request<'a>, Vec<u64>)> = Vec::new();
+        for mut run in runs {
+            run.sort_by_key(|request| (request.priority as u8, request.op, request.lba));
+            // Requests of different runs are never merged
+            let start = merged.len();
+            for request in run {
+                match merged[start..].last_mut() {
+                    Some((last, tags)) if last.can_merge(&request) => {
+                        last.blocks += request.blocks;
+                        last.segments.extend(request.segments);
+                        tags.push(request.tag);
+                    }
+                    _ => {
+                        let tag = request.tag;
+                        merged.push((request, alloc::vec![tag]));
+                    }
+                }
+            }
+        }
+        merged
+    }
+
+    /// Execute a merged request, split at the command limits.
+    fn execute(&self, request: &Request<'a>) -> Result<()> {
+        let ns = &self.ns;
+        match request.op {
+            RequestOp::Flush => ns.flush(),
+            RequestOp::Trim => split(request.lba, request.blocks, MAX_TRIM_BLOCKS)
+                .try_for_each(|(lba, blocks)| ns.trim(lba, blocks)),
+            RequestOp::WriteZeroes => split(request.lba, request.blocks, MAX_COMMAND_BLOCKS)
+                .try_for_each(|(lba, blocks)| ns.write_zeroes(lba, blocks as u16).map(|_| ())),
+            RequestOp::Read | RequestOp::Write => {
+                let unit = ns.transfer_unit();
+                let max_blocks =
+                    (ns.max_transfer_size() as u64 / unit).clamp(1, MAX_COMMAND_BLOCKS);
+                let write = request.op == RequestOp::Write;
+
+                // Segments that follow each other in memory form one span
+                let mut spans: Vec<(usize, usize)> = Vec::new();
+                for (address, len) in request.segments.iter().map(Segment::span) {
+                    match spans.last_mut() {
+                        Some(last) if last.0 + last.1 == address => last.1 += len,
+                        _ => spans.push((address, len)),
+                    }
+                }
+
+                let mut lba = request.lba;
+                for (address, len) in spans {
+                    let blocks = len as u64 / unit;
+                    for (start, count) in split(lba, blocks, max_blocks) {
+                        let offset = ((start - lba) * unit) as usize;
+                        let bytes = (count * unit) as usize;
+                        ns.transfer(start, address + offset, bytes, write, request.flags.hints)?;
+                    }
+                    lba += blocks;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Split `blocks` blocks from `lba` into runs of at most `max` blocks.
+fn split(lba: u64, blocks: u64, max: u64) -> impl Iterator<Item = (u64, u64)> {
+    (0..blocks.div_ceil(max)).map(move |index| {
+        let start = index * max;
+        (lba + start, max.min(blocks - start))
+    })
+}
@@ -4,6 +4,7 @@ use alloc::vec::Vec;
 use core::mem::size_of;
 
 use crate::cmd::Command;
+use crate::endian::{le_struct, read_le};
 use crate::error::{Error, Result};
 
 /// Sanitize action type.
@@ -69,6 +70,47 @@ impl SanitizeOptions {
     }
 }
 
+/// Sanitize capabilities (SANICAP) from Identify Controller.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SanitizeCapabilities {
+    /// Raw SANICAP field
+    pub raw: u32,
+}
+
+impl SanitizeCapabilities {
+    /// Parse from the SANICAP field.
+    pub fn from_sanicap(raw: u32) -> Self {
+        Self { raw }
+    }
+
+    /// Check whether any sanitize action is supported.
+    pub fn any_supported(&self) -> bool {
+        self.raw & 0x7 != 0
+    }
+
+    /// Check whether an action is supported.
+    ///
+    /// Exit failure mode is available whenever sanitize is.
+    pub fn supports(&self, action: SanitizeAction) -> bool {
+        match action {
+            SanitizeAction::ExitFailureMode => self.any_supported(),
+            SanitizeAction::CryptoErase => self.raw & (1 << 0) != 0,
+            SanitizeAction::BlockErase => self.raw & (1 << 1) != 0,
+            SanitizeAction::Overwrite => self.raw & (1 << 2) != 0,
+        }
+    }
+
+    /// Check whether a single namespace can be sanitized (SPNS).
+    pub fn per_namespace(&self) -> bool {
+        self.raw & (1 << 4) != 0
+    }
+
+    /// Check whether the No-Deallocate After Sanitize bit is ignored (NDI).
+    pub fn no_dealloc_inhibited(&self) -> bool {
+        self.raw & (1 << 29) != 0
+    }
+}
+
 /// Sanitize Per Namespace (SPN) configuration for NVMe 2.3.
 #[derive(Debug, Clone)]
 pub struct SanitizePerNamespace {
@@ -105,17 +147,44 @@ impl SanitizePerNamespace {
         self
     }
 
-    /// Build sanitize command for namespace.
-    pub fn build_command(&self, cmd_id: u16) -> Command {
-        Command::sanitize(
+    /// Build the sanitize command.
+    ///
+    /// A single namespace is sanitized with the per-namespace scope, which
+    /// the controller must report in `caps`. Targeting all namespaces
+    /// issues a subsystem-wide sanitize.
+    pub fn build_command(&self, cmd_id: u16, caps: &SanitizeCapabilities) -> Result<Command> {
+        if !caps.supports(self.options.action) {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let options = &self.options;
+        if self.namespace_id == 0xFFFFFFFF {
+            return Ok(Command::sanitize(
+                cmd_id,
+                0,
+                options.action as u8,
+                options.allow_unrestricted_exit,
+                options.overwrite_pass_count,
+                options.overwrite_invert_pattern,
+                options.no_dealloc_after_sanitize,
+            ));
+        }
+
+        if self.namespace_id == 0 {
+            return Err(Error::InvalidNamespace);
+        }
+        if !caps.per_namespace() {
+            return Err(Error::SanitizePerNamespaceNotSupported);
+        }
+        Ok(Command::sanitize_namespace(
             cmd_id,
             self.namespace_id,
-            self.options.action as u8,
-            self.options.allow_unrestricted_exit,
-            self.options.overwrite_pass_count,
-            self.options.overwrite_invert_pattern,
-            self.options.no_dealloc_after_sanitize,
-        )
+            options.action as u8,
+            options.allow_unrestricted_exit,
+            options.overwrite_pass_count,
+            options.overwrite_invert_pattern,
+            options.no_dealloc_after_sanitize,
+        ))
     }
 }
 
@@ -123,7 +192,9 @@ impl SanitizePerNamespace {
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct SanitizeStatus {
-    /// Sanitize progress (0-65535, where 65535 = 100%)
+    /// Sanitize progress, the numerator of a fraction of 65536 (SPROG)
+    ///
+    /// 0xFFFF unless a sanitize is in progress.
     pub progress: u16,
     /// Sanitize status flags
     pub flags: u16,
@@ -143,6 +214,18 @@ pub struct SanitizeStatus {
     pub time_for_crypto_erase_nd: u32,
 }
 
+le_struct!(SanitizeStatus {
+    progress,
+    flags,
+    cdw10_info,
+    time_for_overwrite,
+    time_for_block_erase,
+    time_for_crypto_erase,
+    time_for_overwrite_nd,
+    time_for_block_erase_nd,
+    time_for_crypto_erase_nd,
+});
+
 impl SanitizeStatus {
     /// Parse from log page data.
     pub fn from_log_data(data: &[u8]) -> Result<Self> {
@@ -151,7 +234,7 @@ impl SanitizeStatus {
         }
 
         let status = unsafe {
-            core::ptr::read_unaligned(data.as_ptr() as *const Self)
+            read_le::<Self>(data)
         };
 
         Ok(status)
@@ -173,11 +256,35 @@ impl SanitizeStatus {
     }
 
     /// Get progress percentage.
+    ///
+    /// 100 unless a sanitize is in progress.
     pub fn progress_percent(&self) -> f32 {
-        (self.progress as f32 / 65535.0) * 100.0
+        if !self.is_in_progress() {
+            return 100.0;
+        }
+        (self.progress as f32 / 65536.0) * 100.0
+    }
+
+    /// Check if the last sanitize allowed unrestricted exit (AUSE).
+    ///
+    /// Only then can failure mode be left with the Exit Failure Mode action.
+    pub fn unrestricted_exit(&self) -> bool {
+        self.cdw10_info & (1 << 3) != 0
     }
 }
 
+/// Outcome of leaving sanitize failure mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeRecovery {
+    /// The last sanitize did not fail, so nothing was done
+    NotFailed,
+    /// Failure mode was exited and the namespaces accept I/O again
+    Exited,
+    /// The failed sanitize used restricted completion mode, so only a
+    /// new sanitize that completes successfully leaves failure mode
+    RequiresSanitize,
+}
+
 /// Security protocol identifiers.
 #[derive(Debug, Clone, Copy)]
 pub enum SecurityProtocol {
@@ -203,6 +310,235 @@ impl SecurityProtocol {
     }
 }
 
+/// A security protocol transfer that may span several commands.
+#[derive(Debug, Clone, Copy)]
+pub struct SecurityTransfer {
+    /// Namespace ID
+    pub nsid: u32,
+    /// Security protocol (SECP)
+    pub protocol: SecurityProtocol,
+    /// SP specific field of every command (SPSP), like a TCG ComID
+    pub sp_specific: u16,
+}
+
+impl SecurityTransfer {
+    /// Create a transfer.
+    pub fn new(nsid: u32, protocol: SecurityProtocol, sp_specific: u16) -> Self {
+        Self { nsid, protocol, sp_specific }
+    }
+
+    /// Check whether the transfer carries TCG ComPackets.
+    ///
+    /// True for the TCG protocol on any ComID but level 0 discovery.
+    pub fn carries_compackets(&self) -> bool {
+        matches!(self.protocol, SecurityProtocol::Tcg) && self.sp_specific != TCG_DISCOVERY_COMID
+    }
+}
+
+/// ComID of TCG level 0 discovery.
+const TCG_DISCOVERY_COMID: u16 = 0x0001;
+
+/// Size of a TCG ComPacket header.
+pub const TCG_COMPACKET_HEADER: usize = 20;
+
+/// Get the OutstandingData field of a received TCG ComPacket.
+///
+/// A nonzero value means the response did not fit and the rest is
+/// returned by further IF-RECV commands on the same ComID. `None` if
+/// `compacket` is shorter than the ComPacket header.
+pub fn tcg_outstanding_data(compacket: &[u8]) -> Option<u32> {
+    let bytes = compacket.get(8..12)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Get the size of the TCG ComPacket at the start of `data`, header included.
+///
+/// `None` if `data` is shorter than the header or the ComPacket's Length.
+pub fn tcg_compacket_len(data: &[u8]) -> Option<usize> {
+    let bytes = data.get(16..TCG_COMPACKET_HEADER)?;
+    let len = TCG_COMPACKET_HEADER + u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+    (len <= data.len()).then_some(len)
+}
+
+/// TCG security subsystem class (SSC) reported by level 0 discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcgSsc {
+    /// Enterprise SSC
+    Enterprise,
+    /// Opal SSC 1.0
+    Opal1,
+    /// Opal SSC 2.0
+    Opal2,
+    /// Opalite SSC
+    Opalite,
+    /// Pyrite SSC 1.0
+    Pyrite1,
+    /// Pyrite SSC 2.0
+    Pyrite2,
+    /// Ruby SSC
+    Ruby,
+}
+
+impl TcgSsc {
+    /// Get the SSC of a level 0 feature code.
+    fn from_feature_code(code: u16) -> Option<Self> {
+        match code {
+            0x0100 => Some(Self::Enterprise),
+            0x0200 => Some(Self::Opal1),
+            0x0203 => Some(Self::Opal2),
+            0x0301 => Some(Self::Opalite),
+            0x0302 => Some(Self::Pyrite1),
+            0x0303 => Some(Self::Pyrite2),
+            0x0304 => Some(Self::Ruby),
+            _ => None,
+        }
+    }
+}
+
+/// Locking feature descriptor of level 0 discovery.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockingFeature {
+    /// The locking SP is supported
+    pub supported: bool,
+    /// The locking SP is activated
+    pub enabled: bool,
+    /// A locking range is locked
+    pub locked: bool,
+    /// User data is encrypted, so it can be erased with GenKey
+    pub media_encryption: bool,
+    /// The shadow MBR is enabled
+    pub mbr_enabled: bool,
+    /// The shadow MBR is marked done
+    pub mbr_done: bool,
+    /// The shadow MBR is supported
+    pub mbr_shadowing: bool,
+}
+
+/// An operation on the locking SP, checked with `TcgDiscovery::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcgOperation {
+    /// Configure a locking range (0 = global range)
+    LockingRange(u16),
+    /// Erase a locking range by generating a new media encryption key
+    GenKey,
+    /// Use the shadow MBR
+    ShadowMbr,
+    /// Enable or authenticate as locking SP user `n` (1-based)
+    User(u16),
+}
+
+/// Level 0 discovery response of a TCG drive.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TcgDiscovery {
+    /// Data structure revision
+    pub version: u32,
+    /// Security subsystem class, `None` if no known SSC is reported
+    pub ssc: Option<TcgSsc>,
+    /// First ComID of the SSC
+    pub base_comid: u16,
+    /// Number of ComIDs of the SSC
+    pub num_comids: u16,
+    /// Locking feature, if reported
+    pub locking: Option<LockingFeature>,
+    /// Number of locking SP users, if the SSC reports it
+    pub locking_users: Option<u16>,
+    /// Feature codes of all descriptors
+    pub features: Vec<u16>,
+}
+
+impl TcgDiscovery {
+    /// Size of the level 0 discovery header.
+    const HEADER_SIZE: usize = 48;
+
+    /// Parse a level 0 discovery response.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(Error::InvalidBufferSize);
+        }
+        // The length field does not count itself
+        let length = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        let end = length.saturating_add(4).min(data.len());
+        let mut discovery = Self {
+            version: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            ..Default::default()
+        };
+
+        let be16 = |bytes: &[u8], offset: usize| {
+            u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+        };
+        let mut offset = Self::HEADER_SIZE;
+        while offset + 4 <= end {
+            let code = be16(data, offset);
+            let Some(desc) = data.get(offset..(offset + 4 + data[offset + 3] as usize).min(end))
+            else {
+                break;
+            };
+            discovery.features.push(code);
+
+            if code == 0x0002 && desc.len() > 4 {
+                let flags = desc[4];
+                discovery.locking = Some(LockingFeature {
+                    supported: flags & (1 << 0) != 0,
+                    enabled: flags & (1 << 1) != 0,
+                    locked: flags & (1 << 2) != 0,
+                    media_encryption: flags & (1 << 3) != 0,
+                    mbr_enabled: flags & (1 << 4) != 0,
+                    mbr_done: flags & (1 << 5) != 0,
+                    mbr_shadowing: flags & (1 << 6) == 0,
+                });
+            } else if let Some(ssc) = TcgSsc::from_feature_code(code)
+                && discovery.ssc.is_none()
+                && desc.len() >= 8
+            {
+                discovery.ssc = Some(ssc);
+                discovery.base_comid = be16(desc, 4);
+                discovery.num_comids = be16(desc, 6);
+                if matches!(ssc, TcgSsc::Opal2 | TcgSsc::Ruby) && desc.len() >= 13 {
+                    discovery.locking_users = Some(be16(desc, 11));
+                }
+            }
+            offset += desc.len();
+        }
+
+        Ok(discovery)
+    }
+
+    /// Check whether the drive offers a locking SP operation.
+    ///
+    /// Pyrite and Opalite drives only have the global locking range, and
+    /// drives without media encryption cannot erase with GenKey. Fails
+    /// with `Error::TcgOperationNotSupported` instead of letting the drive
+    /// reject the method call.
+    pub fn check(&self, op: TcgOperation) -> Result<()> {
+        let ssc = self.ssc.ok_or(Error::TcgOperationNotSupported)?;
+        let locking = self
+            .locking
+            .filter(|locking| locking.supported)
+            .ok_or(Error::TcgOperationNotSupported)?;
+
+        let supported = match op {
+            TcgOperation::LockingRange(0) => true,
+            TcgOperation::LockingRange(_) => {
+                matches!(ssc, TcgSsc::Enterprise | TcgSsc::Opal1 | TcgSsc::Opal2 | TcgSsc::Ruby)
+            }
+            TcgOperation::GenKey => locking.media_encryption,
+            TcgOperation::ShadowMbr => locking.mbr_shadowing && ssc != TcgSsc::Enterprise,
+            TcgOperation::User(0) => false,
+            TcgOperation::User(user) => match ssc {
+                // Enterprise drives have band masters instead of users
+                TcgSsc::Enterprise => false,
+                TcgSsc::Pyrite1 | TcgSsc::Pyrite2 | TcgSsc::Opalite => user <= 2,
+                _ => self.locking_users.is_none_or(|users| user <= users),
+            },
+        };
+
+        match supported {
+            true => Ok(()),
+            false => Err(Error::TcgOperationNotSupported),
+        }
+    }
+}
+
 /// TCG (Trusted Computing Group) operations.
 #[derive(Debug, Clone)]
 pub struct TcgOperations {
@@ -222,7 +558,7 @@ impl TcgOperations {
     }
 
     /// Build TCG discovery command.
-    pub fn build_discovery_command(&self, cmd_id: u16, address: usize) -> Command {
+    pub fn build_discovery_command(&self, cmd_id: u16, address: u64) -> Command {
         Command::security_receive(
             cmd_id,
             0, // namespace ID
@@ -234,7 +570,7 @@ impl TcgOperations {
     }
 
     /// Build TCG properties command.
-    pub fn build_properties_command(&self, cmd_id: u16, address: usize) -> Command {
+    pub fn build_properties_command(&self, cmd_id: u16, address: u64) -> Command {
         Command::security_receive(
             cmd_id,
             0,
@@ -282,6 +618,8 @@ pub struct SecurityManager {
     sanitize_history: Vec<(u32, SanitizeAction, u64)>, // (namespace, action, timestamp)
     /// TCG operations handler
     tcg_ops: TcgOperations,
+    /// Last level 0 discovery response
+    tcg_discovery: Option<TcgDiscovery>,
     /// Crypto erase configurations
     crypto_configs: Vec<CryptoEraseConfig>,
 }
@@ -292,6 +630,7 @@ impl Default for SecurityManager {
             sanitize_status: None,
             sanitize_history: Vec::new(),
             tcg_ops: TcgOperations::new(),
+            tcg_discovery: None,
             crypto_configs: Vec::new(),
         }
     }
@@ -321,6 +660,27 @@ impl SecurityManager {
             .unwrap_or(true)
     }
 
+    /// Update the TCG features from a level 0 discovery response.
+    pub fn update_tcg_discovery(&mut self, data: &[u8]) -> Result<&TcgDiscovery> {
+        Ok(self.tcg_discovery.insert(TcgDiscovery::parse(data)?))
+    }
+
+    /// Get the last level 0 discovery response.
+    pub fn get_tcg_discovery(&self) -> Option<&TcgDiscovery> {
+        self.tcg_discovery.as_ref()
+    }
+
+    /// Check whether the drive offers a locking SP operation.
+    ///
+    /// Fails with `Error::TcgOperationNotSupported` before discovery, see
+    /// `TcgDiscovery::check`.
+    pub fn check_tcg_operation(&self, op: TcgOperation) -> Result<()> {
+        self.tcg_discovery
+            .as_ref()
+            .ok_or(Error::TcgOperationNotSupported)?
+            .check(op)
+    }
+
     /// Record sanitize operation.
     pub fn record_sanitize(&mut self, namespace_id: u32, action: SanitizeAction, timestamp: u64) {
         self.sanitize_history.push((namespace_id, action, timestamp));
@@ -369,7 +729,7 @@ impl SecurityManager {
         &self,
         cmd_id: u16,
         namespace_id: u32,
-        address: usize,
+        address: u64,
         protocol: SecurityProtocol,
         sp_specific: u16,
         transfer_length: u32,
@@ -389,7 +749,7 @@ impl SecurityManager {
         &self,
         cmd_id: u16,
         namespace_id: u32,
-        address: usize,
+        address: u64,
         protocol: SecurityProtocol,
         sp_specific: u16,
         allocation_length: u32,
@@ -0,0 +1,138 @@
+//! I/O queue auto-tuning based on completion latency.
+
+/// Queue tuning configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueTuningConfig {
+    /// Minimum number of I/O queues
+    pub min_queues: usize,
+    /// Maximum number of I/O queues
+    pub max_queues: usize,
+    /// Minimum per-queue depth
+    pub min_depth: usize,
+    /// Maximum per-queue depth
+    ///
+    /// Depths are limited to 255, one less than the I/O queue size.
+    pub max_depth: usize,
+    /// Average latency above which the load is considered too high
+    pub high_latency_us: u64,
+    /// Average latency below which there is headroom
+    pub low_latency_us: u64,
+    /// Number of consecutive samples required before acting
+    pub hysteresis: u32,
+}
+
+impl Default for QueueTuningConfig {
+    fn default() -> Self {
+        Self {
+            min_queues: 1,
+            max_queues: 8,
+            min_depth: 2,
+            max_depth: 255,
+            high_latency_us: 1000,
+            low_latency_us: 100,
+            hysteresis: 3,
+        }
+    }
+}
+
+/// Observed queue load over one sampling period.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueSample {
+    /// Current number of I/O queues
+    pub queue_count: usize,
+    /// Current per-queue depth
+    pub depth: usize,
+    /// Average completion latency in microseconds
+    pub avg_latency_us: u64,
+    /// Commands outstanding across all queues
+    pub outstanding: usize,
+}
+
+/// Queue configuration chosen by the tuner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueTarget {
+    /// Number of I/O queues
+    pub queue_count: usize,
+    /// Per-queue depth
+    pub depth: usize,
+}
+
+/// Grows and shrinks the I/O queue configuration based on latency.
+///
+/// High latency with busy queues adds a queue, otherwise it lowers the
+/// per-queue depth to cut queueing delay. Low latency raises the depth
+/// and removes queues that sit idle. A change is only made after
+/// `hysteresis` consecutive samples agree, so the configuration does
+/// not flap around the thresholds.
+#[derive(Debug, Clone)]
+pub struct QueueTuner {
+    /// Tuning configuration
+    config: QueueTuningConfig,
+    /// Consecutive samples above the high latency threshold
+    high_streak: u32,
+    /// Consecutive samples below the low latency threshold
+    low_streak: u32,
+}
+
+impl QueueTuner {
+    /// Create a new queue tuner.
+    pub fn new(config: QueueTuningConfig) -> Self {
+        Self {
+            config,
+            high_streak: 0,
+            low_streak: 0,
+        }
+    }
+
+    /// Get the tuning configuration.
+    pub fn config(&self) -> &QueueTuningConfig {
+        &self.config
+    }
+
+    /// Evaluate a sample.
+    ///
+    /// Returns the new queue configuration if it should change.
+    pub fn evaluate(&mut self, sample: &QueueSample) -> Option<QueueTarget> {
+        let config = &self.config;
+
+        if sample.avg_latency_us > config.high_latency_us {
+            self.high_streak += 1;
+            self.low_streak = 0;
+        } else if sample.avg_latency_us < config.low_latency_us {
+            self.low_streak += 1;
+            self.high_streak = 0;
+        } else {
+            self.high_streak = 0;
+            self.low_streak = 0;
+        }
+
+        let mut target = QueueTarget {
+            queue_count: sample.queue_count,
+            depth: sample.depth,
+        };
+
+        if self.high_streak >= config.hysteresis {
+            if sample.outstanding >= sample.queue_count && sample.queue_count < config.max_queues {
+                target.queue_count += 1;
+            } else {
+                target.depth = (sample.depth / 2).max(config.min_depth);
+            }
+        } else if self.low_streak >= config.hysteresis {
+            if sample.depth < config.max_depth {
+                target.depth = (sample.depth * 2).min(config.max_depth);
+            } else if sample.outstanding < sample.queue_count / 2
+                && sample.queue_count > config.min_queues
+            {
+                target.queue_count -= 1;
+            }
+        }
+
+        if target.queue_count == sample.queue_count && target.depth == sample.depth {
+            return None;
+        }
+
+        self.high_streak = 0;
+        self.low_streak = 0;
+        Some(target)
+    }
+}